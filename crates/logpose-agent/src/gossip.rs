@@ -0,0 +1,815 @@
+//! Gossip membership and failure detection between agents, following SWIM
+//! with the Lifeguard refinements.
+//!
+//! Each agent runs next to one registered instance and joins a pool of
+//! agents over UDP. Once per protocol period it pings one member; a member
+//! that does not answer is pinged indirectly through a few others, then
+//! suspected. A suspected member that does not refute the suspicion within
+//! the suspicion timeout is declared dead. Membership changes travel
+//! piggybacked on probes and in small gossip rounds, and members
+//! periodically exchange their full view to heal partitions.
+//!
+//! From Lifeguard:
+//! - an agent that misses acks and nacks itself slows its probing down
+//!   (local health multiplier), so a starved process does not accuse
+//!   healthy peers;
+//! - suspicion timeouts start long and shrink as independent members
+//!   confirm the suspicion;
+//! - the suspicion is sent along with pings to the suspected member, so it
+//!   can refute quickly.
+//!
+//! Agents report what they observe as [`Report`]s: the agent that starts a
+//! suspicion reports the member [`HealthStatus::Suspect`], the agent whose
+//! suspicion timeout expires reports it `Unhealthy`, and every agent
+//! reports its own instance `Healthy` when it joins or refutes a suspicion.
+//!
+//! With a shared key every datagram is sent behind an HMAC-SHA256 tag of
+//! its contents, and datagrams without a valid tag are dropped, so only
+//! agents holding the key can take part in the pool.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use logpose_core::HealthStatus;
+use logpose_core::credential::hmac_sha256;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Largest number of membership updates carried by one message.
+const MAX_PIGGYBACK: usize = 16;
+/// How often timers are checked.
+const TICK: Duration = Duration::from_millis(20);
+/// How long dead members are remembered, so that stale news of them is not
+/// taken for a rejoin.
+const DEAD_RECLAIM: Duration = Duration::from_secs(30);
+/// How often an agent that knows no members retries its join addresses.
+const JOIN_RETRY: Duration = Duration::from_secs(2);
+/// Length of the tag in front of each datagram when a key is set.
+const TAG_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Unique name of this agent within the pool
+    pub name: String,
+    pub bind: SocketAddr,
+    /// Address other agents reach this one on
+    pub advertise: SocketAddr,
+    /// Agents to join through
+    pub join: Vec<SocketAddr>,
+    /// The instance this agent watches over
+    pub instance: Option<InstanceRef>,
+    /// Shared key authenticating every message; without one messages are
+    /// neither signed nor checked
+    pub key: Option<Vec<u8>>,
+    pub probe_interval: Duration,
+    pub probe_timeout: Duration,
+    /// Members asked to probe a member that missed a direct probe
+    pub indirect_checks: usize,
+    /// Members sent each gossip round
+    pub gossip_nodes: usize,
+    pub gossip_interval: Duration,
+    /// Interval between full state exchanges with a random member
+    pub sync_interval: Duration,
+    /// Scales the number of times an update is passed on
+    pub retransmit_mult: u32,
+    /// Scales the shortest suspicion timeout
+    pub suspicion_mult: u32,
+    /// The longest suspicion timeout, as a multiple of the shortest
+    pub suspicion_max_mult: u32,
+    /// Upper bound of the local health multiplier
+    pub max_local_health: u32,
+}
+
+impl Config {
+    pub fn new(name: impl Into<String>, bind: SocketAddr) -> Self {
+        Self {
+            name: name.into(),
+            bind,
+            advertise: bind,
+            join: Vec::new(),
+            instance: None,
+            key: None,
+            probe_interval: Duration::from_secs(1),
+            probe_timeout: Duration::from_millis(500),
+            indirect_checks: 3,
+            gossip_nodes: 3,
+            gossip_interval: Duration::from_millis(200),
+            sync_interval: Duration::from_secs(30),
+            retransmit_mult: 4,
+            suspicion_mult: 4,
+            suspicion_max_mult: 6,
+            max_local_health: 8,
+        }
+    }
+}
+
+/// A registered instance, as reported to the registry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstanceRef {
+    pub id: Uuid,
+    pub namespace: String,
+}
+
+/// A health observation to report to the registry.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub instance: InstanceRef,
+    pub health: HealthStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+    /// Left the pool on its own
+    Left,
+}
+
+/// A change to one member, passed from agent to agent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Update {
+    Alive {
+        name: String,
+        addr: SocketAddr,
+        incarnation: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<InstanceRef>,
+    },
+    Suspect { name: String, incarnation: u64, from: String },
+    /// `from` equal to `name` means the member left
+    Dead { name: String, incarnation: u64, from: String },
+}
+
+impl Update {
+    fn name(&self) -> &str {
+        match self {
+            Update::Alive { name, .. } | Update::Suspect { name, .. } | Update::Dead { name, .. } => name,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Ping { seq: u64, from: String, target: String, updates: Vec<Update> },
+    /// Asks the receiver to ping `target` and pass its ack on
+    PingReq { seq: u64, from: String, target: String, addr: SocketAddr, updates: Vec<Update> },
+    Ack { seq: u64, from: String, updates: Vec<Update> },
+    /// Sent by a member asked for an indirect probe that got no ack, so the
+    /// prober can tell a dead target from its own trouble
+    Nack { seq: u64, from: String },
+    Gossip { from: String, updates: Vec<Update> },
+    /// The sender's whole view; answered in kind when `reply` is set
+    Sync { from: String, members: Vec<Update>, reply: bool },
+}
+
+struct Member {
+    addr: SocketAddr,
+    instance: Option<InstanceRef>,
+    incarnation: u64,
+    state: MemberState,
+    since: Instant,
+    suspicion: Option<Suspicion>,
+}
+
+/// A running suspicion, whose timeout shrinks from `max` towards `min` as
+/// up to `k` other members confirm it.
+struct Suspicion {
+    started: Instant,
+    /// The member that raised the suspicion, then those that confirmed it
+    confirmers: HashSet<String>,
+    k: u32,
+    min: Duration,
+    max: Duration,
+    deadline: Instant,
+}
+
+impl Suspicion {
+    fn new(from: &str, members: usize, config: &Config) -> Self {
+        let scale = (members.max(1) as f64).log10().max(1.0);
+        let min = config.probe_interval.mul_f64(config.suspicion_mult as f64 * scale);
+        let max = min * config.suspicion_max_mult;
+        let k = match config.suspicion_mult.saturating_sub(2) {
+            k if (members as u32) < k + 2 => 0,
+            k => k,
+        };
+        let started = Instant::now();
+        let deadline = started + if k == 0 { min } else { max };
+        Self { started, confirmers: HashSet::from([from.to_string()]), k, min, max, deadline }
+    }
+
+    /// Counts a confirmation from `from`; false if it was already counted or
+    /// no more are needed.
+    fn confirm(&mut self, from: &str) -> bool {
+        let confirmations = self.confirmers.len() as u32 - 1;
+        if confirmations >= self.k || !self.confirmers.insert(from.to_string()) {
+            return false;
+        }
+        let fraction = ((confirmations + 2) as f64).ln() / ((self.k + 1) as f64).ln();
+        let timeout = self.max.saturating_sub((self.max - self.min).mul_f64(fraction.min(1.0)));
+        self.deadline = self.started + timeout.max(self.min);
+        true
+    }
+}
+
+/// The probe of the current protocol period.
+struct Probe {
+    seq: u64,
+    target: String,
+    /// When to fall back to indirect probes
+    indirect_at: Instant,
+    /// End of the protocol period
+    deadline: Instant,
+    acked: bool,
+    indirect: bool,
+    expected_nacks: usize,
+    nacks: usize,
+}
+
+/// A probe made on behalf of another member.
+struct Relay {
+    origin: SocketAddr,
+    origin_seq: u64,
+    deadline: Instant,
+}
+
+struct Queued {
+    update: Update,
+    transmits: u32,
+}
+
+pub struct Gossip {
+    config: Config,
+    socket: UdpSocket,
+    reports: mpsc::UnboundedSender<Report>,
+    members: HashMap<String, Member>,
+    incarnation: u64,
+    seq: u64,
+    local_health: u32,
+    queue: Vec<Queued>,
+    probe: Option<Probe>,
+    probe_order: Vec<String>,
+    next_probe: Instant,
+    next_gossip: Instant,
+    next_sync: Instant,
+    relays: HashMap<u64, Relay>,
+}
+
+impl Gossip {
+    pub async fn bind(config: Config, reports: mpsc::UnboundedSender<Report>) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(config.bind).await?;
+        let now = Instant::now();
+        Ok(Self {
+            config,
+            socket,
+            reports,
+            members: HashMap::new(),
+            incarnation: 0,
+            seq: 0,
+            local_health: 0,
+            queue: Vec::new(),
+            probe: None,
+            probe_order: Vec::new(),
+            next_probe: now,
+            next_gossip: now,
+            next_sync: now,
+            relays: HashMap::new(),
+        })
+    }
+
+    /// Takes part in the pool until `shutdown` completes, then leaves it.
+    pub async fn run(mut self, shutdown: impl std::future::Future<Output = ()>) {
+        tracing::info!(name = %self.config.name, addr = %self.config.advertise, "gossip agent started");
+        self.report_self();
+        let mut tick = tokio::time::interval(TICK);
+        let mut buf = vec![0u8; 65_536];
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = tick.tick() => self.on_tick().await,
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, src)) => match self.open(&buf[..len]).map(serde_json::from_slice::<Message>) {
+                        Some(Ok(message)) => self.on_message(message, src).await,
+                        Some(Err(e)) => tracing::debug!(%src, "dropping malformed message: {}", e),
+                        None => tracing::debug!(%src, "dropping message without a valid tag"),
+                    },
+                    Err(e) => tracing::debug!("receive failed: {}", e),
+                },
+            }
+        }
+        self.leave().await;
+    }
+
+    async fn leave(&mut self) {
+        let update = Update::Dead {
+            name: self.config.name.clone(),
+            incarnation: self.incarnation,
+            from: self.config.name.clone(),
+        };
+        let message = Message::Gossip { from: self.config.name.clone(), updates: vec![update] };
+        for addr in self.live_members(None).into_iter().map(|(_, addr)| addr).collect::<Vec<_>>() {
+            self.send(addr, &message).await;
+        }
+        tracing::info!(name = %self.config.name, "left the gossip pool");
+    }
+
+    async fn on_tick(&mut self) {
+        let now = Instant::now();
+
+        if self.probe.as_ref().is_some_and(|probe| !probe.acked && !probe.indirect && now >= probe.indirect_at) {
+            self.probe_indirectly().await;
+        }
+        if self.probe.as_ref().is_some_and(|probe| now >= probe.deadline) {
+            self.finish_probe();
+        }
+        if self.probe.is_none() && now >= self.next_probe {
+            self.start_probe().await;
+        }
+
+        let expired: Vec<u64> = self.relays.iter().filter(|(_, r)| now >= r.deadline).map(|(seq, _)| *seq).collect();
+        for seq in expired {
+            if let Some(relay) = self.relays.remove(&seq) {
+                let nack = Message::Nack { seq: relay.origin_seq, from: self.config.name.clone() };
+                self.send(relay.origin, &nack).await;
+            }
+        }
+
+        let confirmed: Vec<(String, u64)> = self
+            .members
+            .iter()
+            .filter(|(_, m)| m.suspicion.as_ref().is_some_and(|s| now >= s.deadline))
+            .map(|(name, m)| (name.clone(), m.incarnation))
+            .collect();
+        for (name, incarnation) in confirmed {
+            let from = self.config.name.clone();
+            self.on_dead(&name, incarnation, &from);
+        }
+
+        self.members
+            .retain(|_, m| !matches!(m.state, MemberState::Dead | MemberState::Left) || now < m.since + DEAD_RECLAIM);
+
+        if now >= self.next_gossip {
+            self.next_gossip = now + self.config.gossip_interval;
+            self.gossip().await;
+        }
+        if now >= self.next_sync {
+            self.sync().await;
+        }
+    }
+
+    /// Members that are alive or suspected, except `except`.
+    fn live_members(&self, except: Option<&str>) -> Vec<(String, SocketAddr)> {
+        self.members
+            .iter()
+            .filter(|(name, m)| matches!(m.state, MemberState::Alive | MemberState::Suspect) && Some(name.as_str()) != except)
+            .map(|(name, m)| (name.clone(), m.addr))
+            .collect()
+    }
+
+    /// Probe timings are stretched while this agent itself seems unwell.
+    fn scaled(&self, duration: Duration) -> Duration {
+        duration * (self.local_health + 1)
+    }
+
+    fn adjust_local_health(&mut self, delta: i32) {
+        let max = self.config.max_local_health.saturating_sub(1);
+        self.local_health = self.local_health.saturating_add_signed(delta).min(max);
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    async fn start_probe(&mut self) {
+        let now = Instant::now();
+        self.next_probe = now + self.scaled(self.config.probe_interval);
+
+        let target = loop {
+            if self.probe_order.is_empty() {
+                self.probe_order = self.live_members(None).into_iter().map(|(name, _)| name).collect();
+                self.probe_order.shuffle(&mut rand::thread_rng());
+                if self.probe_order.is_empty() {
+                    return;
+                }
+            }
+            let name = self.probe_order.pop().unwrap();
+            if self.members.get(&name).is_some_and(|m| matches!(m.state, MemberState::Alive | MemberState::Suspect)) {
+                break name;
+            }
+        };
+        let member = &self.members[&target];
+        let addr = member.addr;
+        // A suspected member hears of it directly, to refute it sooner.
+        let suspicion = (member.state == MemberState::Suspect).then(|| Update::Suspect {
+            name: target.clone(),
+            incarnation: member.incarnation,
+            from: self.config.name.clone(),
+        });
+
+        let seq = self.next_seq();
+        let mut updates = self.piggyback();
+        updates.extend(suspicion);
+        let ping = Message::Ping { seq, from: self.config.name.clone(), target: target.clone(), updates };
+        self.send(addr, &ping).await;
+        self.probe = Some(Probe {
+            seq,
+            target,
+            indirect_at: now + self.scaled(self.config.probe_timeout),
+            deadline: self.next_probe,
+            acked: false,
+            indirect: false,
+            expected_nacks: 0,
+            nacks: 0,
+        });
+    }
+
+    async fn probe_indirectly(&mut self) {
+        let Some(probe) = &self.probe else { return };
+        let (seq, target) = (probe.seq, probe.target.clone());
+        let Some(addr) = self.members.get(&target).map(|m| m.addr) else { return };
+        let mut helpers = self.live_members(Some(&target));
+        helpers.shuffle(&mut rand::thread_rng());
+        helpers.truncate(self.config.indirect_checks);
+        for (_, helper) in &helpers {
+            let request = Message::PingReq {
+                seq,
+                from: self.config.name.clone(),
+                target: target.clone(),
+                addr,
+                updates: self.piggyback(),
+            };
+            self.send(*helper, &request).await;
+        }
+        if let Some(probe) = &mut self.probe {
+            probe.indirect = true;
+            probe.expected_nacks = helpers.len();
+        }
+    }
+
+    fn finish_probe(&mut self) {
+        let Some(probe) = self.probe.take() else { return };
+        if probe.acked {
+            self.adjust_local_health(-1);
+            return;
+        }
+        // Helpers that did not even send a nack suggest the problem is ours.
+        let missed_nacks = probe.indirect && probe.nacks < probe.expected_nacks;
+        self.adjust_local_health(if missed_nacks { 2 } else { 1 });
+        // Raises a suspicion, or confirms one raised by another member.
+        if let Some(member) = self.members.get(&probe.target)
+            && matches!(member.state, MemberState::Alive | MemberState::Suspect)
+        {
+            let incarnation = member.incarnation;
+            let from = self.config.name.clone();
+            tracing::info!(member = %probe.target, "probe failed");
+            self.on_suspect(&probe.target, incarnation, &from);
+        }
+    }
+
+    async fn on_message(&mut self, message: Message, src: SocketAddr) {
+        match message {
+            Message::Ping { seq, target, updates, .. } => {
+                self.merge(updates);
+                if target != self.config.name {
+                    return;
+                }
+                let ack = Message::Ack { seq, from: self.config.name.clone(), updates: self.piggyback() };
+                self.send(src, &ack).await;
+            }
+            Message::PingReq { seq, target, addr, updates, .. } => {
+                self.merge(updates);
+                let relay_seq = self.next_seq();
+                let ping = Message::Ping { seq: relay_seq, from: self.config.name.clone(), target, updates: self.piggyback() };
+                self.send(addr, &ping).await;
+                // Early enough for the nack to reach the prober within its period.
+                let deadline = Instant::now() + self.config.probe_timeout.mul_f64(0.8);
+                self.relays.insert(relay_seq, Relay { origin: src, origin_seq: seq, deadline });
+            }
+            Message::Ack { seq, updates, .. } => {
+                self.merge(updates);
+                if let Some(probe) = self.probe.as_mut().filter(|probe| probe.seq == seq) {
+                    probe.acked = true;
+                } else if let Some(relay) = self.relays.remove(&seq) {
+                    let ack = Message::Ack { seq: relay.origin_seq, from: self.config.name.clone(), updates: Vec::new() };
+                    self.send(relay.origin, &ack).await;
+                }
+            }
+            Message::Nack { seq, .. } => {
+                if let Some(probe) = self.probe.as_mut().filter(|probe| probe.seq == seq) {
+                    probe.nacks += 1;
+                }
+            }
+            Message::Gossip { updates, .. } => self.merge(updates),
+            Message::Sync { members, reply, .. } => {
+                self.merge(members);
+                if reply {
+                    let sync = Message::Sync { from: self.config.name.clone(), members: self.state(), reply: false };
+                    self.send(src, &sync).await;
+                }
+            }
+        }
+    }
+
+    fn merge(&mut self, updates: Vec<Update>) {
+        for update in updates {
+            match update {
+                Update::Alive { name, addr, incarnation, instance } => self.on_alive(&name, addr, incarnation, instance),
+                Update::Suspect { name, incarnation, from } => self.on_suspect(&name, incarnation, &from),
+                Update::Dead { name, incarnation, from } => self.on_dead(&name, incarnation, &from),
+            }
+        }
+    }
+
+    fn on_alive(&mut self, name: &str, addr: SocketAddr, incarnation: u64, instance: Option<InstanceRef>) {
+        if name == self.config.name {
+            // News of an earlier run of this agent.
+            if incarnation > self.incarnation {
+                self.refute(incarnation);
+            }
+            return;
+        }
+        let now = Instant::now();
+        match self.members.get_mut(name) {
+            Some(member) if incarnation <= member.incarnation => return,
+            Some(member) => {
+                if member.state != MemberState::Alive {
+                    tracing::info!(member = name, %addr, "member alive");
+                }
+                if member.state != MemberState::Alive || member.addr != addr {
+                    member.since = now;
+                }
+                member.addr = addr;
+                member.instance = instance.clone();
+                member.incarnation = incarnation;
+                member.state = MemberState::Alive;
+                member.suspicion = None;
+            }
+            None => {
+                tracing::info!(member = name, %addr, "member joined");
+                self.members.insert(
+                    name.to_string(),
+                    Member { addr, instance: instance.clone(), incarnation, state: MemberState::Alive, since: now, suspicion: None },
+                );
+            }
+        }
+        self.broadcast(Update::Alive { name: name.to_string(), addr, incarnation, instance });
+    }
+
+    fn on_suspect(&mut self, name: &str, incarnation: u64, from: &str) {
+        if name == self.config.name {
+            if incarnation >= self.incarnation {
+                tracing::warn!(by = from, "refuting suspicion of this agent");
+                self.adjust_local_health(1);
+                self.refute(incarnation);
+            }
+            return;
+        }
+        let alive = self.live_members(None).len() + 1;
+        let Some(member) = self.members.get_mut(name) else { return };
+        if incarnation < member.incarnation {
+            return;
+        }
+        match member.state {
+            MemberState::Dead | MemberState::Left => return,
+            MemberState::Suspect if incarnation == member.incarnation => {
+                let confirmed = member.suspicion.as_mut().is_some_and(|s| s.confirm(from));
+                if !confirmed {
+                    return;
+                }
+            }
+            _ => {
+                tracing::info!(member = name, by = from, "member suspected");
+                member.incarnation = incarnation;
+                member.state = MemberState::Suspect;
+                member.since = Instant::now();
+                member.suspicion = Some(Suspicion::new(from, alive, &self.config));
+                if from == self.config.name
+                    && let Some(instance) = member.instance.clone()
+                {
+                    self.report(instance, HealthStatus::Suspect);
+                }
+            }
+        }
+        self.broadcast(Update::Suspect { name: name.to_string(), incarnation, from: from.to_string() });
+    }
+
+    fn on_dead(&mut self, name: &str, incarnation: u64, from: &str) {
+        if name == self.config.name {
+            // Also news of an earlier run that left.
+            if incarnation >= self.incarnation {
+                tracing::warn!(by = from, "refuting death of this agent");
+                self.refute(incarnation);
+            }
+            return;
+        }
+        let Some(member) = self.members.get_mut(name) else { return };
+        if incarnation < member.incarnation || matches!(member.state, MemberState::Dead | MemberState::Left) {
+            return;
+        }
+        member.incarnation = incarnation;
+        member.since = Instant::now();
+        member.suspicion = None;
+        if from == name {
+            tracing::info!(member = name, "member left");
+            member.state = MemberState::Left;
+        } else {
+            tracing::info!(member = name, by = from, "member dead");
+            member.state = MemberState::Dead;
+            if from == self.config.name
+                && let Some(instance) = member.instance.clone()
+            {
+                self.report(instance, HealthStatus::Unhealthy);
+            }
+        }
+        self.broadcast(Update::Dead { name: name.to_string(), incarnation, from: from.to_string() });
+    }
+
+    /// Outbids a claim about this agent made at `incarnation`.
+    fn refute(&mut self, incarnation: u64) {
+        self.incarnation = self.incarnation.max(incarnation) + 1;
+        self.broadcast(self.alive());
+        self.report_self();
+    }
+
+    fn report_self(&self) {
+        if let Some(instance) = self.config.instance.clone() {
+            self.report(instance, HealthStatus::Healthy);
+        }
+    }
+
+    fn report(&self, instance: InstanceRef, health: HealthStatus) {
+        let _ = self.reports.send(Report { instance, health });
+    }
+
+    fn alive(&self) -> Update {
+        Update::Alive {
+            name: self.config.name.clone(),
+            addr: self.config.advertise,
+            incarnation: self.incarnation,
+            instance: self.config.instance.clone(),
+        }
+    }
+
+    /// This agent's view of the pool, as updates.
+    fn state(&self) -> Vec<Update> {
+        let mut updates = vec![self.alive()];
+        for (name, member) in &self.members {
+            let (name, incarnation) = (name.clone(), member.incarnation);
+            updates.push(match member.state {
+                MemberState::Alive => {
+                    Update::Alive { name, addr: member.addr, incarnation, instance: member.instance.clone() }
+                }
+                MemberState::Suspect => Update::Suspect { name, incarnation, from: self.config.name.clone() },
+                MemberState::Dead => Update::Dead { name, incarnation, from: self.config.name.clone() },
+                MemberState::Left => Update::Dead { from: name.clone(), name, incarnation },
+            });
+        }
+        updates
+    }
+
+    /// Queues `update` to be passed on, replacing older news of the member.
+    fn broadcast(&mut self, update: Update) {
+        self.queue.retain(|queued| queued.update.name() != update.name());
+        self.queue.push(Queued { update, transmits: 0 });
+    }
+
+    /// Updates to attach to an outgoing message. Each is sent a number of
+    /// times growing with the logarithm of the pool size.
+    fn piggyback(&mut self) -> Vec<Update> {
+        let members = self.live_members(None).len() + 1;
+        let limit = self.config.retransmit_mult * ((members + 1) as f64).log10().ceil().max(1.0) as u32;
+        self.queue.sort_by_key(|queued| queued.transmits);
+        let updates = self
+            .queue
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|queued| {
+                queued.transmits += 1;
+                queued.update.clone()
+            })
+            .collect();
+        self.queue.retain(|queued| queued.transmits < limit);
+        updates
+    }
+
+    async fn gossip(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        let mut targets = self.live_members(None);
+        targets.shuffle(&mut rand::thread_rng());
+        targets.truncate(self.config.gossip_nodes);
+        for (_, addr) in targets {
+            let message = Message::Gossip { from: self.config.name.clone(), updates: self.piggyback() };
+            self.send(addr, &message).await;
+        }
+    }
+
+    async fn sync(&mut self) {
+        let now = Instant::now();
+        let message = Message::Sync { from: self.config.name.clone(), members: self.state(), reply: true };
+        let live = self.live_members(None);
+        if live.is_empty() {
+            self.next_sync = now + JOIN_RETRY;
+            for addr in self.config.join.clone() {
+                if addr != self.config.advertise {
+                    self.send(addr, &message).await;
+                }
+            }
+        } else {
+            self.next_sync = now + self.config.sync_interval;
+            let target = live.choose(&mut rand::thread_rng()).map(|(_, addr)| *addr);
+            if let Some(addr) = target {
+                self.send(addr, &message).await;
+            }
+        }
+    }
+
+    /// The message in `datagram`, or `None` if a key is set and the tag in
+    /// front of it does not match.
+    fn open<'a>(&self, datagram: &'a [u8]) -> Option<&'a [u8]> {
+        let Some(key) = &self.config.key else { return Some(datagram) };
+        let (tag, message) = datagram.split_at_checked(TAG_LEN)?;
+        bool::from(hmac_sha256(key, &[message]).ct_eq(tag)).then_some(message)
+    }
+
+    async fn send(&self, addr: SocketAddr, message: &Message) {
+        let mut bytes = match serde_json::to_vec(message) {
+            Ok(bytes) => bytes,
+            Err(e) => return tracing::error!("failed to encode message: {}", e),
+        };
+        if let Some(key) = &self.config.key {
+            bytes.splice(0..0, hmac_sha256(key, &[&bytes]));
+        }
+        if let Err(e) = self.socket.send_to(&bytes, addr).await {
+            tracing::debug!(%addr, "send failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::new("a", "127.0.0.1:7946".parse().unwrap())
+    }
+
+    fn timeout(suspicion: &Suspicion) -> Duration {
+        suspicion.deadline - suspicion.started
+    }
+
+    #[test]
+    fn suspicion_starts_at_the_longest_timeout() {
+        let suspicion = Suspicion::new("b", 10, &config());
+        assert_eq!((suspicion.min, suspicion.max, suspicion.k), (Duration::from_secs(4), Duration::from_secs(24), 2));
+        assert_eq!(timeout(&suspicion), Duration::from_secs(24));
+    }
+
+    #[test]
+    fn suspicion_timeout_grows_with_the_pool() {
+        let suspicion = Suspicion::new("b", 1000, &config());
+        assert_eq!((suspicion.min, suspicion.max), (Duration::from_secs(12), Duration::from_secs(72)));
+    }
+
+    #[test]
+    fn small_pool_uses_the_shortest_timeout_at_once() {
+        let suspicion = Suspicion::new("b", 3, &config());
+        assert_eq!(suspicion.k, 0);
+        assert_eq!(timeout(&suspicion), suspicion.min);
+    }
+
+    #[test]
+    fn confirmations_shrink_the_timeout_to_the_shortest() {
+        let mut suspicion = Suspicion::new("b", 10, &config());
+        assert!(suspicion.confirm("c"));
+        let after_one = timeout(&suspicion);
+        assert!(after_one < suspicion.max && after_one > suspicion.min, "{:?}", after_one);
+        // ln 2 / ln 3 of the way from 24s down to 4s
+        assert_eq!(after_one.as_millis(), 11_381);
+
+        assert!(suspicion.confirm("d"));
+        assert_eq!(timeout(&suspicion), suspicion.min);
+    }
+
+    #[test]
+    fn confirmations_count_once_per_member_and_up_to_k() {
+        let mut suspicion = Suspicion::new("b", 10, &config());
+        assert!(!suspicion.confirm("b"), "the member that raised it");
+        assert!(suspicion.confirm("c"));
+        assert!(!suspicion.confirm("c"));
+        assert!(suspicion.confirm("d"));
+        assert!(!suspicion.confirm("e"), "k confirmations are enough");
+        assert_eq!(timeout(&suspicion), suspicion.min);
+    }
+}
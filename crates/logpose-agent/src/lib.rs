@@ -1,3 +1,6 @@
+pub mod gossip;
+pub mod report;
+
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
 }
@@ -1,9 +1,70 @@
+use clap::{Args, Parser, Subcommand};
+use logpose_agent::gossip::{self, Gossip, InstanceRef};
+use logpose_agent::report::{Credentials, Reporter};
+use logpose_core::DEFAULT_NAMESPACE;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use reqwest::Client;
 
+/// Shortest gossip key accepted.
+const MIN_KEY_LEN: usize = 16;
+
+/// Serves LogPose to AI assistants over MCP on stdin/stdout, or runs as a
+/// gossip agent next to an instance.
+#[derive(Parser)]
+#[command(name = "logpose-agent")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Join a gossip pool of agents and report the health of its members to the registry
+    Gossip(GossipArgs),
+}
+
+#[derive(Args)]
+struct GossipArgs {
+    /// Unique name of this agent in the pool [default: the advertised address]
+    #[arg(long, env = "LOGPOSE_GOSSIP_NAME")]
+    name: Option<String>,
+    /// UDP address to listen on
+    #[arg(long, env = "LOGPOSE_GOSSIP_BIND", default_value = "127.0.0.1:7946")]
+    bind: SocketAddr,
+    /// Address other agents reach this one on [default: the bind address]
+    #[arg(long, env = "LOGPOSE_GOSSIP_ADVERTISE")]
+    advertise: Option<SocketAddr>,
+    /// Address of an agent already in the pool; repeatable
+    #[arg(long, env = "LOGPOSE_GOSSIP_JOIN", value_delimiter = ',')]
+    join: Vec<SocketAddr>,
+    /// ID of the registered instance this agent runs next to
+    #[arg(long, env = "LOGPOSE_GOSSIP_INSTANCE")]
+    instance: Option<uuid::Uuid>,
+    /// Namespace of that instance
+    #[arg(long, env = "LOGPOSE_NAMESPACE", default_value = DEFAULT_NAMESPACE)]
+    namespace: String,
+    /// Key shared by every agent of the pool; messages not signed with it are dropped
+    #[arg(long, env = "LOGPOSE_GOSSIP_KEY", hide_env_values = true)]
+    key: Option<String>,
+    #[arg(long, default_value_t = 1000)]
+    probe_interval_ms: u64,
+    #[arg(long, default_value_t = 500)]
+    probe_timeout_ms: u64,
+    /// Scales how long a member stays suspected before it is declared dead
+    #[arg(long, default_value_t = 4)]
+    suspicion_mult: u32,
+    /// Identity to report health as, instead of LOGPOSE_TOKEN
+    #[arg(long, env = "LOGPOSE_COMMON_NAME")]
+    common_name: Option<String>,
+    #[arg(long, env = "LOGPOSE_SECRET", hide_env_values = true)]
+    secret: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct JsonRpcRequest {
     jsonrpc: String,
@@ -26,16 +87,67 @@ struct AgentState {
     client: Client,
     server_url: String,
     token: Option<String>,
+    /// Namespace to discover in; the server's default namespace when unset
+    namespace: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
-    
+
+    match Cli::parse().command {
+        Some(Command::Gossip(args)) => run_gossip(args).await,
+        None => serve_mcp().await,
+    }
+}
+
+async fn run_gossip(args: GossipArgs) -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
+
+    if args.probe_timeout_ms == 0 || args.probe_timeout_ms >= args.probe_interval_ms {
+        return Err("--probe-timeout-ms must be non-zero and shorter than --probe-interval-ms".into());
+    }
+    if args.key.as_ref().is_some_and(|key| key.len() < MIN_KEY_LEN) {
+        return Err(format!("--key must be at least {} characters long", MIN_KEY_LEN).into());
+    }
+    let advertise = args.advertise.unwrap_or(args.bind);
+    let mut config = gossip::Config::new(args.name.unwrap_or_else(|| advertise.to_string()), args.bind);
+    config.advertise = advertise;
+    config.join = args.join;
+    config.instance = args.instance.map(|id| InstanceRef { id, namespace: args.namespace });
+    match args.key {
+        Some(key) => config.key = Some(key.into_bytes()),
+        None => tracing::warn!("no --key; gossip messages are not authenticated"),
+    }
+    config.probe_interval = Duration::from_millis(args.probe_interval_ms);
+    config.probe_timeout = Duration::from_millis(args.probe_timeout_ms);
+    config.suspicion_mult = args.suspicion_mult;
+
+    let (reports, received) = tokio::sync::mpsc::unbounded_channel();
+    let credentials = match (std::env::var("LOGPOSE_TOKEN").ok(), args.common_name, args.secret) {
+        (_, Some(common_name), Some(secret)) => Some(Credentials::Secret { common_name, secret }),
+        (Some(token), _, _) => Some(Credentials::Token(token)),
+        _ => None,
+    };
+    match credentials {
+        Some(credentials) => {
+            let server_url = std::env::var("LOGPOSE_SERVER").unwrap_or_else(|_| "http://localhost:3000".to_string());
+            tokio::spawn(Reporter::new(server_url, credentials).run(received));
+        }
+        None => tracing::warn!("no LOGPOSE_TOKEN or --common-name and --secret; health will not be reported"),
+    }
+
+    let gossip = Gossip::bind(config, reports).await?;
+    gossip.run(async { tokio::signal::ctrl_c().await.unwrap_or_default() }).await;
+    Ok(())
+}
+
+async fn serve_mcp() -> Result<(), Box<dyn std::error::Error>> {
     let state = Arc::new(AgentState {
         client: Client::new(),
         server_url: std::env::var("LOGPOSE_SERVER").unwrap_or_else(|_| "http://localhost:3000".to_string()),
         token: std::env::var("LOGPOSE_TOKEN").ok(),
+        namespace: std::env::var("LOGPOSE_NAMESPACE").ok(),
     });
 
     let stdin = io::stdin();
@@ -104,6 +216,53 @@ async fn handle_request(req: JsonRpcRequest, state: Arc<AgentState>) -> Option<J
                         "required": ["service_code"]
                     }
                 },
+                {
+                    "name": "search_catalog",
+                    "description": "Find services by owner team, tier, tags or text in their catalog fields",
+                    "inputSchema": {
+                        "type": "object",
+                        "properties": {
+                            "query": {
+                                "type": "string",
+                                "description": "Text to find in codes, names, descriptions, owners, on-call contacts, repositories, tags and metadata"
+                            },
+                            "owner": {
+                                "type": "string",
+                                "description": "Team that owns the services"
+                            },
+                            "tier": {
+                                "type": "string",
+                                "enum": ["critical", "high", "medium", "low"]
+                            },
+                            "tags": {
+                                "type": "string",
+                                "description": "Comma-separated tags the services must all carry"
+                            }
+                        }
+                    }
+                },
+                {
+                    "name": "get_dependency_graph",
+                    "description": "Get the services with the services they depend on and the health of each",
+                    "inputSchema": {
+                        "type": "object",
+                        "properties": {}
+                    }
+                },
+                {
+                    "name": "service_impact",
+                    "description": "List the services that break, directly or transitively, if a service goes down",
+                    "inputSchema": {
+                        "type": "object",
+                        "properties": {
+                            "service_code": {
+                                "type": "string",
+                                "description": "The unique code of the service that goes down"
+                            }
+                        },
+                        "required": ["service_code"]
+                    }
+                },
                 {
                     "name": "get_mesh_status",
                     "description": "Get an overview of the entire LogPose service mesh status",
@@ -133,6 +292,32 @@ async fn handle_request(req: JsonRpcRequest, state: Arc<AgentState>) -> Option<J
                         Err(e) => Some(json!({ "content": [{ "type": "text", "text": format!("Error: {}", e) }], "isError": true })),
                     }
                 },
+                "search_catalog" => {
+                    let mut url = reqwest::Url::parse("http://localhost/api/services").expect("valid URL");
+                    for (param, arg) in [("q", "query"), ("owner", "owner"), ("tier", "tier"), ("tags", "tags")] {
+                        if let Some(value) = tool_args.get(arg).and_then(|v| v.as_str()) {
+                            url.query_pairs_mut().append_pair(param, value);
+                        }
+                    }
+                    let path = format!("{}?{}", url.path(), url.query().unwrap_or_default());
+                    match call_api(&state, "get", &path).await {
+                        Ok(data) => Some(json!({ "content": [{ "type": "text", "text": format!("Matching services: {}", data) }] })),
+                        Err(e) => Some(json!({ "content": [{ "type": "text", "text": format!("Error: {}", e) }], "isError": true })),
+                    }
+                },
+                "get_dependency_graph" => {
+                    match call_api(&state, "get", "/api/graph").await {
+                        Ok(data) => Some(json!({ "content": [{ "type": "text", "text": format!("Dependency graph: {}", data) }] })),
+                        Err(e) => Some(json!({ "content": [{ "type": "text", "text": format!("Error: {}", e) }], "isError": true })),
+                    }
+                },
+                "service_impact" => {
+                    let code = tool_args.get("service_code").and_then(|v| v.as_str()).unwrap_or_default();
+                    match call_api(&state, "get", &format!("/api/services/{}/impact", code)).await {
+                        Ok(data) => Some(json!({ "content": [{ "type": "text", "text": format!("Impact of {} going down: {}", code, data) }] })),
+                        Err(e) => Some(json!({ "content": [{ "type": "text", "text": format!("Error: {}", e) }], "isError": true })),
+                    }
+                },
                 "get_mesh_status" => {
                     match call_api(&state, "get", "/health").await {
                         Ok(data) => Some(json!({ "content": [{ "type": "text", "text": format!("Mesh Status: Server is {}", data) }] })),
@@ -154,7 +339,10 @@ async fn handle_request(req: JsonRpcRequest, state: Arc<AgentState>) -> Option<J
 }
 
 async fn call_api(state: &AgentState, method: &str, path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let url = format!("{}{}", state.server_url, path);
+    let url = match (&state.namespace, path.strip_prefix("/api/")) {
+        (Some(namespace), Some(rest)) => format!("{}/api/namespaces/{}/{}", state.server_url, namespace, rest),
+        _ => format!("{}{}", state.server_url, path),
+    };
     let builder = match method {
         "get" => state.client.get(&url),
         "post" => state.client.post(&url),
@@ -0,0 +1,106 @@
+//! Forwards the health observations of the gossip layer to the registry.
+
+use std::time::Duration;
+
+use logpose_core::DEFAULT_NAMESPACE;
+use reqwest::{Client, StatusCode};
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::gossip::Report;
+
+/// Attempts per report before it is dropped.
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How the reporter authenticates against the registry.
+pub enum Credentials {
+    Token(String),
+    /// Exchanged for a token at `/api/auth/token`, again whenever it expires
+    Secret { common_name: String, secret: String },
+}
+
+pub struct Reporter {
+    client: Client,
+    server_url: String,
+    credentials: Credentials,
+    token: Option<String>,
+}
+
+impl Reporter {
+    pub fn new(server_url: String, credentials: Credentials) -> Self {
+        let token = match &credentials {
+            Credentials::Token(token) => Some(token.clone()),
+            Credentials::Secret { .. } => None,
+        };
+        Self { client: Client::new(), server_url, credentials, token }
+    }
+
+    /// Sends reports in the order they were made until the gossip layer stops.
+    pub async fn run(mut self, mut reports: mpsc::UnboundedReceiver<Report>) {
+        while let Some(report) = reports.recv().await {
+            for attempt in 1..=ATTEMPTS {
+                match self.send(&report).await {
+                    Ok(()) => {
+                        tracing::info!(instance = %report.instance.id, health = ?report.health, "reported health");
+                        break;
+                    }
+                    Err(e) if attempt == ATTEMPTS => {
+                        tracing::warn!(instance = %report.instance.id, health = ?report.health, "failed to report health: {}", e);
+                    }
+                    Err(e) => {
+                        tracing::debug!(instance = %report.instance.id, "reporting health failed, retrying: {}", e);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, report: &Report) -> Result<(), String> {
+        let token = match &self.token {
+            Some(token) => token.clone(),
+            None => self.authenticate().await?,
+        };
+        let url = match report.instance.namespace.as_str() {
+            DEFAULT_NAMESPACE => format!("{}/api/instances/{}/health", self.server_url, report.instance.id),
+            namespace => format!("{}/api/namespaces/{}/instances/{}/health", self.server_url, namespace, report.instance.id),
+        };
+        let res = self
+            .client
+            .post(&url)
+            .bearer_auth(token)
+            .json(&json!({ "status": report.health }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        match res.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::UNAUTHORIZED if matches!(self.credentials, Credentials::Secret { .. }) => {
+                self.token = None;
+                Err("token expired".to_string())
+            }
+            status => Err(format!("API Error: {}", status)),
+        }
+    }
+
+    async fn authenticate(&mut self) -> Result<String, String> {
+        let Credentials::Secret { common_name, secret } = &self.credentials else {
+            return Err("no credentials".to_string());
+        };
+        let res = self
+            .client
+            .post(format!("{}/api/auth/token", self.server_url))
+            .json(&json!({ "common_name": common_name, "secret": secret }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("authentication failed: {}", res.status()));
+        }
+        let body: serde_json::Value = res.json().await.map_err(|e| e.to_string())?;
+        let token = body["token"].as_str().ok_or("no token in response")?.to_string();
+        self.token = Some(token.clone());
+        Ok(token)
+    }
+}
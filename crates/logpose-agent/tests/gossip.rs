@@ -0,0 +1,150 @@
+//! Several gossip agents on loopback ports, as separate pool members.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use logpose_agent::gossip::{Config, Gossip, InstanceRef, Report};
+use logpose_core::credential::hmac_sha256;
+use logpose_core::HealthStatus;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+struct Agent {
+    addr: SocketAddr,
+    instance: InstanceRef,
+    reports: mpsc::UnboundedReceiver<Report>,
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl Agent {
+    /// Leaves the pool the way Ctrl-C does.
+    async fn stop(mut self) {
+        let _ = self.stop.take().unwrap().send(());
+        self.task.await.unwrap();
+    }
+
+    /// Stops without leaving, as a crashed process would.
+    fn kill(self) {
+        self.task.abort();
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+async fn start(name: &str, join: &[SocketAddr]) -> Agent {
+    let addr = free_addr();
+    let instance = InstanceRef { id: Uuid::new_v4(), namespace: "default".to_string() };
+    let mut config = Config::new(name, addr);
+    config.join = join.to_vec();
+    config.instance = Some(instance.clone());
+    config.key = Some(KEY.to_vec());
+    config.probe_interval = Duration::from_millis(100);
+    config.probe_timeout = Duration::from_millis(50);
+    config.gossip_interval = Duration::from_millis(20);
+    config.sync_interval = Duration::from_millis(500);
+
+    let (reports, received) = mpsc::unbounded_channel();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let gossip = Gossip::bind(config, reports).await.unwrap();
+    let task = tokio::spawn(gossip.run(async {
+        let _ = stopped.await;
+    }));
+    Agent { addr, instance, reports: received, stop: Some(stop), task }
+}
+
+/// The next report of `agent` within `within`.
+async fn next_report(agent: &mut Agent, within: Duration) -> Option<Report> {
+    tokio::time::timeout(within, agent.reports.recv()).await.ok().flatten()
+}
+
+/// Reports about `instance` received by any of `agents` within `within`.
+async fn reports_about(agents: &mut [&mut Agent], instance: &InstanceRef, within: Duration) -> Vec<HealthStatus> {
+    let deadline = tokio::time::Instant::now() + within;
+    let mut seen = Vec::new();
+    while tokio::time::Instant::now() < deadline {
+        for agent in agents.iter_mut() {
+            while let Ok(report) = agent.reports.try_recv() {
+                if report.instance == *instance {
+                    seen.push(report.health);
+                }
+            }
+        }
+        if seen.contains(&HealthStatus::Unhealthy) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    seen
+}
+
+/// A datagram claiming `name` is suspected, tagged with `key` if given.
+fn forged_suspicion(name: &str, key: Option<&[u8]>) -> Vec<u8> {
+    let message = serde_json::json!({
+        "type": "gossip",
+        "from": "intruder",
+        "updates": [{ "kind": "suspect", "name": name, "incarnation": 0, "from": "intruder" }],
+    });
+    let mut datagram = serde_json::to_vec(&message).unwrap();
+    if let Some(key) = key {
+        datagram.splice(0..0, hmac_sha256(key, &[&datagram]));
+    }
+    datagram
+}
+
+#[tokio::test]
+async fn crashed_agent_is_reported_suspect_then_unhealthy() {
+    let mut a = start("a", &[]).await;
+    let mut b = start("b", &[a.addr]).await;
+    let mut c = start("c", &[a.addr]).await;
+    assert_eq!(next_report(&mut c, Duration::from_secs(1)).await.unwrap().health, HealthStatus::Healthy);
+
+    // Long enough for every agent to learn of every other and probe it.
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let instance = c.instance.clone();
+    assert!(reports_about(&mut [&mut a, &mut b], &instance, Duration::ZERO).await.is_empty(), "no false alarms");
+    c.kill();
+
+    let seen = reports_about(&mut [&mut a, &mut b], &instance, Duration::from_secs(10)).await;
+    assert_eq!(seen.first(), Some(&HealthStatus::Suspect), "{:?}", seen);
+    assert_eq!(seen.last(), Some(&HealthStatus::Unhealthy), "{:?}", seen);
+    a.stop().await;
+    b.stop().await;
+}
+
+#[tokio::test]
+async fn agent_that_leaves_is_not_reported() {
+    let mut a = start("a", &[]).await;
+    let mut b = start("b", &[a.addr]).await;
+    let c = start("c", &[a.addr]).await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let instance = c.instance.clone();
+    c.stop().await;
+    let seen = reports_about(&mut [&mut a, &mut b], &instance, Duration::from_secs(3)).await;
+    assert!(seen.is_empty(), "{:?}", seen);
+    a.stop().await;
+    b.stop().await;
+}
+
+#[tokio::test]
+async fn datagrams_without_a_valid_tag_are_dropped() {
+    let mut agent = start("a", &[]).await;
+    assert_eq!(next_report(&mut agent, Duration::from_secs(1)).await.unwrap().health, HealthStatus::Healthy);
+    let intruder = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    // An agent refutes a suspicion of itself and reports its instance again,
+    // so a report shows the datagram was accepted.
+    intruder.send_to(&forged_suspicion("a", None), agent.addr).unwrap();
+    intruder.send_to(&forged_suspicion("a", Some(b"not-the-pool-key")), agent.addr).unwrap();
+    assert!(next_report(&mut agent, Duration::from_millis(500)).await.is_none());
+
+    intruder.send_to(&forged_suspicion("a", Some(KEY)), agent.addr).unwrap();
+    assert_eq!(next_report(&mut agent, Duration::from_secs(1)).await.unwrap().health, HealthStatus::Healthy);
+    agent.stop().await;
+}
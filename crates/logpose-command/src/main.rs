@@ -1,5 +1,9 @@
 use clap::{Parser, Subcommand};
-use logpose_core::{Role, RegistryStore, Service, ServiceInstance, Identity, Protocol, Runtime};
+use logpose_core::audit::{self, identity_target, instance_target, kv_target, query_target, role_target, service_target, session_target, token_target, webhook_target, LOCAL_CLI_ACTOR};
+use logpose_core::{credential, namespace, AuditEntry, AuditFilter, AuditRecord, Grant, Permission, Policy, PreparedQuery, Role, RoleDefinition, RegistryStore, Service, ServiceInstance, Identity, Protocol, Runtime, Webhook, HealthStatus, DEFAULT_NAMESPACE};
+use logpose_core::webhook::EventKind;
+use logpose_core::query::{FailoverTarget, Locality};
+use logpose_core::{Catalog, CatalogSearch, ServiceGraph, ServicePatch, Tier};
 use logpose_db::DbRegistry;
 use std::net::SocketAddr;
 
@@ -12,6 +16,10 @@ struct Cli {
 
     #[arg(long, env = "DATABASE_URL", default_value = "logpose.db")]
     db: String,
+
+    /// Namespace of the services, instances and role assignments to act on
+    #[arg(long, global = true, env = "LOGPOSE_NAMESPACE", default_value = DEFAULT_NAMESPACE)]
+    namespace: String,
 }
 
 #[derive(Subcommand)]
@@ -31,6 +39,46 @@ enum Commands {
         #[command(subcommand)]
         sub: IdentityCommands,
     },
+    /// Custom role management
+    Role {
+        #[command(subcommand)]
+        sub: RoleCommands,
+    },
+    /// Prepared query management
+    Query {
+        #[command(subcommand)]
+        sub: QueryCommands,
+    },
+    /// Key/value store
+    Kv {
+        #[command(subcommand)]
+        sub: KvCommands,
+    },
+    /// Sessions holding locks
+    Session {
+        #[command(subcommand)]
+        sub: SessionCommands,
+    },
+    /// Webhooks receiving registry events
+    Webhook {
+        #[command(subcommand)]
+        sub: WebhookCommands,
+    },
+    /// Inspect authorization decisions
+    Policy {
+        #[command(subcommand)]
+        sub: PolicyCommands,
+    },
+    /// API token management
+    Token {
+        #[command(subcommand)]
+        sub: TokenCommands,
+    },
+    /// Inspect the audit log
+    Audit {
+        #[command(subcommand)]
+        sub: AuditCommands,
+    },
     /// Show registry status overview
     Status,
 }
@@ -45,9 +93,88 @@ enum ServiceCommands {
         code: String,
         #[arg(long)]
         description: String,
+        /// Code of a service this one calls; repeatable
+        #[arg(long = "depends-on")]
+        dependencies: Vec<String>,
+        #[command(flatten)]
+        catalog: CatalogArgs,
+        /// Service metadata as key=value; repeatable
+        #[arg(long = "meta", value_parser = parse_key_val)]
+        metadata: Vec<(String, String)>,
     },
-    /// List all registered services
-    List,
+    /// Change a service's fields; those not given are left as they are
+    Update {
+        #[arg(long)]
+        code: String,
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        /// Replaces the dependencies; repeatable
+        #[arg(long = "depends-on")]
+        dependencies: Vec<String>,
+        #[command(flatten)]
+        catalog: CatalogArgs,
+        /// Sets a metadata key, as key=value; repeatable
+        #[arg(long = "meta", value_parser = parse_key_val)]
+        metadata: Vec<(String, String)>,
+        /// Removes a metadata key; repeatable
+        #[arg(long = "remove-meta")]
+        remove_metadata: Vec<String>,
+        /// Clears a field: owner, on-call, repository, tier, tags or dependencies; repeatable
+        #[arg(long, value_parser = ["owner", "on-call", "repository", "tier", "tags", "dependencies"])]
+        clear: Vec<String>,
+    },
+    /// List the services of the namespace, optionally searching the catalog
+    List {
+        /// Text to find in codes, names, descriptions, catalog fields, tags and metadata
+        #[arg(long)]
+        search: Option<String>,
+        #[arg(long)]
+        owner: Option<String>,
+        #[arg(long)]
+        tier: Option<Tier>,
+        /// Tag the services must carry; repeatable
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+    },
+    /// Print a service, with its catalog fields, as JSON
+    Show {
+        #[arg(long)]
+        code: String,
+    },
+    /// Show the services of the namespace with their dependencies and health
+    Graph,
+    /// List the services affected, directly or transitively, when a service is down
+    Impact {
+        #[arg(long)]
+        code: String,
+    },
+    /// Deregister a service and all of its instances
+    Remove {
+        #[arg(long)]
+        code: String,
+    },
+}
+
+/// Catalog fields of a service.
+#[derive(clap::Args)]
+struct CatalogArgs {
+    /// Team that owns the service
+    #[arg(long)]
+    owner: Option<String>,
+    /// Who to page when the service is down
+    #[arg(long)]
+    on_call: Option<String>,
+    /// URL of the service's source repository
+    #[arg(long)]
+    repository: Option<String>,
+    /// critical, high, medium or low
+    #[arg(long)]
+    tier: Option<Tier>,
+    /// Tag of the service; repeatable, replaces the tags on update
+    #[arg(long = "tag")]
+    tags: Vec<String>,
 }
 
 #[derive(Subcommand)]
@@ -62,12 +189,20 @@ enum InstanceCommands {
         protocol: String,
         #[arg(long, default_value = "Container")]
         runtime: String,
+        /// Instance metadata as key=value, e.g. --meta weight=10 --meta zone=eu-west-1a
+        #[arg(long = "meta", value_parser = parse_key_val)]
+        metadata: Vec<(String, String)>,
     },
     /// List instances for a service or all instances
     List {
         #[arg(long)]
         service: Option<String>,
     },
+    /// Deregister an instance
+    Remove {
+        #[arg(long)]
+        id: uuid::Uuid,
+    },
 }
 
 #[derive(Subcommand)]
@@ -79,6 +214,13 @@ enum IdentityCommands {
         #[arg(long)]
         organization: Option<String>,
     },
+    /// List all identities
+    List,
+    /// Remove an identity together with its roles and secret
+    Remove {
+        #[arg(long)]
+        common_name: String,
+    },
     /// Assign a role to an identity
     AssignRole {
         #[arg(long)]
@@ -86,6 +228,344 @@ enum IdentityCommands {
         #[arg(long)]
         role: String,
     },
+    /// Take a role away from an identity
+    RemoveRole {
+        #[arg(long)]
+        common_name: String,
+        #[arg(long)]
+        role: String,
+    },
+    /// Block an identity from authenticating; its tokens stop working
+    Disable {
+        #[arg(long)]
+        common_name: String,
+    },
+    /// Allow a disabled identity to authenticate again
+    Enable {
+        #[arg(long)]
+        common_name: String,
+    },
+    /// Set the secret an identity exchanges for API tokens
+    SetSecret {
+        #[arg(long)]
+        common_name: String,
+        /// Secret to set; a random one is generated and printed when omitted
+        #[arg(long)]
+        secret: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RoleCommands {
+    /// Define a custom role
+    Create {
+        #[arg(long)]
+        name: String,
+        #[arg(long, default_value = "")]
+        description: String,
+        /// Permission, optionally limited to services matching a glob,
+        /// e.g. --grant ServiceRead --grant InstanceWrite:billing-*
+        #[arg(long = "grant", required = true)]
+        grants: Vec<Grant>,
+    },
+    /// List built-in and custom roles
+    List,
+    /// Delete a custom role and take it away from every identity holding it
+    Delete {
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum QueryCommands {
+    /// Store a prepared query
+    Create {
+        #[arg(long)]
+        name: String,
+        /// Code of the service to discover
+        #[arg(long)]
+        service: String,
+        /// Tag instances must carry in their `tags` metadata; repeatable
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Metadata instances must have, as key=value; repeatable
+        #[arg(long = "meta", value_parser = parse_key_val)]
+        metadata: Vec<(String, String)>,
+        /// Also return instances that are not healthy
+        #[arg(long)]
+        include_unhealthy: bool,
+        /// Locality whose instances are returned first
+        #[arg(long)]
+        region: Option<String>,
+        #[arg(long)]
+        zone: Option<String>,
+        #[arg(long)]
+        sub_zone: Option<String>,
+        /// Where to look when no instance matches, tried in order,
+        /// e.g. --failover service:payments-backup --failover dc:eu-west
+        #[arg(long)]
+        failover: Vec<FailoverTarget>,
+    },
+    /// List the prepared queries of the namespace
+    List,
+    /// Print a prepared query as JSON
+    Show {
+        #[arg(long)]
+        name: String,
+    },
+    /// Delete a prepared query
+    Delete {
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum KvCommands {
+    /// Print the value of a key
+    Get {
+        #[arg(long)]
+        key: String,
+    },
+    /// List the keys starting with a prefix, with their values
+    List {
+        #[arg(long, default_value = "")]
+        prefix: String,
+    },
+    /// Create or update a key
+    Put {
+        #[arg(long)]
+        key: String,
+        #[arg(long)]
+        value: String,
+        /// Write only if the key's modify index is still this; 0 writes only if the key does not exist
+        #[arg(long)]
+        cas: Option<u64>,
+    },
+    /// Delete a key
+    Delete {
+        #[arg(long)]
+        key: String,
+        /// Delete every key starting with the given one
+        #[arg(long)]
+        recurse: bool,
+        /// Delete only if the key's modify index is still this
+        #[arg(long, conflicts_with = "recurse")]
+        cas: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionCommands {
+    /// List the sessions of the namespace
+    List,
+    /// Destroy a session, releasing the locks it holds
+    Destroy {
+        #[arg(long)]
+        id: uuid::Uuid,
+    },
+}
+
+#[derive(Subcommand)]
+enum WebhookCommands {
+    /// Subscribe a URL to the events of the namespace; prints the signing secret
+    Create {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        url: String,
+        /// Event kind to deliver, e.g. instance_health_changed; repeatable, every kind when omitted
+        #[arg(long = "event")]
+        events: Vec<EventKind>,
+        /// Glob of the service codes whose events are delivered
+        #[arg(long, default_value = "*")]
+        services: String,
+        /// Deliver only health changes into this status; repeatable
+        #[arg(long = "status", value_parser = parse_status)]
+        statuses: Vec<HealthStatus>,
+        /// Key of the payload signature; generated when omitted
+        #[arg(long)]
+        secret: Option<String>,
+    },
+    /// List the webhooks of the namespace
+    List,
+    /// Delete a webhook with its delivery log and dead letters
+    Delete {
+        #[arg(long)]
+        id: uuid::Uuid,
+    },
+    /// Show the latest delivery attempts of a webhook
+    Deliveries {
+        #[arg(long)]
+        id: uuid::Uuid,
+    },
+    /// Show the deliveries of a webhook that failed on every attempt
+    DeadLetters {
+        #[arg(long)]
+        id: uuid::Uuid,
+    },
+}
+
+#[derive(Subcommand)]
+enum PolicyCommands {
+    /// Explain whether an identity may perform an action; exits with status 1 when denied
+    Check {
+        #[arg(long)]
+        common_name: String,
+        /// ServiceRead, ServiceWrite, InstanceRead, InstanceWrite, UserManage, AuditRead, ClusterManage, HealthReport, KvRead, KvWrite, Lock or WebhookManage
+        #[arg(long)]
+        permission: Permission,
+        /// Service the action targets, or key for KvRead, KvWrite and Lock
+        #[arg(long)]
+        service: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenCommands {
+    /// Revoke an access or refresh token
+    #[command(group(clap::ArgGroup::new("target").required(true).args(["jti", "token"])))]
+    Revoke {
+        /// ID (`jti` claim) of the token to revoke
+        #[arg(long)]
+        jti: Option<String>,
+        /// The token itself
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuditCommands {
+    /// Print the most recent audit records
+    Tail {
+        /// Number of records to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: u32,
+        #[arg(long)]
+        actor: Option<String>,
+        /// e.g. service.register, instance.health, identity.role.assign
+        #[arg(long)]
+        action: Option<String>,
+        /// e.g. service:default/auth, identity:my-svc
+        #[arg(long)]
+        target: Option<String>,
+        /// Keep printing records as they are appended
+        #[arg(short, long)]
+        follow: bool,
+        /// Print whole records, including before/after state, as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the hash chain of the whole log; exits with status 1 when broken
+    Verify,
+}
+
+impl Commands {
+    /// Whether the command changes the registry.
+    fn writes(&self) -> bool {
+        !matches!(
+            self,
+            Commands::Service { sub: ServiceCommands::List { .. } | ServiceCommands::Show { .. } | ServiceCommands::Graph | ServiceCommands::Impact { .. } }
+                | Commands::Instance { sub: InstanceCommands::List { .. } }
+                | Commands::Identity { sub: IdentityCommands::List }
+                | Commands::Role { sub: RoleCommands::List }
+                | Commands::Query { sub: QueryCommands::List | QueryCommands::Show { .. } }
+                | Commands::Kv { sub: KvCommands::Get { .. } | KvCommands::List { .. } }
+                | Commands::Session { sub: SessionCommands::List }
+                | Commands::Webhook { sub: WebhookCommands::List | WebhookCommands::Deliveries { .. } | WebhookCommands::DeadLetters { .. } }
+                | Commands::Policy { .. }
+                | Commands::Audit { .. }
+                | Commands::Status
+        )
+    }
+}
+
+/// Appends a record of a write made by this CLI. The write has already
+/// happened, so a failure is reported without failing the command.
+fn record(registry: &dyn RegistryStore, entry: AuditEntry) {
+    if let Err(e) = registry.append_audit(entry) {
+        eprintln!("warning: failed to append audit record: {}", e);
+    }
+}
+
+fn cli_entry(action: &str, target: String) -> AuditEntry {
+    AuditEntry::new(LOCAL_CLI_ACTOR, action, target)
+}
+
+fn print_audit_record(record: &AuditRecord, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(record).expect("audit records are serializable"));
+    } else {
+        println!("{:<6} {:<20} {:<24} {:<22} {}",
+            record.seq,
+            format_timestamp(record.timestamp),
+            record.actor,
+            record.action,
+            record.target
+        );
+    }
+}
+
+/// Formats Unix milliseconds as a UTC `YYYY-MM-DD HH:MM:SS`.
+fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // Civil-from-days conversion for the proleptic Gregorian calendar.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Reads `jti` and `exp` from a JWT payload. The signature is not checked:
+/// revoking a forged token is harmless.
+fn token_id(token: &str) -> Result<(String, i64), Box<dyn std::error::Error>> {
+    use base64::Engine;
+
+    #[derive(serde::Deserialize)]
+    struct TokenId {
+        jti: String,
+        exp: i64,
+    }
+
+    let payload = token.split('.').nth(1).ok_or("not a JWT")?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(payload)?;
+    let id: TokenId = serde_json::from_slice(&payload)?;
+    Ok((id.jti, id.exp))
+}
+
+/// Roles `common_name` holds in `namespace`, as recorded in the audit log.
+fn role_assignments(registry: &dyn RegistryStore, common_name: &str, namespace: &str) -> serde_json::Value {
+    let roles = registry.get_identity(common_name).map(|i| i.roles_in(namespace).to_vec()).unwrap_or_default();
+    serde_json::json!({ "namespace": namespace, "roles": roles })
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected key=value, got `{}`", s))
+}
+
+fn parse_status(s: &str) -> Result<HealthStatus, String> {
+    serde_json::from_value(serde_json::Value::String(s.to_string()))
+        .map_err(|_| format!("expected Healthy, Unhealthy, Unknown or Suspect, got `{}`", s))
+}
+
+/// Looks up a webhook of the namespace.
+fn find_webhook(registry: &dyn RegistryStore, ns: &str, id: &uuid::Uuid) -> Result<Webhook, Box<dyn std::error::Error>> {
+    let webhook = registry.get_webhook(id)?;
+    if webhook.namespace != ns {
+        return Err(format!("Webhook {} is not in namespace {}", id, ns).into());
+    }
+    Ok(webhook)
 }
 
 #[tokio::main]
@@ -93,28 +573,132 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
     let cli = Cli::parse();
     
+    namespace::validate(&cli.namespace)?;
+    let ns = cli.namespace.as_str();
+
     let db = DbRegistry::new(&cli.db)?;
     let registry: &dyn RegistryStore = &db;
 
+    // A cluster node's database is a replica: a write made here would never
+    // reach the other nodes and would be overwritten by the next snapshot.
+    if cli.command.writes() && db.is_replicated()? {
+        return Err(format!(
+            "{} is replicated by a LogPose cluster; make changes through the API of any cluster node",
+            cli.db
+        )
+        .into());
+    }
+
     match cli.command {
         Commands::Service { sub } => match sub {
-            ServiceCommands::Register { name, code, description } => {
-                let service = Service::new(name, code.clone(), description);
+            ServiceCommands::Register { name, code, description, dependencies, catalog, metadata } => {
+                let mut service = Service::new(name, code.clone(), description);
+                service.namespace = ns.to_string();
+                service.dependencies = dependencies;
+                service.catalog = Catalog {
+                    owner: catalog.owner,
+                    on_call: catalog.on_call,
+                    repository: catalog.repository,
+                    tier: catalog.tier,
+                    tags: catalog.tags,
+                };
+                service.metadata = metadata.into_iter().collect();
+                service.validate()?;
+                let existing = registry.get_service(ns, &code).ok();
                 registry.add_service(&service)?;
+                let mut entry = cli_entry("service.register", service_target(ns, &code));
+                if let Some(existing) = existing {
+                    entry = entry.before(existing);
+                }
+                record(registry, entry.after(&service));
                 println!("Service registered successfully: {}", code);
             }
-            ServiceCommands::List => {
-                let services = registry.get_all_services()?;
-                println!("Registered Services:");
-                println!("{:<20} {:<20} {:<30}", "Code", "Name", "Description");
-                println!("{}", "-".repeat(70));
+            ServiceCommands::Update { code, name, description, dependencies, catalog, metadata, remove_metadata, clear } => {
+                let existing = registry.get_service(ns, &code)?;
+                let cleared = |field: &str| clear.iter().any(|c| c == field);
+                let set_or_clear = |value: Option<String>, field: &str| if cleared(field) { Some(None) } else { value.map(Some) };
+                let patch = ServicePatch {
+                    name,
+                    description,
+                    owner: set_or_clear(catalog.owner, "owner"),
+                    on_call: set_or_clear(catalog.on_call, "on-call"),
+                    repository: set_or_clear(catalog.repository, "repository"),
+                    tier: if cleared("tier") { Some(None) } else { catalog.tier.map(Some) },
+                    tags: if cleared("tags") { Some(Vec::new()) } else { Some(catalog.tags).filter(|t| !t.is_empty()) },
+                    metadata: Some(
+                        metadata.into_iter().map(|(k, v)| (k, Some(v)))
+                            .chain(remove_metadata.into_iter().map(|k| (k, None)))
+                            .collect(),
+                    ),
+                    dependencies: if cleared("dependencies") { Some(Vec::new()) } else { Some(dependencies).filter(|d| !d.is_empty()) },
+                };
+                let mut service = existing.clone();
+                patch.apply(&mut service);
+                service.validate()?;
+                registry.update_service(&service)?;
+                record(registry, cli_entry("service.update", service_target(ns, &code)).before(existing).after(&service));
+                println!("Service updated: {}", code);
+            }
+            ServiceCommands::List { search, owner, tier, tags } => {
+                let search = CatalogSearch { q: search, owner, tier, tags: Some(tags.join(",")) };
+                let mut services: Vec<Service> = registry.get_all_services()?
+                    .into_iter()
+                    .filter(|svc| svc.namespace == ns && search.matches(svc))
+                    .collect();
+                services.sort_by(|a, b| a.code.cmp(&b.code));
+                println!("Registered Services ({}):", ns);
+                println!("{:<20} {:<20} {:<18} {:<10} {:<30}", "Code", "Name", "Owner", "Tier", "Description");
+                println!("{}", "-".repeat(100));
                 for svc in services {
-                    println!("{:<20} {:<20} {:<30}", svc.code, svc.name, svc.description);
+                    let owner = svc.catalog.owner.as_deref().unwrap_or("-");
+                    let tier = svc.catalog.tier.map_or("-".to_string(), |t| t.to_string());
+                    println!("{:<20} {:<20} {:<18} {:<10} {:<30}", svc.code, svc.name, owner, tier, svc.description);
                 }
             }
+            ServiceCommands::Show { code } => {
+                let service = registry.get_service(ns, &code)?;
+                println!("{}", serde_json::to_string_pretty(&service)?);
+            }
+            ServiceCommands::Graph => {
+                let graph = ServiceGraph::build(ns, &registry.get_all_services()?, &registry.get_all_instances()?);
+                println!("Dependency Graph ({}):", ns);
+                println!("{:<20} {:<10} {:<10} Depends On", "Code", "Health", "Healthy");
+                println!("{}", "-".repeat(70));
+                for node in &graph.nodes {
+                    let dependencies: Vec<&str> = graph.edges.iter().filter(|e| e.from == node.code).map(|e| e.to.as_str()).collect();
+                    let healthy = if node.registered {
+                        format!("{}/{}", node.healthy_instances, node.instances)
+                    } else {
+                        "missing".to_string()
+                    };
+                    println!("{:<20} {:<10} {:<10} {}", node.code, format!("{:?}", node.health), healthy, dependencies.join(", "));
+                }
+            }
+            ServiceCommands::Impact { code } => {
+                let graph = ServiceGraph::build(ns, &registry.get_all_services()?, &registry.get_all_instances()?);
+                let impact = graph.impact(&code).ok_or_else(|| format!("Service not found: {}", code))?;
+                if impact.affected.is_empty() {
+                    println!("Nothing depends on {}", code);
+                } else {
+                    println!("Affected when {} is down ({}):", code, impact.affected.len());
+                    println!("{:<6} {:<20} {:<10} Via", "Depth", "Code", "Health");
+                    println!("{}", "-".repeat(60));
+                    for affected in impact.affected {
+                        println!("{:<6} {:<20} {:<10} {}", affected.depth, affected.code, format!("{:?}", affected.health), affected.via);
+                    }
+                }
+            }
+            ServiceCommands::Remove { code } => {
+                let service = registry.get_service(ns, &code).ok();
+                let instances = registry.get_instances(ns, &code).unwrap_or_default();
+                registry.remove_service(ns, &code)?;
+                record(registry, cli_entry("service.deregister", service_target(ns, &code))
+                    .before(serde_json::json!({ "service": service, "instances": instances })));
+                println!("Service deregistered: {}", code);
+            }
         },
         Commands::Instance { sub } => match sub {
-            InstanceCommands::Add { service, address, protocol, runtime } => {
+            InstanceCommands::Add { service, address, protocol, runtime, metadata } => {
                 let protocol = match protocol.as_str() {
                     "Http" => Protocol::Http,
                     "Https" => Protocol::Https,
@@ -130,25 +714,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     other => Runtime::Custom(other.to_string()),
                 };
 
-                let instance = ServiceInstance::new(
+                let mut instance = ServiceInstance::new(
                     service.clone(),
                     address,
                     protocol,
                     runtime,
                     logpose_core::time::now()
                 );
+                instance.namespace = ns.to_string();
+                for (key, value) in metadata {
+                    instance.add_metadata(key, value);
+                }
 
                 registry.add_instance(&instance)?;
+                record(registry, cli_entry("instance.register", instance_target(ns, &instance.id)).after(&instance));
                 println!("Instance added to service: {}", service);
             }
             InstanceCommands::List { service } => {
                 let instances = if let Some(code) = service {
-                    registry.get_instances(&code)?
+                    registry.get_instances(ns, &code)?
                 } else {
-                    registry.get_all_instances()?
+                    registry.get_all_instances()?.into_iter().filter(|inst| inst.namespace == ns).collect()
                 };
 
-                println!("Service Instances:");
+                println!("Service Instances ({}):", ns);
                 println!("{:<20} {:<20} {:<10} {:<15}", "Service", "Address", "Health", "ID");
                 println!("{}", "-".repeat(70));
                 for inst in instances {
@@ -160,26 +749,346 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     );
                 }
             }
+            InstanceCommands::Remove { id } => {
+                let instance = registry.get_instance(&id)?;
+                if instance.namespace != ns {
+                    return Err(format!("Instance {} is not in namespace {}", id, ns).into());
+                }
+                registry.remove_instance(&id)?;
+                record(registry, cli_entry("instance.deregister", instance_target(ns, &id)).before(&instance));
+                println!("Instance deregistered: {}", id);
+            }
         },
         Commands::Identity { sub } => match sub {
             IdentityCommands::Add { common_name, organization } => {
                 let identity = Identity {
                     common_name: common_name.clone(),
                     organization,
-                    roles: vec![Role::Viewer],
+                    roles: [(ns.to_string(), vec![Role::Viewer])].into(),
+                    disabled: false,
                 };
+                if registry.get_identity(&common_name).is_ok() {
+                    return Err(format!("Identity {} already exists", common_name).into());
+                }
                 registry.add_identity(&identity)?;
+                record(registry, cli_entry("identity.register", identity_target(&common_name))
+                    .after(serde_json::json!({ "identity": identity, "secret_set": false })));
                 println!("Identity registered: {}", common_name);
             }
+            IdentityCommands::List => {
+                let identities = registry.list_identities()?;
+                println!("{:<30} {:<20} {:<10} {:<30}", "Common Name", "Organization", "Status", "Roles");
+                println!("{}", "-".repeat(90));
+                for identity in identities {
+                    let roles: Vec<String> = identity
+                        .roles
+                        .iter()
+                        .flat_map(|(namespace, roles)| roles.iter().map(move |role| format!("{}:{}", namespace, role)))
+                        .collect();
+                    println!("{:<30} {:<20} {:<10} {:<30}",
+                        identity.common_name,
+                        identity.organization.unwrap_or_default(),
+                        if identity.disabled { "disabled" } else { "active" },
+                        roles.join(", ")
+                    );
+                }
+            }
+            IdentityCommands::Remove { common_name } => {
+                let identity = registry.get_identity(&common_name).ok();
+                registry.remove_identity(&common_name)?;
+                record(registry, cli_entry("identity.remove", identity_target(&common_name)).before(identity));
+                println!("Identity removed: {}", common_name);
+            }
+            IdentityCommands::RemoveRole { common_name, role } => {
+                let before = role_assignments(registry, &common_name, ns);
+                registry.remove_role_from_identity(&common_name, ns, Role::from(role.clone()))?;
+                record(registry, cli_entry("identity.role.remove", identity_target(&common_name))
+                    .before(before)
+                    .after(role_assignments(registry, &common_name, ns)));
+                println!("Role {} in namespace {} removed from identity: {}", role, ns, common_name);
+            }
+            IdentityCommands::Disable { common_name } => {
+                let was_disabled = registry.get_identity(&common_name).ok().map(|i| i.disabled);
+                registry.disable_identity(&common_name)?;
+                record(registry, cli_entry("identity.disable", identity_target(&common_name))
+                    .before(serde_json::json!({ "disabled": was_disabled }))
+                    .after(serde_json::json!({ "disabled": true })));
+                println!("Identity disabled: {}", common_name);
+            }
+            IdentityCommands::Enable { common_name } => {
+                let was_disabled = registry.get_identity(&common_name).ok().map(|i| i.disabled);
+                registry.enable_identity(&common_name)?;
+                record(registry, cli_entry("identity.enable", identity_target(&common_name))
+                    .before(serde_json::json!({ "disabled": was_disabled }))
+                    .after(serde_json::json!({ "disabled": false })));
+                println!("Identity enabled: {}", common_name);
+            }
             IdentityCommands::AssignRole { common_name, role } => {
                 let role_enum = match role.as_str() {
                     "Admin" | "admin" => Role::Admin,
                     "Agent" | "agent" => Role::Agent,
                     "Viewer" | "viewer" => Role::Viewer,
-                    _ => return Err("Invalid role. Use Admin, Agent, or Viewer.".into()),
+                    custom => {
+                        if registry.get_role(custom).is_err() {
+                            return Err(format!("Unknown role `{}`. Use Admin, Agent, Viewer or a role from `role list`.", custom).into());
+                        }
+                        Role::Custom(custom.to_string())
+                    }
+                };
+                let before = role_assignments(registry, &common_name, ns);
+                registry.add_role_to_identity(&common_name, ns, role_enum.clone())?;
+                record(registry, cli_entry("identity.role.assign", identity_target(&common_name))
+                    .before(before)
+                    .after(role_assignments(registry, &common_name, ns)));
+                println!("Role {} in namespace {} assigned to identity: {}", role_enum, ns, common_name);
+            }
+            IdentityCommands::SetSecret { common_name, secret } => {
+                let generated = secret.is_none();
+                let secret = secret.unwrap_or_else(credential::generate_secret);
+                let had_secret = registry.get_identity_secret(&common_name)?.is_some();
+                registry.set_identity_secret(&common_name, &credential::hash_secret(&secret))?;
+                // Only whether a secret is set is recorded, never the secret.
+                record(registry, cli_entry("identity.secret", identity_target(&common_name))
+                    .before(serde_json::json!({ "secret_set": had_secret }))
+                    .after(serde_json::json!({ "secret_set": true })));
+                println!("Secret updated for identity: {}", common_name);
+                if generated {
+                    println!("Generated secret: {}", secret);
+                }
+            }
+        },
+        Commands::Role { sub } => match sub {
+            RoleCommands::Create { name, description, grants } => {
+                let role = RoleDefinition { name: name.clone(), description, grants };
+                role.validate()?;
+                registry.add_role(&role)?;
+                record(registry, cli_entry("role.create", role_target(&name)).after(&role));
+                println!("Role created: {}", name);
+            }
+            RoleCommands::List => {
+                let builtin = [Role::Admin, Role::Agent, Role::Viewer].iter().filter_map(RoleDefinition::builtin);
+                let roles: Vec<RoleDefinition> = builtin.chain(registry.get_roles()?).collect();
+                println!("{:<20} {:<50} {:<30}", "Name", "Grants", "Description");
+                println!("{}", "-".repeat(100));
+                for role in roles {
+                    let grants: Vec<String> = role.grants.iter().map(|g| format!("{}:{}", g.permission, g.services)).collect();
+                    println!("{:<20} {:<50} {:<30}", role.name, grants.join(" "), role.description);
+                }
+            }
+            RoleCommands::Delete { name } => {
+                if Role::from(name.clone()).is_builtin() {
+                    return Err(format!("{} is a built-in role and cannot be deleted", name).into());
+                }
+                let role = registry.get_role(&name).ok();
+                registry.remove_role(&name)?;
+                record(registry, cli_entry("role.delete", role_target(&name)).before(role));
+                println!("Role deleted: {}", name);
+            }
+        },
+        Commands::Query { sub } => match sub {
+            QueryCommands::Create { name, service, tags, metadata, include_unhealthy, region, zone, sub_zone, failover } => {
+                let near = Locality { region, zone, sub_zone };
+                let query = PreparedQuery {
+                    name: name.clone(),
+                    namespace: ns.to_string(),
+                    service,
+                    tags,
+                    metadata: metadata.into_iter().collect(),
+                    only_healthy: !include_unhealthy,
+                    near: (!near.is_empty()).then_some(near),
+                    failover,
                 };
-                registry.add_role_to_identity(&common_name, role_enum.clone())?;
-                println!("Role {:?} assigned to identity: {}", role_enum, common_name);
+                query.validate()?;
+                registry.add_query(&query)?;
+                record(registry, cli_entry("query.create", query_target(ns, &name)).after(&query));
+                println!("Query created: {}", name);
+            }
+            QueryCommands::List => {
+                println!("Prepared Queries ({}):", ns);
+                println!("{:<24} {:<20} {:<30} {:<30}", "Name", "Service", "Tags", "Failover");
+                println!("{}", "-".repeat(100));
+                for query in registry.get_queries(ns)? {
+                    let failover: Vec<String> = query.failover.iter().map(ToString::to_string).collect();
+                    println!("{:<24} {:<20} {:<30} {:<30}", query.name, query.service, query.tags.join(","), failover.join(" "));
+                }
+            }
+            QueryCommands::Show { name } => {
+                let query = registry.get_query(ns, &name)?;
+                println!("{}", serde_json::to_string_pretty(&query)?);
+            }
+            QueryCommands::Delete { name } => {
+                let query = registry.get_query(ns, &name)?;
+                registry.remove_query(ns, &name)?;
+                record(registry, cli_entry("query.delete", query_target(ns, &name)).before(&query));
+                println!("Query deleted: {}", name);
+            }
+        },
+        Commands::Kv { sub } => match sub {
+            KvCommands::Get { key } => {
+                println!("{}", registry.get_key(ns, &key)?.value);
+            }
+            KvCommands::List { prefix } => {
+                println!("Keys ({}):", ns);
+                println!("{:<40} {:<8} {:<30}", "Key", "Index", "Value");
+                println!("{}", "-".repeat(80));
+                for entry in registry.get_keys(ns, &prefix)? {
+                    println!("{:<40} {:<8} {:<30}", entry.key, entry.modify_index, entry.value);
+                }
+            }
+            KvCommands::Put { key, value, cas } => {
+                logpose_core::kv::validate_key(&key)?;
+                if value.len() > logpose_core::kv::MAX_VALUE_LEN {
+                    return Err(format!("value is larger than {} bytes", logpose_core::kv::MAX_VALUE_LEN).into());
+                }
+                let existing = registry.get_key(ns, &key).ok();
+                let entry = registry.put_key(ns, &key, &value, cas)?;
+                let mut audit_entry = cli_entry("kv.put", kv_target(ns, &key));
+                if let Some(existing) = existing {
+                    audit_entry = audit_entry.before(existing);
+                }
+                record(registry, audit_entry.after(&entry));
+                println!("Key written: {} (index {})", key, entry.modify_index);
+            }
+            KvCommands::Delete { key, recurse, cas } => {
+                let before = if recurse {
+                    let entries = registry.get_keys(ns, &key)?;
+                    registry.remove_keys(ns, &key)?;
+                    serde_json::json!(entries)
+                } else {
+                    let entry = registry.get_key(ns, &key)?;
+                    registry.remove_key(ns, &key, cas)?;
+                    serde_json::json!(entry)
+                };
+                record(registry, cli_entry("kv.delete", kv_target(ns, &key)).before(before));
+                println!("Key deleted: {}", key);
+            }
+        },
+        Commands::Session { sub } => match sub {
+            SessionCommands::List => {
+                println!("Sessions ({}):", ns);
+                println!("{:<38} {:<20} {:<38} {:<8} {:<20}", "ID", "Name", "Instance", "TTL", "Owner");
+                println!("{}", "-".repeat(124));
+                for session in registry.get_sessions(ns)? {
+                    let instance = session.instance.map(|id| id.to_string()).unwrap_or_else(|| "-".to_string());
+                    let ttl = session.ttl_secs.map(|ttl| format!("{}s", ttl)).unwrap_or_else(|| "-".to_string());
+                    let owner = session.owner.as_deref().unwrap_or("-");
+                    println!("{:<38} {:<20} {:<38} {:<8} {:<20}", session.id, session.name, instance, ttl, owner);
+                }
+            }
+            SessionCommands::Destroy { id } => {
+                let session = registry.get_session(&id)?;
+                if session.namespace != ns {
+                    return Err(format!("Session {} is not in namespace {}", id, ns).into());
+                }
+                registry.remove_session(&id)?;
+                record(registry, cli_entry("session.destroy", session_target(ns, &id)).before(&session));
+                println!("Session destroyed: {}", id);
+            }
+        },
+        Commands::Webhook { sub } => match sub {
+            WebhookCommands::Create { name, url, events, services, statuses, secret } => {
+                let webhook = Webhook {
+                    id: uuid::Uuid::new_v4(),
+                    namespace: ns.to_string(),
+                    name,
+                    url,
+                    events,
+                    services,
+                    statuses,
+                    secret: Some(secret.unwrap_or_else(credential::generate_secret)),
+                    created_at: logpose_core::time::now(),
+                };
+                webhook.validate()?;
+                registry.add_webhook(&webhook)?;
+                record(registry, cli_entry("webhook.create", webhook_target(ns, &webhook.id)).after(webhook.clone().redacted()));
+                println!("Webhook created: {}", webhook.id);
+                println!("Secret: {}", webhook.secret.unwrap_or_default());
+            }
+            WebhookCommands::List => {
+                println!("Webhooks ({}):", ns);
+                println!("{:<38} {:<20} {:<20} {:<40}", "ID", "Name", "Services", "URL");
+                println!("{}", "-".repeat(118));
+                for webhook in registry.get_webhooks(ns)? {
+                    println!("{:<38} {:<20} {:<20} {:<40}", webhook.id, webhook.name, webhook.services, webhook.url);
+                }
+            }
+            WebhookCommands::Delete { id } => {
+                let webhook = find_webhook(registry, ns, &id)?;
+                registry.remove_webhook(&id)?;
+                record(registry, cli_entry("webhook.delete", webhook_target(ns, &id)).before(webhook.redacted()));
+                println!("Webhook deleted: {}", id);
+            }
+            WebhookCommands::Deliveries { id } => {
+                find_webhook(registry, ns, &id)?;
+                println!("{:<38} {:<24} {:<8} {:<7} {:<30}", "Delivery", "Kind", "Attempt", "Status", "Error");
+                println!("{}", "-".repeat(110));
+                for attempt in registry.get_deliveries(&id)? {
+                    let status = attempt.status_code.map(|code| code.to_string()).unwrap_or_else(|| "-".to_string());
+                    let error = attempt.error.as_deref().unwrap_or("-");
+                    println!("{:<38} {:<24} {:<8} {:<7} {:<30}", attempt.delivery, attempt.kind, attempt.attempt, status, error);
+                }
+            }
+            WebhookCommands::DeadLetters { id } => {
+                find_webhook(registry, ns, &id)?;
+                println!("{:<38} {:<24} {:<9} {:<30}", "Delivery", "Kind", "Attempts", "Last error");
+                println!("{}", "-".repeat(104));
+                for dead_letter in registry.get_dead_letters(&id)? {
+                    println!("{:<38} {:<24} {:<9} {:<30}", dead_letter.delivery, dead_letter.kind, dead_letter.attempts, dead_letter.last_error);
+                }
+            }
+        },
+        Commands::Policy { sub } => match sub {
+            PolicyCommands::Check { common_name, permission, service } => {
+                let identity = registry.get_identity(&common_name)?;
+                let decision = Policy::resolve(identity.roles_in(ns), registry)?.check(permission, service.as_deref());
+                println!("Namespace: {}", ns);
+                print!("{}", decision);
+                if !decision.allowed() {
+                    std::process::exit(1);
+                }
+            }
+        },
+        Commands::Token { sub } => match sub {
+            TokenCommands::Revoke { jti, token } => {
+                // Without the token its expiry is unknown, so keep the entry forever.
+                let (jti, exp) = match (jti, token) {
+                    (_, Some(token)) => token_id(&token)?,
+                    (Some(jti), None) => (jti, i64::MAX),
+                    (None, None) => unreachable!("clap requires --jti or --token"),
+                };
+                registry.revoke_token(&jti, exp)?;
+                record(registry, cli_entry("token.revoke", token_target(&jti)));
+                println!("Token revoked: {}", jti);
+            }
+        },
+        Commands::Audit { sub } => match sub {
+            AuditCommands::Tail { lines, actor, action, target, follow, json } => {
+                let mut filter = AuditFilter { actor, action, target, limit: Some(lines), ..Default::default() };
+                if !json {
+                    println!("{:<6} {:<20} {:<24} {:<22} Target", "Seq", "Time (UTC)", "Actor", "Action");
+                    println!("{}", "-".repeat(100));
+                }
+                loop {
+                    for record in registry.get_audit(&filter)? {
+                        print_audit_record(&record, json);
+                        filter.after_seq = Some(record.seq);
+                    }
+                    if !follow {
+                        break;
+                    }
+                    filter.limit = None;
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                }
+            }
+            AuditCommands::Verify => {
+                let records = registry.get_audit(&AuditFilter::default())?;
+                match audit::verify(&records, registry.get_audit_head()?.as_ref()) {
+                    Ok(()) => println!("Audit log intact: {} records", records.len()),
+                    Err(e) => {
+                        println!("Audit log BROKEN: {}", e);
+                        std::process::exit(1);
+                    }
+                }
             }
         },
         Commands::Status => {
@@ -0,0 +1,314 @@
+//! Audit log of registry mutations.
+//!
+//! Records are hash-chained: each stores the hash of its predecessor and a
+//! SHA-256 over its own contents and that hash, so editing, removing or
+//! reordering any record breaks every hash after it. Removing the newest
+//! records leaves a shorter chain that is still intact, so the store also
+//! keeps the [`AuditHead`], the seq and hash of the last record written,
+//! apart from the log. [`verify`] walks a chain, checks that it ends at the
+//! head and reports the first record that does not fit. Someone able to
+//! rewrite the database can still rewrite the head along with the log.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use utoipa::{IntoParams, ToSchema};
+
+/// Actor recorded for writes made with `logpose-command`.
+pub const LOCAL_CLI_ACTOR: &str = "local-cli";
+
+/// `prev_hash` of the first record.
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A mutation about to be recorded; the store assigns its place in the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix milliseconds, taken when the entry is created so that every
+    /// replica of a clustered registry records the same value
+    pub timestamp: u64,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub source_ip: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(actor: impl Into<String>, action: impl Into<String>, target: impl Into<String>) -> Self {
+        Self {
+            timestamp: crate::time::now(),
+            actor: actor.into(),
+            action: action.into(),
+            target: target.into(),
+            before: None,
+            after: None,
+            source_ip: None,
+        }
+    }
+
+    /// State of the target before the mutation.
+    pub fn before(mut self, state: impl Serialize) -> Self {
+        self.before = serde_json::to_value(state).ok();
+        self
+    }
+
+    /// State of the target after the mutation.
+    pub fn after(mut self, state: impl Serialize) -> Self {
+        self.after = serde_json::to_value(state).ok();
+        self
+    }
+
+    pub fn source_ip(mut self, source_ip: Option<String>) -> Self {
+        self.source_ip = source_ip;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditRecord {
+    /// Position in the chain, starting at 1
+    pub seq: u64,
+    /// Unix milliseconds
+    pub timestamp: u64,
+    pub actor: String,
+    /// e.g. `service.register`, `instance.health`, `identity.role.assign`
+    pub action: String,
+    /// e.g. `service:default/auth`, `instance:default/<id>`, `identity:<cn>`
+    pub target: String,
+    #[schema(value_type = Option<Object>)]
+    pub before: Option<Value>,
+    #[schema(value_type = Option<Object>)]
+    pub after: Option<Value>,
+    pub source_ip: Option<String>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditRecord {
+    /// Places `entry` after the record with hash `prev_hash`.
+    pub fn chain(entry: AuditEntry, seq: u64, prev_hash: String) -> Self {
+        let mut record = Self {
+            seq,
+            timestamp: entry.timestamp,
+            actor: entry.actor,
+            action: entry.action,
+            target: entry.target,
+            before: entry.before,
+            after: entry.after,
+            source_ip: entry.source_ip,
+            prev_hash,
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash();
+        record
+    }
+
+    /// SHA-256 (hex) over every field but `hash`.
+    pub fn compute_hash(&self) -> String {
+        let content = (
+            self.seq,
+            self.timestamp,
+            &self.actor,
+            &self.action,
+            &self.target,
+            &self.before,
+            &self.after,
+            &self.source_ip,
+            &self.prev_hash,
+        );
+        let encoded = serde_json::to_vec(&content).expect("audit records are serializable");
+        hex::encode(Sha256::digest(encoded))
+    }
+}
+
+/// The last record appended to a log, stored apart from the log itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditHead {
+    pub seq: u64,
+    pub hash: String,
+}
+
+impl From<&AuditRecord> for AuditHead {
+    fn from(record: &AuditRecord) -> Self {
+        Self { seq: record.seq, hash: record.hash.clone() }
+    }
+}
+
+// Targets are named `<kind>:<name>`, with the namespace for namespaced kinds.
+
+pub fn service_target(namespace: &str, code: &str) -> String {
+    format!("service:{}/{}", namespace, code)
+}
+
+pub fn instance_target(namespace: &str, id: &uuid::Uuid) -> String {
+    format!("instance:{}/{}", namespace, id)
+}
+
+pub fn identity_target(common_name: &str) -> String {
+    format!("identity:{}", common_name)
+}
+
+pub fn role_target(name: &str) -> String {
+    format!("role:{}", name)
+}
+
+pub fn token_target(jti: &str) -> String {
+    format!("token:{}", jti)
+}
+
+pub fn query_target(namespace: &str, name: &str) -> String {
+    format!("query:{}/{}", namespace, name)
+}
+
+pub fn kv_target(namespace: &str, key: &str) -> String {
+    format!("kv:{}/{}", namespace, key)
+}
+
+pub fn session_target(namespace: &str, id: &uuid::Uuid) -> String {
+    format!("session:{}/{}", namespace, id)
+}
+
+pub fn webhook_target(namespace: &str, id: &uuid::Uuid) -> String {
+    format!("webhook:{}/{}", namespace, id)
+}
+
+pub fn member_target(node_id: u64) -> String {
+    format!("member:{}", node_id)
+}
+
+/// Filters for reading the log; unset fields match everything.
+#[derive(Debug, Clone, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    /// Only records with a larger `seq`
+    pub after_seq: Option<u64>,
+    /// Unix milliseconds, inclusive
+    pub since: Option<u64>,
+    /// Unix milliseconds, exclusive
+    pub until: Option<u64>,
+    /// Return only the newest `limit` matching records
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ChainError {
+    #[error("audit record {seq} follows record {expected_seq}; records are missing")]
+    Gap { seq: u64, expected_seq: u64 },
+
+    #[error("audit record {seq} does not reference the hash of its predecessor")]
+    BrokenLink { seq: u64 },
+
+    #[error("audit record {seq} has been modified")]
+    Modified { seq: u64 },
+
+    #[error("audit log ends at record {seq}, but the last record written was {head_seq}")]
+    Truncated { seq: u64, head_seq: u64 },
+}
+
+/// Checks a complete log, oldest record first, against the head stored with
+/// it. Logs written before heads were stored have none.
+pub fn verify(records: &[AuditRecord], head: Option<&AuditHead>) -> Result<(), ChainError> {
+    let mut prev_hash = GENESIS_HASH;
+    for (expected_seq, record) in (1..).zip(records) {
+        if record.seq != expected_seq {
+            return Err(ChainError::Gap { seq: record.seq, expected_seq });
+        }
+        if record.prev_hash != prev_hash {
+            return Err(ChainError::BrokenLink { seq: record.seq });
+        }
+        if record.compute_hash() != record.hash {
+            return Err(ChainError::Modified { seq: record.seq });
+        }
+        prev_hash = &record.hash;
+    }
+    let Some(head) = head else {
+        return Ok(());
+    };
+    match records.last() {
+        Some(last) if last.seq == head.seq && last.hash != head.hash => Err(ChainError::Modified { seq: last.seq }),
+        Some(last) if last.seq == head.seq => Ok(()),
+        last => Err(ChainError::Truncated { seq: last.map_or(0, |r| r.seq), head_seq: head.seq }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(len: u64) -> Vec<AuditRecord> {
+        let mut records: Vec<AuditRecord> = Vec::new();
+        for seq in 1..=len {
+            let prev_hash = records.last().map_or(GENESIS_HASH.to_string(), |r| r.hash.clone());
+            let entry = AuditEntry::new("admin", "service.register", service_target("default", &format!("svc-{}", seq)))
+                .after(serde_json::json!({ "seq": seq }));
+            records.push(AuditRecord::chain(entry, seq, prev_hash));
+        }
+        records
+    }
+
+    fn head(records: &[AuditRecord]) -> Option<AuditHead> {
+        records.last().map(AuditHead::from)
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let records = chain(3);
+        assert_eq!(verify(&records, head(&records).as_ref()), Ok(()));
+        assert_eq!(verify(&records, None), Ok(()));
+        assert_eq!(verify(&[], None), Ok(()));
+    }
+
+    #[test]
+    fn edited_record_is_detected() {
+        let mut records = chain(3);
+        records[1].actor = "someone-else".to_string();
+        assert_eq!(verify(&records, None), Err(ChainError::Modified { seq: 2 }));
+    }
+
+    #[test]
+    fn rehashed_edit_breaks_the_next_link() {
+        let mut records = chain(3);
+        records[1].after = Some(serde_json::json!({ "seq": 42 }));
+        records[1].hash = records[1].compute_hash();
+        assert_eq!(verify(&records, None), Err(ChainError::BrokenLink { seq: 3 }));
+    }
+
+    #[test]
+    fn removed_record_is_detected() {
+        let mut records = chain(3);
+        records.remove(1);
+        assert_eq!(verify(&records, None), Err(ChainError::Gap { seq: 3, expected_seq: 2 }));
+    }
+
+    #[test]
+    fn reordered_records_are_detected() {
+        let mut records = chain(3);
+        records.swap(1, 2);
+        assert_eq!(verify(&records, None), Err(ChainError::Gap { seq: 3, expected_seq: 2 }));
+    }
+
+    #[test]
+    fn removed_newest_records_are_detected_by_the_head() {
+        let mut records = chain(3);
+        let head = head(&records).unwrap();
+        records.truncate(1);
+        assert_eq!(verify(&records, None), Ok(()), "the chain alone cannot tell");
+        assert_eq!(verify(&records, Some(&head)), Err(ChainError::Truncated { seq: 1, head_seq: 3 }));
+        assert_eq!(verify(&[], Some(&head)), Err(ChainError::Truncated { seq: 0, head_seq: 3 }));
+    }
+
+    #[test]
+    fn rehashed_newest_record_is_detected_by_the_head() {
+        let mut records = chain(3);
+        let head = head(&records).unwrap();
+        records[2].actor = "someone-else".to_string();
+        records[2].hash = records[2].compute_hash();
+        assert_eq!(verify(&records, None), Ok(()));
+        assert_eq!(verify(&records, Some(&head)), Err(ChainError::Modified { seq: 3 }));
+    }
+}
@@ -1,56 +1,289 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema, PartialEq, Eq, Hash)]
 pub enum Permission {
     ServiceRead,
     ServiceWrite,
     InstanceRead,
     InstanceWrite,
     UserManage,
+    AuditRead,
+    ClusterManage,
+    /// Report the health of instances registered by other identities
+    HealthReport,
+    /// Read key/value entries; grants are narrowed by key rather than service
+    KvRead,
+    /// Write and delete key/value entries; grants are narrowed by key rather
+    /// than service
+    KvWrite,
+    /// Create sessions and acquire locks with them; grants are narrowed by
+    /// lock key
+    Lock,
+    /// Manage webhooks and their deliveries
+    WebhookManage,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+impl Permission {
+    pub const ALL: [Permission; 12] = [
+        Permission::ServiceRead,
+        Permission::ServiceWrite,
+        Permission::InstanceRead,
+        Permission::InstanceWrite,
+        Permission::UserManage,
+        Permission::AuditRead,
+        Permission::ClusterManage,
+        Permission::HealthReport,
+        Permission::KvRead,
+        Permission::KvWrite,
+        Permission::Lock,
+        Permission::WebhookManage,
+    ];
+
+    /// Whether grants of this permission can be narrowed to some services,
+    /// or for the key/value and lock permissions to some keys.
+    pub fn is_service_scoped(&self) -> bool {
+        !matches!(self, Permission::UserManage | Permission::AuditRead | Permission::ClusterManage | Permission::WebhookManage)
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .into_iter()
+            .find(|p| p.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown permission `{}`", s))
+    }
+}
+
+/// A role held by an identity: one of the built-in roles or the name of a
+/// role defined in the registry. Serialized as its name.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(from = "String", into = "String")]
 pub enum Role {
     Admin,
     Agent,
     Viewer,
+    Custom(String),
 }
 
 impl Role {
-    pub fn permissions(&self) -> HashSet<Permission> {
+    pub fn name(&self) -> &str {
         match self {
-            Role::Admin => [
-                Permission::ServiceRead,
-                Permission::ServiceWrite,
-                Permission::InstanceRead,
-                Permission::InstanceWrite,
-                Permission::UserManage,
-            ].into_iter().collect(),
-            Role::Agent => [
-                Permission::ServiceRead,
-                Permission::InstanceRead,
-                Permission::InstanceWrite,
-            ].into_iter().collect(),
-            Role::Viewer => [
-                Permission::ServiceRead,
-                Permission::InstanceRead,
-            ].into_iter().collect(),
+            Role::Admin => "Admin",
+            Role::Agent => "Agent",
+            Role::Viewer => "Viewer",
+            Role::Custom(name) => name,
         }
     }
+
+    pub fn is_builtin(&self) -> bool {
+        !matches!(self, Role::Custom(_))
+    }
+
+    /// Grants of a built-in role; custom roles are looked up in the registry.
+    pub fn builtin_grants(&self) -> Option<Vec<Grant>> {
+        let permissions: &[Permission] = match self {
+            Role::Admin => &Permission::ALL,
+            Role::Agent => &[Permission::ServiceRead, Permission::InstanceRead, Permission::InstanceWrite, Permission::KvRead, Permission::Lock],
+            Role::Viewer => &[Permission::ServiceRead, Permission::InstanceRead, Permission::KvRead],
+            Role::Custom(_) => return None,
+        };
+        Some(permissions.iter().map(|&permission| Grant::all_services(permission)).collect())
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl From<String> for Role {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "Admin" => Role::Admin,
+            "Agent" => Role::Agent,
+            "Viewer" => Role::Viewer,
+            _ => Role::Custom(name),
+        }
+    }
+}
+
+impl From<Role> for String {
+    fn from(role: Role) -> Self {
+        role.name().to_string()
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl<'s> ToSchema<'s> for Role {
+    fn schema() -> (&'s str, utoipa::openapi::RefOr<utoipa::openapi::schema::Schema>) {
+        let schema = utoipa::openapi::ObjectBuilder::new()
+            .schema_type(utoipa::openapi::SchemaType::String)
+            .description(Some("`Admin`, `Agent`, `Viewer` or the name of a custom role"))
+            .example(Some("Agent".into()))
+            .build();
+        ("Role", schema.into())
+    }
+}
+
+/// A permission granted on every service whose code matches the `services`
+/// glob (`*`, `?` and `[...]` wildcards). For `KvRead`, `KvWrite` and `Lock`
+/// the glob matches keys instead, e.g. `config/billing/*`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct Grant {
+    pub permission: Permission,
+    #[serde(default = "all_services")]
+    pub services: String,
+}
+
+fn all_services() -> String {
+    "*".to_string()
+}
+
+impl Grant {
+    pub fn all_services(permission: Permission) -> Self {
+        Self { permission, services: all_services() }
+    }
+
+    /// Whether the grant covers `permission` on `service`. Actions that do
+    /// not target a single service (`None`) are covered by any grant of the
+    /// permission, and their results are narrowed to the granted services.
+    pub fn covers(&self, permission: Permission, service: Option<&str>) -> bool {
+        self.permission == permission
+            && service.is_none_or(|code| {
+                glob::Pattern::new(&self.services).is_ok_and(|pattern| pattern.matches(code))
+            })
+    }
+}
+
+/// Parses `Permission` or `Permission:glob`, e.g. `InstanceWrite:billing-*`.
+impl FromStr for Grant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (permission, services) = s.split_once(':').unwrap_or((s, "*"));
+        Ok(Grant { permission: permission.parse()?, services: services.to_string() })
+    }
+}
+
+impl fmt::Display for Grant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} on `{}`", self.permission, self.services)
+    }
+}
+
+/// A user-defined role stored in the registry.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RoleDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RoleError {
+    #[error("role name must not be empty")]
+    EmptyName,
+    #[error("`{0}` is a built-in role")]
+    Builtin(String),
+    #[error("role must grant at least one permission")]
+    NoGrants,
+    #[error("invalid service pattern `{0}`")]
+    InvalidPattern(String),
+    #[error("{0} cannot be limited to services")]
+    Unscoped(Permission),
+}
+
+impl RoleDefinition {
+    pub fn builtin(role: &Role) -> Option<Self> {
+        Some(Self {
+            name: role.name().to_string(),
+            description: String::new(),
+            grants: role.builtin_grants()?,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), RoleError> {
+        if self.name.trim().is_empty() {
+            return Err(RoleError::EmptyName);
+        }
+        if Role::from(self.name.clone()).is_builtin() {
+            return Err(RoleError::Builtin(self.name.clone()));
+        }
+        if self.grants.is_empty() {
+            return Err(RoleError::NoGrants);
+        }
+        for grant in &self.grants {
+            if glob::Pattern::new(&grant.services).is_err() {
+                return Err(RoleError::InvalidPattern(grant.services.clone()));
+            }
+            if !grant.permission.is_service_scoped() && grant.services != "*" {
+                return Err(RoleError::Unscoped(grant.permission));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Identity {
     pub common_name: String,
     pub organization: Option<String>,
-    pub roles: Vec<Role>,
+    /// Roles held in each namespace
+    pub roles: BTreeMap<String, Vec<Role>>,
+    /// A disabled identity keeps its roles but cannot authenticate.
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // Subject (Common Name)
-    pub roles: Vec<Role>,
+    pub roles: BTreeMap<String, Vec<Role>>, // Roles per namespace
     pub exp: usize,
+    pub iat: usize,
+    pub jti: String, // Token ID, used for revocation
+}
+
+impl Identity {
+    /// Roles held in `namespace`.
+    pub fn roles_in(&self, namespace: &str) -> &[Role] {
+        roles_in(&self.roles, namespace)
+    }
+}
+
+impl Claims {
+    /// Roles held in `namespace` when the token was issued.
+    pub fn roles_in(&self, namespace: &str) -> &[Role] {
+        roles_in(&self.roles, namespace)
+    }
+}
+
+fn roles_in<'a>(roles: &'a BTreeMap<String, Vec<Role>>, namespace: &str) -> &'a [Role] {
+    roles.get(namespace).map_or(&[], Vec::as_slice)
+}
+
+/// A persisted JWT signing key. `private_key` is PKCS#8 PEM; the public half
+/// is derived from it when the key is loaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningKey {
+    pub kid: String,
+    /// JWT `alg` the key signs with, e.g. `RS256` or `EdDSA`
+    pub algorithm: String,
+    pub private_key: String,
+    /// Unix timestamp of the key's creation
+    pub created_at: i64,
 }
@@ -0,0 +1,292 @@
+//! Service catalog: who owns a service and how much it matters.
+//!
+//! Every service carries a [`Catalog`] of ownership and classification
+//! fields. [`ServicePatch`] updates a registered service field by field, and
+//! [`CatalogSearch`] finds services by any of them.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::Service;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Catalog {
+    /// Team that owns the service
+    #[schema(example = "identity-team")]
+    pub owner: Option<String>,
+    /// Who to page when the service is down: a person, rotation or channel
+    #[schema(example = "#identity-oncall")]
+    pub on_call: Option<String>,
+    #[schema(example = "https://github.com/example/auth-svc")]
+    pub repository: Option<String>,
+    pub tier: Option<Tier>,
+    #[serde(default)]
+    #[schema(example = json!(["auth", "public"]))]
+    pub tags: Vec<String>,
+}
+
+/// How critical a service is to the business.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    Critical,
+    High,
+    Medium,
+    Low,
+}
+
+impl Tier {
+    pub const ALL: [Tier; 4] = [Tier::Critical, Tier::High, Tier::Medium, Tier::Low];
+}
+
+/// Formats as the snake_case name used in the API, e.g. `critical`.
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = serde_json::to_value(self).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+impl FromStr for Tier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Tier::ALL
+            .into_iter()
+            .find(|tier| tier.to_string() == s)
+            .ok_or_else(|| format!("expected critical, high, medium or low, got `{}`", s))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CatalogError {
+    #[error("repository must be an http://, https://, ssh:// or git@ URL, got `{0}`")]
+    InvalidRepository(String),
+    #[error("tag `{0}` must be non-empty and contain no commas or whitespace")]
+    InvalidTag(String),
+    #[error("a service cannot depend on itself")]
+    SelfDependency,
+}
+
+impl Catalog {
+    pub fn validate(&self) -> Result<(), CatalogError> {
+        if let Some(repository) = &self.repository
+            && !["http://", "https://", "ssh://", "git@"].iter().any(|scheme| repository.starts_with(scheme))
+        {
+            return Err(CatalogError::InvalidRepository(repository.clone()));
+        }
+        if let Some(tag) = self.tags.iter().find(|t| t.is_empty() || t.contains(',') || t.contains(char::is_whitespace)) {
+            return Err(CatalogError::InvalidTag(tag.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Tells an absent field, left unchanged, from `null`, which clears it.
+fn nullable<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Changes to a registered service. Absent fields are left as they are;
+/// `null` clears an optional one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ServicePatch {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub owner: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub on_call: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, nullable)]
+    pub repository: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<Tier>, nullable)]
+    pub tier: Option<Option<Tier>>,
+    /// Replaces the service's tags
+    pub tags: Option<Vec<String>>,
+    /// Merged into the service's metadata; a `null` value removes its key
+    #[schema(value_type = Option<HashMap<String, String>>)]
+    pub metadata: Option<HashMap<String, Option<String>>>,
+    /// Replaces the service's dependencies
+    pub dependencies: Option<Vec<String>>,
+}
+
+impl ServicePatch {
+    pub fn apply(self, service: &mut Service) {
+        if let Some(name) = self.name {
+            service.name = name;
+        }
+        if let Some(description) = self.description {
+            service.description = description;
+        }
+        if let Some(owner) = self.owner {
+            service.catalog.owner = owner;
+        }
+        if let Some(on_call) = self.on_call {
+            service.catalog.on_call = on_call;
+        }
+        if let Some(repository) = self.repository {
+            service.catalog.repository = repository;
+        }
+        if let Some(tier) = self.tier {
+            service.catalog.tier = tier;
+        }
+        if let Some(tags) = self.tags {
+            service.catalog.tags = tags;
+        }
+        for (key, value) in self.metadata.into_iter().flatten() {
+            match value {
+                Some(value) => service.metadata.insert(key, value),
+                None => service.metadata.remove(&key),
+            };
+        }
+        if let Some(dependencies) = self.dependencies {
+            service.dependencies = dependencies;
+        }
+    }
+}
+
+/// Criteria a service must all meet to be found.
+#[derive(Debug, Clone, Default, Deserialize, utoipa::IntoParams)]
+pub struct CatalogSearch {
+    /// Text found, ignoring case, in the service's code, name, description,
+    /// catalog fields, tags or metadata values
+    pub q: Option<String>,
+    /// Owning team, ignoring case
+    pub owner: Option<String>,
+    #[param(value_type = Option<String>, example = "critical")]
+    pub tier: Option<Tier>,
+    /// Comma-separated tags the service must all carry
+    pub tags: Option<String>,
+}
+
+impl CatalogSearch {
+    pub fn matches(&self, service: &Service) -> bool {
+        let catalog = &service.catalog;
+        if let Some(owner) = &self.owner
+            && !catalog.owner.as_ref().is_some_and(|o| o.eq_ignore_ascii_case(owner))
+        {
+            return false;
+        }
+        if self.tier.is_some() && catalog.tier != self.tier {
+            return false;
+        }
+        let mut tags = self.tags.iter().flat_map(|t| t.split(',')).map(str::trim).filter(|t| !t.is_empty());
+        if !tags.all(|tag| catalog.tags.iter().any(|t| t == tag)) {
+            return false;
+        }
+        let Some(q) = self.q.as_ref().map(|q| q.to_lowercase()) else {
+            return true;
+        };
+        [&service.code, &service.name, &service.description]
+            .into_iter()
+            .chain([&catalog.owner, &catalog.on_call, &catalog.repository].into_iter().flatten())
+            .chain(&catalog.tags)
+            .chain(service.metadata.values())
+            .any(|text| text.to_lowercase().contains(&q))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn service() -> Service {
+        let mut service = Service::new("Auth", "auth-svc", "Handles logins");
+        service.catalog = Catalog {
+            owner: Some("identity-team".to_string()),
+            on_call: Some("#identity-oncall".to_string()),
+            repository: Some("https://github.com/example/auth-svc".to_string()),
+            tier: Some(Tier::Critical),
+            tags: vec!["auth".to_string(), "public".to_string()],
+        };
+        service.add_metadata("region", "eu-west-1");
+        service.add_metadata("lang", "rust");
+        service.dependencies = vec!["users-db".to_string()];
+        service
+    }
+
+    fn patched(patch: serde_json::Value) -> Service {
+        let mut service = service();
+        serde_json::from_value::<ServicePatch>(patch).unwrap().apply(&mut service);
+        service
+    }
+
+    #[test]
+    fn empty_patch_changes_nothing() {
+        assert_eq!(serde_json::to_value(patched(json!({}))).unwrap(), serde_json::to_value(service()).unwrap());
+    }
+
+    #[test]
+    fn patch_sets_clears_and_keeps_fields() {
+        let service = patched(json!({
+            "description": "Handles logins and tokens",
+            "owner": "platform-team",
+            "on_call": null,
+            "tier": "high",
+            "tags": ["auth"],
+            "metadata": { "lang": null, "team": "platform" },
+        }));
+        assert_eq!(service.name, "Auth");
+        assert_eq!(service.description, "Handles logins and tokens");
+        assert_eq!(service.catalog.owner.as_deref(), Some("platform-team"));
+        assert_eq!(service.catalog.on_call, None);
+        assert_eq!(service.catalog.repository.as_deref(), Some("https://github.com/example/auth-svc"));
+        assert_eq!(service.catalog.tier, Some(Tier::High));
+        assert_eq!(service.catalog.tags, ["auth"]);
+        assert_eq!(service.get_metadata("region").map(String::as_str), Some("eu-west-1"));
+        assert_eq!(service.get_metadata("team").map(String::as_str), Some("platform"));
+        assert_eq!(service.get_metadata("lang"), None);
+        assert_eq!(service.dependencies, ["users-db"]);
+
+        let cleared = patched(json!({ "tier": null, "repository": null, "tags": [], "dependencies": [] }));
+        assert_eq!((cleared.catalog.tier, cleared.catalog.repository), (None, None));
+        assert!(cleared.catalog.tags.is_empty() && cleared.dependencies.is_empty());
+        assert_eq!(cleared.catalog.owner.as_deref(), Some("identity-team"));
+    }
+
+    #[test]
+    fn search_matches_owner_tier_and_tags() {
+        let service = service();
+        let search = |query: serde_json::Value| serde_json::from_value::<CatalogSearch>(query).unwrap().matches(&service);
+
+        assert!(search(json!({})));
+        assert!(search(json!({ "owner": "Identity-Team" })));
+        assert!(!search(json!({ "owner": "identity" })));
+        assert!(search(json!({ "tier": "critical" })));
+        assert!(!search(json!({ "tier": "low" })));
+        assert!(search(json!({ "tags": "public" })));
+        assert!(search(json!({ "tags": "public, auth" })));
+        assert!(!search(json!({ "tags": "public,internal" })));
+        assert!(search(json!({ "owner": "identity-team", "tier": "critical", "tags": "auth" })));
+        assert!(!search(json!({ "owner": "identity-team", "tier": "high" })));
+
+        let mut unowned = Service::new("Cache", "cache", "");
+        unowned.catalog.tags = vec!["Auth".to_string()];
+        let search = |query: serde_json::Value| serde_json::from_value::<CatalogSearch>(query).unwrap().matches(&unowned);
+        assert!(!search(json!({ "owner": "identity-team" })));
+        assert!(!search(json!({ "tier": "critical" })));
+        assert!(!search(json!({ "tags": "auth" })), "tags match exactly");
+    }
+
+    #[test]
+    fn search_text_covers_catalog_fields_and_metadata_ignoring_case() {
+        let service = service();
+        let search = |q: &str| CatalogSearch { q: Some(q.to_string()), ..Default::default() }.matches(&service);
+        for q in ["AUTH-SVC", "logins", "oncall", "github.com/example", "public", "EU-WEST"] {
+            assert!(search(q), "{}", q);
+        }
+        assert!(!search("payments"));
+        assert!(!search("users-db"), "dependencies are not searched");
+    }
+}
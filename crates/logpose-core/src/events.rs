@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::health::HealthStatus;
+
+/// Notification emitted whenever the contents of the registry change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RegistryEvent {
+    ServiceRegistered {
+        namespace: String,
+        code: String,
+    },
+    /// A registered service's name, description, catalog fields, metadata
+    /// or dependencies changed.
+    ServiceUpdated {
+        namespace: String,
+        code: String,
+    },
+    ServiceDeregistered {
+        namespace: String,
+        code: String,
+    },
+    InstanceRegistered {
+        namespace: String,
+        service_code: String,
+        id: Uuid,
+    },
+    InstanceDeregistered {
+        namespace: String,
+        service_code: String,
+        id: Uuid,
+    },
+    InstanceHealthChanged {
+        namespace: String,
+        service_code: String,
+        id: Uuid,
+        from: HealthStatus,
+        to: HealthStatus,
+    },
+    /// A key/value write; `key` is the prefix of a recursive delete.
+    KeyChanged {
+        namespace: String,
+        key: String,
+    },
+    /// A session ended, releasing any locks it held.
+    SessionDestroyed {
+        namespace: String,
+        id: Uuid,
+    },
+    /// The whole registry was replaced, e.g. by a snapshot received from a
+    /// cluster leader.
+    Replaced,
+}
+
+impl RegistryEvent {
+    /// The namespace the event happened in; `None` for `Replaced`.
+    pub fn namespace(&self) -> Option<&str> {
+        match self {
+            RegistryEvent::ServiceRegistered { namespace, .. }
+            | RegistryEvent::ServiceUpdated { namespace, .. }
+            | RegistryEvent::ServiceDeregistered { namespace, .. }
+            | RegistryEvent::InstanceRegistered { namespace, .. }
+            | RegistryEvent::InstanceDeregistered { namespace, .. }
+            | RegistryEvent::InstanceHealthChanged { namespace, .. }
+            | RegistryEvent::KeyChanged { namespace, .. }
+            | RegistryEvent::SessionDestroyed { namespace, .. } => Some(namespace),
+            RegistryEvent::Replaced => None,
+        }
+    }
+
+    /// The code of the service the event is about, if it is about one.
+    pub fn service(&self) -> Option<&str> {
+        match self {
+            RegistryEvent::ServiceRegistered { code, .. }
+            | RegistryEvent::ServiceUpdated { code, .. }
+            | RegistryEvent::ServiceDeregistered { code, .. } => Some(code),
+            RegistryEvent::InstanceRegistered { service_code, .. }
+            | RegistryEvent::InstanceDeregistered { service_code, .. }
+            | RegistryEvent::InstanceHealthChanged { service_code, .. } => Some(service_code),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Dependency graph of the services of a namespace.
+//!
+//! Services declare the codes of the services they call in their
+//! `dependencies`. An edge runs from a service to each of its dependencies,
+//! and each node rolls up the health of the service's instances. Impact
+//! analysis walks the edges backwards: when a service is down, every service
+//! that depends on it, directly or through others, is affected.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{HealthStatus, Service, ServiceInstance};
+
+/// The health of a service as a whole, rolled up from its instances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum ServiceHealth {
+    /// Every instance is healthy
+    Healthy,
+    /// Some instances are healthy, some are not
+    Degraded,
+    /// No instance is healthy
+    Unhealthy,
+    /// The service has no instances, or none has been checked yet
+    Unknown,
+}
+
+impl ServiceHealth {
+    pub fn of(instances: &[&ServiceInstance]) -> Self {
+        let healthy = instances.iter().filter(|i| i.health == HealthStatus::Healthy).count();
+        let unknown = instances.iter().filter(|i| i.health == HealthStatus::Unknown).count();
+        if unknown == instances.len() {
+            ServiceHealth::Unknown
+        } else if healthy == instances.len() {
+            ServiceHealth::Healthy
+        } else if healthy > 0 {
+            ServiceHealth::Degraded
+        } else {
+            ServiceHealth::Unhealthy
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GraphNode {
+    #[schema(example = "auth-svc")]
+    pub code: String,
+    pub name: String,
+    /// `false` for a code some service depends on that is not registered
+    pub registered: bool,
+    pub health: ServiceHealth,
+    pub instances: usize,
+    pub healthy_instances: usize,
+}
+
+/// `from` depends on `to`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct GraphEdge {
+    #[schema(example = "checkout")]
+    pub from: String,
+    #[schema(example = "auth-svc")]
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ServiceGraph {
+    pub namespace: String,
+    /// Ordered by code
+    pub nodes: Vec<GraphNode>,
+    /// Ordered by `from`, then `to`
+    pub edges: Vec<GraphEdge>,
+}
+
+/// A service affected by an outage of another.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AffectedService {
+    pub code: String,
+    pub health: ServiceHealth,
+    /// 1 for a direct dependent, 2 for a dependent of one, and so on
+    pub depth: usize,
+    /// The dependency the outage reaches this service through
+    pub via: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Impact {
+    pub service: String,
+    pub health: ServiceHealth,
+    /// Nearest first, then by code
+    pub affected: Vec<AffectedService>,
+}
+
+impl ServiceGraph {
+    /// Builds the graph of `services` from the health of `instances`, both
+    /// of `namespace`. Dependencies on other namespaces are not followed.
+    pub fn build(namespace: &str, services: &[Service], instances: &[ServiceInstance]) -> Self {
+        let mut nodes = BTreeMap::new();
+        let mut edges = Vec::new();
+        for service in services.iter().filter(|s| s.namespace == namespace) {
+            let own: Vec<&ServiceInstance> = instances
+                .iter()
+                .filter(|i| i.namespace == namespace && i.service_name == service.code)
+                .collect();
+            nodes.insert(service.code.clone(), GraphNode {
+                code: service.code.clone(),
+                name: service.name.clone(),
+                registered: true,
+                health: ServiceHealth::of(&own),
+                instances: own.len(),
+                healthy_instances: own.iter().filter(|i| i.health == HealthStatus::Healthy).count(),
+            });
+            for dependency in &service.dependencies {
+                edges.push(GraphEdge { from: service.code.clone(), to: dependency.clone() });
+            }
+        }
+        for edge in &edges {
+            nodes.entry(edge.to.clone()).or_insert_with(|| GraphNode {
+                code: edge.to.clone(),
+                name: edge.to.clone(),
+                registered: false,
+                health: ServiceHealth::Unknown,
+                instances: 0,
+                healthy_instances: 0,
+            });
+        }
+        edges.sort_by(|a, b| (&a.from, &a.to).cmp(&(&b.from, &b.to)));
+        edges.dedup();
+        Self { namespace: namespace.to_string(), nodes: nodes.into_values().collect(), edges }
+    }
+
+    pub fn node(&self, code: &str) -> Option<&GraphNode> {
+        self.nodes.iter().find(|n| n.code == code)
+    }
+
+    /// The services affected when `code` is down, or `None` if the graph
+    /// has no such node. Cycles are walked once.
+    pub fn impact(&self, code: &str) -> Option<Impact> {
+        let root = self.node(code)?;
+        let mut seen = HashSet::from([code]);
+        let mut queue = VecDeque::from([(code, 0)]);
+        let mut affected = Vec::new();
+        while let Some((current, depth)) = queue.pop_front() {
+            for edge in self.edges.iter().filter(|e| e.to == current) {
+                if !seen.insert(edge.from.as_str()) {
+                    continue;
+                }
+                let health = self.node(&edge.from).map_or(ServiceHealth::Unknown, |n| n.health);
+                affected.push(AffectedService { code: edge.from.clone(), health, depth: depth + 1, via: current.to_string() });
+                queue.push_back((edge.from.as_str(), depth + 1));
+            }
+        }
+        affected.sort_by(|a, b| (a.depth, &a.code).cmp(&(b.depth, &b.code)));
+        Some(Impact { service: code.to_string(), health: root.health, affected })
+    }
+
+    /// Drops the nodes `keep` rejects, with their edges.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.nodes.retain(|n| keep(&n.code));
+        self.edges.retain(|e| keep(&e.from) && keep(&e.to));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Protocol, Runtime};
+
+    fn service(code: &str, dependencies: &[&str]) -> Service {
+        let mut service = Service::new(code, code, "");
+        service.dependencies = dependencies.iter().map(|d| d.to_string()).collect();
+        service
+    }
+
+    fn instance(service: &str, health: HealthStatus) -> ServiceInstance {
+        let runtime = Runtime::Vm { provider: None, id: None };
+        let mut instance = ServiceInstance::new(service, "127.0.0.1:8080".parse().unwrap(), Protocol::Http, runtime, 0);
+        instance.set_health(health);
+        instance
+    }
+
+    /// web -> checkout -> payments -> db, web -> auth -> db, admin -> auth
+    fn graph() -> ServiceGraph {
+        let services = [
+            service("web", &["checkout", "auth"]),
+            service("checkout", &["payments"]),
+            service("payments", &["db"]),
+            service("auth", &["db"]),
+            service("admin", &["auth"]),
+            service("db", &[]),
+        ];
+        let instances = [
+            instance("db", HealthStatus::Unhealthy),
+            instance("auth", HealthStatus::Healthy),
+            instance("auth", HealthStatus::Unhealthy),
+        ];
+        ServiceGraph::build("default", &services, &instances)
+    }
+
+    fn affected(impact: &Impact) -> Vec<(&str, usize, &str)> {
+        impact.affected.iter().map(|a| (a.code.as_str(), a.depth, a.via.as_str())).collect()
+    }
+
+    #[test]
+    fn impact_walks_dependents_nearest_first() {
+        let impact = graph().impact("db").unwrap();
+        assert_eq!(impact.health, ServiceHealth::Unhealthy);
+        assert_eq!(
+            affected(&impact),
+            [("auth", 1, "db"), ("payments", 1, "db"), ("admin", 2, "auth"), ("checkout", 2, "payments"), ("web", 2, "auth")]
+        );
+    }
+
+    #[test]
+    fn impact_of_a_leaf_dependent_is_empty() {
+        let impact = graph().impact("web").unwrap();
+        assert!(impact.affected.is_empty());
+        assert!(graph().impact("missing").is_none());
+    }
+
+    #[test]
+    fn impact_walks_cycles_once() {
+        let services = [service("a", &["b"]), service("b", &["c"]), service("c", &["a"])];
+        let impact = ServiceGraph::build("default", &services, &[]).impact("a").unwrap();
+        assert_eq!(affected(&impact), [("c", 1, "a"), ("b", 2, "c")]);
+    }
+
+    #[test]
+    fn build_rolls_up_health_and_adds_unregistered_dependencies() {
+        let graph = ServiceGraph::build("default", &[service("web", &["cache", "cache"])], &[instance("web", HealthStatus::Healthy)]);
+        assert_eq!(graph.edges, [GraphEdge { from: "web".into(), to: "cache".into() }]);
+        let cache = graph.node("cache").unwrap();
+        assert!(!cache.registered);
+        assert_eq!(cache.health, ServiceHealth::Unknown);
+        assert_eq!(graph.node("web").unwrap().health, ServiceHealth::Healthy);
+
+        let instances = [instance("auth", HealthStatus::Healthy), instance("auth", HealthStatus::Unhealthy)];
+        let graph = ServiceGraph::build("default", &[service("auth", &[])], &instances);
+        assert_eq!(graph.node("auth").unwrap().health, ServiceHealth::Degraded);
+        assert_eq!(graph.node("auth").unwrap().healthy_instances, 1);
+    }
+
+    #[test]
+    fn build_ignores_other_namespaces() {
+        let mut other = service("billing", &[]);
+        other.namespace = "prod".to_string();
+        let graph = ServiceGraph::build("default", &[service("web", &[]), other], &[]);
+        assert_eq!(graph.nodes.iter().map(|n| n.code.as_str()).collect::<Vec<_>>(), ["web"]);
+    }
+
+    #[test]
+    fn retain_drops_edges_of_hidden_nodes() {
+        let mut graph = graph();
+        graph.retain(|code| code != "auth");
+        assert!(graph.node("auth").is_none());
+        assert!(graph.edges.iter().all(|e| e.from != "auth" && e.to != "auth"));
+        assert_eq!(affected(&graph.impact("db").unwrap()), [("payments", 1, "db"), ("checkout", 2, "payments"), ("web", 3, "checkout")]);
+    }
+}
@@ -1,9 +1,12 @@
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
 pub enum HealthStatus {
     Healthy,
     Unhealthy,
     Unknown,
+    /// Reported by a gossip agent that failed to reach the instance; not yet
+    /// confirmed by its peers
+    Suspect,
 }
@@ -7,10 +7,13 @@ use utoipa::ToSchema;
 use crate::protocol::Protocol;
 use crate::runtime::Runtime;
 use crate::health::HealthStatus;
+use crate::namespace::default_namespace;
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct ServiceInstance {
     pub id: Uuid,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
     pub service_name: String,
     /// Network address (IP + port)
     pub address: SocketAddr,
@@ -19,6 +22,9 @@ pub struct ServiceInstance {
     pub metadata: HashMap<String, String>,
     pub last_seen: u64,
     pub health: HealthStatus,
+    /// Common name of the identity that registered the instance over the API
+    #[serde(default)]
+    pub registered_by: Option<String>,
 }
 
 impl ServiceInstance {
@@ -31,6 +37,7 @@ impl ServiceInstance {
     ) -> Self {
         Self {
             id: Uuid::new_v4(),
+            namespace: default_namespace(),
             service_name: service_name.into(),
             address,
             protocol,
@@ -38,6 +45,7 @@ impl ServiceInstance {
             metadata: HashMap::new(),
             last_seen,
             health: HealthStatus::Unknown,
+            registered_by: None,
         }
     }
 
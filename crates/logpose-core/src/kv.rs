@@ -0,0 +1,64 @@
+//! Hierarchical key/value store for configuration and feature flags.
+//!
+//! Keys are `/`-separated paths such as `config/billing/timeout`, held per
+//! namespace. Every write takes the next value of a store-wide index, which
+//! the written entry keeps as its `modify_index`. Writers pass the index
+//! they read as `cas` to update only if nobody changed the key since, and
+//! readers pass it to a blocking read to wait for the next change.
+//!
+//! A key is also a lock: a [`Session`](crate::Session) acquires it, and it
+//! is released by the session or when the session ends.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Longest key accepted, in bytes.
+pub const MAX_KEY_LEN: usize = 512;
+/// Largest value accepted, in bytes.
+pub const MAX_VALUE_LEN: usize = 512 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct KvEntry {
+    pub namespace: String,
+    #[schema(example = "config/billing/timeout")]
+    pub key: String,
+    #[schema(example = "30s")]
+    pub value: String,
+    /// Index of the write that created the key
+    pub create_index: u64,
+    /// Index of the write that last changed the key
+    pub modify_index: u64,
+    /// Session holding the key as a lock
+    #[serde(default)]
+    pub session: Option<uuid::Uuid>,
+    /// How many times the lock has been acquired
+    #[serde(default)]
+    pub lock_index: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidKey {
+    #[error("key must not be empty")]
+    Empty,
+    #[error("key is longer than {MAX_KEY_LEN} bytes")]
+    TooLong,
+    #[error("invalid key `{0}`: keys must not start with `/` or contain empty segments")]
+    Malformed(String),
+}
+
+/// Keys must be non-empty paths without a leading `/` or empty segments; a
+/// trailing `/` is allowed, for keys that mark a folder.
+pub fn validate_key(key: &str) -> Result<(), InvalidKey> {
+    if key.is_empty() {
+        return Err(InvalidKey::Empty);
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(InvalidKey::TooLong);
+    }
+    let segments = key.strip_suffix('/').unwrap_or(key);
+    if segments.split('/').any(str::is_empty) {
+        return Err(InvalidKey::Malformed(key.to_string()));
+    }
+    Ok(())
+}
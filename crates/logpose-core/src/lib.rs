@@ -7,6 +7,18 @@ pub mod registry;
 pub mod errors;
 pub mod time;
 pub mod auth;
+pub mod credential;
+pub mod events;
+pub mod policy;
+pub mod namespace;
+pub mod audit;
+pub mod snapshot;
+pub mod query;
+pub mod kv;
+pub mod session;
+pub mod webhook;
+pub mod graph;
+pub mod catalog;
 
 pub use service::Service;
 pub use instance::ServiceInstance;
@@ -14,4 +26,15 @@ pub use runtime::Runtime;
 pub use protocol::Protocol;
 pub use health::HealthStatus;
 pub use registry::{RegistryError, RegistryStore};
-pub use auth::{Identity, Role, Permission, Claims};
+pub use auth::{Identity, Role, Permission, Grant, RoleDefinition, RoleError, Claims, SigningKey};
+pub use policy::{Policy, Decision};
+pub use namespace::DEFAULT_NAMESPACE;
+pub use events::RegistryEvent;
+pub use audit::{AuditEntry, AuditFilter, AuditHead, AuditRecord};
+pub use snapshot::RegistrySnapshot;
+pub use query::PreparedQuery;
+pub use kv::KvEntry;
+pub use session::Session;
+pub use webhook::Webhook;
+pub use graph::ServiceGraph;
+pub use catalog::{Catalog, CatalogSearch, ServicePatch, Tier};
@@ -0,0 +1,31 @@
+//! Namespaces partition services, instances and role assignments so that
+//! teams sharing one registry can reuse service codes.
+
+use thiserror::Error;
+
+/// Namespace of everything created without naming one, including all data
+/// from before namespaces existed.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+#[derive(Debug, Error)]
+#[error("invalid namespace `{0}`: use 1-63 lowercase letters, digits and hyphens, starting and ending with a letter or digit")]
+pub struct InvalidNamespace(pub String);
+
+/// Namespaces must be valid DNS labels so they can appear in DNS names.
+pub fn validate(namespace: &str) -> Result<(), InvalidNamespace> {
+    let valid = (1..=63).contains(&namespace.len())
+        && namespace.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !namespace.starts_with('-')
+        && !namespace.ends_with('-');
+    if valid { Ok(()) } else { Err(InvalidNamespace(namespace.to_string())) }
+}
+
+/// Name of a service that is unique across namespaces, `<code>.<namespace>`.
+/// DNS names for services must be built from this rather than the bare code.
+pub fn qualified_name(namespace: &str, code: &str) -> String {
+    format!("{}.{}", code, namespace)
+}
+
+pub(crate) fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
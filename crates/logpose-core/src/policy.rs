@@ -0,0 +1,191 @@
+//! Evaluation of an identity's roles against a requested action, shared by
+//! the server and `logpose policy check`.
+
+use std::fmt;
+
+use crate::{Grant, Permission, RegistryError, RegistryStore, Role};
+
+/// The grants behind each of an identity's roles.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// `None` for a custom role that is no longer defined
+    roles: Vec<(Role, Option<Vec<Grant>>)>,
+}
+
+impl Policy {
+    /// Looks up the grants of every role; custom roles come from `store`.
+    pub fn resolve(roles: &[Role], store: &dyn RegistryStore) -> Result<Self, RegistryError> {
+        let roles = roles
+            .iter()
+            .map(|role| {
+                let grants = match role.builtin_grants() {
+                    Some(grants) => Some(grants),
+                    None => match store.get_role(role.name()) {
+                        Ok(definition) => Some(definition.grants),
+                        Err(RegistryError::RoleNotFound) => None,
+                        Err(e) => return Err(e),
+                    },
+                };
+                Ok((role.clone(), grants))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { roles })
+    }
+
+    /// Whether any role grants `permission` on `service`; see [`Grant::covers`].
+    pub fn allows(&self, permission: Permission, service: Option<&str>) -> bool {
+        self.roles
+            .iter()
+            .flat_map(|(_, grants)| grants.iter().flatten())
+            .any(|grant| grant.covers(permission, service))
+    }
+
+    /// Like [`Policy::allows`], recording what each role contributed.
+    pub fn check(&self, permission: Permission, service: Option<&str>) -> Decision {
+        let roles = self
+            .roles
+            .iter()
+            .map(|(role, grants)| {
+                let outcome = match grants {
+                    None => RoleOutcome::Undefined,
+                    Some(grants) => match grants.iter().find(|g| g.covers(permission, service)) {
+                        Some(grant) => RoleOutcome::Grants(grant.clone()),
+                        None => RoleOutcome::NoMatch(grants.clone()),
+                    },
+                };
+                RoleEvaluation { role: role.clone(), outcome }
+            })
+            .collect();
+        Decision {
+            permission,
+            service: service.map(str::to_string),
+            roles,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RoleOutcome {
+    /// The role allows the action through this grant
+    Grants(Grant),
+    /// None of the role's grants covers the action
+    NoMatch(Vec<Grant>),
+    /// The role is not defined in the registry
+    Undefined,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoleEvaluation {
+    pub role: Role,
+    pub outcome: RoleOutcome,
+}
+
+/// The outcome of [`Policy::check`].
+#[derive(Debug, Clone)]
+pub struct Decision {
+    pub permission: Permission,
+    pub service: Option<String>,
+    pub roles: Vec<RoleEvaluation>,
+}
+
+impl Decision {
+    pub fn allowed(&self) -> bool {
+        self.roles.iter().any(|r| matches!(r.outcome, RoleOutcome::Grants(_)))
+    }
+}
+
+/// A human-readable explanation: the verdict followed by one line per role.
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = match &self.service {
+            Some(code) => format!("{} on service `{}`", self.permission, code),
+            None => self.permission.to_string(),
+        };
+        writeln!(f, "{}: {}", if self.allowed() { "ALLOWED" } else { "DENIED" }, target)?;
+        if self.roles.is_empty() {
+            writeln!(f, "  identity holds no roles")?;
+        }
+        for evaluation in &self.roles {
+            let reason = match &evaluation.outcome {
+                RoleOutcome::Grants(grant) => format!("grants {}", grant),
+                RoleOutcome::NoMatch(grants) if grants.iter().any(|g| g.permission == self.permission) => {
+                    let scopes: Vec<String> = grants
+                        .iter()
+                        .filter(|g| g.permission == self.permission)
+                        .map(|g| format!("`{}`", g.services))
+                        .collect();
+                    format!("grants {} only on {}", self.permission, scopes.join(", "))
+                }
+                RoleOutcome::NoMatch(_) => format!("does not grant {}", self.permission),
+                RoleOutcome::Undefined => "is not defined".to_string(),
+            };
+            writeln!(f, "  role {} {}", evaluation.role, reason)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(spec: &str) -> Grant {
+        spec.parse().unwrap()
+    }
+
+    fn policy(roles: Vec<(Role, Option<Vec<Grant>>)>) -> Policy {
+        Policy { roles }
+    }
+
+    #[test]
+    fn grant_glob_scopes_services() {
+        let policy = policy(vec![(Role::Custom("billing-ops".into()), Some(vec![grant("InstanceWrite:billing-*")]))]);
+        assert!(policy.allows(Permission::InstanceWrite, Some("billing-api")));
+        assert!(policy.allows(Permission::InstanceWrite, Some("billing-")));
+        assert!(!policy.allows(Permission::InstanceWrite, Some("auth-svc")));
+        assert!(!policy.allows(Permission::InstanceWrite, Some("my-billing-api")));
+        assert!(!policy.allows(Permission::InstanceRead, Some("billing-api")));
+    }
+
+    #[test]
+    fn actions_on_no_single_service_need_any_grant_of_the_permission() {
+        let policy = policy(vec![(Role::Custom("billing-ops".into()), Some(vec![grant("InstanceWrite:billing-*")]))]);
+        assert!(policy.allows(Permission::InstanceWrite, None));
+        assert!(!policy.allows(Permission::ServiceWrite, None));
+    }
+
+    #[test]
+    fn grants_of_every_role_are_combined() {
+        let policy = policy(vec![
+            (Role::Viewer, Role::Viewer.builtin_grants()),
+            (Role::Custom("auth-writer".into()), Some(vec![grant("InstanceWrite:auth-?vc")])),
+            (Role::Custom("gone".into()), None),
+        ]);
+        assert!(policy.allows(Permission::InstanceRead, Some("anything")));
+        assert!(policy.allows(Permission::InstanceWrite, Some("auth-svc")));
+        assert!(!policy.allows(Permission::InstanceWrite, Some("auth-service")));
+        assert!(!policy.allows(Permission::UserManage, None));
+    }
+
+    #[test]
+    fn invalid_glob_grants_nothing() {
+        let policy = policy(vec![(Role::Custom("broken".into()), Some(vec![grant("InstanceRead:[auth")]))]);
+        assert!(!policy.allows(Permission::InstanceRead, Some("[auth")));
+        assert!(!policy.allows(Permission::InstanceRead, Some("auth")));
+    }
+
+    #[test]
+    fn check_explains_the_decision() {
+        let policy = policy(vec![
+            (Role::Custom("billing-ops".into()), Some(vec![grant("InstanceWrite:billing-*")])),
+            (Role::Custom("gone".into()), None),
+        ]);
+        let decision = policy.check(Permission::InstanceWrite, Some("auth-svc"));
+        assert!(!decision.allowed());
+        let explanation = decision.to_string();
+        assert!(explanation.starts_with("DENIED: "));
+        assert!(explanation.contains("role billing-ops grants InstanceWrite only on `billing-*`"));
+        assert!(explanation.contains("role gone is not defined"));
+        assert!(policy.check(Permission::InstanceWrite, Some("billing-api")).allowed());
+    }
+}
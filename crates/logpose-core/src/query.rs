@@ -0,0 +1,255 @@
+//! Prepared queries: named, stored discovery requests.
+//!
+//! A query names a service, filters its instances by tag, metadata and
+//! health, orders them nearest to a locality first, and lists where to look
+//! next when the service has no matching instance: other services of the same
+//! namespace, or the same service in other datacenters.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::namespace::default_namespace;
+use crate::{HealthStatus, ServiceInstance};
+
+/// Instance metadata key holding a comma-separated list of tags.
+pub const TAGS_METADATA_KEY: &str = "tags";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PreparedQuery {
+    #[schema(example = "payments-nearby")]
+    pub name: String,
+    /// Set from the namespace the query is created in
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    /// Code of the service to discover
+    #[schema(example = "payments")]
+    pub service: String,
+    /// Tags every returned instance must carry in its `tags` metadata
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Metadata every returned instance must have
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// Return only instances whose health is `Healthy`
+    #[serde(default = "only_healthy")]
+    pub only_healthy: bool,
+    /// Locality whose instances are returned first
+    #[serde(default)]
+    pub near: Option<Locality>,
+    /// Tried in order when no instance of the service matches
+    #[serde(default)]
+    pub failover: Vec<FailoverTarget>,
+}
+
+fn only_healthy() -> bool {
+    true
+}
+
+/// A position in the `region`/`zone`/`sub_zone` hierarchy of instance
+/// metadata, the keys xDS also takes localities from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Locality {
+    pub region: Option<String>,
+    pub zone: Option<String>,
+    pub sub_zone: Option<String>,
+}
+
+impl Locality {
+    pub fn is_empty(&self) -> bool {
+        self.region.is_none() && self.zone.is_none() && self.sub_zone.is_none()
+    }
+
+    /// How many levels of the hierarchy, from the region down, `instance`
+    /// shares with this locality. Levels left unset match any instance.
+    pub fn proximity(&self, instance: &ServiceInstance) -> usize {
+        [("region", &self.region), ("zone", &self.zone), ("sub_zone", &self.sub_zone)]
+            .into_iter()
+            .take_while(|(key, value)| value.as_ref().is_none_or(|v| instance.get_metadata(key) == Some(v)))
+            .count()
+    }
+}
+
+/// Where a query looks when the service has no matching instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FailoverTarget {
+    /// Another service of the query's namespace
+    Service(String),
+    /// The query's service in a federated datacenter
+    Datacenter(String),
+}
+
+/// Parses `service:<code>` or `dc:<datacenter>`.
+impl FromStr for FailoverTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("service", code)) => Ok(FailoverTarget::Service(code.to_string())),
+            Some(("dc", dc)) => Ok(FailoverTarget::Datacenter(dc.to_string())),
+            _ => Err(format!("expected service:<code> or dc:<datacenter>, got `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for FailoverTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailoverTarget::Service(code) => write!(f, "service:{}", code),
+            FailoverTarget::Datacenter(dc) => write!(f, "dc:{}", dc),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("invalid query name `{0}`: use 1-63 lowercase letters, digits and hyphens, starting and ending with a letter or digit")]
+    InvalidName(String),
+    #[error("query must name a service")]
+    NoService,
+    #[error("empty tag")]
+    EmptyTag,
+    #[error("failover target must not be empty")]
+    EmptyFailover,
+}
+
+impl PreparedQuery {
+    pub fn validate(&self) -> Result<(), QueryError> {
+        // Names appear in paths, so they follow the rules for namespaces.
+        crate::namespace::validate(&self.name).map_err(|_| QueryError::InvalidName(self.name.clone()))?;
+        if self.service.trim().is_empty() {
+            return Err(QueryError::NoService);
+        }
+        if self.tags.iter().any(|tag| tag.trim().is_empty()) {
+            return Err(QueryError::EmptyTag);
+        }
+        let empty_target = self.failover.iter().any(|target| match target {
+            FailoverTarget::Service(name) | FailoverTarget::Datacenter(name) => name.trim().is_empty(),
+        });
+        if empty_target {
+            return Err(QueryError::EmptyFailover);
+        }
+        Ok(())
+    }
+
+    /// Whether `instance` passes the query's health, tag and metadata filters.
+    pub fn matches(&self, instance: &ServiceInstance) -> bool {
+        if self.only_healthy && instance.health != HealthStatus::Healthy {
+            return false;
+        }
+        let tags: Vec<&str> = instance
+            .get_metadata(TAGS_METADATA_KEY)
+            .map(|tags| tags.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        self.tags.iter().all(|tag| tags.contains(&tag.as_str()))
+            && self.metadata.iter().all(|(key, value)| instance.get_metadata(key) == Some(value))
+    }
+
+    /// The matching instances of `instances`, nearest to `near` first.
+    pub fn select(&self, instances: Vec<ServiceInstance>, near: Option<&Locality>) -> Vec<ServiceInstance> {
+        let mut selected: Vec<_> = instances.into_iter().filter(|i| self.matches(i)).collect();
+        if let Some(near) = near {
+            // Stable, so instances equally near keep the registry's order.
+            selected.sort_by_key(|i| std::cmp::Reverse(near.proximity(i)));
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Protocol, Runtime};
+
+    fn query() -> PreparedQuery {
+        PreparedQuery {
+            name: "payments-nearby".to_string(),
+            namespace: default_namespace(),
+            service: "payments".to_string(),
+            tags: Vec::new(),
+            metadata: BTreeMap::new(),
+            only_healthy: true,
+            near: None,
+            failover: Vec::new(),
+        }
+    }
+
+    /// A healthy instance on `port` with `metadata`.
+    fn instance(port: u16, metadata: &[(&str, &str)]) -> ServiceInstance {
+        let address = format!("127.0.0.1:{}", port).parse().unwrap();
+        let mut instance = ServiceInstance::new("payments", address, Protocol::Http, Runtime::Vm { provider: None, id: None }, 0);
+        instance.set_health(HealthStatus::Healthy);
+        for (key, value) in metadata {
+            instance.add_metadata(*key, *value);
+        }
+        instance
+    }
+
+    fn ports(instances: &[ServiceInstance]) -> Vec<u16> {
+        instances.iter().map(|i| i.address.port()).collect()
+    }
+
+    fn locality(region: &str, zone: Option<&str>) -> Locality {
+        Locality { region: Some(region.to_string()), zone: zone.map(str::to_string), sub_zone: None }
+    }
+
+    #[test]
+    fn select_filters_by_health_tags_and_metadata() {
+        let mut unhealthy = instance(1, &[("tags", "v2, primary")]);
+        unhealthy.set_health(HealthStatus::Unhealthy);
+        let instances = vec![
+            unhealthy,
+            instance(2, &[("tags", "v2,primary"), ("tier", "gold")]),
+            instance(3, &[("tags", "v2"), ("tier", "gold")]),
+            instance(4, &[("tags", "primary, v2"), ("tier", "silver")]),
+        ];
+
+        let mut query = query();
+        query.tags = vec!["v2".to_string(), "primary".to_string()];
+        assert_eq!(ports(&query.select(instances.clone(), None)), [2, 4]);
+
+        query.metadata.insert("tier".to_string(), "gold".to_string());
+        assert_eq!(ports(&query.select(instances.clone(), None)), [2]);
+
+        query.metadata.clear();
+        query.only_healthy = false;
+        assert_eq!(ports(&query.select(instances, None)), [1, 2, 4]);
+    }
+
+    #[test]
+    fn select_orders_nearest_first_keeping_ties_in_order() {
+        let instances = vec![
+            instance(1, &[("region", "eu"), ("zone", "eu-b")]),
+            instance(2, &[("region", "us"), ("zone", "us-a")]),
+            instance(3, &[("region", "eu"), ("zone", "eu-a")]),
+            instance(4, &[]),
+            instance(5, &[("region", "eu"), ("zone", "eu-a")]),
+        ];
+        let query = query();
+        assert_eq!(ports(&query.select(instances.clone(), None)), [1, 2, 3, 4, 5]);
+        assert_eq!(ports(&query.select(instances.clone(), Some(&locality("eu", Some("eu-a"))))), [3, 5, 1, 2, 4]);
+        assert_eq!(ports(&query.select(instances, Some(&locality("eu", None)))), [1, 3, 5, 2, 4]);
+    }
+
+    #[test]
+    fn proximity_stops_at_the_first_differing_level() {
+        let instance = instance(1, &[("region", "eu"), ("zone", "eu-a"), ("sub_zone", "rack-1")]);
+        assert_eq!(locality("eu", Some("eu-a")).proximity(&instance), 3);
+        assert_eq!(locality("eu", Some("eu-b")).proximity(&instance), 1);
+        assert_eq!(locality("us", Some("eu-a")).proximity(&instance), 0);
+    }
+
+    #[test]
+    fn failover_targets_parse_and_display() {
+        for text in ["service:payments-backup", "dc:eu-west"] {
+            assert_eq!(text.parse::<FailoverTarget>().unwrap().to_string(), text);
+        }
+        assert!("payments".parse::<FailoverTarget>().is_err());
+        assert!("region:eu".parse::<FailoverTarget>().is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use crate::{Service, ServiceInstance, Identity, Role};
+use crate::snapshot::RevokedToken;
+use crate::webhook::{DeadLetter, DeliveryAttempt};
+use crate::{KvEntry, Session, Webhook, PreparedQuery, AuditEntry, AuditFilter, AuditHead, AuditRecord, Service, ServiceInstance, Identity, Role, RoleDefinition, SigningKey};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,17 +11,147 @@ pub enum RegistryError {
     InstanceNotFound,
     #[error("Duplicate instance")]
     DuplicateInstance,
+    #[error("Identity not found")]
+    IdentityNotFound,
+    #[error("Role not found")]
+    RoleNotFound,
+    #[error("Duplicate role")]
+    DuplicateRole,
+    #[error("Query not found")]
+    QueryNotFound,
+    #[error("Duplicate query")]
+    DuplicateQuery,
+    #[error("Key not found")]
+    KeyNotFound,
+    #[error("Key was modified since the given index")]
+    CasMismatch,
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error("Lock is held by another session")]
+    LockHeld,
+    #[error("Lock is not held by the session")]
+    LockNotHeld,
+    #[error("Webhook not found")]
+    WebhookNotFound,
+    #[error("Dead letter not found")]
+    DeadLetterNotFound,
+    #[error("Storage error")]
+    Storage,
 }
 
 pub trait RegistryStore {
     fn add_service(&self, service: &Service) -> Result<(), RegistryError>;
+    /// Replaces the fields of a registered service, keeping its instances.
+    /// Fails with `ServiceNotFound` if the service is not registered.
+    fn update_service(&self, service: &Service) -> Result<(), RegistryError>;
     fn add_instance(&self, instance: &ServiceInstance) -> Result<(), RegistryError>;
-    fn get_service(&self, code: &str) -> Result<Service, RegistryError>;
-    fn get_instances(&self, service_code: &str) -> Result<Vec<ServiceInstance>, RegistryError>;
+    fn get_service(&self, namespace: &str, code: &str) -> Result<Service, RegistryError>;
+    fn get_instances(&self, namespace: &str, service_code: &str) -> Result<Vec<ServiceInstance>, RegistryError>;
+    fn get_instance(&self, id: &uuid::Uuid) -> Result<ServiceInstance, RegistryError>;
+    fn remove_instance(&self, id: &uuid::Uuid) -> Result<(), RegistryError>;
+    /// Removes the service together with all of its instances.
+    fn remove_service(&self, namespace: &str, code: &str) -> Result<(), RegistryError>;
     fn add_identity(&self, identity: &Identity) -> Result<(), RegistryError>;
     fn get_identity(&self, common_name: &str) -> Result<Identity, RegistryError>;
-    fn add_role_to_identity(&self, common_name: &str, role: Role) -> Result<(), RegistryError>;
+    /// Returns all identities, ordered by common name.
+    fn list_identities(&self) -> Result<Vec<Identity>, RegistryError>;
+    /// Deletes the identity together with its roles and secret.
+    fn remove_identity(&self, common_name: &str) -> Result<(), RegistryError>;
+    fn add_role_to_identity(&self, common_name: &str, namespace: &str, role: Role) -> Result<(), RegistryError>;
+    /// Fails with `RoleNotFound` if the identity does not hold the role in
+    /// `namespace`.
+    fn remove_role_from_identity(&self, common_name: &str, namespace: &str, role: Role) -> Result<(), RegistryError>;
+    /// Blocks the identity from authenticating until it is re-enabled.
+    fn disable_identity(&self, common_name: &str) -> Result<(), RegistryError>;
+    fn enable_identity(&self, common_name: &str) -> Result<(), RegistryError>;
+    /// Stores a custom role; fails with `DuplicateRole` if the name is taken.
+    fn add_role(&self, role: &RoleDefinition) -> Result<(), RegistryError>;
+    fn get_role(&self, name: &str) -> Result<RoleDefinition, RegistryError>;
+    /// Returns all custom roles, ordered by name.
+    fn get_roles(&self) -> Result<Vec<RoleDefinition>, RegistryError>;
+    /// Deletes a custom role and takes it away from every identity holding it,
+    /// in every namespace.
+    fn remove_role(&self, name: &str) -> Result<(), RegistryError>;
+    /// Stores the hash of the identity's secret, replacing any previous one.
+    fn set_identity_secret(&self, common_name: &str, secret_hash: &str) -> Result<(), RegistryError>;
+    /// Returns the identity's secret hash, or `None` if it has no credential yet.
+    fn get_identity_secret(&self, common_name: &str) -> Result<Option<String>, RegistryError>;
+    /// Adds a token ID to the revocation list until `expires_at` (Unix seconds),
+    /// after which the token is rejected on expiry alone.
+    fn revoke_token(&self, jti: &str, expires_at: i64) -> Result<(), RegistryError>;
+    fn is_token_revoked(&self, jti: &str) -> Result<bool, RegistryError>;
+    fn list_revoked_tokens(&self) -> Result<Vec<RevokedToken>, RegistryError>;
+    fn add_signing_key(&self, key: &SigningKey) -> Result<(), RegistryError>;
+    /// Returns all signing keys, oldest first.
+    fn get_signing_keys(&self) -> Result<Vec<SigningKey>, RegistryError>;
+    fn remove_signing_key(&self, kid: &str) -> Result<(), RegistryError>;
     fn update_instance_health(&self, id: &uuid::Uuid, health: crate::HealthStatus) -> Result<(), RegistryError>;
+    /// Returns the instances of every namespace.
     fn get_all_instances(&self) -> Result<Vec<ServiceInstance>, RegistryError>;
+    /// Returns the services of every namespace.
     fn get_all_services(&self) -> Result<Vec<Service>, RegistryError>;
+    /// Chains `entry` onto the end of the audit log.
+    fn append_audit(&self, entry: AuditEntry) -> Result<AuditRecord, RegistryError>;
+    /// Returns the matching audit records, oldest first.
+    fn get_audit(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>, RegistryError>;
+    /// Returns the last record appended to the audit log, as stored apart
+    /// from it; `None` if nothing was appended since heads were stored.
+    fn get_audit_head(&self) -> Result<Option<AuditHead>, RegistryError>;
+    /// Stores a prepared query; fails with `DuplicateQuery` if its namespace
+    /// already has one of that name.
+    fn add_query(&self, query: &PreparedQuery) -> Result<(), RegistryError>;
+    fn get_query(&self, namespace: &str, name: &str) -> Result<PreparedQuery, RegistryError>;
+    /// Returns the prepared queries of `namespace`, ordered by name.
+    fn get_queries(&self, namespace: &str) -> Result<Vec<PreparedQuery>, RegistryError>;
+    /// Returns the prepared queries of every namespace.
+    fn get_all_queries(&self) -> Result<Vec<PreparedQuery>, RegistryError>;
+    fn remove_query(&self, namespace: &str, name: &str) -> Result<(), RegistryError>;
+    /// Creates or updates a key. With `cas`, only if the key's
+    /// `modify_index` is still `cas`, or if it does not exist for 0;
+    /// otherwise fails with `CasMismatch`.
+    fn put_key(&self, namespace: &str, key: &str, value: &str, cas: Option<u64>) -> Result<KvEntry, RegistryError>;
+    fn get_key(&self, namespace: &str, key: &str) -> Result<KvEntry, RegistryError>;
+    /// Returns the keys of `namespace` starting with `prefix`, ordered by key.
+    fn get_keys(&self, namespace: &str, prefix: &str) -> Result<Vec<KvEntry>, RegistryError>;
+    /// Returns the keys of every namespace.
+    fn get_all_keys(&self) -> Result<Vec<KvEntry>, RegistryError>;
+    /// Deletes a key; with `cas`, only if its `modify_index` is still `cas`.
+    fn remove_key(&self, namespace: &str, key: &str, cas: Option<u64>) -> Result<(), RegistryError>;
+    /// Deletes every key of `namespace` starting with `prefix`, if any.
+    fn remove_keys(&self, namespace: &str, prefix: &str) -> Result<(), RegistryError>;
+    /// The index of the latest write to the key/value store.
+    fn kv_index(&self) -> Result<u64, RegistryError>;
+    fn add_session(&self, session: &Session) -> Result<(), RegistryError>;
+    fn get_session(&self, id: &uuid::Uuid) -> Result<Session, RegistryError>;
+    /// Returns the sessions of `namespace`, oldest first.
+    fn get_sessions(&self, namespace: &str) -> Result<Vec<Session>, RegistryError>;
+    /// Returns the sessions of every namespace.
+    fn get_all_sessions(&self) -> Result<Vec<Session>, RegistryError>;
+    /// Moves the session's expiry to `expires_at` (Unix ms).
+    fn renew_session(&self, id: &uuid::Uuid, expires_at: u64) -> Result<(), RegistryError>;
+    /// Ends the session and releases every lock it holds.
+    fn remove_session(&self, id: &uuid::Uuid) -> Result<(), RegistryError>;
+    /// Acquires `key` for `session`, creating the key if needed and setting
+    /// its value. Fails with `LockHeld` if another session holds it.
+    fn acquire_lock(&self, namespace: &str, key: &str, value: &str, session: &uuid::Uuid) -> Result<KvEntry, RegistryError>;
+    /// Releases `key`, keeping its value. Fails with `LockNotHeld` unless
+    /// `session` holds it.
+    fn release_lock(&self, namespace: &str, key: &str, session: &uuid::Uuid) -> Result<KvEntry, RegistryError>;
+    fn add_webhook(&self, webhook: &Webhook) -> Result<(), RegistryError>;
+    fn get_webhook(&self, id: &uuid::Uuid) -> Result<Webhook, RegistryError>;
+    fn get_webhooks(&self, namespace: &str) -> Result<Vec<Webhook>, RegistryError>;
+    fn get_all_webhooks(&self) -> Result<Vec<Webhook>, RegistryError>;
+    /// Removes the webhook with its delivery log and dead letters.
+    fn remove_webhook(&self, id: &uuid::Uuid) -> Result<(), RegistryError>;
+    /// Appends to the webhook's delivery log, dropping its oldest attempts
+    /// beyond [`DELIVERY_LOG_LIMIT`](crate::webhook::DELIVERY_LOG_LIMIT).
+    fn record_delivery(&self, attempt: &DeliveryAttempt) -> Result<(), RegistryError>;
+    /// Returns the webhook's delivery log, newest first.
+    fn get_deliveries(&self, webhook: &uuid::Uuid) -> Result<Vec<DeliveryAttempt>, RegistryError>;
+    fn get_all_deliveries(&self) -> Result<Vec<DeliveryAttempt>, RegistryError>;
+    fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), RegistryError>;
+    /// Returns the webhook's dead letters, oldest first.
+    fn get_dead_letters(&self, webhook: &uuid::Uuid) -> Result<Vec<DeadLetter>, RegistryError>;
+    fn get_all_dead_letters(&self) -> Result<Vec<DeadLetter>, RegistryError>;
+    fn remove_dead_letter(&self, delivery: &uuid::Uuid) -> Result<(), RegistryError>;
 }
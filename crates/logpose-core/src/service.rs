@@ -1,13 +1,23 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use utoipa::ToSchema;
+use crate::catalog::{Catalog, CatalogError};
 use crate::instance::ServiceInstance;
+use crate::namespace::default_namespace;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Service {
+    pub namespace: String,
     pub name: String,
     pub code: String,
     pub description: String,
     pub instances: Vec<ServiceInstance>,
     pub metadata: HashMap<String, String>,
+    /// Codes of the services of the same namespace this one calls
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub catalog: Catalog,
 }
 
 impl Service {
@@ -17,13 +27,24 @@ impl Service {
         description: impl Into<String>,
     ) -> Self {
         Self {
+            namespace: default_namespace(),
             name: name.into(),
             code: code.into(),
             description: description.into(),
             instances: Vec::new(),
             metadata: HashMap::new(),
+            dependencies: Vec::new(),
+            catalog: Catalog::default(),
         }
     }
+
+    /// Checks the dependencies and catalog fields set by a client.
+    pub fn validate(&self) -> Result<(), CatalogError> {
+        if self.dependencies.contains(&self.code) {
+            return Err(CatalogError::SelfDependency);
+        }
+        self.catalog.validate()
+    }
     pub fn add_instance(&mut self, instance: ServiceInstance) {
         self.instances.push(instance);
     }
@@ -0,0 +1,83 @@
+//! Sessions, which hold locks on keys of the key/value store.
+//!
+//! A session follows the liveness of an instance, expires after a TTL
+//! unless renewed, or both. It ends when its instance turns `Unhealthy` or
+//! is deregistered, when its TTL runs out, or when it is destroyed; every
+//! lock it holds is then released, so another session can take over.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::namespace::default_namespace;
+
+/// Shortest and longest TTL a session may have, in seconds.
+pub const MIN_TTL_SECS: u64 = 10;
+pub const MAX_TTL_SECS: u64 = 86400;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Session {
+    pub id: Uuid,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    #[schema(example = "report-builder")]
+    pub name: String,
+    /// Instance whose liveness the session follows
+    pub instance: Option<Uuid>,
+    /// Seconds the session lives without being renewed
+    pub ttl_secs: Option<u64>,
+    /// Unix ms at which the session expires unless renewed
+    pub expires_at: Option<u64>,
+    /// Unix ms
+    pub created_at: u64,
+    /// Common name of the identity that created the session
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("session name must not be empty")]
+    EmptyName,
+    #[error("a session needs an instance, a TTL or both")]
+    Unbounded,
+    #[error("session TTL must be between {MIN_TTL_SECS} and {MAX_TTL_SECS} seconds")]
+    InvalidTtl,
+}
+
+impl Session {
+    /// A session named `name` in `namespace`; its expiry is set from the TTL.
+    pub fn new(namespace: impl Into<String>, name: impl Into<String>, instance: Option<Uuid>, ttl_secs: Option<u64>) -> Self {
+        let created_at = crate::time::now();
+        Self {
+            id: Uuid::new_v4(),
+            namespace: namespace.into(),
+            name: name.into(),
+            instance,
+            ttl_secs,
+            expires_at: ttl_secs.map(|ttl| created_at + ttl * 1000),
+            created_at,
+            owner: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), SessionError> {
+        if self.name.trim().is_empty() {
+            return Err(SessionError::EmptyName);
+        }
+        match self.ttl_secs {
+            None if self.instance.is_none() => Err(SessionError::Unbounded),
+            Some(ttl) if !(MIN_TTL_SECS..=MAX_TTL_SECS).contains(&ttl) => Err(SessionError::InvalidTtl),
+            _ => Ok(()),
+        }
+    }
+
+    /// Unix ms the session would expire at if renewed at `now`.
+    pub fn renewed_at(&self, now: u64) -> Option<u64> {
+        self.ttl_secs.map(|ttl| now + ttl * 1000)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
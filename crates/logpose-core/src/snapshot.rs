@@ -0,0 +1,76 @@
+//! Point-in-time copies of a registry's contents.
+//!
+//! A snapshot holds everything a [`RegistryStore`] persists, read through
+//! the trait, so that a store can be rebuilt elsewhere, e.g. on a cluster
+//! node catching up with its leader.
+
+use serde::{Deserialize, Serialize};
+
+use crate::webhook::{DeadLetter, DeliveryAttempt};
+use crate::{KvEntry, Session, Webhook, PreparedQuery, AuditFilter, AuditRecord, Identity, RegistryError, RegistryStore, RoleDefinition, Service, ServiceInstance, SigningKey};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityRecord {
+    pub identity: Identity,
+    pub secret_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub expires_at: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RegistrySnapshot {
+    pub services: Vec<Service>,
+    pub instances: Vec<ServiceInstance>,
+    pub identities: Vec<IdentityRecord>,
+    pub roles: Vec<RoleDefinition>,
+    pub revoked_tokens: Vec<RevokedToken>,
+    pub signing_keys: Vec<SigningKey>,
+    pub audit: Vec<AuditRecord>,
+    #[serde(default)]
+    pub queries: Vec<PreparedQuery>,
+    #[serde(default)]
+    pub keys: Vec<KvEntry>,
+    #[serde(default)]
+    pub kv_index: u64,
+    #[serde(default)]
+    pub sessions: Vec<Session>,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    #[serde(default)]
+    pub deliveries: Vec<DeliveryAttempt>,
+    #[serde(default)]
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+impl RegistrySnapshot {
+    pub fn capture(store: &dyn RegistryStore) -> Result<Self, RegistryError> {
+        let identities = store
+            .list_identities()?
+            .into_iter()
+            .map(|identity| {
+                let secret_hash = store.get_identity_secret(&identity.common_name)?;
+                Ok(IdentityRecord { identity, secret_hash })
+            })
+            .collect::<Result<_, RegistryError>>()?;
+        Ok(Self {
+            services: store.get_all_services()?,
+            instances: store.get_all_instances()?,
+            identities,
+            roles: store.get_roles()?,
+            revoked_tokens: store.list_revoked_tokens()?,
+            signing_keys: store.get_signing_keys()?,
+            audit: store.get_audit(&AuditFilter::default())?,
+            queries: store.get_all_queries()?,
+            keys: store.get_all_keys()?,
+            kv_index: store.kv_index()?,
+            sessions: store.get_all_sessions()?,
+            webhooks: store.get_all_webhooks()?,
+            deliveries: store.get_all_deliveries()?,
+            dead_letters: store.get_all_dead_letters()?,
+        })
+    }
+}
@@ -0,0 +1,289 @@
+//! Outbound webhooks for registry events.
+//!
+//! A webhook subscribes a URL to the events of one namespace, optionally
+//! narrowed by kind, by service and, for health changes, by new status.
+//! Every delivery is a JSON [`WebhookPayload`] signed with the webhook's
+//! secret. Failed deliveries are retried; once out of attempts they become
+//! [`DeadLetter`]s, which can be redelivered by hand. Each attempt is kept
+//! in a bounded delivery log.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::credential::hmac_sha256;
+use crate::namespace::default_namespace;
+use crate::{HealthStatus, RegistryEvent};
+
+/// Attempts kept in the delivery log of each webhook.
+pub const DELIVERY_LOG_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Webhook {
+    pub id: Uuid,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    #[schema(example = "oncall-alerts")]
+    pub name: String,
+    #[schema(example = "https://alerts.example.com/hooks/logpose")]
+    pub url: String,
+    /// Kinds of event delivered; every kind when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// Glob of the service codes whose events are delivered; events not
+    /// about a service, such as key changes, are not filtered by it
+    #[serde(default = "all_services")]
+    pub services: String,
+    /// Health changes delivered, by new status; every change when empty
+    #[serde(default)]
+    pub statuses: Vec<HealthStatus>,
+    /// Key of the HMAC-SHA256 payload signature; only returned when the
+    /// webhook is created
+    pub secret: Option<String>,
+    /// Unix ms
+    pub created_at: u64,
+}
+
+fn all_services() -> String {
+    "*".to_string()
+}
+
+/// The kinds of [`RegistryEvent`] a webhook can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    ServiceRegistered,
+    ServiceUpdated,
+    ServiceDeregistered,
+    InstanceRegistered,
+    InstanceDeregistered,
+    InstanceHealthChanged,
+    KeyChanged,
+    SessionDestroyed,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 8] = [
+        EventKind::ServiceRegistered,
+        EventKind::ServiceUpdated,
+        EventKind::ServiceDeregistered,
+        EventKind::InstanceRegistered,
+        EventKind::InstanceDeregistered,
+        EventKind::InstanceHealthChanged,
+        EventKind::KeyChanged,
+        EventKind::SessionDestroyed,
+    ];
+
+    /// The kind of `event`; `None` for events that are not delivered, such
+    /// as a registry replaced by a snapshot.
+    pub fn of(event: &RegistryEvent) -> Option<Self> {
+        match event {
+            RegistryEvent::ServiceRegistered { .. } => Some(EventKind::ServiceRegistered),
+            RegistryEvent::ServiceUpdated { .. } => Some(EventKind::ServiceUpdated),
+            RegistryEvent::ServiceDeregistered { .. } => Some(EventKind::ServiceDeregistered),
+            RegistryEvent::InstanceRegistered { .. } => Some(EventKind::InstanceRegistered),
+            RegistryEvent::InstanceDeregistered { .. } => Some(EventKind::InstanceDeregistered),
+            RegistryEvent::InstanceHealthChanged { .. } => Some(EventKind::InstanceHealthChanged),
+            RegistryEvent::KeyChanged { .. } => Some(EventKind::KeyChanged),
+            RegistryEvent::SessionDestroyed { .. } => Some(EventKind::SessionDestroyed),
+            RegistryEvent::Replaced => None,
+        }
+    }
+}
+
+/// Formats as the snake_case name used in payloads, e.g. `instance_health_changed`.
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = serde_json::to_value(self).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventKind::ALL
+            .into_iter()
+            .find(|kind| kind.to_string() == s)
+            .ok_or_else(|| format!("unknown event kind `{}`", s))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("webhook name must not be empty")]
+    EmptyName,
+    #[error("webhook url must be an http:// or https:// URL, got `{0}`")]
+    InvalidUrl(String),
+    #[error("invalid service glob `{0}`")]
+    InvalidServices(String),
+}
+
+impl Webhook {
+    pub fn validate(&self) -> Result<(), WebhookError> {
+        if self.name.trim().is_empty() {
+            return Err(WebhookError::EmptyName);
+        }
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(WebhookError::InvalidUrl(self.url.clone()));
+        }
+        if glob::Pattern::new(&self.services).is_err() {
+            return Err(WebhookError::InvalidServices(self.services.clone()));
+        }
+        Ok(())
+    }
+
+    /// Whether `event` is delivered to this webhook.
+    pub fn matches(&self, event: &RegistryEvent) -> bool {
+        let Some(kind) = EventKind::of(event) else {
+            return false;
+        };
+        if event.namespace() != Some(self.namespace.as_str()) {
+            return false;
+        }
+        if !self.events.is_empty() && !self.events.contains(&kind) {
+            return false;
+        }
+        if let Some(service) = event.service()
+            && !glob::Pattern::new(&self.services).is_ok_and(|pattern| pattern.matches(service))
+        {
+            return false;
+        }
+        match event {
+            RegistryEvent::InstanceHealthChanged { to, .. } => self.statuses.is_empty() || self.statuses.contains(to),
+            _ => true,
+        }
+    }
+
+    /// The webhook without its secret, as returned after creation.
+    pub fn redacted(mut self) -> Self {
+        self.secret = None;
+        self
+    }
+}
+
+/// The body of a delivery.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct WebhookPayload {
+    /// Delivery ID, the same on every attempt
+    pub id: Uuid,
+    pub webhook: Uuid,
+    pub kind: EventKind,
+    /// Unix ms at which the event happened
+    pub timestamp: u64,
+    #[schema(value_type = Object)]
+    pub event: RegistryEvent,
+}
+
+/// One attempt at delivering a payload, as kept in the delivery log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct DeliveryAttempt {
+    /// Delivery ID, the same on every attempt
+    pub delivery: Uuid,
+    pub webhook: Uuid,
+    pub kind: EventKind,
+    /// 1 for the first attempt
+    pub attempt: u32,
+    /// Unix ms
+    pub timestamp: u64,
+    /// HTTP status the endpoint answered with, if it answered
+    pub status_code: Option<u16>,
+    /// Why the attempt failed; `None` if it succeeded
+    pub error: Option<String>,
+}
+
+/// A delivery that failed on every attempt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct DeadLetter {
+    /// Delivery ID
+    pub delivery: Uuid,
+    pub webhook: Uuid,
+    pub kind: EventKind,
+    /// The payload, as JSON
+    pub payload: String,
+    pub attempts: u32,
+    pub last_error: String,
+    /// Unix ms
+    pub failed_at: u64,
+}
+
+/// HMAC-SHA256 of `{timestamp}.{body}` keyed with `secret`, hex-encoded,
+/// as sent in the `x-logpose-signature` header.
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let prefix = format!("{}.", timestamp);
+    hex::encode(hmac_sha256(secret.as_bytes(), &[prefix.as_bytes(), body]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook() -> Webhook {
+        Webhook {
+            id: Uuid::new_v4(),
+            namespace: "default".to_string(),
+            name: "alerts".to_string(),
+            url: "https://alerts.example.com/hook".to_string(),
+            events: Vec::new(),
+            services: all_services(),
+            statuses: Vec::new(),
+            secret: None,
+            created_at: 0,
+        }
+    }
+
+    fn health_changed(service: &str, to: HealthStatus) -> RegistryEvent {
+        RegistryEvent::InstanceHealthChanged {
+            namespace: "default".to_string(),
+            service_code: service.to_string(),
+            id: Uuid::new_v4(),
+            from: HealthStatus::Healthy,
+            to,
+        }
+    }
+
+    #[test]
+    fn sign_is_hmac_sha256_of_timestamp_and_body() {
+        // Expected values computed independently with Python's hmac module.
+        assert_eq!(
+            sign("whsec_test", 1_700_000_000_000, br#"{"kind":"service_registered"}"#),
+            "e8d236bfe72750d53b9be05b725007d294c638887f0c0f8c4bd1d79133999ffe"
+        );
+        // Keys longer than the SHA-256 block are hashed first.
+        assert_eq!(
+            sign(&"k".repeat(100), 1, b"body"),
+            "bdb7ea4e322fd7238fe87a2276f762d9ffc4402c07e3b94d0bab8a7eb262ccda"
+        );
+    }
+
+    #[test]
+    fn sign_depends_on_secret_timestamp_and_body() {
+        let signature = sign("secret", 1, b"body");
+        assert_ne!(sign("other", 1, b"body"), signature);
+        assert_ne!(sign("secret", 2, b"body"), signature);
+        assert_ne!(sign("secret", 1, b"b0dy"), signature);
+    }
+
+    #[test]
+    fn matches_filters_by_kind_service_and_status() {
+        let mut hook = webhook();
+        assert!(hook.matches(&health_changed("auth", HealthStatus::Unhealthy)));
+        assert!(!hook.matches(&RegistryEvent::Replaced));
+
+        hook.services = "auth*".to_string();
+        hook.statuses = vec![HealthStatus::Unhealthy];
+        assert!(hook.matches(&health_changed("auth-svc", HealthStatus::Unhealthy)));
+        assert!(!hook.matches(&health_changed("billing", HealthStatus::Unhealthy)));
+        assert!(!hook.matches(&health_changed("auth-svc", HealthStatus::Healthy)));
+
+        hook.events = vec![EventKind::ServiceUpdated];
+        assert!(!hook.matches(&health_changed("auth-svc", HealthStatus::Unhealthy)));
+        assert!(hook.matches(&RegistryEvent::ServiceUpdated { namespace: "default".to_string(), code: "auth-svc".to_string() }));
+        assert!(!hook.matches(&RegistryEvent::ServiceUpdated { namespace: "prod".to_string(), code: "auth-svc".to_string() }));
+    }
+}
@@ -1,3 +1,5 @@
+pub mod raft;
 pub mod sqlite;
 
+pub use raft::RaftStorage;
 pub use sqlite::DbRegistry;
@@ -0,0 +1,136 @@
+//! Durable state of a Raft node: its term and vote, its log, and the latest
+//! snapshot of the replicated registry.
+//!
+//! Kept in a database file of its own, separate from the registry it
+//! replicates. Log entries and snapshots are stored as the JSON the server
+//! hands over; this module does not interpret them.
+
+use rusqlite::{params, Connection, OptionalExtension, Result as SqlResult};
+
+use std::sync::Mutex;
+
+/// A log entry as stored: its position, the term it was created in, and
+/// the serialized entry.
+#[derive(Debug, Clone)]
+pub struct StoredEntry {
+    pub index: u64,
+    pub term: u64,
+    pub data: String,
+}
+
+/// The last snapshot taken or installed; it covers the log up to and
+/// including `index`.
+#[derive(Debug, Clone)]
+pub struct StoredSnapshot {
+    pub index: u64,
+    pub term: u64,
+    pub membership: String,
+    pub data: String,
+}
+
+/// Everything a node reads back when it restarts.
+#[derive(Debug, Default)]
+pub struct RaftState {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+    pub snapshot: Option<StoredSnapshot>,
+    /// Entries after the snapshot, in order
+    pub entries: Vec<StoredEntry>,
+}
+
+pub struct RaftStorage {
+    conn: Mutex<Connection>,
+}
+
+impl RaftStorage {
+    pub fn new(path: &str) -> SqlResult<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS raft_meta (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                term INTEGER NOT NULL,
+                voted_for INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS raft_log (
+                idx INTEGER PRIMARY KEY,
+                term INTEGER NOT NULL,
+                entry TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS raft_snapshot (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                idx INTEGER NOT NULL,
+                term INTEGER NOT NULL,
+                membership TEXT NOT NULL,
+                data TEXT NOT NULL
+            );
+            "
+        )?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    pub fn load(&self) -> SqlResult<RaftState> {
+        let conn = self.conn.lock().unwrap();
+        let (term, voted_for) = conn
+            .query_row("SELECT term, voted_for FROM raft_meta WHERE id = 0", [], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?
+            .unwrap_or((0, None));
+        let snapshot = read_snapshot(&conn)?;
+        let mut stmt = conn.prepare("SELECT idx, term, entry FROM raft_log ORDER BY idx")?;
+        let entries = stmt
+            .query_map([], |row| Ok(StoredEntry { index: row.get(0)?, term: row.get(1)?, data: row.get(2)? }))?
+            .collect::<SqlResult<Vec<_>>>()?;
+        Ok(RaftState { term, voted_for, snapshot, entries })
+    }
+
+    pub fn snapshot(&self) -> SqlResult<Option<StoredSnapshot>> {
+        read_snapshot(&self.conn.lock().unwrap())
+    }
+
+    pub fn save_hard_state(&self, term: u64, voted_for: Option<u64>) -> SqlResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO raft_meta (id, term, voted_for) VALUES (0, ?1, ?2)",
+            params![term, voted_for],
+        )?;
+        Ok(())
+    }
+
+    pub fn append(&self, entries: &[StoredEntry]) -> SqlResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for entry in entries {
+            tx.execute(
+                "INSERT OR REPLACE INTO raft_log (idx, term, entry) VALUES (?1, ?2, ?3)",
+                params![entry.index, entry.term, entry.data],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Drops the entry at `index` and every entry after it.
+    pub fn truncate_from(&self, index: u64) -> SqlResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM raft_log WHERE idx >= ?1", params![index])?;
+        Ok(())
+    }
+
+    /// Stores `snapshot` and drops the entries it covers.
+    pub fn save_snapshot(&self, snapshot: &StoredSnapshot) -> SqlResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO raft_snapshot (id, idx, term, membership, data) VALUES (0, ?1, ?2, ?3, ?4)",
+            params![snapshot.index, snapshot.term, snapshot.membership, snapshot.data],
+        )?;
+        tx.execute("DELETE FROM raft_log WHERE idx <= ?1", params![snapshot.index])?;
+        tx.commit()
+    }
+}
+
+fn read_snapshot(conn: &Connection) -> SqlResult<Option<StoredSnapshot>> {
+    conn.query_row("SELECT idx, term, membership, data FROM raft_snapshot WHERE id = 0", [], |row| {
+        Ok(StoredSnapshot { index: row.get(0)?, term: row.get(1)?, membership: row.get(2)?, data: row.get(3)? })
+    })
+    .optional()
+}
@@ -1,9 +1,12 @@
-use rusqlite::{params, Connection, Result as SqlResult};
+use rusqlite::{params, Connection, Result as SqlResult, TransactionBehavior};
 use serde_json;
 use uuid::Uuid;
 
-use logpose_core::{Service, ServiceInstance, Protocol, Runtime, HealthStatus, RegistryError, RegistryStore, Identity, Role};
+use logpose_core::snapshot::RevokedToken;
+use logpose_core::webhook::{DeadLetter, DeliveryAttempt, DELIVERY_LOG_LIMIT};
+use logpose_core::{audit, RegistrySnapshot, KvEntry, PreparedQuery, Session, Webhook, AuditEntry, AuditFilter, AuditHead, AuditRecord, Service, ServiceInstance, Protocol, Runtime, HealthStatus, RegistryError, RegistryStore, Identity, Role, RoleDefinition, SigningKey};
 
+use std::collections::BTreeMap;
 use std::sync::Mutex;
 
 pub struct DbRegistry {
@@ -19,208 +22,1004 @@ impl DbRegistry {
     }
 
     fn init_tables(&self) -> SqlResult<()> {
-        let conn = self.conn.lock().unwrap();
+        let mut conn = self.conn.lock().unwrap();
+        // Rebuilding `services` changes the key `instances` refers to, so
+        // foreign keys stay off until every table has been rebuilt.
+        conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+        let rebuilt = rebuild_if_missing(&mut conn, "services", "namespace", SERVICES_TABLE)
+            .and_then(|_| rebuild_if_missing(&mut conn, "instances", "namespace", INSTANCES_TABLE))
+            .and_then(|_| rebuild_if_missing(&mut conn, "identity_roles", "namespace", IDENTITY_ROLES_TABLE));
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+        rebuilt?;
+        conn.execute_batch(SERVICES_TABLE)?;
+        conn.execute_batch(INSTANCES_TABLE)?;
+        conn.execute_batch(IDENTITY_ROLES_TABLE)?;
         conn.execute_batch(
             "
-            CREATE TABLE IF NOT EXISTS services (
-                code TEXT PRIMARY KEY,
+            CREATE TABLE IF NOT EXISTS identities (
+                common_name TEXT PRIMARY KEY,
+                organization TEXT,
+                metadata TEXT,
+                secret_hash TEXT,
+                disabled INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS revoked_tokens (
+                jti TEXT PRIMARY KEY,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS signing_keys (
+                kid TEXT PRIMARY KEY,
+                algorithm TEXT NOT NULL,
+                private_key TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS roles (
+                name TEXT PRIMARY KEY,
+                description TEXT NOT NULL,
+                grants TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS audit_log (
+                seq INTEGER PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                actor TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target TEXT NOT NULL,
+                before TEXT,
+                after TEXT,
+                source_ip TEXT,
+                prev_hash TEXT NOT NULL,
+                hash TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS audit_head (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                seq INTEGER NOT NULL,
+                hash TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS prepared_queries (
+                namespace TEXT NOT NULL,
                 name TEXT NOT NULL,
-                description TEXT,
-                metadata TEXT
+                definition TEXT NOT NULL,
+                PRIMARY KEY(namespace, name)
+            );
+            CREATE TABLE IF NOT EXISTS kv (
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                create_index INTEGER NOT NULL,
+                modify_index INTEGER NOT NULL,
+                session TEXT,
+                lock_index INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY(namespace, key)
             );
-            CREATE TABLE IF NOT EXISTS instances (
+            CREATE TABLE IF NOT EXISTS sessions (
                 id TEXT PRIMARY KEY,
-                service_code TEXT NOT NULL,
-                address TEXT NOT NULL,
-                protocol TEXT NOT NULL,
-                runtime TEXT NOT NULL,
-                metadata TEXT,
-                health TEXT NOT NULL,
-                FOREIGN KEY(service_code) REFERENCES services(code)
+                namespace TEXT NOT NULL,
+                name TEXT NOT NULL,
+                instance TEXT,
+                ttl_secs INTEGER,
+                expires_at INTEGER,
+                created_at INTEGER NOT NULL,
+                owner TEXT
             );
-            CREATE TABLE IF NOT EXISTS identities (
-                common_name TEXT PRIMARY KEY,
-                organization TEXT,
-                metadata TEXT
+            CREATE TABLE IF NOT EXISTS webhooks (
+                id TEXT PRIMARY KEY,
+                namespace TEXT NOT NULL,
+                definition TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                seq INTEGER PRIMARY KEY,
+                webhook TEXT NOT NULL,
+                attempt TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS webhook_dead_letters (
+                delivery TEXT PRIMARY KEY,
+                webhook TEXT NOT NULL,
+                dead_letter TEXT NOT NULL
             );
-            CREATE TABLE IF NOT EXISTS identity_roles (
-                common_name TEXT,
-                role TEXT,
-                PRIMARY KEY(common_name, role),
-                FOREIGN KEY(common_name) REFERENCES identities(common_name)
+            CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
             );
 
             "
         )?;
+        conn.execute_batch(AUDIT_TRIGGERS)?;
+        add_column_if_missing(&conn, "services", "dependencies", "TEXT")?;
+        add_column_if_missing(&conn, "services", "catalog", "TEXT")?;
+        add_column_if_missing(&conn, "identities", "secret_hash", "TEXT")?;
+        add_column_if_missing(&conn, "identities", "disabled", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "kv", "session", "TEXT")?;
+        add_column_if_missing(&conn, "kv", "lock_index", "INTEGER NOT NULL DEFAULT 0")?;
         Ok(())
     }
+
+    fn set_identity_disabled(&self, common_name: &str, disabled: bool) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE identities SET disabled = ?1 WHERE common_name = ?2",
+            params![disabled, common_name]
+        ).map_err(|_| RegistryError::Storage)?;
+        if updated == 0 {
+            return Err(RegistryError::IdentityNotFound);
+        }
+        Ok(())
+    }
+
+    /// Replaces the entire contents of the registry with `snapshot`, in one
+    /// transaction: if any row fails, the registry is left as it was.
+    pub fn restore(&self, snapshot: &RegistrySnapshot) -> Result<(), RegistryError> {
+        {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().map_err(|_| RegistryError::Storage)?;
+            tx.execute_batch(
+                "DROP TRIGGER IF EXISTS audit_log_no_update;
+                 DROP TRIGGER IF EXISTS audit_log_no_delete;
+                 DELETE FROM identity_roles;
+                 DELETE FROM instances;
+                 DELETE FROM services;
+                 DELETE FROM identities;
+                 DELETE FROM roles;
+                 DELETE FROM revoked_tokens;
+                 DELETE FROM signing_keys;
+                 DELETE FROM prepared_queries;
+                 DELETE FROM kv;
+                 DELETE FROM sessions;
+                 DELETE FROM webhooks;
+                 DELETE FROM webhook_deliveries;
+                 DELETE FROM webhook_dead_letters;
+                 DELETE FROM audit_log;",
+            ).map_err(|_| RegistryError::Storage)?;
+            tx.execute_batch(AUDIT_TRIGGERS).map_err(|_| RegistryError::Storage)?;
+            let json = |value: &Option<serde_json::Value>| value.as_ref().map(|v| v.to_string());
+            for record in &snapshot.audit {
+                tx.execute(
+                    &format!("INSERT INTO audit_log ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", AUDIT_COLUMNS),
+                    params![
+                        record.seq, record.timestamp, record.actor, record.action, record.target,
+                        json(&record.before), json(&record.after), record.source_ip, record.prev_hash, record.hash
+                    ],
+                ).map_err(|_| RegistryError::Storage)?;
+            }
+            set_audit_head(&tx, snapshot.audit.last().map(AuditHead::from).as_ref())
+                .map_err(|_| RegistryError::Storage)?;
+            // Entries keep their indexes, which clients hold on to for
+            // check-and-set and blocking reads.
+            for entry in &snapshot.keys {
+                insert_key(&tx, entry).map_err(|_| RegistryError::Storage)?;
+            }
+            set_kv_index(&tx, snapshot.kv_index).map_err(|_| RegistryError::Storage)?;
+            restore_rows(&tx, snapshot).map_err(|_| RegistryError::Storage)?;
+            tx.commit().map_err(|_| RegistryError::Storage)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the database as the replica of a cluster node, whose writes
+    /// must go through the cluster rather than directly to the file.
+    pub fn set_replicated(&self, replicated: bool) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO settings (key, value) VALUES ('replicated', ?1)",
+            params![replicated.to_string()],
+        ).map_err(|_| RegistryError::Storage)?;
+        Ok(())
+    }
+
+    pub fn is_replicated(&self) -> Result<bool, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let value: Option<String> = conn.query_row(
+            "SELECT value FROM settings WHERE key = 'replicated'",
+            [],
+            |row| row.get(0),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        }).map_err(|_| RegistryError::Storage)?;
+        Ok(value.as_deref() == Some("true"))
+    }
+}
+
+const SERVICES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS services (
+        namespace TEXT NOT NULL DEFAULT 'default',
+        code TEXT NOT NULL,
+        name TEXT NOT NULL,
+        description TEXT,
+        metadata TEXT,
+        dependencies TEXT,
+        catalog TEXT,
+        PRIMARY KEY(namespace, code)
+    );";
+
+/// The audit log is append-only; only `restore` lifts this, to replace the
+/// whole registry.
+const AUDIT_TRIGGERS: &str = "
+    CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+    BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END;
+    CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+    BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END;";
+
+const INSTANCES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS instances (
+        id TEXT PRIMARY KEY,
+        namespace TEXT NOT NULL DEFAULT 'default',
+        service_code TEXT NOT NULL,
+        address TEXT NOT NULL,
+        protocol TEXT NOT NULL,
+        runtime TEXT NOT NULL,
+        metadata TEXT,
+        health TEXT NOT NULL,
+        registered_by TEXT,
+        FOREIGN KEY(namespace, service_code) REFERENCES services(namespace, code)
+    );";
+
+const IDENTITY_ROLES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS identity_roles (
+        common_name TEXT,
+        namespace TEXT NOT NULL DEFAULT 'default',
+        role TEXT,
+        PRIMARY KEY(common_name, namespace, role),
+        FOREIGN KEY(common_name) REFERENCES identities(common_name)
+    );";
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> SqlResult<bool> {
+    conn.query_row(
+        &format!("SELECT COUNT(*) > 0 FROM pragma_table_info('{}') WHERE name = ?1", table),
+        [column],
+        |row| row.get(0),
+    )
+}
+
+/// Upgrades tables created by an older version of the schema.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> SqlResult<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))?;
+    }
+    Ok(())
+}
+
+/// Recreates an existing `table` that lacks `column` from `create`, copying
+/// the columns both versions share. Used where the new column joins a
+/// primary or foreign key, which `ALTER TABLE` cannot change.
+fn rebuild_if_missing(conn: &mut Connection, table: &str, column: &str, create: &str) -> SqlResult<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    )?;
+    if !exists || has_column(conn, table, column)? {
+        return Ok(());
+    }
+    let create = create.replace(&format!("CREATE TABLE IF NOT EXISTS {table} "), &format!("CREATE TABLE {table}_new "));
+    let tx = conn.transaction()?;
+    tx.execute_batch(&create)?;
+    let columns = tx
+        .prepare(&format!(
+            "SELECT name FROM pragma_table_info('{table}')
+             WHERE name IN (SELECT name FROM pragma_table_info('{table}_new'))"
+        ))?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<SqlResult<Vec<_>>>()?
+        .join(", ");
+    tx.execute_batch(&format!(
+        "INSERT INTO {table}_new ({columns}) SELECT {columns} FROM {table};
+         DROP TABLE {table};
+         ALTER TABLE {table}_new RENAME TO {table};"
+    ))?;
+    tx.commit()
+}
+
+const AUDIT_COLUMNS: &str = "seq, timestamp, actor, action, target, before, after, source_ip, prev_hash, hash";
+
+fn audit_from_row(row: &rusqlite::Row) -> SqlResult<AuditRecord> {
+    let json = |idx: usize| -> SqlResult<Option<serde_json::Value>> {
+        Ok(row.get::<_, Option<String>>(idx)?.and_then(|raw| serde_json::from_str(&raw).ok()))
+    };
+    Ok(AuditRecord {
+        seq: row.get(0)?,
+        timestamp: row.get(1)?,
+        actor: row.get(2)?,
+        action: row.get(3)?,
+        target: row.get(4)?,
+        before: json(5)?,
+        after: json(6)?,
+        source_ip: row.get(7)?,
+        prev_hash: row.get(8)?,
+        hash: row.get(9)?,
+    })
+}
+
+const IDENTITY_COLUMNS: &str = "common_name, organization, disabled";
+
+/// Reads an identity without its roles; see `identity_roles`.
+fn identity_from_row(row: &rusqlite::Row) -> SqlResult<Identity> {
+    Ok(Identity {
+        common_name: row.get(0)?,
+        organization: row.get(1)?,
+        roles: BTreeMap::new(),
+        disabled: row.get(2)?,
+    })
+}
+
+fn identity_roles(conn: &Connection, common_name: &str) -> Result<BTreeMap<String, Vec<Role>>, RegistryError> {
+    let mut stmt = conn.prepare("SELECT namespace, role FROM identity_roles WHERE common_name = ?1 ORDER BY namespace")
+        .map_err(|_| RegistryError::Storage)?;
+    let rows = stmt.query_map([common_name], |row| Ok((row.get::<_, String>(0)?, Role::from(row.get::<_, String>(1)?))))
+        .map_err(|_| RegistryError::Storage)?;
+    let mut roles: BTreeMap<String, Vec<Role>> = BTreeMap::new();
+    for row in rows {
+        let (namespace, role) = row.map_err(|_| RegistryError::Storage)?;
+        roles.entry(namespace).or_default().push(role);
+    }
+    Ok(roles)
+}
+
+fn role_from_row(row: &rusqlite::Row) -> SqlResult<RoleDefinition> {
+    let grants: String = row.get(2)?;
+    Ok(RoleDefinition {
+        name: row.get(0)?,
+        description: row.get(1)?,
+        grants: serde_json::from_str(&grants).unwrap_or_default(),
+    })
+}
+
+fn query_from_row(row: &rusqlite::Row) -> SqlResult<PreparedQuery> {
+    let definition: String = row.get(0)?;
+    serde_json::from_str(&definition)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+/// Reads a JSON column, as webhooks and their deliveries are stored.
+fn json_from_row<T: serde::de::DeserializeOwned>(row: &rusqlite::Row) -> SqlResult<T> {
+    let json: String = row.get(0)?;
+    serde_json::from_str(&json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+const KV_COLUMNS: &str = "namespace, key, value, create_index, modify_index, session, lock_index";
+
+fn kv_from_row(row: &rusqlite::Row) -> SqlResult<KvEntry> {
+    let session: Option<String> = row.get(5)?;
+    Ok(KvEntry {
+        namespace: row.get(0)?,
+        key: row.get(1)?,
+        value: row.get(2)?,
+        create_index: row.get(3)?,
+        modify_index: row.get(4)?,
+        session: session.and_then(|id| Uuid::parse_str(&id).ok()),
+        lock_index: row.get(6)?,
+    })
+}
+
+fn insert_key(conn: &Connection, entry: &KvEntry) -> SqlResult<()> {
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO kv ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)", KV_COLUMNS),
+        params![
+            entry.namespace, entry.key, entry.value, entry.create_index, entry.modify_index,
+            entry.session.map(|id| id.to_string()), entry.lock_index
+        ],
+    )?;
+    Ok(())
+}
+
+/// Writes every row of `snapshot` other than the audit log and keys.
+fn restore_rows(conn: &Connection, snapshot: &RegistrySnapshot) -> SqlResult<()> {
+    for service in &snapshot.services {
+        insert_service(conn, service)?;
+    }
+    for instance in &snapshot.instances {
+        insert_instance(conn, instance)?;
+    }
+    for record in &snapshot.identities {
+        insert_identity(conn, &record.identity)?;
+        conn.execute(
+            "UPDATE identities SET disabled = ?1, secret_hash = ?2 WHERE common_name = ?3",
+            params![record.identity.disabled, record.secret_hash, record.identity.common_name],
+        )?;
+    }
+    for role in &snapshot.roles {
+        insert_role(conn, role)?;
+    }
+    for token in &snapshot.revoked_tokens {
+        conn.execute(
+            "INSERT OR REPLACE INTO revoked_tokens (jti, expires_at) VALUES (?1, ?2)",
+            params![token.jti, token.expires_at],
+        )?;
+    }
+    for key in &snapshot.signing_keys {
+        insert_signing_key(conn, key)?;
+    }
+    for query in &snapshot.queries {
+        insert_query(conn, query)?;
+    }
+    for session in &snapshot.sessions {
+        insert_session(conn, session)?;
+    }
+    for webhook in &snapshot.webhooks {
+        insert_webhook(conn, webhook)?;
+    }
+    // Oldest first, so the log keeps its order.
+    for attempt in snapshot.deliveries.iter().rev() {
+        insert_delivery(conn, attempt)?;
+    }
+    for dead_letter in &snapshot.dead_letters {
+        insert_dead_letter(conn, dead_letter)?;
+    }
+    Ok(())
+}
+
+fn insert_service(conn: &Connection, service: &Service) -> SqlResult<()> {
+    let metadata = serde_json::to_string(&service.metadata).unwrap_or_default();
+    let dependencies = serde_json::to_string(&service.dependencies).unwrap_or_default();
+    let catalog = serde_json::to_string(&service.catalog).unwrap_or_default();
+    conn.execute(
+        "INSERT OR REPLACE INTO services (namespace, code, name, description, metadata, dependencies, catalog) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![service.namespace, service.code, service.name, service.description, metadata, dependencies, catalog]
+    )?;
+    Ok(())
+}
+
+fn insert_instance(conn: &Connection, instance: &ServiceInstance) -> SqlResult<()> {
+    let metadata = serde_json::to_string(&instance.metadata).unwrap_or_default();
+    conn.execute(
+        "INSERT OR REPLACE INTO instances (id, service_code, address, protocol, runtime, metadata, health, registered_by, namespace)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            instance.id.to_string(),
+            instance.service_name,
+            instance.address.to_string(),
+            format!("{:?}", instance.protocol),
+            format!("{:?}", instance.runtime),
+            metadata,
+            format!("{:?}", instance.health),
+            instance.registered_by,
+            instance.namespace
+        ]
+    )?;
+    Ok(())
+}
+
+/// Creates or updates the identity, replacing its roles in every namespace.
+fn insert_identity(conn: &Connection, identity: &Identity) -> SqlResult<()> {
+    conn.execute(
+        "INSERT INTO identities (common_name, organization, metadata) VALUES (?1, ?2, ?3)
+         ON CONFLICT(common_name) DO UPDATE SET organization = excluded.organization",
+        params![identity.common_name, identity.organization, "{}"]
+    )?;
+    conn.execute("DELETE FROM identity_roles WHERE common_name = ?1", params![identity.common_name])?;
+    for (namespace, roles) in &identity.roles {
+        for role in roles {
+            conn.execute(
+                "INSERT OR REPLACE INTO identity_roles (common_name, namespace, role) VALUES (?1, ?2, ?3)",
+                params![identity.common_name, namespace, role.name()]
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn insert_role(conn: &Connection, role: &RoleDefinition) -> SqlResult<()> {
+    let grants = serde_json::to_string(&role.grants).unwrap_or_default();
+    conn.execute(
+        "INSERT INTO roles (name, description, grants) VALUES (?1, ?2, ?3)",
+        params![role.name, role.description, grants]
+    )?;
+    Ok(())
+}
+
+fn insert_signing_key(conn: &Connection, key: &SigningKey) -> SqlResult<()> {
+    conn.execute(
+        "INSERT INTO signing_keys (kid, algorithm, private_key, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![key.kid, key.algorithm, key.private_key, key.created_at]
+    )?;
+    Ok(())
+}
+
+fn insert_query(conn: &Connection, query: &PreparedQuery) -> SqlResult<()> {
+    let definition = serde_json::to_string(query).unwrap_or_default();
+    conn.execute(
+        "INSERT INTO prepared_queries (namespace, name, definition) VALUES (?1, ?2, ?3)",
+        params![query.namespace, query.name, definition]
+    )?;
+    Ok(())
+}
+
+fn insert_session(conn: &Connection, session: &Session) -> SqlResult<()> {
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO sessions ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)", SESSION_COLUMNS),
+        params![
+            session.id.to_string(), session.namespace, session.name, session.instance.map(|id| id.to_string()),
+            session.ttl_secs, session.expires_at, session.created_at, session.owner
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_webhook(conn: &Connection, webhook: &Webhook) -> SqlResult<()> {
+    let definition = serde_json::to_string(webhook).unwrap_or_default();
+    conn.execute(
+        "INSERT OR REPLACE INTO webhooks (id, namespace, definition) VALUES (?1, ?2, ?3)",
+        params![webhook.id.to_string(), webhook.namespace, definition]
+    )?;
+    Ok(())
+}
+
+/// Appends to the webhook's delivery log, dropping its oldest attempts
+/// beyond [`DELIVERY_LOG_LIMIT`].
+fn insert_delivery(conn: &Connection, attempt: &DeliveryAttempt) -> SqlResult<()> {
+    let json = serde_json::to_string(attempt).unwrap_or_default();
+    let webhook = attempt.webhook.to_string();
+    conn.execute("INSERT INTO webhook_deliveries (webhook, attempt) VALUES (?1, ?2)", params![webhook, json])?;
+    conn.execute(
+        "DELETE FROM webhook_deliveries WHERE webhook = ?1 AND seq NOT IN
+            (SELECT seq FROM webhook_deliveries WHERE webhook = ?1 ORDER BY seq DESC LIMIT ?2)",
+        params![webhook, DELIVERY_LOG_LIMIT],
+    )?;
+    Ok(())
+}
+
+fn insert_dead_letter(conn: &Connection, dead_letter: &DeadLetter) -> SqlResult<()> {
+    let json = serde_json::to_string(dead_letter).unwrap_or_default();
+    conn.execute(
+        "INSERT OR REPLACE INTO webhook_dead_letters (delivery, webhook, dead_letter) VALUES (?1, ?2, ?3)",
+        params![dead_letter.delivery.to_string(), dead_letter.webhook.to_string(), json]
+    )?;
+    Ok(())
+}
+
+const SESSION_COLUMNS: &str = "id, namespace, name, instance, ttl_secs, expires_at, created_at, owner";
+
+fn session_from_row(row: &rusqlite::Row) -> SqlResult<Session> {
+    let id: String = row.get(0)?;
+    let instance: Option<String> = row.get(3)?;
+    Ok(Session {
+        id: Uuid::parse_str(&id).unwrap_or_default(),
+        namespace: row.get(1)?,
+        name: row.get(2)?,
+        instance: instance.and_then(|id| Uuid::parse_str(&id).ok()),
+        ttl_secs: row.get(4)?,
+        expires_at: row.get(5)?,
+        created_at: row.get(6)?,
+        owner: row.get(7)?,
+    })
+}
+
+/// Looks up a session inside a write transaction.
+fn find_session(conn: &Connection, id: &Uuid) -> Result<Option<Session>, RegistryError> {
+    conn.query_row(
+        &format!("SELECT {} FROM sessions WHERE id = ?1", SESSION_COLUMNS),
+        params![id.to_string()],
+        session_from_row,
+    ).map(Some).or_else(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+        e => Err(e),
+    }).map_err(|_| RegistryError::Storage)
+}
+
+fn get_kv_index(conn: &Connection) -> SqlResult<u64> {
+    conn.query_row("SELECT value FROM settings WHERE key = 'kv_index'", [], |row| row.get::<_, String>(0))
+        .map(|value| value.parse().unwrap_or(0))
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(0),
+            e => Err(e),
+        })
+}
+
+fn set_kv_index(conn: &Connection, index: u64) -> SqlResult<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO settings (key, value) VALUES ('kv_index', ?1)",
+        params![index.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Records `head` as the last audit record, or that there is none.
+fn set_audit_head(conn: &Connection, head: Option<&AuditHead>) -> SqlResult<()> {
+    match head {
+        Some(head) => conn.execute(
+            "INSERT OR REPLACE INTO audit_head (id, seq, hash) VALUES (0, ?1, ?2)",
+            params![head.seq, head.hash],
+        )?,
+        None => conn.execute("DELETE FROM audit_head", [])?,
+    };
+    Ok(())
+}
+
+/// Looks up a key inside a write transaction.
+fn find_key(conn: &Connection, namespace: &str, key: &str) -> Result<Option<KvEntry>, RegistryError> {
+    conn.query_row(
+        &format!("SELECT {} FROM kv WHERE namespace = ?1 AND key = ?2", KV_COLUMNS),
+        params![namespace, key],
+        kv_from_row,
+    ).map(Some).or_else(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+        e => Err(e),
+    }).map_err(|_| RegistryError::Storage)
+}
+
+const INSTANCE_COLUMNS: &str = "id, service_code, address, protocol, runtime, metadata, health, registered_by, namespace";
+
+fn instance_from_row(row: &rusqlite::Row) -> SqlResult<ServiceInstance> {
+    let id: String = row.get(0)?;
+    let service_code: String = row.get(1)?;
+    let address: String = row.get(2)?;
+    let protocol: String = row.get(3)?;
+    let runtime: String = row.get(4)?;
+    let metadata_json: String = row.get(5)?;
+    let health_str: String = row.get(6)?;
+    let registered_by: Option<String> = row.get(7)?;
+    let namespace: String = row.get(8)?;
+
+    let address = address.parse().unwrap();
+    let protocol = match protocol.as_str() {
+        "Http" => Protocol::Http,
+        "Https" => Protocol::Https,
+        "Tcp" => Protocol::Tcp,
+        "Grpc" => Protocol::Grpc,
+        "Udp" => Protocol::Udp,
+        other => Protocol::Custom(other.to_string()),
+    };
+    // Runtimes are stored in their Debug form, e.g. `Container { container_id: "" }`.
+    let runtime = match runtime.split(|c: char| !c.is_alphanumeric()).next().unwrap_or("") {
+        "Vm" => Runtime::Vm { provider: None, id: None },
+        "Container" => Runtime::Container { container_id: "".to_string() },
+        "Serverless" => Runtime::Serverless { function_name: "".to_string(), region: None },
+        other => Runtime::Custom(other.to_string()),
+    };
+    let metadata: std::collections::HashMap<String, String> = serde_json::from_str(&metadata_json).unwrap_or_default();
+    let health = match health_str.as_str() {
+        "Healthy" => HealthStatus::Healthy,
+        "Unhealthy" => HealthStatus::Unhealthy,
+        "Suspect" => HealthStatus::Suspect,
+        _ => HealthStatus::Unknown,
+    };
+
+    Ok(ServiceInstance {
+        id: Uuid::parse_str(&id).unwrap(),
+        namespace,
+        service_name: service_code,
+        address,
+        protocol,
+        runtime,
+        metadata,
+        last_seen: 0,
+        health,
+        registered_by,
+    })
 }
 
 impl RegistryStore for DbRegistry {
+    #[tracing::instrument(name = "registry.add_service", skip_all, fields(code = %service.code), err(level = "debug"))]
     fn add_service(&self, service: &Service) -> Result<(), RegistryError> {
-        let metadata = serde_json::to_string(&service.metadata).unwrap_or_default();
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT OR REPLACE INTO services (code, name, description, metadata) VALUES (?1, ?2, ?3, ?4)",
-            params![service.code, service.name, service.description, metadata]
-        ).map_err(|_| RegistryError::DuplicateInstance)?;
+        insert_service(&conn, service).map_err(|_| RegistryError::DuplicateInstance)
+    }
+
+    #[tracing::instrument(name = "registry.update_service", skip_all, fields(code = %service.code), err(level = "debug"))]
+    fn update_service(&self, service: &Service) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let metadata = serde_json::to_string(&service.metadata).unwrap_or_default();
+        let dependencies = serde_json::to_string(&service.dependencies).unwrap_or_default();
+        let catalog = serde_json::to_string(&service.catalog).unwrap_or_default();
+        let updated = conn
+            .execute(
+                "UPDATE services SET name = ?3, description = ?4, metadata = ?5, dependencies = ?6, catalog = ?7 WHERE namespace = ?1 AND code = ?2",
+                params![service.namespace, service.code, service.name, service.description, metadata, dependencies, catalog],
+            )
+            .map_err(|_| RegistryError::Storage)?;
+        if updated == 0 {
+            return Err(RegistryError::ServiceNotFound);
+        }
         Ok(())
     }
 
+    #[tracing::instrument(name = "registry.add_instance", skip_all, fields(id = %instance.id, service = %instance.service_name), err(level = "debug"))]
     fn add_instance(&self, instance: &ServiceInstance) -> Result<(), RegistryError> {
-        let metadata = serde_json::to_string(&instance.metadata).unwrap_or_default();
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT OR REPLACE INTO instances (id, service_code, address, protocol, runtime, metadata, health)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            params![
-                instance.id.to_string(),
-                instance.service_name,
-                instance.address.to_string(),
-                format!("{:?}", instance.protocol),
-                format!("{:?}", instance.runtime),
-                metadata,
-                format!("{:?}", instance.health)
-            ]
-        ).map_err(|_| RegistryError::DuplicateInstance)?;
-        Ok(())
+        insert_instance(&conn, instance).map_err(|_| RegistryError::DuplicateInstance)
     }
 
-    fn get_service(&self, code: &str) -> Result<Service, RegistryError> {
+    #[tracing::instrument(name = "registry.get_service", skip(self), err(level = "debug"))]
+    fn get_service(&self, namespace: &str, code: &str) -> Result<Service, RegistryError> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT code, name, description, metadata FROM services WHERE code = ?1").map_err(|_| RegistryError::ServiceNotFound)?;
-        let service = stmt.query_row([code], |row| {
+        let mut stmt = conn.prepare("SELECT code, name, description, metadata, namespace, dependencies, catalog FROM services WHERE namespace = ?1 AND code = ?2").map_err(|_| RegistryError::ServiceNotFound)?;
+        let service = stmt.query_row([namespace, code], |row| {
             let code: String = row.get(0)?;
             let name: String = row.get(1)?;
             let description: String = row.get(2)?;
             let metadata_json: String = row.get(3)?;
+            let namespace: String = row.get(4)?;
             let metadata: std::collections::HashMap<String, String> = serde_json::from_str(&metadata_json).unwrap_or_default();
+            let dependencies: Option<String> = row.get(5)?;
+            let dependencies = dependencies.and_then(|d| serde_json::from_str(&d).ok()).unwrap_or_default();
+            let catalog: Option<String> = row.get(6)?;
+            let catalog = catalog.and_then(|c| serde_json::from_str(&c).ok()).unwrap_or_default();
 
             Ok(Service {
+                namespace,
                 code,
                 name,
                 description,
                 instances: Vec::new(),
                 metadata,
+                dependencies,
+                catalog,
             })
         }).map_err(|_| RegistryError::ServiceNotFound)?;
         Ok(service)
     }
 
-    fn get_instances(&self, service_code: &str) -> Result<Vec<ServiceInstance>, RegistryError> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT id, address, protocol, runtime, metadata, health FROM instances WHERE service_code = ?1").map_err(|_| RegistryError::ServiceNotFound)?;
-        let rows = stmt.query_map([service_code], |row| {
-            let id: String = row.get(0)?;
-            let address: String = row.get(1)?;
-            let protocol: String = row.get(2)?;
-            let runtime: String = row.get(3)?;
-            let metadata_json: String = row.get(4)?;
-            let health_str: String = row.get(5)?;
-
-            let address = address.parse().unwrap();
-            let protocol = match protocol.as_str() {
-                "Http" => Protocol::Http,
-                "Https" => Protocol::Https,
-                "Tcp" => Protocol::Tcp,
-                "Grpc" => Protocol::Grpc,
-                "Udp" => Protocol::Udp,
-                other => Protocol::Custom(other.to_string()),
-            };
-            let runtime = match runtime.split(':').next().unwrap_or("") {
-                "Vm" => Runtime::Vm { provider: None, id: None },
-                "Container" => Runtime::Container { container_id: "".to_string() },
-                "Serverless" => Runtime::Serverless { function_name: "".to_string(), region: None },
-                other => Runtime::Custom(other.to_string()),
-            };
-            let metadata: std::collections::HashMap<String, String> = serde_json::from_str(&metadata_json).unwrap_or_default();
-            let health = match health_str.as_str() {
-                "Healthy" => HealthStatus::Healthy,
-                "Unhealthy" => HealthStatus::Unhealthy,
-                _ => HealthStatus::Unknown,
-            };
-
-            Ok(ServiceInstance {
-                id: Uuid::parse_str(&id).unwrap(),
-                service_name: service_code.to_string(),
-                address,
-                protocol,
-                runtime,
-                metadata,
-                last_seen: 0,
-                health,
-            })
-        }).map_err(|_| RegistryError::ServiceNotFound)?;
+    #[tracing::instrument(name = "registry.get_instances", skip(self), err(level = "debug"))]
+    fn get_instances(&self, namespace: &str, service_code: &str) -> Result<Vec<ServiceInstance>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM instances WHERE namespace = ?1 AND service_code = ?2", INSTANCE_COLUMNS)).map_err(|_| RegistryError::ServiceNotFound)?;
+        let rows = stmt.query_map([namespace, service_code], instance_from_row).map_err(|_| RegistryError::ServiceNotFound)?;
 
         rows.collect::<Result<Vec<_>, _>>()
             .map_err(|_| RegistryError::ServiceNotFound)
     }
 
+    #[tracing::instrument(name = "registry.get_instance", skip(self), fields(id = %id), err(level = "debug"))]
+    fn get_instance(&self, id: &Uuid) -> Result<ServiceInstance, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM instances WHERE id = ?1", INSTANCE_COLUMNS)).map_err(|_| RegistryError::InstanceNotFound)?;
+        stmt.query_row([id.to_string()], instance_from_row)
+            .map_err(|_| RegistryError::InstanceNotFound)
+    }
+
+    #[tracing::instrument(name = "registry.remove_instance", skip(self), fields(id = %id), err(level = "debug"))]
+    fn remove_instance(&self, id: &Uuid) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM instances WHERE id = ?1",
+            params![id.to_string()]
+        ).map_err(|_| RegistryError::InstanceNotFound)?;
+        if removed == 0 {
+            return Err(RegistryError::InstanceNotFound);
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.remove_service", skip(self), err(level = "debug"))]
+    fn remove_service(&self, namespace: &str, code: &str) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|_| RegistryError::ServiceNotFound)?;
+        tx.execute("DELETE FROM instances WHERE namespace = ?1 AND service_code = ?2", params![namespace, code])
+            .map_err(|_| RegistryError::ServiceNotFound)?;
+        let removed = tx.execute("DELETE FROM services WHERE namespace = ?1 AND code = ?2", params![namespace, code])
+            .map_err(|_| RegistryError::ServiceNotFound)?;
+        if removed == 0 {
+            return Err(RegistryError::ServiceNotFound);
+        }
+        tx.commit().map_err(|_| RegistryError::ServiceNotFound)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.add_identity", skip_all, fields(common_name = %identity.common_name), err(level = "debug"))]
     fn add_identity(&self, identity: &Identity) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|_| RegistryError::Storage)?;
+        insert_identity(&tx, identity).map_err(|_| RegistryError::DuplicateInstance)?;
+        tx.commit().map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.get_identity", skip(self), err(level = "debug"))]
+    fn get_identity(&self, common_name: &str) -> Result<Identity, RegistryError> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT OR REPLACE INTO identities (common_name, organization, metadata) VALUES (?1, ?2, ?3)",
-            params![identity.common_name, identity.organization, "{}"]
-        ).map_err(|_| RegistryError::DuplicateInstance)?;
+        let mut identity = conn.query_row(
+            &format!("SELECT {} FROM identities WHERE common_name = ?1", IDENTITY_COLUMNS),
+            [common_name],
+            identity_from_row,
+        ).map_err(|_| RegistryError::IdentityNotFound)?;
+        identity.roles = identity_roles(&conn, common_name)?;
+        Ok(identity)
+    }
+
+    #[tracing::instrument(name = "registry.list_identities", skip(self), err(level = "debug"))]
+    fn list_identities(&self) -> Result<Vec<Identity>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM identities ORDER BY common_name", IDENTITY_COLUMNS))
+            .map_err(|_| RegistryError::Storage)?;
+        let mut identities = stmt.query_map([], identity_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        for identity in &mut identities {
+            identity.roles = identity_roles(&conn, &identity.common_name)?;
+        }
+        Ok(identities)
+    }
 
-        // For simplicity, we just clear and re-add roles. Better to use a transaction.
+    #[tracing::instrument(name = "registry.remove_identity", skip(self), err(level = "debug"))]
+    fn remove_identity(&self, common_name: &str) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|_| RegistryError::Storage)?;
+        tx.execute("DELETE FROM identity_roles WHERE common_name = ?1", params![common_name])
+            .map_err(|_| RegistryError::Storage)?;
+        let removed = tx.execute("DELETE FROM identities WHERE common_name = ?1", params![common_name])
+            .map_err(|_| RegistryError::Storage)?;
+        if removed == 0 {
+            return Err(RegistryError::IdentityNotFound);
+        }
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.add_role_to_identity", skip(self), err(level = "debug"))]
+    fn add_role_to_identity(&self, common_name: &str, namespace: &str, role: Role) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
         conn.execute(
-            "DELETE FROM identity_roles WHERE common_name = ?1",
-            params![identity.common_name]
+            "INSERT OR REPLACE INTO identity_roles (common_name, namespace, role) VALUES (?1, ?2, ?3)",
+            params![common_name, namespace, role.name()]
         ).map_err(|_| RegistryError::DuplicateInstance)?;
+        Ok(())
+    }
 
-        for role in &identity.roles {
-            self.add_role_to_identity(&identity.common_name, role.clone())?;
+    #[tracing::instrument(name = "registry.remove_role_from_identity", skip(self), err(level = "debug"))]
+    fn remove_role_from_identity(&self, common_name: &str, namespace: &str, role: Role) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM identity_roles WHERE common_name = ?1 AND namespace = ?2 AND role = ?3",
+            params![common_name, namespace, role.name()]
+        ).map_err(|_| RegistryError::Storage)?;
+        if removed == 0 {
+            return Err(RegistryError::RoleNotFound);
         }
+        Ok(())
+    }
 
+    #[tracing::instrument(name = "registry.disable_identity", skip(self), err(level = "debug"))]
+    fn disable_identity(&self, common_name: &str) -> Result<(), RegistryError> {
+        self.set_identity_disabled(common_name, true)
+    }
+
+    #[tracing::instrument(name = "registry.enable_identity", skip(self), err(level = "debug"))]
+    fn enable_identity(&self, common_name: &str) -> Result<(), RegistryError> {
+        self.set_identity_disabled(common_name, false)
+    }
+
+    #[tracing::instrument(name = "registry.add_role", skip_all, fields(name = %role.name), err(level = "debug"))]
+    fn add_role(&self, role: &RoleDefinition) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        insert_role(&conn, role).map_err(|e| match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::ConstraintViolation) => RegistryError::DuplicateRole,
+            _ => RegistryError::Storage,
+        })
+    }
+
+    #[tracing::instrument(name = "registry.get_role", skip(self), err(level = "debug"))]
+    fn get_role(&self, name: &str) -> Result<RoleDefinition, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT name, description, grants FROM roles WHERE name = ?1",
+            [name],
+            role_from_row,
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => RegistryError::RoleNotFound,
+            _ => RegistryError::Storage,
+        })
+    }
+
+    #[tracing::instrument(name = "registry.get_roles", skip(self), err(level = "debug"))]
+    fn get_roles(&self) -> Result<Vec<RoleDefinition>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name, description, grants FROM roles ORDER BY name")
+            .map_err(|_| RegistryError::Storage)?;
+        let roles = stmt.query_map([], role_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(roles)
+    }
+
+    #[tracing::instrument(name = "registry.remove_role", skip(self), err(level = "debug"))]
+    fn remove_role(&self, name: &str) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|_| RegistryError::Storage)?;
+        tx.execute("DELETE FROM identity_roles WHERE role = ?1", params![name])
+            .map_err(|_| RegistryError::Storage)?;
+        let removed = tx.execute("DELETE FROM roles WHERE name = ?1", params![name])
+            .map_err(|_| RegistryError::Storage)?;
+        if removed == 0 {
+            return Err(RegistryError::RoleNotFound);
+        }
+        tx.commit().map_err(|_| RegistryError::Storage)?;
         Ok(())
     }
 
-    fn get_identity(&self, common_name: &str) -> Result<Identity, RegistryError> {
+    #[tracing::instrument(name = "registry.set_identity_secret", skip(self, secret_hash), err(level = "debug"))]
+    fn set_identity_secret(&self, common_name: &str, secret_hash: &str) -> Result<(), RegistryError> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT common_name, organization FROM identities WHERE common_name = ?1")
-            .map_err(|_| RegistryError::ServiceNotFound)?;
-        
-        let (cn, org) = stmt.query_row([common_name], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
-        }).map_err(|_| RegistryError::ServiceNotFound)?;
+        let updated = conn.execute(
+            "UPDATE identities SET secret_hash = ?1 WHERE common_name = ?2",
+            params![secret_hash, common_name]
+        ).map_err(|_| RegistryError::IdentityNotFound)?;
+        if updated == 0 {
+            return Err(RegistryError::IdentityNotFound);
+        }
+        Ok(())
+    }
 
-        let mut stmt = conn.prepare("SELECT role FROM identity_roles WHERE common_name = ?1")
-            .map_err(|_| RegistryError::ServiceNotFound)?;
-        
-        let roles = stmt.query_map([common_name], |row| {
-            let role_str: String = row.get(0)?;
-            Ok(match role_str.as_str() {
-                "Admin" => Role::Admin,
-                "Agent" => Role::Agent,
-                "Viewer" => Role::Viewer,
-                _ => Role::Viewer, // Default to viewer
+    #[tracing::instrument(name = "registry.get_identity_secret", skip(self), err(level = "debug"))]
+    fn get_identity_secret(&self, common_name: &str) -> Result<Option<String>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT secret_hash FROM identities WHERE common_name = ?1",
+            [common_name],
+            |row| row.get(0),
+        ).map_err(|_| RegistryError::IdentityNotFound)
+    }
+
+    #[tracing::instrument(name = "registry.revoke_token", skip(self), err(level = "debug"))]
+    fn revoke_token(&self, jti: &str, expires_at: i64) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        // Expired entries no longer need to be remembered.
+        conn.execute(
+            "DELETE FROM revoked_tokens WHERE expires_at < strftime('%s', 'now')",
+            [],
+        ).map_err(|_| RegistryError::Storage)?;
+        conn.execute(
+            "INSERT OR REPLACE INTO revoked_tokens (jti, expires_at) VALUES (?1, ?2)",
+            params![jti, expires_at]
+        ).map_err(|_| RegistryError::Storage)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.is_token_revoked", skip(self), err(level = "debug"))]
+    fn is_token_revoked(&self, jti: &str) -> Result<bool, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT COUNT(*) > 0 FROM revoked_tokens WHERE jti = ?1",
+            [jti],
+            |row| row.get(0),
+        ).map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.add_signing_key", skip_all, fields(kid = %key.kid), err(level = "debug"))]
+    fn add_signing_key(&self, key: &SigningKey) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        insert_signing_key(&conn, key).map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.list_revoked_tokens", skip(self), err(level = "debug"))]
+    fn list_revoked_tokens(&self) -> Result<Vec<RevokedToken>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT jti, expires_at FROM revoked_tokens ORDER BY jti")
+            .map_err(|_| RegistryError::Storage)?;
+        let tokens = stmt.query_map([], |row| Ok(RevokedToken { jti: row.get(0)?, expires_at: row.get(1)? }))
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(tokens)
+    }
+
+    #[tracing::instrument(name = "registry.get_signing_keys", skip(self), err(level = "debug"))]
+    fn get_signing_keys(&self) -> Result<Vec<SigningKey>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT kid, algorithm, private_key, created_at FROM signing_keys ORDER BY created_at, kid")
+            .map_err(|_| RegistryError::Storage)?;
+        let keys = stmt.query_map([], |row| {
+            Ok(SigningKey {
+                kid: row.get(0)?,
+                algorithm: row.get(1)?,
+                private_key: row.get(2)?,
+                created_at: row.get(3)?,
             })
-        }).map_err(|_| RegistryError::ServiceNotFound)?
+        }).map_err(|_| RegistryError::Storage)?
         .collect::<Result<Vec<_>, _>>()
-        .map_err(|_| RegistryError::ServiceNotFound)?;
-
-        Ok(Identity {
-            common_name: cn,
-            organization: org,
-            roles,
-        })
+        .map_err(|_| RegistryError::Storage)?;
+        Ok(keys)
     }
 
-    fn add_role_to_identity(&self, common_name: &str, role: Role) -> Result<(), RegistryError> {
-        let role_str = match role {
-            Role::Admin => "Admin",
-            Role::Agent => "Agent",
-            Role::Viewer => "Viewer",
-        };
+    #[tracing::instrument(name = "registry.remove_signing_key", skip(self), err(level = "debug"))]
+    fn remove_signing_key(&self, kid: &str) -> Result<(), RegistryError> {
         let conn = self.conn.lock().unwrap();
-        conn.execute(
-            "INSERT OR REPLACE INTO identity_roles (common_name, role) VALUES (?1, ?2)",
-            params![common_name, role_str]
-        ).map_err(|_| RegistryError::DuplicateInstance)?;
+        conn.execute("DELETE FROM signing_keys WHERE kid = ?1", [kid])
+            .map_err(|_| RegistryError::Storage)?;
         Ok(())
     }
 
+    #[tracing::instrument(name = "registry.update_instance_health", skip(self), fields(id = %id), err(level = "debug"))]
     fn update_instance_health(&self, id: &uuid::Uuid, health: HealthStatus) -> Result<(), RegistryError> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
@@ -233,76 +1032,728 @@ impl RegistryStore for DbRegistry {
         Ok(())
     }
 
+    #[tracing::instrument(name = "registry.get_all_instances", skip(self), err(level = "debug"))]
     fn get_all_instances(&self) -> Result<Vec<ServiceInstance>, RegistryError> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT id, service_code, address, protocol, runtime, metadata, health FROM instances").map_err(|_| RegistryError::ServiceNotFound)?;
-        let rows = stmt.query_map([], |row| {
-            let id: String = row.get(0)?;
-            let service_code: String = row.get(1)?;
-            let address: String = row.get(2)?;
-            let protocol: String = row.get(3)?;
-            let runtime: String = row.get(4)?;
-            let metadata_json: String = row.get(5)?;
-            let health_str: String = row.get(6)?;
-
-            let address = address.parse().unwrap();
-            let protocol = match protocol.as_str() {
-                "Http" => Protocol::Http,
-                "Https" => Protocol::Https,
-                "Tcp" => Protocol::Tcp,
-                "Grpc" => Protocol::Grpc,
-                "Udp" => Protocol::Udp,
-                other => Protocol::Custom(other.to_string()),
-            };
-            let runtime = match runtime.split(':').next().unwrap_or("") {
-                "Vm" => Runtime::Vm { provider: None, id: None },
-                "Container" => Runtime::Container { container_id: "".to_string() },
-                "Serverless" => Runtime::Serverless { function_name: "".to_string(), region: None },
-                other => Runtime::Custom(other.to_string()),
-            };
-            let metadata: std::collections::HashMap<String, String> = serde_json::from_str(&metadata_json).unwrap_or_default();
-            let health = match health_str.as_str() {
-                "Healthy" => HealthStatus::Healthy,
-                "Unhealthy" => HealthStatus::Unhealthy,
-                _ => HealthStatus::Unknown,
-            };
-
-            Ok(ServiceInstance {
-                id: Uuid::parse_str(&id).unwrap(),
-                service_name: service_code,
-                address,
-                protocol,
-                runtime,
-                metadata,
-                last_seen: 0,
-                health,
-            })
-        }).map_err(|_| RegistryError::ServiceNotFound)?;
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM instances", INSTANCE_COLUMNS)).map_err(|_| RegistryError::ServiceNotFound)?;
+        let rows = stmt.query_map([], instance_from_row).map_err(|_| RegistryError::ServiceNotFound)?;
 
         rows.collect::<Result<Vec<_>, _>>()
             .map_err(|_| RegistryError::ServiceNotFound)
     }
 
+    #[tracing::instrument(name = "registry.get_all_services", skip(self), err(level = "debug"))]
     fn get_all_services(&self) -> Result<Vec<Service>, RegistryError> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT name, code, description, metadata FROM services").map_err(|_| RegistryError::ServiceNotFound)?;
+        let mut stmt = conn.prepare("SELECT name, code, description, metadata, namespace, dependencies, catalog FROM services").map_err(|_| RegistryError::ServiceNotFound)?;
         let rows = stmt.query_map([], |row| {
             let name: String = row.get(0)?;
             let code: String = row.get(1)?;
             let description: String = row.get(2)?;
             let metadata_json: String = row.get(3)?;
+            let namespace: String = row.get(4)?;
             let metadata: std::collections::HashMap<String, String> = serde_json::from_str(&metadata_json).unwrap_or_default();
+            let dependencies: Option<String> = row.get(5)?;
+            let dependencies = dependencies.and_then(|d| serde_json::from_str(&d).ok()).unwrap_or_default();
+            let catalog: Option<String> = row.get(6)?;
+            let catalog = catalog.and_then(|c| serde_json::from_str(&c).ok()).unwrap_or_default();
 
             Ok(Service {
+                namespace,
                 name,
                 code,
                 description,
                 instances: Vec::new(), // We could load instances too, but for listing, name/code is usually enough or we load them separately
                 metadata,
+                dependencies,
+                catalog,
             })
         }).map_err(|_| RegistryError::ServiceNotFound)?;
 
         rows.collect::<Result<Vec<_>, _>>()
             .map_err(|_| RegistryError::ServiceNotFound)
     }
+
+    #[tracing::instrument(name = "registry.append_audit", skip_all, fields(action = %entry.action, target = %entry.target), err(level = "debug"))]
+    fn append_audit(&self, entry: AuditEntry) -> Result<AuditRecord, RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        // IMMEDIATE takes the write lock up front, so a server and the CLI
+        // sharing the database cannot both chain onto the same record.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| RegistryError::Storage)?;
+        let last: Option<(u64, String)> = tx.query_row(
+            "SELECT seq, hash FROM audit_log ORDER BY seq DESC LIMIT 1",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        }).map_err(|_| RegistryError::Storage)?;
+        let (seq, prev_hash) = last.map_or((1, audit::GENESIS_HASH.to_string()), |(seq, hash)| (seq + 1, hash));
+
+        let record = AuditRecord::chain(entry, seq, prev_hash);
+        let json = |value: &Option<serde_json::Value>| value.as_ref().map(|v| v.to_string());
+        tx.execute(
+            &format!("INSERT INTO audit_log ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)", AUDIT_COLUMNS),
+            params![
+                record.seq, record.timestamp, record.actor, record.action, record.target,
+                json(&record.before), json(&record.after), record.source_ip, record.prev_hash, record.hash
+            ],
+        ).map_err(|_| RegistryError::Storage)?;
+        set_audit_head(&tx, Some(&AuditHead::from(&record))).map_err(|_| RegistryError::Storage)?;
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(record)
+    }
+
+    fn get_audit_head(&self) -> Result<Option<AuditHead>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT seq, hash FROM audit_head WHERE id = 0",
+            [],
+            |row| Ok(AuditHead { seq: row.get(0)?, hash: row.get(1)? }),
+        ).map(Some).or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        }).map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.get_audit", skip(self), err(level = "debug"))]
+    fn get_audit(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        // The newest `limit` records are selected, then put back in order.
+        let mut stmt = conn.prepare(&format!(
+            "SELECT * FROM (
+                SELECT {} FROM audit_log
+                WHERE (?1 IS NULL OR actor = ?1) AND (?2 IS NULL OR action = ?2) AND (?3 IS NULL OR target = ?3)
+                  AND (?4 IS NULL OR seq > ?4) AND (?5 IS NULL OR timestamp >= ?5) AND (?6 IS NULL OR timestamp < ?6)
+                ORDER BY seq DESC LIMIT ?7
+            ) ORDER BY seq",
+            AUDIT_COLUMNS
+        )).map_err(|_| RegistryError::Storage)?;
+        let limit = filter.limit.map_or(-1, i64::from);
+        let records = stmt.query_map(
+            params![filter.actor, filter.action, filter.target, filter.after_seq, filter.since, filter.until, limit],
+            audit_from_row,
+        )
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(records)
+    }
+
+    #[tracing::instrument(name = "registry.add_query", skip_all, fields(namespace = %query.namespace, name = %query.name), err(level = "debug"))]
+    fn add_query(&self, query: &PreparedQuery) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        insert_query(&conn, query).map_err(|e| match e.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::ConstraintViolation) => RegistryError::DuplicateQuery,
+            _ => RegistryError::Storage,
+        })
+    }
+
+    #[tracing::instrument(name = "registry.get_query", skip(self), err(level = "debug"))]
+    fn get_query(&self, namespace: &str, name: &str) -> Result<PreparedQuery, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT definition FROM prepared_queries WHERE namespace = ?1 AND name = ?2",
+            params![namespace, name],
+            query_from_row,
+        ).map_err(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => RegistryError::QueryNotFound,
+            _ => RegistryError::Storage,
+        })
+    }
+
+    #[tracing::instrument(name = "registry.get_queries", skip(self), err(level = "debug"))]
+    fn get_queries(&self, namespace: &str) -> Result<Vec<PreparedQuery>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT definition FROM prepared_queries WHERE namespace = ?1 ORDER BY name")
+            .map_err(|_| RegistryError::Storage)?;
+        let queries = stmt.query_map([namespace], query_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(queries)
+    }
+
+    #[tracing::instrument(name = "registry.get_all_queries", skip(self), err(level = "debug"))]
+    fn get_all_queries(&self) -> Result<Vec<PreparedQuery>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT definition FROM prepared_queries ORDER BY namespace, name")
+            .map_err(|_| RegistryError::Storage)?;
+        let queries = stmt.query_map([], query_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(queries)
+    }
+
+    #[tracing::instrument(name = "registry.remove_query", skip(self), err(level = "debug"))]
+    fn remove_query(&self, namespace: &str, name: &str) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute(
+            "DELETE FROM prepared_queries WHERE namespace = ?1 AND name = ?2",
+            params![namespace, name]
+        ).map_err(|_| RegistryError::Storage)?;
+        if removed == 0 {
+            return Err(RegistryError::QueryNotFound);
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.put_key", skip(self, value), err(level = "debug"))]
+    fn put_key(&self, namespace: &str, key: &str, value: &str, cas: Option<u64>) -> Result<KvEntry, RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        // IMMEDIATE, so that the check and the write see the same key.
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| RegistryError::Storage)?;
+        let existing = find_key(&tx, namespace, key)?;
+        if let Some(cas) = cas
+            && existing.as_ref().map_or(0, |entry| entry.modify_index) != cas
+        {
+            return Err(RegistryError::CasMismatch);
+        }
+        let index = get_kv_index(&tx).map_err(|_| RegistryError::Storage)? + 1;
+        // A plain write keeps the lock on the key, if any.
+        let entry = KvEntry {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            create_index: existing.as_ref().map_or(index, |entry| entry.create_index),
+            modify_index: index,
+            session: existing.as_ref().and_then(|entry| entry.session),
+            lock_index: existing.as_ref().map_or(0, |entry| entry.lock_index),
+        };
+        insert_key(&tx, &entry).map_err(|_| RegistryError::Storage)?;
+        set_kv_index(&tx, index).map_err(|_| RegistryError::Storage)?;
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(entry)
+    }
+
+    #[tracing::instrument(name = "registry.get_key", skip(self), err(level = "debug"))]
+    fn get_key(&self, namespace: &str, key: &str) -> Result<KvEntry, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        find_key(&conn, namespace, key)?.ok_or(RegistryError::KeyNotFound)
+    }
+
+    #[tracing::instrument(name = "registry.get_keys", skip(self), err(level = "debug"))]
+    fn get_keys(&self, namespace: &str, prefix: &str) -> Result<Vec<KvEntry>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM kv WHERE namespace = ?1 AND substr(key, 1, length(?2)) = ?2 ORDER BY key",
+            KV_COLUMNS
+        )).map_err(|_| RegistryError::Storage)?;
+        let entries = stmt.query_map(params![namespace, prefix], kv_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(entries)
+    }
+
+    #[tracing::instrument(name = "registry.get_all_keys", skip(self), err(level = "debug"))]
+    fn get_all_keys(&self) -> Result<Vec<KvEntry>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM kv ORDER BY namespace, key", KV_COLUMNS))
+            .map_err(|_| RegistryError::Storage)?;
+        let entries = stmt.query_map([], kv_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(entries)
+    }
+
+    #[tracing::instrument(name = "registry.remove_key", skip(self), err(level = "debug"))]
+    fn remove_key(&self, namespace: &str, key: &str, cas: Option<u64>) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| RegistryError::Storage)?;
+        let existing = find_key(&tx, namespace, key)?.ok_or(RegistryError::KeyNotFound)?;
+        if cas.is_some_and(|cas| cas != existing.modify_index) {
+            return Err(RegistryError::CasMismatch);
+        }
+        tx.execute("DELETE FROM kv WHERE namespace = ?1 AND key = ?2", params![namespace, key])
+            .map_err(|_| RegistryError::Storage)?;
+        let index = get_kv_index(&tx).map_err(|_| RegistryError::Storage)? + 1;
+        set_kv_index(&tx, index).map_err(|_| RegistryError::Storage)?;
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.remove_keys", skip(self), err(level = "debug"))]
+    fn remove_keys(&self, namespace: &str, prefix: &str) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| RegistryError::Storage)?;
+        let removed = tx.execute(
+            "DELETE FROM kv WHERE namespace = ?1 AND substr(key, 1, length(?2)) = ?2",
+            params![namespace, prefix],
+        ).map_err(|_| RegistryError::Storage)?;
+        if removed > 0 {
+            let index = get_kv_index(&tx).map_err(|_| RegistryError::Storage)? + 1;
+            set_kv_index(&tx, index).map_err(|_| RegistryError::Storage)?;
+        }
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.kv_index", skip(self), err(level = "debug"))]
+    fn kv_index(&self) -> Result<u64, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        get_kv_index(&conn).map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.add_session", skip_all, fields(id = %session.id, name = %session.name), err(level = "debug"))]
+    fn add_session(&self, session: &Session) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        insert_session(&conn, session).map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.get_session", skip(self), err(level = "debug"))]
+    fn get_session(&self, id: &Uuid) -> Result<Session, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        find_session(&conn, id)?.ok_or(RegistryError::SessionNotFound)
+    }
+
+    #[tracing::instrument(name = "registry.get_sessions", skip(self), err(level = "debug"))]
+    fn get_sessions(&self, namespace: &str) -> Result<Vec<Session>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM sessions WHERE namespace = ?1 ORDER BY created_at, id",
+            SESSION_COLUMNS
+        )).map_err(|_| RegistryError::Storage)?;
+        let sessions = stmt.query_map([namespace], session_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(sessions)
+    }
+
+    #[tracing::instrument(name = "registry.get_all_sessions", skip(self), err(level = "debug"))]
+    fn get_all_sessions(&self) -> Result<Vec<Session>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM sessions ORDER BY created_at, id", SESSION_COLUMNS))
+            .map_err(|_| RegistryError::Storage)?;
+        let sessions = stmt.query_map([], session_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(sessions)
+    }
+
+    #[tracing::instrument(name = "registry.renew_session", skip(self), err(level = "debug"))]
+    fn renew_session(&self, id: &Uuid, expires_at: u64) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let updated = conn.execute(
+            "UPDATE sessions SET expires_at = ?1 WHERE id = ?2",
+            params![expires_at, id.to_string()],
+        ).map_err(|_| RegistryError::Storage)?;
+        if updated == 0 {
+            return Err(RegistryError::SessionNotFound);
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.remove_session", skip(self), err(level = "debug"))]
+    fn remove_session(&self, id: &Uuid) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| RegistryError::Storage)?;
+        let removed = tx.execute("DELETE FROM sessions WHERE id = ?1", params![id.to_string()])
+            .map_err(|_| RegistryError::Storage)?;
+        if removed == 0 {
+            return Err(RegistryError::SessionNotFound);
+        }
+        // Releasing the session's locks is one write to the store.
+        let index = get_kv_index(&tx).map_err(|_| RegistryError::Storage)? + 1;
+        let released = tx.execute(
+            "UPDATE kv SET session = NULL, modify_index = ?1 WHERE session = ?2",
+            params![index, id.to_string()],
+        ).map_err(|_| RegistryError::Storage)?;
+        if released > 0 {
+            set_kv_index(&tx, index).map_err(|_| RegistryError::Storage)?;
+        }
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.acquire_lock", skip(self, value), err(level = "debug"))]
+    fn acquire_lock(&self, namespace: &str, key: &str, value: &str, session: &Uuid) -> Result<KvEntry, RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| RegistryError::Storage)?;
+        match find_session(&tx, session)? {
+            Some(found) if found.namespace == namespace => {}
+            _ => return Err(RegistryError::SessionNotFound),
+        }
+        let existing = find_key(&tx, namespace, key)?;
+        let holder = existing.as_ref().and_then(|entry| entry.session);
+        if holder.is_some_and(|holder| holder != *session) {
+            return Err(RegistryError::LockHeld);
+        }
+        let index = get_kv_index(&tx).map_err(|_| RegistryError::Storage)? + 1;
+        let lock_index = existing.as_ref().map_or(0, |entry| entry.lock_index);
+        let entry = KvEntry {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value: value.to_string(),
+            create_index: existing.as_ref().map_or(index, |entry| entry.create_index),
+            modify_index: index,
+            session: Some(*session),
+            // Re-acquiring a lock already held is not a new acquisition.
+            lock_index: if holder.is_some() { lock_index } else { lock_index + 1 },
+        };
+        insert_key(&tx, &entry).map_err(|_| RegistryError::Storage)?;
+        set_kv_index(&tx, index).map_err(|_| RegistryError::Storage)?;
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(entry)
+    }
+
+    #[tracing::instrument(name = "registry.release_lock", skip(self), err(level = "debug"))]
+    fn release_lock(&self, namespace: &str, key: &str, session: &Uuid) -> Result<KvEntry, RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|_| RegistryError::Storage)?;
+        let mut entry = find_key(&tx, namespace, key)?
+            .filter(|entry| entry.session == Some(*session))
+            .ok_or(RegistryError::LockNotHeld)?;
+        let index = get_kv_index(&tx).map_err(|_| RegistryError::Storage)? + 1;
+        entry.session = None;
+        entry.modify_index = index;
+        insert_key(&tx, &entry).map_err(|_| RegistryError::Storage)?;
+        set_kv_index(&tx, index).map_err(|_| RegistryError::Storage)?;
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(entry)
+    }
+
+    #[tracing::instrument(name = "registry.add_webhook", skip_all, fields(id = %webhook.id, name = %webhook.name), err(level = "debug"))]
+    fn add_webhook(&self, webhook: &Webhook) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        insert_webhook(&conn, webhook).map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.get_webhook", skip(self), err(level = "debug"))]
+    fn get_webhook(&self, id: &Uuid) -> Result<Webhook, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row("SELECT definition FROM webhooks WHERE id = ?1", [id.to_string()], json_from_row)
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => RegistryError::WebhookNotFound,
+                _ => RegistryError::Storage,
+            })
+    }
+
+    #[tracing::instrument(name = "registry.get_webhooks", skip(self), err(level = "debug"))]
+    fn get_webhooks(&self, namespace: &str) -> Result<Vec<Webhook>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT definition FROM webhooks WHERE namespace = ?1 ORDER BY rowid")
+            .map_err(|_| RegistryError::Storage)?;
+        let webhooks = stmt.query_map([namespace], json_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(webhooks)
+    }
+
+    #[tracing::instrument(name = "registry.get_all_webhooks", skip(self), err(level = "debug"))]
+    fn get_all_webhooks(&self) -> Result<Vec<Webhook>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT definition FROM webhooks ORDER BY rowid")
+            .map_err(|_| RegistryError::Storage)?;
+        let webhooks = stmt.query_map([], json_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(webhooks)
+    }
+
+    #[tracing::instrument(name = "registry.remove_webhook", skip(self), err(level = "debug"))]
+    fn remove_webhook(&self, id: &Uuid) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|_| RegistryError::Storage)?;
+        let removed = tx.execute("DELETE FROM webhooks WHERE id = ?1", [id.to_string()])
+            .map_err(|_| RegistryError::Storage)?;
+        if removed == 0 {
+            return Err(RegistryError::WebhookNotFound);
+        }
+        tx.execute("DELETE FROM webhook_deliveries WHERE webhook = ?1", [id.to_string()])
+            .map_err(|_| RegistryError::Storage)?;
+        tx.execute("DELETE FROM webhook_dead_letters WHERE webhook = ?1", [id.to_string()])
+            .map_err(|_| RegistryError::Storage)?;
+        tx.commit().map_err(|_| RegistryError::Storage)?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "registry.record_delivery", skip_all, fields(delivery = %attempt.delivery, attempt = attempt.attempt), err(level = "debug"))]
+    fn record_delivery(&self, attempt: &DeliveryAttempt) -> Result<(), RegistryError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|_| RegistryError::Storage)?;
+        insert_delivery(&tx, attempt).map_err(|_| RegistryError::Storage)?;
+        tx.commit().map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.get_deliveries", skip(self), err(level = "debug"))]
+    fn get_deliveries(&self, webhook: &Uuid) -> Result<Vec<DeliveryAttempt>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT attempt FROM webhook_deliveries WHERE webhook = ?1 ORDER BY seq DESC")
+            .map_err(|_| RegistryError::Storage)?;
+        let attempts = stmt.query_map([webhook.to_string()], json_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(attempts)
+    }
+
+    #[tracing::instrument(name = "registry.get_all_deliveries", skip(self), err(level = "debug"))]
+    fn get_all_deliveries(&self) -> Result<Vec<DeliveryAttempt>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT attempt FROM webhook_deliveries ORDER BY seq DESC")
+            .map_err(|_| RegistryError::Storage)?;
+        let attempts = stmt.query_map([], json_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(attempts)
+    }
+
+    #[tracing::instrument(name = "registry.add_dead_letter", skip_all, fields(delivery = %dead_letter.delivery), err(level = "debug"))]
+    fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        insert_dead_letter(&conn, dead_letter).map_err(|_| RegistryError::Storage)
+    }
+
+    #[tracing::instrument(name = "registry.get_dead_letters", skip(self), err(level = "debug"))]
+    fn get_dead_letters(&self, webhook: &Uuid) -> Result<Vec<DeadLetter>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT dead_letter FROM webhook_dead_letters WHERE webhook = ?1 ORDER BY rowid")
+            .map_err(|_| RegistryError::Storage)?;
+        let dead_letters = stmt.query_map([webhook.to_string()], json_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(dead_letters)
+    }
+
+    #[tracing::instrument(name = "registry.get_all_dead_letters", skip(self), err(level = "debug"))]
+    fn get_all_dead_letters(&self) -> Result<Vec<DeadLetter>, RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT dead_letter FROM webhook_dead_letters ORDER BY rowid")
+            .map_err(|_| RegistryError::Storage)?;
+        let dead_letters = stmt.query_map([], json_from_row)
+            .map_err(|_| RegistryError::Storage)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| RegistryError::Storage)?;
+        Ok(dead_letters)
+    }
+
+    #[tracing::instrument(name = "registry.remove_dead_letter", skip(self), err(level = "debug"))]
+    fn remove_dead_letter(&self, delivery: &Uuid) -> Result<(), RegistryError> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn.execute("DELETE FROM webhook_dead_letters WHERE delivery = ?1", [delivery.to_string()])
+            .map_err(|_| RegistryError::Storage)?;
+        if removed == 0 {
+            return Err(RegistryError::DeadLetterNotFound);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> DbRegistry {
+        DbRegistry::new(":memory:").unwrap()
+    }
+
+    fn service(code: &str) -> Service {
+        Service::new(code, code, "")
+    }
+
+    fn role(name: &str) -> RoleDefinition {
+        RoleDefinition { name: name.to_string(), description: String::new(), grants: Vec::new() }
+    }
+
+    #[test]
+    fn audit_head_catches_removed_newest_records() {
+        let registry = registry();
+        assert_eq!(registry.get_audit_head().unwrap(), None);
+        for action in ["service.register", "service.update", "service.deregister"] {
+            registry.append_audit(AuditEntry::new("admin", action, audit::service_target("default", "auth"))).unwrap();
+        }
+        let records = registry.get_audit(&AuditFilter::default()).unwrap();
+        let head = registry.get_audit_head().unwrap();
+        assert_eq!(head, records.last().map(AuditHead::from));
+        assert_eq!(audit::verify(&records, head.as_ref()), Ok(()));
+
+        // Only the head notices records removed past the triggers.
+        registry.conn.lock().unwrap().execute_batch(
+            "DROP TRIGGER audit_log_no_delete; DELETE FROM audit_log WHERE seq > 1;",
+        ).unwrap();
+        let records = registry.get_audit(&AuditFilter::default()).unwrap();
+        let head = registry.get_audit_head().unwrap();
+        assert_eq!(audit::verify(&records, None), Ok(()));
+        assert_eq!(audit::verify(&records, head.as_ref()), Err(audit::ChainError::Truncated { seq: 1, head_seq: 3 }));
+
+        // A restored log brings its own head.
+        let restored = DbRegistry::new(":memory:").unwrap();
+        restored.append_audit(AuditEntry::new("admin", "service.register", "service:default/other".to_string())).unwrap();
+        restored.restore(&RegistrySnapshot::capture(&registry).unwrap()).unwrap();
+        assert_eq!(restored.get_audit_head().unwrap().map(|head| head.seq), Some(1));
+    }
+
+    #[test]
+    fn restore_replaces_the_registry() {
+        let source = registry();
+        source.add_service(&service("auth")).unwrap();
+        source.add_role(&role("reader")).unwrap();
+        let snapshot = RegistrySnapshot::capture(&source).unwrap();
+
+        let target = registry();
+        target.add_service(&service("stale")).unwrap();
+        target.restore(&snapshot).unwrap();
+
+        let codes: Vec<String> = target.get_all_services().unwrap().into_iter().map(|s| s.code).collect();
+        assert_eq!(codes, ["auth"]);
+        assert_eq!(target.get_role("reader").unwrap().name, "reader");
+    }
+
+    #[test]
+    fn update_service_requires_a_registered_service() {
+        let registry = registry();
+        let mut auth = service("auth");
+        assert!(matches!(registry.update_service(&auth), Err(RegistryError::ServiceNotFound)));
+
+        registry.add_service(&auth).unwrap();
+        auth.description = "Issues tokens".to_string();
+        registry.update_service(&auth).unwrap();
+        assert_eq!(registry.get_service(&auth.namespace, "auth").unwrap().description, "Issues tokens");
+    }
+
+    #[test]
+    fn failed_restore_leaves_the_registry_unchanged() {
+        let target = registry();
+        target.add_service(&service("auth")).unwrap();
+
+        // The second role breaks the primary key after the services are written.
+        let snapshot = RegistrySnapshot {
+            services: vec![service("billing")],
+            roles: vec![role("reader"), role("reader")],
+            ..Default::default()
+        };
+        assert!(target.restore(&snapshot).is_err());
+
+        let codes: Vec<String> = target.get_all_services().unwrap().into_iter().map(|s| s.code).collect();
+        assert_eq!(codes, ["auth"]);
+        assert!(target.get_roles().unwrap().is_empty());
+    }
+
+    #[test]
+    fn put_key_with_cas_zero_only_creates() {
+        let registry = registry();
+        let created = registry.put_key("default", "config/timeout", "30s", Some(0)).unwrap();
+        assert_eq!((created.create_index, created.modify_index), (1, 1));
+        assert!(matches!(registry.put_key("default", "config/timeout", "60s", Some(0)), Err(RegistryError::CasMismatch)));
+        assert_eq!(registry.get_key("default", "config/timeout").unwrap().value, "30s");
+    }
+
+    #[test]
+    fn put_key_with_cas_requires_the_current_index() {
+        let registry = registry();
+        let first = registry.put_key("default", "config/timeout", "30s", None).unwrap();
+        let second = registry.put_key("default", "config/timeout", "45s", Some(first.modify_index)).unwrap();
+        assert_eq!(second.create_index, first.create_index);
+        assert!(second.modify_index > first.modify_index);
+
+        // A writer still holding the first index lost the race.
+        assert!(matches!(
+            registry.put_key("default", "config/timeout", "60s", Some(first.modify_index)),
+            Err(RegistryError::CasMismatch)
+        ));
+        assert_eq!(registry.get_key("default", "config/timeout").unwrap().value, "45s");
+        assert_eq!(registry.kv_index().unwrap(), second.modify_index);
+    }
+
+    #[test]
+    fn remove_key_with_cas_requires_the_current_index() {
+        let registry = registry();
+        let first = registry.put_key("default", "config/timeout", "30s", None).unwrap();
+        let second = registry.put_key("default", "config/timeout", "45s", None).unwrap();
+        assert!(matches!(
+            registry.remove_key("default", "config/timeout", Some(first.modify_index)),
+            Err(RegistryError::CasMismatch)
+        ));
+        registry.remove_key("default", "config/timeout", Some(second.modify_index)).unwrap();
+        assert!(matches!(registry.get_key("default", "config/timeout"), Err(RegistryError::KeyNotFound)));
+        assert!(matches!(registry.remove_key("default", "config/timeout", None), Err(RegistryError::KeyNotFound)));
+    }
+
+    #[test]
+    fn keys_are_scoped_by_namespace_and_prefix() {
+        let registry = registry();
+        registry.put_key("default", "config/a", "1", None).unwrap();
+        registry.put_key("default", "config/b", "2", None).unwrap();
+        registry.put_key("default", "other", "3", None).unwrap();
+        registry.put_key("prod", "config/a", "4", None).unwrap();
+
+        let keys: Vec<String> = registry.get_keys("default", "config/").unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, ["config/a", "config/b"]);
+        registry.remove_keys("default", "config/").unwrap();
+        let keys: Vec<String> = registry.get_keys("default", "").unwrap().into_iter().map(|e| e.key).collect();
+        assert_eq!(keys, ["other"]);
+        assert_eq!(registry.get_key("prod", "config/a").unwrap().value, "4");
+    }
+
+    fn session(registry: &DbRegistry, namespace: &str) -> Uuid {
+        let session = Session::new(namespace, "worker", None, Some(30));
+        registry.add_session(&session).unwrap();
+        session.id
+    }
+
+    #[test]
+    fn lock_is_exclusive_until_released() {
+        let registry = registry();
+        let (a, b) = (session(&registry, "default"), session(&registry, "default"));
+
+        let held = registry.acquire_lock("default", "leader", "node-a", &a).unwrap();
+        assert_eq!((held.session, held.lock_index), (Some(a), 1));
+        assert!(matches!(registry.acquire_lock("default", "leader", "node-b", &b), Err(RegistryError::LockHeld)));
+        assert!(matches!(registry.release_lock("default", "leader", &b), Err(RegistryError::LockNotHeld)));
+
+        // Re-acquiring a held lock updates the value without a new acquisition.
+        let again = registry.acquire_lock("default", "leader", "node-a2", &a).unwrap();
+        assert_eq!((again.value.as_str(), again.lock_index), ("node-a2", 1));
+
+        let released = registry.release_lock("default", "leader", &a).unwrap();
+        assert_eq!((released.session, released.value.as_str()), (None, "node-a2"));
+        let taken = registry.acquire_lock("default", "leader", "node-b", &b).unwrap();
+        assert_eq!((taken.session, taken.lock_index), (Some(b), 2));
+    }
+
+    #[test]
+    fn ending_a_session_releases_its_locks() {
+        let registry = registry();
+        let (a, b) = (session(&registry, "default"), session(&registry, "default"));
+        let held = registry.acquire_lock("default", "leader", "node-a", &a).unwrap();
+
+        registry.remove_session(&a).unwrap();
+        let entry = registry.get_key("default", "leader").unwrap();
+        assert_eq!(entry.session, None);
+        assert!(entry.modify_index > held.modify_index);
+        registry.acquire_lock("default", "leader", "node-b", &b).unwrap();
+    }
+
+    #[test]
+    fn lock_needs_a_session_of_its_namespace() {
+        let registry = registry();
+        let other = session(&registry, "prod");
+        assert!(matches!(registry.acquire_lock("default", "leader", "x", &other), Err(RegistryError::SessionNotFound)));
+        assert!(matches!(registry.acquire_lock("default", "leader", "x", &Uuid::new_v4()), Err(RegistryError::SessionNotFound)));
+    }
+
+    #[test]
+    fn plain_write_keeps_the_lock() {
+        let registry = registry();
+        let a = session(&registry, "default");
+        registry.acquire_lock("default", "leader", "node-a", &a).unwrap();
+        let written = registry.put_key("default", "leader", "node-a:8080", None).unwrap();
+        assert_eq!(written.session, Some(a));
+    }
 }
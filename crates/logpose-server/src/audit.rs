@@ -0,0 +1,48 @@
+//! Recording API mutations in the audit log.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::request::Parts,
+};
+use logpose_core::{AuditEntry, Claims, RegistryStore};
+
+/// Who made a request and from where. Handlers only run after
+/// `auth_middleware`, so the claims are always present on audited routes.
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub sub: String,
+    pub source_ip: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let sub = parts.extensions.get::<Claims>().map(|claims| claims.sub.clone()).unwrap_or_default();
+        let source_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        Ok(Self { sub, source_ip })
+    }
+}
+
+impl Actor {
+    pub fn entry(&self, action: &str, target: String) -> AuditEntry {
+        AuditEntry::new(self.sub.clone(), action, target).source_ip(self.source_ip.clone())
+    }
+}
+
+/// Appends `entry` to the audit log. The change it describes has already
+/// been made, so a failure is logged rather than failing the request.
+pub fn record(registry: &dyn RegistryStore, entry: AuditEntry) {
+    let (action, target) = (entry.action.clone(), entry.target.clone());
+    if let Err(e) = registry.append_audit(entry) {
+        tracing::error!(action = %action, target = %target, "failed to append audit record: {}", e);
+    }
+}
@@ -0,0 +1,319 @@
+//! Route authorization.
+//!
+//! Every authenticated route declares what it requires in [`route_access`],
+//! and [`authorize`] enforces it once `auth_middleware` has attached the
+//! caller's claims. Routes missing from the table are refused, so a new
+//! route cannot be exposed without deciding who may call it.
+//!
+//! Permissions may be granted on a subset of services. The service a request
+//! acts on is taken from its `:code` path parameter, the service of its
+//! `:id` instance, or on Prometheus service discovery, the only route taking
+//! one, its `service` query parameter. Handlers of routes that
+//! span several services receive the caller's [`Policy`] as an extension and
+//! narrow their results with it. Key/value and lock routes are narrowed by
+//! key, in their handlers, the same way.
+//!
+//! Roles are held per namespace. Namespaced routes are checked against the
+//! roles held in the namespace the request addresses; global routes, such as
+//! identity and role administration, against those held in the default
+//! namespace. Role assignments still apply to the namespace addressed, so
+//! default-namespace administrators bootstrap every other namespace.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{MatchedPath, Query, RawPathParams, State},
+    http::{Method, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use logpose_core::{Claims, Permission, Policy, RegistryStore, DEFAULT_NAMESPACE};
+
+use crate::namespace::Namespace;
+use crate::AppState;
+
+pub enum Access {
+    /// Any authenticated identity
+    Authenticated,
+    /// A permission in the namespace the request addresses
+    Permission(Permission),
+    /// A permission in the default namespace
+    Global(Permission),
+}
+
+pub fn route_access(method: &Method, route: &str) -> Option<Access> {
+    use Permission::*;
+
+    let global = match (method.as_str(), route) {
+        ("POST", "/api/auth/revoke") => return Some(Access::Authenticated),
+        ("GET", "/api/cluster") => return Some(Access::Authenticated),
+        ("GET", "/api/federation") => return Some(Access::Authenticated),
+        ("GET", "/api/identities") => Some(UserManage),
+        ("POST", "/api/identities") => Some(UserManage),
+        ("DELETE", "/api/identities/:cn") => Some(UserManage),
+        ("POST", "/api/identities/:cn/disable") => Some(UserManage),
+        ("POST", "/api/identities/:cn/enable") => Some(UserManage),
+        ("POST", "/api/identities/:cn/roles") => Some(UserManage),
+        ("DELETE", "/api/identities/:cn/roles/:role") => Some(UserManage),
+        ("GET", "/api/roles") => Some(UserManage),
+        ("POST", "/api/roles") => Some(UserManage),
+        ("DELETE", "/api/roles/:name") => Some(UserManage),
+        ("GET", "/api/audit") => Some(AuditRead),
+        ("GET", "/api/audit/verify") => Some(AuditRead),
+        ("POST", "/api/cluster/members") => Some(ClusterManage),
+        ("DELETE", "/api/cluster/members/:id") => Some(ClusterManage),
+        _ => None,
+    };
+    if let Some(permission) = global {
+        return Some(Access::Global(permission));
+    }
+
+    let permission = match (method.as_str(), route) {
+        ("GET", "/api/services") => ServiceRead,
+        ("POST", "/api/services") => ServiceWrite,
+        ("GET", "/api/services/:code") => ServiceRead,
+        ("PATCH", "/api/services/:code") => ServiceWrite,
+        ("DELETE", "/api/services/:code") => ServiceWrite,
+        ("GET", "/api/services/:code/impact") => ServiceRead,
+        ("GET", "/api/graph") => ServiceRead,
+        ("GET", "/api/services/:code/instances") => InstanceRead,
+        ("POST", "/api/services/:code/instances") => InstanceWrite,
+        ("GET", "/api/discover/:code") => InstanceRead,
+        ("DELETE", "/api/instances/:id") => InstanceWrite,
+        ("POST", "/api/instances/:id/health") => InstanceWrite,
+        ("GET", "/api/sd/prometheus") => InstanceRead,
+        ("GET", "/api/query") => ServiceRead,
+        ("POST", "/api/query") => ServiceWrite,
+        ("GET", "/api/query/:name") => ServiceRead,
+        ("DELETE", "/api/query/:name") => ServiceWrite,
+        ("GET", "/api/query/:name/execute") => InstanceRead,
+        ("GET", "/api/kv") => KvRead,
+        ("GET", "/api/kv/*key") => KvRead,
+        ("PUT", "/api/kv/*key") => KvWrite,
+        ("DELETE", "/api/kv/*key") => KvWrite,
+        ("GET", "/api/sessions") => KvRead,
+        ("POST", "/api/sessions") => Lock,
+        ("GET", "/api/sessions/:session") => KvRead,
+        ("DELETE", "/api/sessions/:session") => Lock,
+        ("POST", "/api/sessions/:session/renew") => Lock,
+        ("GET", "/api/locks/*key") => KvRead,
+        ("PUT", "/api/locks/*key") => Lock,
+        ("DELETE", "/api/locks/*key") => Lock,
+        ("GET", "/api/webhooks") => WebhookManage,
+        ("POST", "/api/webhooks") => WebhookManage,
+        ("GET", "/api/webhooks/:webhook") => WebhookManage,
+        ("DELETE", "/api/webhooks/:webhook") => WebhookManage,
+        ("GET", "/api/webhooks/:webhook/deliveries") => WebhookManage,
+        ("GET", "/api/webhooks/:webhook/dead-letters") => WebhookManage,
+        ("DELETE", "/api/webhooks/:webhook/dead-letters/:delivery") => WebhookManage,
+        ("POST", "/api/webhooks/:webhook/dead-letters/:delivery/redeliver") => WebhookManage,
+        _ => return None,
+    };
+    Some(Access::Permission(permission))
+}
+
+/// Route whose `service` query parameter names the service it acts on.
+const SERVICE_QUERY_ROUTE: &str = "/api/sd/prometheus";
+
+/// The service a request acts on, or `None` if it is not about a single one.
+fn target_service(
+    state: &AppState,
+    namespace: &str,
+    route: &str,
+    params: Option<&RawPathParams>,
+    uri: &Uri,
+) -> Option<String> {
+    for (key, value) in params.into_iter().flatten() {
+        match key {
+            "code" => return Some(value.to_string()),
+            // Unknown instances are left to the handler to report.
+            "id" => {
+                let id = uuid::Uuid::parse_str(value).ok()?;
+                return state
+                    .registry
+                    .get_instance(&id)
+                    .ok()
+                    .filter(|instance| instance.namespace == namespace)
+                    .map(|instance| instance.service_name);
+            }
+            _ => {}
+        }
+    }
+    // Other routes ignore the parameter, so it must not decide their access.
+    if route != SERVICE_QUERY_ROUTE {
+        return None;
+    }
+    let Query(query) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+    query.get("service").cloned()
+}
+
+pub async fn authorize<B>(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    params: Option<RawPathParams>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // Public routes arrive without claims; auth_middleware has already
+    // turned away every other unauthenticated request.
+    let Some(claims) = req.extensions().get::<Claims>() else {
+        return next.run(req).await;
+    };
+    // Unmatched paths fall through to the 404 fallback.
+    let Some(route) = req.extensions().get::<MatchedPath>() else {
+        return next.run(req).await;
+    };
+
+    let Some(access) = route_access(req.method(), route.as_str()) else {
+        tracing::error!(method = %req.method(), route = route.as_str(), "route has no access policy");
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    };
+
+    let policy_namespace = match access {
+        Access::Permission(_) => &*namespace,
+        Access::Authenticated | Access::Global(_) => DEFAULT_NAMESPACE,
+    };
+    let policy = match Policy::resolve(claims.roles_in(policy_namespace), state.registry.as_ref()) {
+        Ok(policy) => policy,
+        Err(e) => {
+            tracing::error!("failed to resolve roles of {}: {}", claims.sub, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to resolve roles").into_response();
+        }
+    };
+
+    let allowed = match access {
+        Access::Authenticated => true,
+        Access::Permission(permission) => {
+            let service = target_service(&state, &namespace, route.as_str(), params.as_ref(), req.uri());
+            policy.allows(permission, service.as_deref())
+        }
+        Access::Global(permission) => policy.allows(permission, None),
+    };
+    if !allowed {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+
+    req.extensions_mut().insert(policy);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing, Router};
+    use logpose_core::{HealthStatus, RegistryStore, Role};
+    use reqwest::StatusCode;
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::testing;
+
+    async fn get(url: &str, token: &str) -> StatusCode {
+        reqwest::Client::new().get(url).bearer_auth(token).send().await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn routes_without_an_access_policy_are_refused() {
+        let state = testing::state(Config::default());
+        let admin = testing::identity(&state, "admin", &[("default", Role::Admin)]);
+        let app = Router::new()
+            .route("/api/unlisted", routing::get(|| async { "listed nowhere" }))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), super::authorize))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), crate::auth_middleware))
+            .with_state(state.clone());
+        let url = testing::serve(app);
+
+        let token = testing::token(&state, &admin);
+        assert_eq!(get(&format!("{}/api/unlisted", url), &token).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn scoped_grants_cover_only_matching_services() {
+        let state = testing::state(Config::default());
+        let role = testing::role(
+            &state,
+            "billing-reader",
+            &["ServiceRead:billing-*", "InstanceRead:billing-*", "KvRead:billing/*"],
+        );
+        let reader = testing::identity(&state, "reader", &[("default", role)]);
+        let billing = testing::service(&state, "default", "billing-api");
+        let auth = testing::service(&state, "default", "auth-svc");
+        testing::instance(&state, &billing, 8080, "agent");
+        testing::instance(&state, &auth, 8081, "agent");
+        let (url, token) = (testing::api(&state), testing::token(&state, &reader));
+
+        assert_eq!(get(&format!("{}/api/services/billing-api", url), &token).await, StatusCode::OK);
+        assert_eq!(get(&format!("{}/api/services/auth-svc", url), &token).await, StatusCode::FORBIDDEN);
+        assert_eq!(get(&format!("{}/api/services/auth-svc/instances", url), &token).await, StatusCode::FORBIDDEN);
+
+        // Only Prometheus service discovery takes its service from the query;
+        // elsewhere it neither grants nor denies. Key/value grants go by key.
+        let kv = format!("{}/api/kv/billing/limits?service=", url);
+        assert_eq!(get(&format!("{}auth-svc", kv), &token).await, StatusCode::NOT_FOUND);
+        assert_eq!(get(&format!("{}/api/kv/auth/limits?service=billing-api", url), &token).await, StatusCode::FORBIDDEN);
+        let sd = format!("{}/api/sd/prometheus?service=", url);
+        assert_eq!(get(&format!("{}billing-api", sd), &token).await, StatusCode::OK);
+        assert_eq!(get(&format!("{}auth-svc", sd), &token).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn instances_of_other_namespaces_are_refused() {
+        let state = testing::state(Config::default());
+        let admin = testing::identity(&state, "admin", &[("default", Role::Admin)]);
+        let service = testing::service(&state, "payments", "billing-api");
+        let instance = testing::instance(&state, &service, 8080, "agent");
+        let (url, token) = (testing::api(&state), testing::token(&state, &admin));
+        let client = reqwest::Client::new();
+
+        let response = client.delete(format!("{}/api/instances/{}", url, instance.id)).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let namespaced = format!("{}/api/namespaces/payments/instances/{}", url, instance.id);
+        let response = client.delete(namespaced).bearer_auth(&token).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(state.registry.get_instance(&instance.id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn global_routes_need_roles_in_the_default_namespace() {
+        let state = testing::state(Config::default());
+        let local = testing::identity(&state, "payments-admin", &[("payments", Role::Admin)]);
+        let admin = testing::identity(&state, "admin", &[("default", Role::Admin)]);
+        let url = testing::api(&state);
+
+        for path in ["/api/identities", "/api/namespaces/payments/identities", "/api/audit"] {
+            let url = format!("{}{}", url, path);
+            assert_eq!(get(&url, &testing::token(&state, &local)).await, StatusCode::FORBIDDEN, "{}", path);
+            assert_eq!(get(&url, &testing::token(&state, &admin)).await, StatusCode::OK, "{}", path);
+        }
+        // Namespaced routes still follow the namespace's roles.
+        let services = format!("{}/api/namespaces/payments/services", url);
+        assert_eq!(get(&services, &testing::token(&state, &local)).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn health_is_reported_by_the_registrant_an_admin_or_a_health_reporter() {
+        let state = testing::state(Config::default());
+        let reporter = testing::role(&state, "billing-health", &["InstanceWrite:*", "HealthReport:billing-*"]);
+        let elsewhere = testing::role(&state, "auth-health", &["InstanceWrite:*", "HealthReport:auth-*"]);
+        let callers = [
+            (testing::identity(&state, "registrant", &[("default", Role::Agent)]), StatusCode::OK),
+            (testing::identity(&state, "admin", &[("default", Role::Admin)]), StatusCode::OK),
+            (testing::identity(&state, "reporter", &[("default", reporter)]), StatusCode::OK),
+            (testing::identity(&state, "other-agent", &[("default", Role::Agent)]), StatusCode::FORBIDDEN),
+            (testing::identity(&state, "other-reporter", &[("default", elsewhere)]), StatusCode::FORBIDDEN),
+        ];
+        let service = testing::service(&state, "default", "billing-api");
+        let instance = testing::instance(&state, &service, 8080, "registrant");
+        let url = format!("{}/api/instances/{}/health", testing::api(&state), instance.id);
+
+        for (caller, expected) in callers {
+            let response = reqwest::Client::new()
+                .post(&url)
+                .bearer_auth(testing::token(&state, &caller))
+                .json(&json!({ "status": HealthStatus::Unhealthy }))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), expected, "{}", caller.common_name);
+        }
+    }
+}
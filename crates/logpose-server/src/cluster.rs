@@ -0,0 +1,643 @@
+//! Replicating the registry across a cluster.
+//!
+//! Handlers write through [`Registry`]: on a standalone server it writes to
+//! the database directly, on a clustered one it proposes each write to the
+//! Raft log and returns once the write is committed and applied locally.
+//! Reads are always served from the local database, so a follower may lag
+//! slightly behind the leader. Writes sent to a follower are forwarded to
+//! the leader by [`forward_writes`].
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, FromRequest, State},
+    http::{header, HeaderValue, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use logpose_core::{
+    AuditEntry, AuditFilter, AuditHead, AuditRecord, HealthStatus, Identity, KvEntry, RegistryError, RegistryEvent, RegistrySnapshot,
+    PreparedQuery, RegistryStore, Role, RoleDefinition, Service, ServiceInstance, Session, SigningKey, Webhook,
+};
+use logpose_core::snapshot::RevokedToken;
+use logpose_core::webhook::{DeadLetter, DeliveryAttempt};
+use logpose_db::DbRegistry;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::raft::{self, ProposeError, Raft};
+
+/// Header with the client address of a write a follower forwarded.
+const FORWARDED_FOR_HEADER: &str = "x-logpose-forwarded-for";
+
+/// A registry write, as replicated through the Raft log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    AddService(Service),
+    UpdateService(Service),
+    RemoveService { namespace: String, code: String },
+    AddInstance(ServiceInstance),
+    RemoveInstance(Uuid),
+    UpdateInstanceHealth { id: Uuid, health: HealthStatus },
+    AddIdentity(Identity),
+    RemoveIdentity(String),
+    AddRoleToIdentity { common_name: String, namespace: String, role: Role },
+    RemoveRoleFromIdentity { common_name: String, namespace: String, role: Role },
+    DisableIdentity(String),
+    EnableIdentity(String),
+    SetIdentitySecret { common_name: String, secret_hash: String },
+    AddRole(RoleDefinition),
+    RemoveRole(String),
+    RevokeToken { jti: String, expires_at: i64 },
+    AddSigningKey(SigningKey),
+    RemoveSigningKey(String),
+    AppendAudit(AuditEntry),
+    AddQuery(PreparedQuery),
+    RemoveQuery { namespace: String, name: String },
+    PutKey { namespace: String, key: String, value: String, cas: Option<u64> },
+    RemoveKey { namespace: String, key: String, cas: Option<u64> },
+    RemoveKeys { namespace: String, prefix: String },
+    AddSession(Session),
+    RenewSession { id: Uuid, expires_at: u64 },
+    RemoveSession(Uuid),
+    AcquireLock { namespace: String, key: String, value: String, session: Uuid },
+    ReleaseLock { namespace: String, key: String, session: Uuid },
+    AddWebhook(Webhook),
+    RemoveWebhook(Uuid),
+    RecordDelivery(DeliveryAttempt),
+    AddDeadLetter(DeadLetter),
+    RemoveDeadLetter(Uuid),
+}
+
+/// What applying a command produced.
+#[derive(Debug)]
+pub enum Applied {
+    Done,
+    Audit(Box<AuditRecord>),
+    Key(Box<KvEntry>),
+}
+
+impl Command {
+    fn apply(&self, db: &DbRegistry) -> Result<Applied, RegistryError> {
+        let done = match self {
+            Command::AppendAudit(entry) => return db.append_audit(entry.clone()).map(|record| Applied::Audit(Box::new(record))),
+            Command::AddService(service) => db.add_service(service),
+            Command::UpdateService(service) => db.update_service(service),
+            Command::RemoveService { namespace, code } => db.remove_service(namespace, code),
+            Command::AddInstance(instance) => db.add_instance(instance),
+            Command::RemoveInstance(id) => db.remove_instance(id),
+            Command::UpdateInstanceHealth { id, health } => db.update_instance_health(id, *health),
+            Command::AddIdentity(identity) => db.add_identity(identity),
+            Command::RemoveIdentity(common_name) => db.remove_identity(common_name),
+            Command::AddRoleToIdentity { common_name, namespace, role } => {
+                db.add_role_to_identity(common_name, namespace, role.clone())
+            }
+            Command::RemoveRoleFromIdentity { common_name, namespace, role } => {
+                db.remove_role_from_identity(common_name, namespace, role.clone())
+            }
+            Command::DisableIdentity(common_name) => db.disable_identity(common_name),
+            Command::EnableIdentity(common_name) => db.enable_identity(common_name),
+            Command::SetIdentitySecret { common_name, secret_hash } => db.set_identity_secret(common_name, secret_hash),
+            Command::AddRole(role) => db.add_role(role),
+            Command::RemoveRole(name) => db.remove_role(name),
+            Command::RevokeToken { jti, expires_at } => db.revoke_token(jti, *expires_at),
+            Command::PutKey { namespace, key, value, cas } => {
+                return db.put_key(namespace, key, value, *cas).map(|entry| Applied::Key(Box::new(entry)));
+            }
+            Command::AddSigningKey(key) => db.add_signing_key(key),
+            Command::RemoveSigningKey(kid) => db.remove_signing_key(kid),
+            Command::AddQuery(query) => db.add_query(query),
+            Command::RemoveQuery { namespace, name } => db.remove_query(namespace, name),
+            Command::RemoveKey { namespace, key, cas } => db.remove_key(namespace, key, *cas),
+            Command::RemoveKeys { namespace, prefix } => db.remove_keys(namespace, prefix),
+            Command::AddSession(session) => db.add_session(session),
+            Command::RenewSession { id, expires_at } => db.renew_session(id, *expires_at),
+            Command::RemoveSession(id) => db.remove_session(id),
+            Command::AcquireLock { namespace, key, value, session } => {
+                return db.acquire_lock(namespace, key, value, session).map(|entry| Applied::Key(Box::new(entry)));
+            }
+            Command::ReleaseLock { namespace, key, session } => {
+                return db.release_lock(namespace, key, session).map(|entry| Applied::Key(Box::new(entry)));
+            }
+            Command::AddWebhook(webhook) => db.add_webhook(webhook),
+            Command::RemoveWebhook(id) => db.remove_webhook(id),
+            Command::RecordDelivery(attempt) => db.record_delivery(attempt),
+            Command::AddDeadLetter(dead_letter) => db.add_dead_letter(dead_letter),
+            Command::RemoveDeadLetter(delivery) => db.remove_dead_letter(delivery),
+        };
+        done.map(|()| Applied::Done)
+    }
+
+    /// The change notification for this command, given the registry before
+    /// it is applied.
+    fn event(&self, db: &DbRegistry) -> Option<RegistryEvent> {
+        match self {
+            Command::AddService(service) => Some(RegistryEvent::ServiceRegistered {
+                namespace: service.namespace.clone(),
+                code: service.code.clone(),
+            }),
+            Command::UpdateService(service) => Some(RegistryEvent::ServiceUpdated {
+                namespace: service.namespace.clone(),
+                code: service.code.clone(),
+            }),
+            Command::RemoveService { namespace, code } => Some(RegistryEvent::ServiceDeregistered {
+                namespace: namespace.clone(),
+                code: code.clone(),
+            }),
+            Command::AddInstance(instance) => Some(RegistryEvent::InstanceRegistered {
+                namespace: instance.namespace.clone(),
+                service_code: instance.service_name.clone(),
+                id: instance.id,
+            }),
+            Command::RemoveInstance(id) => db.get_instance(id).ok().map(|instance| RegistryEvent::InstanceDeregistered {
+                namespace: instance.namespace,
+                service_code: instance.service_name,
+                id: instance.id,
+            }),
+            Command::UpdateInstanceHealth { id, health } => db
+                .get_instance(id)
+                .ok()
+                .filter(|instance| instance.health != *health)
+                .map(|instance| RegistryEvent::InstanceHealthChanged {
+                    namespace: instance.namespace,
+                    service_code: instance.service_name,
+                    id: instance.id,
+                    from: instance.health,
+                    to: *health,
+                }),
+            Command::PutKey { namespace, key, .. } | Command::RemoveKey { namespace, key, .. } => {
+                Some(RegistryEvent::KeyChanged { namespace: namespace.clone(), key: key.clone() })
+            }
+            Command::RemoveKeys { namespace, prefix } => {
+                Some(RegistryEvent::KeyChanged { namespace: namespace.clone(), key: prefix.clone() })
+            }
+            Command::AcquireLock { namespace, key, .. } | Command::ReleaseLock { namespace, key, .. } => {
+                Some(RegistryEvent::KeyChanged { namespace: namespace.clone(), key: key.clone() })
+            }
+            Command::RemoveSession(id) => db.get_session(id).ok().map(|session| RegistryEvent::SessionDestroyed {
+                namespace: session.namespace,
+                id: session.id,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The replicated state: this node's registry database.
+pub struct Machine {
+    db: Arc<DbRegistry>,
+    events: broadcast::Sender<RegistryEvent>,
+}
+
+impl Machine {
+    pub fn new(db: Arc<DbRegistry>, events: broadcast::Sender<RegistryEvent>) -> Self {
+        Self { db, events }
+    }
+
+    /// Applies a committed command. Writes proposed on this node are
+    /// announced by the handler that made them; with `publish` the change is
+    /// announced here instead.
+    pub fn apply(&self, command: &Command, publish: bool) -> Result<Applied, RegistryError> {
+        let event = publish.then(|| command.event(&self.db)).flatten();
+        let applied = command.apply(&self.db)?;
+        if let Some(event) = event {
+            let _ = self.events.send(event);
+        }
+        Ok(applied)
+    }
+
+    pub fn capture(&self) -> Result<String, RegistryError> {
+        let snapshot = RegistrySnapshot::capture(self.db.as_ref())?;
+        serde_json::to_string(&snapshot).map_err(|_| RegistryError::Storage)
+    }
+
+    pub fn restore(&self, data: &str) -> Result<(), RegistryError> {
+        let snapshot: RegistrySnapshot = serde_json::from_str(data).map_err(|_| RegistryError::Storage)?;
+        self.db.restore(&snapshot)?;
+        let _ = self.events.send(RegistryEvent::Replaced);
+        Ok(())
+    }
+
+    /// Empties the registry, for a node about to join a cluster.
+    pub fn reset(&self) -> Result<(), RegistryError> {
+        self.db.restore(&RegistrySnapshot::default())?;
+        let _ = self.events.send(RegistryEvent::Replaced);
+        Ok(())
+    }
+}
+
+/// The registry as seen by handlers and background tasks.
+pub struct Registry {
+    db: Arc<DbRegistry>,
+    raft: Option<Arc<Raft>>,
+}
+
+impl Registry {
+    pub fn standalone(db: Arc<DbRegistry>) -> Self {
+        Self { db, raft: None }
+    }
+
+    pub fn clustered(db: Arc<DbRegistry>, raft: Arc<Raft>) -> Self {
+        Self { db, raft: Some(raft) }
+    }
+
+    pub fn raft(&self) -> Option<&Arc<Raft>> {
+        self.raft.as_ref()
+    }
+
+    pub fn is_clustered(&self) -> bool {
+        self.raft.is_some()
+    }
+
+    /// Whether this node makes writes on its own initiative, such as health
+    /// checks and key rotation: always on a standalone server, only on the
+    /// leader of a cluster.
+    pub fn is_leader(&self) -> bool {
+        self.raft.as_ref().is_none_or(|raft| raft.is_leader())
+    }
+
+    fn write(&self, command: Command) -> Result<Applied, RegistryError> {
+        let Some(raft) = &self.raft else {
+            return command.apply(&self.db);
+        };
+        // Callers are synchronous; the runtime is multi-threaded, so this
+        // worker may block while other workers drive replication.
+        let proposed = tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(raft.propose(command)));
+        proposed.map_err(|e| match e {
+            ProposeError::Registry(e) => e,
+            e => {
+                tracing::warn!("replicated write failed: {}", e);
+                RegistryError::Storage
+            }
+        })
+    }
+}
+
+impl RegistryStore for Registry {
+    fn add_service(&self, service: &Service) -> Result<(), RegistryError> {
+        self.write(Command::AddService(service.clone())).map(drop)
+    }
+
+    fn update_service(&self, service: &Service) -> Result<(), RegistryError> {
+        self.write(Command::UpdateService(service.clone())).map(drop)
+    }
+
+    fn add_instance(&self, instance: &ServiceInstance) -> Result<(), RegistryError> {
+        self.write(Command::AddInstance(instance.clone())).map(drop)
+    }
+
+    fn get_service(&self, namespace: &str, code: &str) -> Result<Service, RegistryError> {
+        self.db.get_service(namespace, code)
+    }
+
+    fn get_instances(&self, namespace: &str, service_code: &str) -> Result<Vec<ServiceInstance>, RegistryError> {
+        self.db.get_instances(namespace, service_code)
+    }
+
+    fn get_instance(&self, id: &Uuid) -> Result<ServiceInstance, RegistryError> {
+        self.db.get_instance(id)
+    }
+
+    fn remove_instance(&self, id: &Uuid) -> Result<(), RegistryError> {
+        self.write(Command::RemoveInstance(*id)).map(drop)
+    }
+
+    fn remove_service(&self, namespace: &str, code: &str) -> Result<(), RegistryError> {
+        self.write(Command::RemoveService { namespace: namespace.to_string(), code: code.to_string() }).map(drop)
+    }
+
+    fn add_identity(&self, identity: &Identity) -> Result<(), RegistryError> {
+        self.write(Command::AddIdentity(identity.clone())).map(drop)
+    }
+
+    fn get_identity(&self, common_name: &str) -> Result<Identity, RegistryError> {
+        self.db.get_identity(common_name)
+    }
+
+    fn list_identities(&self) -> Result<Vec<Identity>, RegistryError> {
+        self.db.list_identities()
+    }
+
+    fn remove_identity(&self, common_name: &str) -> Result<(), RegistryError> {
+        self.write(Command::RemoveIdentity(common_name.to_string())).map(drop)
+    }
+
+    fn add_role_to_identity(&self, common_name: &str, namespace: &str, role: Role) -> Result<(), RegistryError> {
+        let (common_name, namespace) = (common_name.to_string(), namespace.to_string());
+        self.write(Command::AddRoleToIdentity { common_name, namespace, role }).map(drop)
+    }
+
+    fn remove_role_from_identity(&self, common_name: &str, namespace: &str, role: Role) -> Result<(), RegistryError> {
+        let (common_name, namespace) = (common_name.to_string(), namespace.to_string());
+        self.write(Command::RemoveRoleFromIdentity { common_name, namespace, role }).map(drop)
+    }
+
+    fn disable_identity(&self, common_name: &str) -> Result<(), RegistryError> {
+        self.write(Command::DisableIdentity(common_name.to_string())).map(drop)
+    }
+
+    fn enable_identity(&self, common_name: &str) -> Result<(), RegistryError> {
+        self.write(Command::EnableIdentity(common_name.to_string())).map(drop)
+    }
+
+    fn add_role(&self, role: &RoleDefinition) -> Result<(), RegistryError> {
+        self.write(Command::AddRole(role.clone())).map(drop)
+    }
+
+    fn get_role(&self, name: &str) -> Result<RoleDefinition, RegistryError> {
+        self.db.get_role(name)
+    }
+
+    fn get_roles(&self) -> Result<Vec<RoleDefinition>, RegistryError> {
+        self.db.get_roles()
+    }
+
+    fn remove_role(&self, name: &str) -> Result<(), RegistryError> {
+        self.write(Command::RemoveRole(name.to_string())).map(drop)
+    }
+
+    fn set_identity_secret(&self, common_name: &str, secret_hash: &str) -> Result<(), RegistryError> {
+        let (common_name, secret_hash) = (common_name.to_string(), secret_hash.to_string());
+        self.write(Command::SetIdentitySecret { common_name, secret_hash }).map(drop)
+    }
+
+    fn get_identity_secret(&self, common_name: &str) -> Result<Option<String>, RegistryError> {
+        self.db.get_identity_secret(common_name)
+    }
+
+    fn revoke_token(&self, jti: &str, expires_at: i64) -> Result<(), RegistryError> {
+        self.write(Command::RevokeToken { jti: jti.to_string(), expires_at }).map(drop)
+    }
+
+    fn is_token_revoked(&self, jti: &str) -> Result<bool, RegistryError> {
+        self.db.is_token_revoked(jti)
+    }
+
+    fn list_revoked_tokens(&self) -> Result<Vec<RevokedToken>, RegistryError> {
+        self.db.list_revoked_tokens()
+    }
+
+    fn add_signing_key(&self, key: &SigningKey) -> Result<(), RegistryError> {
+        self.write(Command::AddSigningKey(key.clone())).map(drop)
+    }
+
+    fn get_signing_keys(&self) -> Result<Vec<SigningKey>, RegistryError> {
+        self.db.get_signing_keys()
+    }
+
+    fn remove_signing_key(&self, kid: &str) -> Result<(), RegistryError> {
+        self.write(Command::RemoveSigningKey(kid.to_string())).map(drop)
+    }
+
+    fn update_instance_health(&self, id: &Uuid, health: HealthStatus) -> Result<(), RegistryError> {
+        self.write(Command::UpdateInstanceHealth { id: *id, health }).map(drop)
+    }
+
+    fn get_all_instances(&self) -> Result<Vec<ServiceInstance>, RegistryError> {
+        self.db.get_all_instances()
+    }
+
+    fn get_all_services(&self) -> Result<Vec<Service>, RegistryError> {
+        self.db.get_all_services()
+    }
+
+    fn append_audit(&self, entry: AuditEntry) -> Result<AuditRecord, RegistryError> {
+        match self.write(Command::AppendAudit(entry))? {
+            Applied::Audit(record) => Ok(*record),
+            _ => Err(RegistryError::Storage),
+        }
+    }
+
+    fn get_audit(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>, RegistryError> {
+        self.db.get_audit(filter)
+    }
+
+    fn get_audit_head(&self) -> Result<Option<AuditHead>, RegistryError> {
+        self.db.get_audit_head()
+    }
+
+    fn add_query(&self, query: &PreparedQuery) -> Result<(), RegistryError> {
+        self.write(Command::AddQuery(query.clone())).map(drop)
+    }
+
+    fn get_query(&self, namespace: &str, name: &str) -> Result<PreparedQuery, RegistryError> {
+        self.db.get_query(namespace, name)
+    }
+
+    fn get_queries(&self, namespace: &str) -> Result<Vec<PreparedQuery>, RegistryError> {
+        self.db.get_queries(namespace)
+    }
+
+    fn get_all_queries(&self) -> Result<Vec<PreparedQuery>, RegistryError> {
+        self.db.get_all_queries()
+    }
+
+    fn remove_query(&self, namespace: &str, name: &str) -> Result<(), RegistryError> {
+        let (namespace, name) = (namespace.to_string(), name.to_string());
+        self.write(Command::RemoveQuery { namespace, name }).map(drop)
+    }
+
+    fn put_key(&self, namespace: &str, key: &str, value: &str, cas: Option<u64>) -> Result<KvEntry, RegistryError> {
+        let (namespace, key, value) = (namespace.to_string(), key.to_string(), value.to_string());
+        match self.write(Command::PutKey { namespace, key, value, cas })? {
+            Applied::Key(entry) => Ok(*entry),
+            _ => Err(RegistryError::Storage),
+        }
+    }
+
+    fn get_key(&self, namespace: &str, key: &str) -> Result<KvEntry, RegistryError> {
+        self.db.get_key(namespace, key)
+    }
+
+    fn get_keys(&self, namespace: &str, prefix: &str) -> Result<Vec<KvEntry>, RegistryError> {
+        self.db.get_keys(namespace, prefix)
+    }
+
+    fn get_all_keys(&self) -> Result<Vec<KvEntry>, RegistryError> {
+        self.db.get_all_keys()
+    }
+
+    fn remove_key(&self, namespace: &str, key: &str, cas: Option<u64>) -> Result<(), RegistryError> {
+        let (namespace, key) = (namespace.to_string(), key.to_string());
+        self.write(Command::RemoveKey { namespace, key, cas }).map(drop)
+    }
+
+    fn remove_keys(&self, namespace: &str, prefix: &str) -> Result<(), RegistryError> {
+        let (namespace, prefix) = (namespace.to_string(), prefix.to_string());
+        self.write(Command::RemoveKeys { namespace, prefix }).map(drop)
+    }
+
+    fn kv_index(&self) -> Result<u64, RegistryError> {
+        self.db.kv_index()
+    }
+
+    fn add_session(&self, session: &Session) -> Result<(), RegistryError> {
+        self.write(Command::AddSession(session.clone())).map(drop)
+    }
+
+    fn get_session(&self, id: &Uuid) -> Result<Session, RegistryError> {
+        self.db.get_session(id)
+    }
+
+    fn get_sessions(&self, namespace: &str) -> Result<Vec<Session>, RegistryError> {
+        self.db.get_sessions(namespace)
+    }
+
+    fn get_all_sessions(&self) -> Result<Vec<Session>, RegistryError> {
+        self.db.get_all_sessions()
+    }
+
+    fn renew_session(&self, id: &Uuid, expires_at: u64) -> Result<(), RegistryError> {
+        self.write(Command::RenewSession { id: *id, expires_at }).map(drop)
+    }
+
+    fn remove_session(&self, id: &Uuid) -> Result<(), RegistryError> {
+        self.write(Command::RemoveSession(*id)).map(drop)
+    }
+
+    fn acquire_lock(&self, namespace: &str, key: &str, value: &str, session: &Uuid) -> Result<KvEntry, RegistryError> {
+        let (namespace, key, value) = (namespace.to_string(), key.to_string(), value.to_string());
+        match self.write(Command::AcquireLock { namespace, key, value, session: *session })? {
+            Applied::Key(entry) => Ok(*entry),
+            _ => Err(RegistryError::Storage),
+        }
+    }
+
+    fn release_lock(&self, namespace: &str, key: &str, session: &Uuid) -> Result<KvEntry, RegistryError> {
+        let (namespace, key) = (namespace.to_string(), key.to_string());
+        match self.write(Command::ReleaseLock { namespace, key, session: *session })? {
+            Applied::Key(entry) => Ok(*entry),
+            _ => Err(RegistryError::Storage),
+        }
+    }
+
+    fn add_webhook(&self, webhook: &Webhook) -> Result<(), RegistryError> {
+        self.write(Command::AddWebhook(webhook.clone())).map(drop)
+    }
+
+    fn get_webhook(&self, id: &Uuid) -> Result<Webhook, RegistryError> {
+        self.db.get_webhook(id)
+    }
+
+    fn get_webhooks(&self, namespace: &str) -> Result<Vec<Webhook>, RegistryError> {
+        self.db.get_webhooks(namespace)
+    }
+
+    fn get_all_webhooks(&self) -> Result<Vec<Webhook>, RegistryError> {
+        self.db.get_all_webhooks()
+    }
+
+    fn remove_webhook(&self, id: &Uuid) -> Result<(), RegistryError> {
+        self.write(Command::RemoveWebhook(*id)).map(drop)
+    }
+
+    fn record_delivery(&self, attempt: &DeliveryAttempt) -> Result<(), RegistryError> {
+        self.write(Command::RecordDelivery(attempt.clone())).map(drop)
+    }
+
+    fn get_deliveries(&self, webhook: &Uuid) -> Result<Vec<DeliveryAttempt>, RegistryError> {
+        self.db.get_deliveries(webhook)
+    }
+
+    fn get_all_deliveries(&self) -> Result<Vec<DeliveryAttempt>, RegistryError> {
+        self.db.get_all_deliveries()
+    }
+
+    fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), RegistryError> {
+        self.write(Command::AddDeadLetter(dead_letter.clone())).map(drop)
+    }
+
+    fn get_dead_letters(&self, webhook: &Uuid) -> Result<Vec<DeadLetter>, RegistryError> {
+        self.db.get_dead_letters(webhook)
+    }
+
+    fn get_all_dead_letters(&self) -> Result<Vec<DeadLetter>, RegistryError> {
+        self.db.get_all_dead_letters()
+    }
+
+    fn remove_dead_letter(&self, delivery: &Uuid) -> Result<(), RegistryError> {
+        self.write(Command::RemoveDeadLetter(*delivery)).map(drop)
+    }
+}
+
+/// Forwards API writes reaching a follower to the leader, so clients may
+/// send every request to any node. Runs outside every other layer; the
+/// leader authenticates and authorizes forwarded requests as usual.
+pub async fn forward_writes(State(registry): State<Arc<Registry>>, mut req: Request<Body>, next: Next<Body>) -> Response {
+    let Some(raft) = registry.raft() else {
+        return next.run(req).await;
+    };
+    if req.method() == Method::GET || !req.uri().path().starts_with("/api/") {
+        return next.run(req).await;
+    }
+
+    // A write a follower forwarded: record the client's address rather than
+    // the follower's.
+    if raft.secret_matches(req.headers()) {
+        if !raft.is_leader() {
+            return (StatusCode::SERVICE_UNAVAILABLE, "Cluster leader changed; retry the request").into_response();
+        }
+        let client = req
+            .headers()
+            .get(FORWARDED_FOR_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<IpAddr>().ok());
+        if let Some(ip) = client {
+            req.extensions_mut().insert(ConnectInfo(SocketAddr::new(ip, 0)));
+        }
+        return next.run(req).await;
+    }
+
+    if raft.is_leader() {
+        return next.run(req).await;
+    }
+    let Some(leader) = raft.leader() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "No cluster leader").into_response();
+    };
+
+    let client = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+    let url = format!(
+        "{}{}",
+        leader.url.trim_end_matches('/'),
+        req.uri().path_and_query().map_or("/", |p| p.as_str())
+    );
+    let method = req.method().clone();
+    let mut headers = req.headers().clone();
+    for name in [header::HOST, header::CONTENT_LENGTH, header::CONNECTION, header::TRANSFER_ENCODING] {
+        headers.remove(name);
+    }
+    let body = match Bytes::from_request(req, &()).await {
+        Ok(body) => body,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if let Ok(secret) = HeaderValue::from_str(raft.secret()) {
+        headers.insert(raft::SECRET_HEADER, secret);
+    }
+    if let Some(ip) = client.and_then(|ip| HeaderValue::from_str(&ip.to_string()).ok()) {
+        headers.insert(FORWARDED_FOR_HEADER, ip);
+    }
+
+    tracing::debug!(leader = leader.id, %url, "forwarding write to leader");
+    let response = match raft.client().request(method, url).headers(headers).body(body).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!(leader = leader.id, "failed to forward write to leader: {}", e);
+            return (StatusCode::BAD_GATEWAY, "Failed to reach the cluster leader").into_response();
+        }
+    };
+    let status = response.status();
+    let mut headers = response.headers().clone();
+    for name in [header::CONTENT_LENGTH, header::CONNECTION, header::TRANSFER_ENCODING] {
+        headers.remove(name);
+    }
+    match response.bytes().await {
+        Ok(body) => (status, headers, body).into_response(),
+        Err(e) => {
+            tracing::warn!(leader = leader.id, "failed to read the leader's response: {}", e);
+            (StatusCode::BAD_GATEWAY, "Failed to reach the cluster leader").into_response()
+        }
+    }
+}
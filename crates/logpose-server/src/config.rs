@@ -0,0 +1,705 @@
+//! Server configuration.
+//!
+//! Values are layered, later sources overriding earlier ones:
+//! 1. built-in defaults,
+//! 2. the TOML file given by `--config` (or `./logpose.toml` if present),
+//! 3. environment variables: `LOGPOSE_<SECTION>_<KEY>` for any key, plus the
+//!    legacy `DATABASE_URL`, `JWT_SECRET` and `XDS_ADDR`,
+//! 4. command-line flags.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_CONFIG_FILE: &str = "logpose.toml";
+const ENV_PREFIX: &str = "LOGPOSE_";
+const DEFAULT_JWT_SECRET: &str = "super-secret-key";
+
+#[derive(Parser, Debug)]
+#[command(name = "logpose-server")]
+#[command(about = "LogPose service discovery server", long_about = None)]
+pub struct Cli {
+    /// Path to the configuration file
+    #[arg(long, env = "LOGPOSE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address the HTTP API listens on (overrides `server.bind`)
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+
+    /// Print the effective configuration and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Allow insecure development settings (sets `auth.dev_mode`)
+    #[arg(long)]
+    pub dev: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+
+    #[error("invalid config file {path}: {message}")]
+    Parse { path: PathBuf, message: String },
+
+    #[error("invalid value for environment variable {var}: {message}")]
+    Env { var: String, message: String },
+
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub storage: StorageConfig,
+    pub health: HealthConfig,
+    pub auth: AuthConfig,
+    pub dns: DnsConfig,
+    pub metrics: MetricsConfig,
+    pub limits: LimitsConfig,
+    pub cluster: ClusterConfig,
+    pub federation: FederationConfig,
+    pub webhooks: WebhooksConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address of the HTTP API
+    pub bind: SocketAddr,
+    /// Address of the Envoy xDS (ADS) gRPC server
+    pub xds_bind: SocketAddr,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            xds_bind: SocketAddr::from(([127, 0, 0, 1], 18000)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Serve the HTTP API over HTTPS on `server.bind`
+    pub enabled: bool,
+    /// PEM-encoded certificate chain
+    pub cert_path: Option<PathBuf>,
+    /// PEM-encoded private key
+    pub key_path: Option<PathBuf>,
+    /// PEM-encoded CA bundle; enables client certificate authentication
+    pub client_ca_path: Option<PathBuf>,
+    /// Reject connections without a client certificate instead of falling
+    /// back to bearer tokens
+    pub require_client_cert: bool,
+    /// Seconds between checks of the certificate files for changes
+    pub reload_interval_secs: u64,
+    /// Optional plain-HTTP listener redirecting to the HTTPS API
+    pub redirect_bind: Option<SocketAddr>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: None,
+            key_path: None,
+            client_ca_path: None,
+            require_client_cert: false,
+            reload_interval_secs: 60,
+            redirect_bind: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Path to the SQLite database file
+    pub database_url: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self { database_url: "logpose.db".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Seconds between two health worker passes
+    pub interval_secs: u64,
+    /// Seconds before a single probe is considered failed
+    pub timeout_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self { interval_secs: 30, timeout_secs: 2 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigningAlgorithm {
+    /// Shared-secret HMAC using `jwt_secret`
+    HS256,
+    /// RSA 2048 keys generated and rotated by the server
+    RS256,
+    /// Ed25519 keys generated and rotated by the server
+    EdDSA,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Algorithm access and refresh tokens are signed with
+    pub algorithm: SigningAlgorithm,
+    /// Secret used to sign and verify JWTs with HS256
+    pub jwt_secret: String,
+    /// Days an RS256/EdDSA key signs new tokens before a new one replaces it
+    pub key_rotation_days: u64,
+    /// Permit the built-in default `jwt_secret`; never enable in production
+    pub dev_mode: bool,
+    /// Identity seeded with the Admin role on first start
+    pub admin_common_name: String,
+    /// Lifetime of access tokens issued by `/api/auth/token`
+    pub access_token_ttl_secs: u64,
+    /// Lifetime of refresh tokens; each refresh issues a new one
+    pub refresh_token_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: SigningAlgorithm::HS256,
+            jwt_secret: DEFAULT_JWT_SECRET.to_string(),
+            key_rotation_days: 30,
+            dev_mode: false,
+            admin_common_name: "admin.logpose.local".to_string(),
+            access_token_ttl_secs: 900,
+            refresh_token_ttl_secs: 86400,
+        }
+    }
+}
+
+/// Reserved for the DNS interface; names are served under `domain`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    pub enabled: bool,
+    pub bind: SocketAddr,
+    pub domain: String,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: SocketAddr::from(([127, 0, 0, 1], 8600)),
+            domain: "logpose".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Route the Prometheus exposition is served on
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self { enabled: true, path: "/metrics".to_string() }
+    }
+}
+
+/// Per-identity rate limits and registration quotas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub enabled: bool,
+    /// Sustained `GET` requests per second allowed to one identity
+    pub read_per_sec: u64,
+    /// `GET` requests one identity may make in a burst
+    pub read_burst: u64,
+    /// Sustained non-`GET` requests per second allowed to one identity
+    pub write_per_sec: u64,
+    /// Non-`GET` requests one identity may make in a burst
+    pub write_burst: u64,
+    /// Instances one service may have; 0 for no limit
+    pub max_instances_per_service: u64,
+    /// Services one namespace may have; 0 for no limit
+    pub max_services_per_namespace: u64,
+    /// `Retry-After` sent with registrations refused by a quota
+    pub quota_retry_after_secs: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            read_per_sec: 50,
+            read_burst: 100,
+            write_per_sec: 10,
+            write_burst: 20,
+            max_instances_per_service: 1000,
+            max_services_per_namespace: 500,
+            quota_retry_after_secs: 60,
+        }
+    }
+}
+
+/// Raft replication of the registry across several servers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub enabled: bool,
+    /// Identifies this node within the cluster; unique and never reused
+    pub node_id: u64,
+    /// Base URL other nodes reach this node's HTTP API on
+    pub advertise_url: String,
+    /// Form a new single-node cluster on first start; other nodes join it
+    /// through `POST /api/cluster/members`
+    pub bootstrap: bool,
+    /// Path to the SQLite file holding the Raft log and snapshots
+    pub raft_path: String,
+    /// Shared secret authenticating Raft messages between nodes
+    pub secret: String,
+    /// Milliseconds between leader heartbeats
+    pub heartbeat_interval_ms: u64,
+    /// Milliseconds without a heartbeat before a follower stands for
+    /// election; each node waits a random time up to twice this
+    pub election_timeout_ms: u64,
+    /// Log entries applied between two snapshots
+    pub snapshot_threshold: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            node_id: 1,
+            advertise_url: "http://127.0.0.1:3000".to_string(),
+            bootstrap: false,
+            raft_path: "logpose-raft.db".to_string(),
+            secret: String::new(),
+            heartbeat_interval_ms: 150,
+            election_timeout_ms: 1000,
+            snapshot_threshold: 1000,
+        }
+    }
+}
+
+/// Links to the LogPose servers of other datacenters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationConfig {
+    pub enabled: bool,
+    /// Name of this server's datacenter, as peers know it
+    pub datacenter: String,
+    /// Shared secret authenticating requests between datacenters
+    pub secret: String,
+    /// Other datacenters, in the order `?dc=any` fails over to them
+    pub peers: Vec<PeerConfig>,
+    /// Milliseconds to wait for a peer's answer
+    pub timeout_ms: u64,
+    /// Seconds a peer's answer is served without asking again
+    pub cache_ttl_secs: u64,
+    /// Seconds an expired answer may still be served while it is refreshed
+    /// or while the peer is unreachable
+    pub max_stale_secs: u64,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            datacenter: "dc1".to_string(),
+            secret: String::new(),
+            peers: Vec::new(),
+            timeout_ms: 2000,
+            cache_ttl_secs: 10,
+            max_stale_secs: 300,
+        }
+    }
+}
+
+/// Delivery of registry events to webhooks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub enabled: bool,
+    /// Attempts at each delivery before it becomes a dead letter
+    pub max_attempts: u32,
+    /// Milliseconds before the first retry; doubled for every further one
+    pub initial_backoff_ms: u64,
+    /// Longest wait between two attempts
+    pub max_backoff_secs: u64,
+    /// Milliseconds to wait for an endpoint's answer
+    pub timeout_ms: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            max_backoff_secs: 300,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    /// The peer's `federation.datacenter`
+    pub name: String,
+    /// Base URL of the peer's HTTP API
+    pub url: String,
+}
+
+impl Config {
+    /// Builds the effective configuration from all layers and validates it.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut table = match &cli.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => read_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => toml::Table::new(),
+        };
+
+        apply_env(&mut table, std::env::vars())?;
+
+        let mut config: Config = table
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::Invalid(e.to_string().trim().to_string()))?;
+
+        if let Some(bind) = cli.bind {
+            config.server.bind = bind;
+        }
+        if cli.dev {
+            config.auth.dev_mode = true;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.storage.database_url.trim().is_empty() {
+            return Err(ConfigError::Invalid("storage.database_url must not be empty".into()));
+        }
+        if self.health.interval_secs == 0 {
+            return Err(ConfigError::Invalid("health.interval_secs must be greater than 0".into()));
+        }
+        if self.health.timeout_secs == 0 || self.health.timeout_secs >= self.health.interval_secs {
+            return Err(ConfigError::Invalid(format!(
+                "health.timeout_secs must be between 1 and health.interval_secs ({}), got {}",
+                self.health.interval_secs, self.health.timeout_secs
+            )));
+        }
+        if self.auth.algorithm == SigningAlgorithm::HS256 {
+            if self.auth.jwt_secret.is_empty() {
+                return Err(ConfigError::Invalid("auth.jwt_secret must not be empty".into()));
+            }
+            if self.auth.jwt_secret == DEFAULT_JWT_SECRET && !self.auth.dev_mode {
+                return Err(ConfigError::Invalid(
+                    "auth.jwt_secret is the built-in default; set JWT_SECRET, switch auth.algorithm to RS256 or EdDSA, \
+                     or start with --dev for local development"
+                        .into(),
+                ));
+            }
+        } else if self.auth.key_rotation_days == 0 {
+            return Err(ConfigError::Invalid("auth.key_rotation_days must be greater than 0".into()));
+        }
+        if self.auth.admin_common_name.trim().is_empty() {
+            return Err(ConfigError::Invalid("auth.admin_common_name must not be empty".into()));
+        }
+        if self.auth.access_token_ttl_secs == 0 {
+            return Err(ConfigError::Invalid("auth.access_token_ttl_secs must be greater than 0".into()));
+        }
+        if self.auth.refresh_token_ttl_secs < self.auth.access_token_ttl_secs {
+            return Err(ConfigError::Invalid(format!(
+                "auth.refresh_token_ttl_secs must be at least auth.access_token_ttl_secs ({}), got {}",
+                self.auth.access_token_ttl_secs, self.auth.refresh_token_ttl_secs
+            )));
+        }
+        if self.tls.enabled {
+            for (key, path) in [("tls.cert_path", &self.tls.cert_path), ("tls.key_path", &self.tls.key_path)] {
+                match path {
+                    None => return Err(ConfigError::Invalid(format!("{} is required when tls.enabled = true", key))),
+                    Some(path) if !path.is_file() => {
+                        return Err(ConfigError::Invalid(format!("{} = {} does not exist", key, path.display())))
+                    }
+                    Some(_) => {}
+                }
+            }
+            if let Some(path) = self.tls.client_ca_path.as_ref().filter(|p| !p.is_file()) {
+                return Err(ConfigError::Invalid(format!("tls.client_ca_path = {} does not exist", path.display())));
+            }
+            if self.tls.require_client_cert && self.tls.client_ca_path.is_none() {
+                return Err(ConfigError::Invalid("tls.require_client_cert requires tls.client_ca_path".into()));
+            }
+            if self.tls.reload_interval_secs == 0 {
+                return Err(ConfigError::Invalid("tls.reload_interval_secs must be greater than 0".into()));
+            }
+            if self.tls.redirect_bind == Some(self.server.bind) {
+                return Err(ConfigError::Invalid("tls.redirect_bind must differ from server.bind".into()));
+            }
+        } else if self.tls.redirect_bind.is_some() {
+            return Err(ConfigError::Invalid("tls.redirect_bind requires tls.enabled = true".into()));
+        }
+        if self.dns.enabled {
+            return Err(ConfigError::Invalid("dns.enabled: the DNS interface is not available yet".into()));
+        }
+        if self.dns.domain.is_empty()
+            || !self.dns.domain.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+        {
+            return Err(ConfigError::Invalid(format!("dns.domain `{}` is not a valid domain name", self.dns.domain)));
+        }
+        if !self.metrics.path.starts_with('/') {
+            return Err(ConfigError::Invalid(format!("metrics.path must start with `/`, got `{}`", self.metrics.path)));
+        }
+        if self.limits.enabled {
+            for (key, value) in [
+                ("limits.read_per_sec", self.limits.read_per_sec),
+                ("limits.read_burst", self.limits.read_burst),
+                ("limits.write_per_sec", self.limits.write_per_sec),
+                ("limits.write_burst", self.limits.write_burst),
+                ("limits.quota_retry_after_secs", self.limits.quota_retry_after_secs),
+            ] {
+                if value == 0 {
+                    return Err(ConfigError::Invalid(format!("{} must be greater than 0", key)));
+                }
+            }
+        }
+        if self.cluster.enabled {
+            if self.cluster.secret.is_empty() {
+                return Err(ConfigError::Invalid("cluster.secret is required when cluster.enabled = true".into()));
+            }
+            if self.cluster.node_id == 0 {
+                return Err(ConfigError::Invalid("cluster.node_id must be greater than 0".into()));
+            }
+            if !self.cluster.advertise_url.starts_with("http://") && !self.cluster.advertise_url.starts_with("https://") {
+                return Err(ConfigError::Invalid(format!(
+                    "cluster.advertise_url must be an http:// or https:// URL, got `{}`",
+                    self.cluster.advertise_url
+                )));
+            }
+            if self.cluster.raft_path.trim().is_empty() || self.cluster.raft_path == self.storage.database_url {
+                return Err(ConfigError::Invalid("cluster.raft_path must be set and differ from storage.database_url".into()));
+            }
+            if self.cluster.heartbeat_interval_ms == 0 || self.cluster.election_timeout_ms <= 2 * self.cluster.heartbeat_interval_ms {
+                return Err(ConfigError::Invalid(format!(
+                    "cluster.election_timeout_ms must be more than twice cluster.heartbeat_interval_ms ({}), got {}",
+                    self.cluster.heartbeat_interval_ms, self.cluster.election_timeout_ms
+                )));
+            }
+            if self.cluster.snapshot_threshold == 0 {
+                return Err(ConfigError::Invalid("cluster.snapshot_threshold must be greater than 0".into()));
+            }
+        }
+        if self.federation.enabled {
+            let federation = &self.federation;
+            if federation.secret.is_empty() {
+                return Err(ConfigError::Invalid("federation.secret is required when federation.enabled = true".into()));
+            }
+            let mut names = vec![federation.datacenter.as_str()];
+            for name in std::iter::once(&federation.datacenter).chain(federation.peers.iter().map(|p| &p.name)) {
+                if name == "any" || logpose_core::namespace::validate(name).is_err() {
+                    return Err(ConfigError::Invalid(format!(
+                        "datacenter name `{}` must be 1-63 lowercase letters, digits and hyphens, and not `any`",
+                        name
+                    )));
+                }
+            }
+            for peer in &federation.peers {
+                if names.contains(&peer.name.as_str()) {
+                    return Err(ConfigError::Invalid(format!("federation.peers: datacenter `{}` appears twice", peer.name)));
+                }
+                names.push(&peer.name);
+                if !peer.url.starts_with("http://") && !peer.url.starts_with("https://") {
+                    return Err(ConfigError::Invalid(format!(
+                        "federation.peers: url of `{}` must be an http:// or https:// URL, got `{}`",
+                        peer.name, peer.url
+                    )));
+                }
+            }
+            if federation.timeout_ms == 0 || federation.cache_ttl_secs == 0 {
+                return Err(ConfigError::Invalid("federation.timeout_ms and federation.cache_ttl_secs must be greater than 0".into()));
+            }
+            if federation.max_stale_secs < federation.cache_ttl_secs {
+                return Err(ConfigError::Invalid(format!(
+                    "federation.max_stale_secs must be at least federation.cache_ttl_secs ({}), got {}",
+                    federation.cache_ttl_secs, federation.max_stale_secs
+                )));
+            }
+        }
+        if self.webhooks.max_attempts == 0 || self.webhooks.timeout_ms == 0 {
+            return Err(ConfigError::Invalid("webhooks.max_attempts and webhooks.timeout_ms must be greater than 0".into()));
+        }
+        Ok(())
+    }
+
+    /// TOML rendering of the configuration with secrets masked.
+    pub fn to_redacted_toml(&self) -> String {
+        let mut redacted = self.clone();
+        redacted.auth.jwt_secret = "<redacted>".to_string();
+        if !redacted.cluster.secret.is_empty() {
+            redacted.cluster.secret = "<redacted>".to_string();
+        }
+        if !redacted.federation.secret.is_empty() {
+            redacted.federation.secret = "<redacted>".to_string();
+        }
+        toml::to_string_pretty(&redacted).expect("config is serializable")
+    }
+}
+
+fn read_file(path: &Path) -> Result<toml::Table, ConfigError> {
+    let raw = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    raw.parse::<toml::Table>().map_err(|e| ConfigError::Parse {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })
+}
+
+/// Overlays environment variables onto the parsed file. Each value is
+/// coerced to the type of the key's default, so `LOGPOSE_HEALTH_INTERVAL_SECS=10`
+/// becomes an integer while `JWT_SECRET=123` stays a string.
+///
+/// `LOGPOSE_*` variables that do not start with a config section, such as
+/// `LOGPOSE_TOKEN` or `LOGPOSE_NAMESPACE`, belong to the CLI and agents,
+/// which often share a `.env` with the server, and are ignored.
+fn apply_env(table: &mut toml::Table, env: impl Iterator<Item = (String, String)>) -> Result<(), ConfigError> {
+    let defaults = toml::Table::try_from(Config::default()).expect("config is serializable");
+    let env: BTreeMap<String, String> = env.collect();
+
+    let mut vars: Vec<(String, &str, String)> = Vec::new();
+    for (var, key) in [
+        ("DATABASE_URL", "storage.database_url"),
+        ("JWT_SECRET", "auth.jwt_secret"),
+        ("XDS_ADDR", "server.xds_bind"),
+    ] {
+        if let Some(value) = env.get(var) {
+            vars.push((var.to_string(), key, value.clone()));
+        }
+    }
+    let is_section = |var: &str| {
+        var[ENV_PREFIX.len()..]
+            .split_once('_')
+            .is_some_and(|(section, _)| matches!(defaults.get(&section.to_ascii_lowercase()), Some(toml::Value::Table(_))))
+    };
+    let prefixed: Vec<(String, String)> = env
+        .iter()
+        .filter(|(k, _)| k.starts_with(ENV_PREFIX) && is_section(k))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    for (var, key, raw) in vars
+        .into_iter()
+        .map(|(var, key, raw)| (var, key.replacen('.', "_", 1), raw))
+        .chain(prefixed.into_iter().map(|(var, raw)| {
+            let key = var[ENV_PREFIX.len()..].to_ascii_lowercase();
+            (var, key, raw)
+        }))
+    {
+        let Some((section, key)) = key.split_once('_') else {
+            return Err(ConfigError::Env { var, message: "expected LOGPOSE_<SECTION>_<KEY>".into() });
+        };
+        let Some(toml::Value::Table(section_defaults)) = defaults.get(section) else {
+            return Err(ConfigError::Env { var, message: format!("unknown config section `{}`", section) });
+        };
+        let value = match section_defaults.get(key) {
+            Some(toml::Value::Integer(_)) => raw.parse().map(toml::Value::Integer).map_err(|_| ConfigError::Env {
+                var: var.clone(),
+                message: format!("expected an integer, got `{}`", raw),
+            })?,
+            Some(toml::Value::Boolean(_)) => raw.parse().map(toml::Value::Boolean).map_err(|_| ConfigError::Env {
+                var: var.clone(),
+                message: format!("expected true or false, got `{}`", raw),
+            })?,
+            Some(toml::Value::Array(_)) => toml::Value::Array(
+                raw.split(',').map(|item| toml::Value::String(item.trim().to_string())).collect(),
+            ),
+            _ => toml::Value::String(raw),
+        };
+        set(table, &var, section, key, value)?;
+    }
+    Ok(())
+}
+
+fn set(table: &mut toml::Table, var: &str, section: &str, key: &str, value: toml::Value) -> Result<(), ConfigError> {
+    let entry = table
+        .entry(section.to_string())
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    match entry {
+        toml::Value::Table(section) => {
+            section.insert(key.to_string(), value);
+            Ok(())
+        }
+        _ => Err(ConfigError::Env {
+            var: var.to_string(),
+            message: format!("`{}` is not a config section", section),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn env_sets_config_sections() {
+        let mut table = toml::Table::new();
+        apply_env(&mut table, env(&[("LOGPOSE_HEALTH_INTERVAL_SECS", "10"), ("JWT_SECRET", "s3cret")])).unwrap();
+        assert_eq!(table["health"]["interval_secs"], toml::Value::Integer(10));
+        assert_eq!(table["auth"]["jwt_secret"], toml::Value::String("s3cret".into()));
+    }
+
+    #[test]
+    fn env_ignores_variables_of_other_tools() {
+        let mut table = toml::Table::new();
+        apply_env(
+            &mut table,
+            env(&[
+                ("LOGPOSE_NAMESPACE", "prod"),
+                ("LOGPOSE_TOKEN", "abc"),
+                ("LOGPOSE_GOSSIP_BIND", "0.0.0.0:7946"),
+                ("LOGPOSE_SERVER", "http://localhost:3000"),
+                ("LOGPOSE_SECRET", "hunter2"),
+            ]),
+        )
+        .unwrap();
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn env_rejects_malformed_values() {
+        let mut table = toml::Table::new();
+        let err = apply_env(&mut table, env(&[("LOGPOSE_HEALTH_INTERVAL_SECS", "soon")])).unwrap_err();
+        assert!(matches!(err, ConfigError::Env { var, .. } if var == "LOGPOSE_HEALTH_INTERVAL_SECS"));
+    }
+}
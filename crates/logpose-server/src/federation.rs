@@ -0,0 +1,526 @@
+//! Federation between the LogPose servers of several datacenters.
+//!
+//! Each server knows its peers from `federation.peers`. Discovery with
+//! `?dc=<name>` asks that datacenter's server for the instances of a service
+//! over `/federation/discover/...`, authenticated by the shared
+//! `federation.secret`. `?dc=any` answers from the local datacenter while it
+//! has a healthy instance, and otherwise fails over to the first peer, in
+//! configured order, that has one.
+//!
+//! Peer answers are cached for `federation.cache_ttl_secs`. After that they
+//! are still served, marked stale, for up to `federation.max_stale_secs`
+//! while a refresh runs in the background. A peer that fails to answer is
+//! not asked again for `cache_ttl_secs`, so an unreachable datacenter costs
+//! one `federation.timeout_ms` rather than slowing down every query.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use logpose_core::{HealthStatus, RegistryStore, ServiceInstance};
+use logpose_db::DbRegistry;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+use crate::config::{FederationConfig, PeerConfig};
+
+/// Header carrying `federation.secret` on requests between datacenters.
+pub const SECRET_HEADER: &str = "x-logpose-federation-secret";
+
+#[derive(Debug, thiserror::Error)]
+pub enum FederationError {
+    #[error("unknown datacenter `{0}`")]
+    UnknownDatacenter(String),
+    #[error("datacenter `{0}` is unreachable")]
+    Unreachable(String),
+}
+
+/// How current a peer's answer is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// Fetched within `cache_ttl_secs`
+    Fresh,
+    /// Older, served while a refresh runs or the peer is unreachable
+    Stale,
+}
+
+impl Freshness {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Freshness::Fresh => "fresh",
+            Freshness::Stale => "stale",
+        }
+    }
+}
+
+/// Instances of a service in a peer datacenter.
+pub struct Answer {
+    pub datacenter: String,
+    pub instances: Vec<ServiceInstance>,
+    /// Time since the peer was asked
+    pub age: Duration,
+    pub freshness: Freshness,
+}
+
+impl Answer {
+    fn has_healthy(&self) -> bool {
+        self.instances.iter().any(|i| i.health == HealthStatus::Healthy)
+    }
+}
+
+/// What a server answers its peers.
+#[derive(Serialize, Deserialize)]
+struct PeerAnswer {
+    datacenter: String,
+    instances: Vec<ServiceInstance>,
+}
+
+/// Reachability of a peer datacenter, as last seen by this server.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct PeerStatus {
+    pub name: String,
+    pub url: String,
+    /// Unix ms of the last successful request
+    pub last_success: Option<u64>,
+    /// Error of the last request, unless it succeeded
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FederationStatus {
+    pub datacenter: String,
+    pub peers: Vec<PeerStatus>,
+}
+
+struct Cached {
+    instances: Vec<ServiceInstance>,
+    fetched: Instant,
+    refreshing: bool,
+}
+
+#[derive(Default)]
+struct Health {
+    last_success: Option<u64>,
+    last_error: Option<String>,
+    /// Requests to the peer are skipped until then
+    down_until: Option<Instant>,
+}
+
+/// (datacenter, namespace, service code)
+type Key = (String, String, String);
+
+pub struct Federation {
+    config: FederationConfig,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<Key, Cached>>,
+    health: Mutex<HashMap<String, Health>>,
+}
+
+impl Federation {
+    pub fn new(config: FederationConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .expect("HTTP client configuration is valid");
+        Self { config, client, cache: Mutex::new(HashMap::new()), health: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn datacenter(&self) -> &str {
+        &self.config.datacenter
+    }
+
+    pub fn status(&self) -> FederationStatus {
+        let health = self.health.lock().unwrap();
+        let peers = self
+            .config
+            .peers
+            .iter()
+            .map(|peer| {
+                let health = health.get(&peer.name);
+                PeerStatus {
+                    name: peer.name.clone(),
+                    url: peer.url.clone(),
+                    last_success: health.and_then(|h| h.last_success),
+                    last_error: health.and_then(|h| h.last_error.clone()),
+                }
+            })
+            .collect();
+        FederationStatus { datacenter: self.config.datacenter.clone(), peers }
+    }
+
+    /// Instances of `code` in the peer datacenter `dc`.
+    pub async fn discover(self: &Arc<Self>, dc: &str, namespace: &str, code: &str) -> Result<Answer, FederationError> {
+        let peer = self
+            .config
+            .peers
+            .iter()
+            .find(|peer| peer.name == dc)
+            .ok_or_else(|| FederationError::UnknownDatacenter(dc.to_string()))?;
+        let key = (dc.to_string(), namespace.to_string(), code.to_string());
+        let ttl = Duration::from_secs(self.config.cache_ttl_secs);
+        let max_stale = Duration::from_secs(self.config.max_stale_secs);
+
+        let down = self.is_down(dc);
+
+        if let Some(cached) = self.cache.lock().unwrap().get_mut(&key) {
+            let age = cached.fetched.elapsed();
+            if age < ttl {
+                return Ok(self.answer(dc, cached, Freshness::Fresh));
+            }
+            if age < max_stale {
+                if !cached.refreshing && !down {
+                    cached.refreshing = true;
+                    let federation = self.clone();
+                    let (peer, key) = (peer.clone(), key.clone());
+                    tokio::spawn(async move {
+                        let _ = federation.fetch(&peer, key).await;
+                    });
+                }
+                return Ok(self.answer(dc, cached, Freshness::Stale));
+            }
+        }
+
+        if down {
+            return Err(FederationError::Unreachable(dc.to_string()));
+        }
+        let instances = self.fetch(peer, key).await?;
+        Ok(Answer { datacenter: dc.to_string(), instances, age: Duration::ZERO, freshness: Freshness::Fresh })
+    }
+
+    /// The first peer datacenter, in configured order, with a healthy
+    /// instance of `code`. Peers are asked concurrently.
+    pub async fn failover(self: &Arc<Self>, namespace: &str, code: &str) -> Option<Answer> {
+        let lookups: Vec<_> = self
+            .config
+            .peers
+            .iter()
+            .map(|peer| {
+                let federation = self.clone();
+                let (dc, namespace, code) = (peer.name.clone(), namespace.to_string(), code.to_string());
+                tokio::spawn(async move { federation.discover(&dc, &namespace, &code).await })
+            })
+            .collect();
+        for lookup in lookups {
+            if let Ok(Ok(answer)) = lookup.await
+                && answer.has_healthy()
+            {
+                return Some(answer);
+            }
+        }
+        None
+    }
+
+    fn answer(&self, dc: &str, cached: &Cached, freshness: Freshness) -> Answer {
+        Answer {
+            datacenter: dc.to_string(),
+            instances: cached.instances.clone(),
+            age: cached.fetched.elapsed(),
+            freshness,
+        }
+    }
+
+    fn is_down(&self, dc: &str) -> bool {
+        let health = self.health.lock().unwrap();
+        health.get(dc).and_then(|h| h.down_until).is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Asks `peer` and caches its answer under `key`.
+    async fn fetch(&self, peer: &PeerConfig, key: Key) -> Result<Vec<ServiceInstance>, FederationError> {
+        let (_, namespace, code) = &key;
+        let url = format!("{}/federation/discover/{}/{}", peer.url.trim_end_matches('/'), namespace, code);
+        let result = async {
+            let response = self
+                .client
+                .get(&url)
+                .header(SECRET_HEADER, &self.config.secret)
+                .send()
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("peer answered {}", response.status()));
+            }
+            let answer: PeerAnswer = response.json().await.map_err(|e| e.to_string())?;
+            if answer.datacenter != peer.name {
+                return Err(format!("peer is datacenter `{}`", answer.datacenter));
+            }
+            Ok(answer.instances)
+        }
+        .await;
+
+        match result {
+            Ok(instances) => {
+                let mut health = self.health.lock().unwrap();
+                let health = health.entry(peer.name.clone()).or_default();
+                health.last_success = Some(logpose_core::time::now());
+                health.last_error = None;
+                health.down_until = None;
+                let cached = Cached { instances: instances.clone(), fetched: Instant::now(), refreshing: false };
+                self.cache.lock().unwrap().insert(key, cached);
+                Ok(instances)
+            }
+            Err(e) => {
+                tracing::warn!(datacenter = %peer.name, "federated discovery failed: {}", e);
+                let mut health = self.health.lock().unwrap();
+                let health = health.entry(peer.name.clone()).or_default();
+                health.last_error = Some(e);
+                health.down_until = Some(Instant::now() + Duration::from_secs(self.config.cache_ttl_secs));
+                if let Some(cached) = self.cache.lock().unwrap().get_mut(&key) {
+                    cached.refreshing = false;
+                }
+                Err(FederationError::Unreachable(peer.name.clone()))
+            }
+        }
+    }
+}
+
+/// Routes peers call, authenticated by `federation.secret` rather than a
+/// token.
+pub fn routes(federation: Arc<Federation>, registry: Arc<DbRegistry>) -> Router {
+    Router::new()
+        .route("/federation/discover/:namespace/:code", get(discover))
+        .layer(middleware::from_fn_with_state(federation.clone(), check_secret))
+        .with_state((federation, registry))
+}
+
+async fn check_secret<B>(State(federation): State<Arc<Federation>>, req: Request<B>, next: Next<B>) -> Response {
+    if !secret_matches(&federation, req.headers()) {
+        return (StatusCode::UNAUTHORIZED, "Invalid federation secret").into_response();
+    }
+    next.run(req).await
+}
+
+fn secret_matches(federation: &Federation, headers: &HeaderMap) -> bool {
+    headers
+        .get(SECRET_HEADER)
+        .is_some_and(|v| bool::from(v.as_bytes().ct_eq(federation.config.secret.as_bytes())))
+}
+
+async fn discover(
+    State((federation, registry)): State<(Arc<Federation>, Arc<DbRegistry>)>,
+    Path((namespace, code)): Path<(String, String)>,
+) -> Response {
+    match registry.get_instances(&namespace, &code) {
+        Ok(instances) => {
+            let answer = PeerAnswer { datacenter: federation.config.datacenter.clone(), instances };
+            (StatusCode::OK, Json(answer)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use logpose_core::{Protocol, Runtime, Service};
+
+    use super::*;
+
+    /// A peer datacenter served on a loopback port by [`routes`]; counts the
+    /// requests it receives and answers 503 while `up` is false.
+    struct Peer {
+        url: String,
+        registry: Arc<DbRegistry>,
+        up: Arc<AtomicBool>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    type PeerState = (Arc<AtomicBool>, Arc<AtomicUsize>);
+
+    async fn gate<B>(State((up, requests)): State<PeerState>, req: Request<B>, next: Next<B>) -> Response {
+        requests.fetch_add(1, Ordering::SeqCst);
+        if !up.load(Ordering::SeqCst) {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+        next.run(req).await
+    }
+
+    fn peer(datacenter: &str, secret: &str) -> Peer {
+        let registry = Arc::new(DbRegistry::new(":memory:").unwrap());
+        registry.add_service(&Service::new("billing", "billing", "")).unwrap();
+        let federation = Arc::new(Federation::new(config(datacenter, secret, Vec::new())));
+        let (up, requests) = (Arc::new(AtomicBool::new(true)), Arc::new(AtomicUsize::new(0)));
+        let app = routes(federation, registry.clone())
+            .layer(middleware::from_fn_with_state((up.clone(), requests.clone()), gate));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service());
+        tokio::spawn(server);
+        Peer { url, registry, up, requests }
+    }
+
+    impl Peer {
+        fn add(&self, port: u16, health: HealthStatus) {
+            let runtime = Runtime::Vm { provider: None, id: None };
+            let mut instance =
+                ServiceInstance::new("billing", ([10, 0, 0, 1], port).into(), Protocol::Http, runtime, 0);
+            instance.health = health;
+            self.registry.add_instance(&instance).unwrap();
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    fn config(datacenter: &str, secret: &str, peers: Vec<(&str, &Peer)>) -> FederationConfig {
+        FederationConfig {
+            enabled: true,
+            datacenter: datacenter.to_string(),
+            secret: secret.to_string(),
+            peers: peers
+                .into_iter()
+                .map(|(name, peer)| PeerConfig { name: name.to_string(), url: peer.url.clone() })
+                .collect(),
+            timeout_ms: 1000,
+            cache_ttl_secs: 10,
+            max_stale_secs: 60,
+        }
+    }
+
+    fn federation(peers: Vec<(&str, &Peer)>) -> Arc<Federation> {
+        Arc::new(Federation::new(config("dc1", "s3cret", peers)))
+    }
+
+    /// Moves the time `dc` was last asked about billing `by` into the past.
+    fn age(federation: &Federation, dc: &str, by: Duration) {
+        let key = (dc.to_string(), "default".to_string(), "billing".to_string());
+        federation.cache.lock().unwrap().get_mut(&key).unwrap().fetched -= by;
+    }
+
+    /// Ends the backoff of `dc`, if it is backed off.
+    fn recover(federation: &Federation, dc: &str) {
+        if let Some(health) = federation.health.lock().unwrap().get_mut(dc) {
+            health.down_until = None;
+        }
+    }
+
+    async fn discover(federation: &Arc<Federation>, dc: &str) -> Result<(Freshness, usize), FederationError> {
+        let answer = federation.discover(dc, "default", "billing").await?;
+        assert_eq!(answer.datacenter, dc);
+        Ok((answer.freshness, answer.instances.len()))
+    }
+
+    /// Polls until a background refresh has settled on `expected`.
+    async fn eventually(federation: &Arc<Federation>, dc: &str, expected: (Freshness, usize)) {
+        for _ in 0..100 {
+            if discover(federation, dc).await.ok() == Some(expected) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("{} never answered {:?}", dc, expected);
+    }
+
+    #[tokio::test]
+    async fn answers_are_fresh_then_stale_until_max_stale() {
+        let dc2 = peer("dc2", "s3cret");
+        dc2.add(8080, HealthStatus::Healthy);
+        let federation = federation(vec![("dc2", &dc2)]);
+
+        assert_eq!(discover(&federation, "dc2").await.unwrap(), (Freshness::Fresh, 1));
+        dc2.add(8081, HealthStatus::Healthy);
+        assert_eq!(discover(&federation, "dc2").await.unwrap(), (Freshness::Fresh, 1));
+        assert_eq!(dc2.requests(), 1);
+
+        // Past the TTL the cached answer is served while one refresh runs.
+        age(&federation, "dc2", Duration::from_secs(11));
+        assert_eq!(discover(&federation, "dc2").await.unwrap(), (Freshness::Stale, 1));
+        eventually(&federation, "dc2", (Freshness::Fresh, 2)).await;
+        assert_eq!(dc2.requests(), 2);
+
+        // Past max_stale the peer is asked before answering.
+        dc2.add(8082, HealthStatus::Healthy);
+        age(&federation, "dc2", Duration::from_secs(61));
+        assert_eq!(discover(&federation, "dc2").await.unwrap(), (Freshness::Fresh, 3));
+        assert_eq!(dc2.requests(), 3);
+
+        assert!(matches!(discover(&federation, "dc9").await, Err(FederationError::UnknownDatacenter(_))));
+    }
+
+    #[tokio::test]
+    async fn failing_peers_are_backed_off_and_served_stale() {
+        let dc2 = peer("dc2", "s3cret");
+        dc2.add(8080, HealthStatus::Healthy);
+        let federation = federation(vec![("dc2", &dc2)]);
+        discover(&federation, "dc2").await.unwrap();
+
+        dc2.up.store(false, Ordering::SeqCst);
+        age(&federation, "dc2", Duration::from_secs(11));
+        assert_eq!(discover(&federation, "dc2").await.unwrap(), (Freshness::Stale, 1));
+        for _ in 0..100 {
+            if federation.is_down("dc2") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let status = federation.status();
+        assert!(status.peers[0].last_error.as_deref().unwrap().contains("503"));
+        assert!(status.peers[0].last_success.is_some());
+
+        // While backed off the peer is not asked, even once it is up again.
+        dc2.up.store(true, Ordering::SeqCst);
+        assert_eq!(discover(&federation, "dc2").await.unwrap(), (Freshness::Stale, 1));
+        assert_eq!(dc2.requests(), 2);
+        age(&federation, "dc2", Duration::from_secs(61));
+        assert!(matches!(discover(&federation, "dc2").await, Err(FederationError::Unreachable(_))));
+        assert_eq!(dc2.requests(), 2);
+
+        recover(&federation, "dc2");
+        assert_eq!(discover(&federation, "dc2").await.unwrap(), (Freshness::Fresh, 1));
+        assert_eq!(federation.status().peers[0].last_error, None);
+    }
+
+    #[tokio::test]
+    async fn peers_need_the_shared_secret() {
+        let dc2 = peer("dc2", "s3cret");
+        dc2.add(8080, HealthStatus::Healthy);
+        let federation = Arc::new(Federation::new(config("dc1", "guess", vec![("dc2", &dc2)])));
+
+        assert!(matches!(discover(&federation, "dc2").await, Err(FederationError::Unreachable(_))));
+        assert!(federation.status().peers[0].last_error.as_deref().unwrap().contains("401"));
+
+        let url = format!("{}/federation/discover/default/billing", dc2.url);
+        let unauthenticated = reqwest::get(&url).await.unwrap();
+        assert_eq!(unauthenticated.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let authenticated = reqwest::Client::new().get(&url).header(SECRET_HEADER, "s3cret").send().await.unwrap();
+        assert_eq!(authenticated.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn peers_answering_as_another_datacenter_are_refused() {
+        let dc2 = peer("dc3", "s3cret");
+        let federation = federation(vec![("dc2", &dc2)]);
+        assert!(matches!(discover(&federation, "dc2").await, Err(FederationError::Unreachable(_))));
+        assert!(federation.status().peers[0].last_error.as_deref().unwrap().contains("dc3"));
+    }
+
+    #[tokio::test]
+    async fn failover_takes_the_first_peer_with_a_healthy_instance() {
+        let (dc2, dc3, dc4) = (peer("dc2", "s3cret"), peer("dc3", "s3cret"), peer("dc4", "s3cret"));
+        dc2.up.store(false, Ordering::SeqCst);
+        dc3.add(8080, HealthStatus::Unhealthy);
+        dc4.add(8080, HealthStatus::Healthy);
+        let unhealthy_only = federation(vec![("dc3", &dc3)]);
+        let federation = federation(vec![("dc2", &dc2), ("dc3", &dc3), ("dc4", &dc4)]);
+
+        let answer = federation.failover("default", "billing").await.unwrap();
+        assert_eq!(answer.datacenter, "dc4");
+
+        dc2.up.store(true, Ordering::SeqCst);
+        dc2.add(8080, HealthStatus::Healthy);
+        recover(&federation, "dc2");
+        assert_eq!(federation.failover("default", "billing").await.unwrap().datacenter, "dc2");
+        assert!(unhealthy_only.failover("default", "billing").await.is_none());
+    }
+}
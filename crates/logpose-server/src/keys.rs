@@ -0,0 +1,404 @@
+//! JWT signing keys.
+//!
+//! With HS256 every token is signed with `auth.jwt_secret`. With RS256 or
+//! EdDSA the server generates its own key pairs, stores them in the registry
+//! and identifies each by a `kid` header. The newest key signs; it is
+//! replaced every `auth.key_rotation_days`, and a replaced key stays in the
+//! JWKS until every token it signed has expired.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters,
+    OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{errors::Error, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use logpose_core::{RegistryError, RegistryStore, SigningKey};
+use rand::rngs::OsRng;
+use rsa::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+use rsa::traits::PublicKeyParts;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::cluster::Registry;
+use crate::config::{AuthConfig, SigningAlgorithm};
+use crate::tokens::now;
+
+const RSA_BITS: usize = 2048;
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+/// How often a clustered node reloads keys the leader may have replicated.
+const CLUSTER_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("failed to access signing keys: {0}")]
+    Registry(#[from] RegistryError),
+
+    #[error("invalid signing key {kid}: {message}")]
+    Invalid { kid: String, message: String },
+
+    #[error("failed to generate signing key: {0}")]
+    Generate(String),
+}
+
+struct VerifyingKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// The keys in use at one point in time.
+pub struct KeyRing {
+    algorithm: Algorithm,
+    signing_kid: Option<String>,
+    encoding: Option<EncodingKey>,
+    verifying: HashMap<Option<String>, VerifyingKey>,
+    jwks: JwkSet,
+}
+
+impl KeyRing {
+    fn hmac(secret: &str) -> Self {
+        let verifying = VerifyingKey {
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret.as_bytes()),
+        };
+        Self {
+            algorithm: Algorithm::HS256,
+            signing_kid: None,
+            encoding: Some(EncodingKey::from_secret(secret.as_bytes())),
+            verifying: HashMap::from([(None, verifying)]),
+            jwks: JwkSet { keys: Vec::new() },
+        }
+    }
+
+    /// Builds a ring from stored keys (oldest first); the last one signs.
+    /// Without keys, as on a cluster node that has not caught up with its
+    /// leader yet, nothing can be signed or verified.
+    fn from_stored(algorithm: Algorithm, keys: &[SigningKey]) -> Result<Self, KeyError> {
+        let mut verifying = HashMap::new();
+        let mut jwks = Vec::new();
+        let mut signing = None;
+        for stored in keys {
+            let (algorithm, encoding, decoding, jwk) = load(stored)?;
+            verifying.insert(Some(stored.kid.clone()), VerifyingKey { algorithm, key: decoding });
+            jwks.push(jwk);
+            signing = Some((algorithm, stored.kid.clone(), encoding));
+        }
+        let (algorithm, signing_kid, encoding) = match signing {
+            Some((algorithm, kid, encoding)) => (algorithm, Some(kid), Some(encoding)),
+            None => (algorithm, None, None),
+        };
+        Ok(Self {
+            algorithm,
+            signing_kid,
+            encoding,
+            verifying,
+            jwks: JwkSet { keys: jwks },
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.signing_kid.clone();
+        let encoding = self
+            .encoding
+            .as_ref()
+            .ok_or_else(|| Error::from(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat))?;
+        jsonwebtoken::encode(&header, claims, encoding)
+    }
+
+    /// Verifies a token against the key named by its `kid`. Expired tokens
+    /// are accepted when `validate_exp` is false.
+    pub fn decode<T: DeserializeOwned>(&self, token: &str, validate_exp: bool) -> Result<T, Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let kid = if self.signing_kid.is_some() { header.kid } else { None };
+        let key = self
+            .verifying
+            .get(&kid)
+            .ok_or_else(|| Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken))?;
+        let mut validation = Validation::new(key.algorithm);
+        validation.validate_exp = validate_exp;
+        jsonwebtoken::decode::<T>(token, &key.key, &validation).map(|data| data.claims)
+    }
+
+    /// Public keys for `/.well-known/jwks.json`; empty with HS256.
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+/// Shared handle to the current key ring, swapped on rotation.
+pub struct Keys {
+    ring: RwLock<Arc<KeyRing>>,
+}
+
+impl Keys {
+    pub fn current(&self) -> Arc<KeyRing> {
+        self.ring.read().unwrap().clone()
+    }
+}
+
+/// Loads the signing keys, generating the first one (or a due replacement)
+/// when an asymmetric algorithm is configured.
+pub fn init(auth: &AuthConfig, registry: &dyn RegistryStore) -> Result<Arc<Keys>, KeyError> {
+    if auth.algorithm != SigningAlgorithm::HS256 {
+        rotate_if_due(auth, registry)?;
+    }
+    load_stored(auth, registry)
+}
+
+/// Loads the signing keys as stored, without generating any.
+pub fn load_stored(auth: &AuthConfig, registry: &dyn RegistryStore) -> Result<Arc<Keys>, KeyError> {
+    let ring = match auth.algorithm {
+        SigningAlgorithm::HS256 => KeyRing::hmac(&auth.jwt_secret),
+        algorithm => KeyRing::from_stored(jwt_algorithm(algorithm), &registry.get_signing_keys()?)?,
+    };
+    Ok(Arc::new(Keys { ring: RwLock::new(Arc::new(ring)) }))
+}
+
+/// Periodically rotates asymmetric keys and prunes retired ones. In a
+/// cluster only the leader rotates; every node reloads often enough to pick
+/// up replicated keys soon after they are made.
+pub fn spawn_rotation(keys: Arc<Keys>, registry: Arc<Registry>, auth: AuthConfig) {
+    if auth.algorithm == SigningAlgorithm::HS256 {
+        return;
+    }
+    let period = if registry.is_clustered() { CLUSTER_RELOAD_INTERVAL } else { ROTATION_CHECK_INTERVAL };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let rotated = if registry.is_leader() { rotate_if_due(&auth, registry.as_ref()) } else { Ok(()) };
+            let reloaded = rotated.and_then(|_| {
+                KeyRing::from_stored(jwt_algorithm(auth.algorithm), &registry.get_signing_keys()?)
+            });
+            match reloaded {
+                Ok(ring) => *keys.ring.write().unwrap() = Arc::new(ring),
+                Err(e) => tracing::error!("signing key rotation failed: {}", e),
+            }
+        }
+    });
+}
+
+fn rotate_if_due(auth: &AuthConfig, registry: &dyn RegistryStore) -> Result<(), KeyError> {
+    let algorithm = jwt_algorithm(auth.algorithm);
+    let now = now() as i64;
+    let mut keys = registry.get_signing_keys()?;
+
+    let rotation = (auth.key_rotation_days * 86400) as i64;
+    let current = keys.last().filter(|k| k.algorithm == format!("{:?}", algorithm));
+    if current.is_none_or(|k| now - k.created_at >= rotation) {
+        let key = generate(algorithm, now)?;
+        tracing::info!(kid = %key.kid, "generated new {:?} signing key", algorithm);
+        registry.add_signing_key(&key)?;
+        keys.push(key);
+    }
+
+    // A key stops signing when the next one is created; once every token it
+    // signed has expired it can be dropped.
+    let max_token_lifetime = auth.refresh_token_ttl_secs.max(auth.access_token_ttl_secs) as i64;
+    for pair in keys.windows(2) {
+        let (retired, successor) = (&pair[0], &pair[1]);
+        if now - successor.created_at > max_token_lifetime {
+            tracing::info!(kid = %retired.kid, "removing retired signing key");
+            registry.remove_signing_key(&retired.kid)?;
+        }
+    }
+    Ok(())
+}
+
+fn jwt_algorithm(algorithm: SigningAlgorithm) -> Algorithm {
+    match algorithm {
+        SigningAlgorithm::HS256 => Algorithm::HS256,
+        SigningAlgorithm::RS256 => Algorithm::RS256,
+        SigningAlgorithm::EdDSA => Algorithm::EdDSA,
+    }
+}
+
+fn generate(algorithm: Algorithm, created_at: i64) -> Result<SigningKey, KeyError> {
+    let pem = match algorithm {
+        Algorithm::RS256 => rsa::RsaPrivateKey::new(&mut OsRng, RSA_BITS)
+            .map_err(|e| KeyError::Generate(e.to_string()))?
+            .to_pkcs8_pem(Default::default())
+            .map_err(|e| KeyError::Generate(e.to_string()))?,
+        Algorithm::EdDSA => ed25519_dalek::SigningKey::generate(&mut OsRng)
+            .to_pkcs8_pem(Default::default())
+            .map_err(|e| KeyError::Generate(e.to_string()))?,
+        other => return Err(KeyError::Generate(format!("{:?} keys are not generated", other))),
+    };
+    Ok(SigningKey {
+        kid: uuid::Uuid::new_v4().to_string(),
+        algorithm: format!("{:?}", algorithm),
+        private_key: pem.to_string(),
+        created_at,
+    })
+}
+
+fn load(stored: &SigningKey) -> Result<(Algorithm, EncodingKey, DecodingKey, Jwk), KeyError> {
+    let invalid = |message: String| KeyError::Invalid { kid: stored.kid.clone(), message };
+    let pem = stored.private_key.as_bytes();
+
+    let (algorithm, key_algorithm, params) = match stored.algorithm.as_str() {
+        "RS256" => {
+            let key = rsa::RsaPrivateKey::from_pkcs8_pem(&stored.private_key).map_err(|e| invalid(e.to_string()))?;
+            let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+                e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+            });
+            (Algorithm::RS256, KeyAlgorithm::RS256, params)
+        }
+        "EdDSA" => {
+            let key = ed25519_dalek::SigningKey::from_pkcs8_pem(&stored.private_key)
+                .map_err(|e| invalid(e.to_string()))?;
+            let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(key.verifying_key().to_bytes()),
+            });
+            (Algorithm::EdDSA, KeyAlgorithm::EdDSA, params)
+        }
+        other => return Err(invalid(format!("unsupported algorithm {}", other))),
+    };
+
+    let encoding = match algorithm {
+        Algorithm::RS256 => EncodingKey::from_rsa_pem(pem),
+        _ => EncodingKey::from_ed_pem(pem),
+    }
+    .map_err(|e| invalid(e.to_string()))?;
+
+    let jwk = Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(stored.kid.clone()),
+            ..Default::default()
+        },
+        algorithm: params,
+    };
+    let decoding = DecodingKey::from_jwk(&jwk).map_err(|e| invalid(e.to_string()))?;
+    Ok((algorithm, encoding, decoding, jwk))
+}
+
+#[cfg(test)]
+mod tests {
+    use logpose_db::DbRegistry;
+    use serde::Deserialize;
+
+    use super::*;
+
+    const DAY: i64 = 86400;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestClaims {
+        sub: String,
+        exp: usize,
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims { sub: "ci".to_string(), exp: now() + 60 }
+    }
+
+    fn auth(algorithm: SigningAlgorithm) -> AuthConfig {
+        AuthConfig { algorithm, ..Default::default() }
+    }
+
+    /// Signs with a fresh `algorithm` key and checks that only its own ring
+    /// verifies the token; returns the ring.
+    fn signs_and_verifies_by_kid(algorithm: Algorithm) -> KeyRing {
+        let key = generate(algorithm, 0).unwrap();
+        let ring = KeyRing::from_stored(algorithm, std::slice::from_ref(&key)).unwrap();
+        let token = ring.encode(&claims()).unwrap();
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        assert_eq!((header.alg, header.kid.as_deref()), (algorithm, Some(key.kid.as_str())));
+        assert_eq!(ring.decode::<TestClaims>(&token, true).unwrap(), claims());
+
+        // Another key of the same algorithm neither knows the kid nor
+        // verifies the signature under it.
+        let mut other = generate(algorithm, 0).unwrap();
+        let stranger = KeyRing::from_stored(algorithm, std::slice::from_ref(&other)).unwrap();
+        assert!(stranger.decode::<TestClaims>(&token, true).is_err());
+        other.kid = key.kid.clone();
+        let impostor = KeyRing::from_stored(algorithm, &[other]).unwrap();
+        assert!(impostor.decode::<TestClaims>(&token, true).is_err());
+        ring
+    }
+
+    #[test]
+    fn rs256_tokens_are_signed_and_verified_by_kid() {
+        let ring = signs_and_verifies_by_kid(Algorithm::RS256);
+        let jwks = serde_json::to_value(ring.jwks()).unwrap();
+        let key = &jwks["keys"][0];
+        assert_eq!((&key["kty"], &key["alg"], &key["e"]), (&"RSA".into(), &"RS256".into(), &"AQAB".into()));
+        assert_eq!(URL_SAFE_NO_PAD.decode(key["n"].as_str().unwrap()).unwrap().len(), RSA_BITS / 8);
+    }
+
+    #[test]
+    fn eddsa_tokens_are_signed_and_verified_by_kid() {
+        signs_and_verifies_by_kid(Algorithm::EdDSA);
+    }
+
+    #[test]
+    fn tokens_without_a_known_kid_are_rejected() {
+        let ring = KeyRing::from_stored(Algorithm::EdDSA, &[generate(Algorithm::EdDSA, 0).unwrap()]).unwrap();
+        let hmac = KeyRing::hmac("secret");
+        assert!(ring.decode::<TestClaims>(&hmac.encode(&claims()).unwrap(), true).is_err());
+        assert!(KeyRing::from_stored(Algorithm::EdDSA, &[]).unwrap().encode(&claims()).is_err());
+    }
+
+    #[test]
+    fn rotation_keeps_the_previous_key_verifiable() {
+        let registry = DbRegistry::new(":memory:").unwrap();
+        let auth = auth(SigningAlgorithm::EdDSA);
+        let old = generate(Algorithm::EdDSA, now() as i64 - 31 * DAY).unwrap();
+        registry.add_signing_key(&old).unwrap();
+        let before = init(&auth, &registry).unwrap().current();
+        let token = KeyRing::from_stored(Algorithm::EdDSA, std::slice::from_ref(&old)).unwrap().encode(&claims()).unwrap();
+
+        let keys = registry.get_signing_keys().unwrap();
+        assert_eq!(keys.len(), 2, "a key older than key_rotation_days is replaced");
+        assert_eq!(keys[0].kid, old.kid);
+        let signed = jsonwebtoken::decode_header(&before.encode(&claims()).unwrap()).unwrap();
+        assert_eq!(signed.kid, Some(keys[1].kid.clone()));
+        assert_eq!(before.decode::<TestClaims>(&token, true).unwrap(), claims());
+
+        // Not due again until the new key is as old.
+        rotate_if_due(&auth, &registry).unwrap();
+        assert_eq!(registry.get_signing_keys().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn retired_keys_are_dropped_once_their_tokens_have_expired() {
+        let registry = DbRegistry::new(":memory:").unwrap();
+        let now = now() as i64;
+        let retired = generate(Algorithm::EdDSA, now - 40 * DAY).unwrap();
+        let previous = generate(Algorithm::EdDSA, now - 31 * DAY).unwrap();
+        registry.add_signing_key(&retired).unwrap();
+        registry.add_signing_key(&previous).unwrap();
+
+        rotate_if_due(&auth(SigningAlgorithm::EdDSA), &registry).unwrap();
+        let kids: Vec<String> = registry.get_signing_keys().unwrap().into_iter().map(|k| k.kid).collect();
+        assert_eq!(kids.len(), 2);
+        assert!(!kids.contains(&retired.kid), "replaced longer ago than a refresh token lives");
+        assert_eq!(kids[0], previous.kid, "replaced just now, so its tokens are still live");
+    }
+
+    #[test]
+    fn jwks_lists_public_keys_by_kid() {
+        let ed = generate(Algorithm::EdDSA, 0).unwrap();
+        let ring = KeyRing::from_stored(Algorithm::EdDSA, std::slice::from_ref(&ed)).unwrap();
+        let jwks = serde_json::to_value(ring.jwks()).unwrap();
+        let key = &jwks["keys"][0];
+        assert_eq!(jwks["keys"].as_array().unwrap().len(), 1);
+        assert_eq!((&key["kid"], &key["kty"], &key["crv"]), (&ed.kid.clone().into(), &"OKP".into(), &"Ed25519".into()));
+        assert_eq!((&key["use"], &key["alg"]), (&"sig".into(), &"EdDSA".into()));
+        let x = URL_SAFE_NO_PAD.decode(key["x"].as_str().unwrap()).unwrap();
+        assert_eq!(x.len(), 32);
+        assert!(key.get("d").is_none(), "no private part");
+
+        assert!(KeyRing::hmac("secret").jwks().keys.is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+//! Blocking reads of the key/value store.
+//!
+//! A read with `index` set to the `x-logpose-index` of an earlier response
+//! returns at once if the store has been written since, and otherwise waits
+//! up to `wait` seconds for a write to the key, or below the prefix of a
+//! recursive read. The index covers the whole store, so a read may return
+//! without its own keys having changed; clients compare `modify_index`.
+
+use std::time::Duration;
+
+use logpose_core::RegistryEvent;
+use tokio::sync::broadcast;
+
+/// Response header with the index of the latest key/value write.
+pub const INDEX_HEADER: &str = "x-logpose-index";
+/// Seconds a blocking read waits when no `wait` is given, and the longest
+/// it may ask for.
+pub const DEFAULT_WAIT_SECS: u64 = 60;
+pub const MAX_WAIT_SECS: u64 = 300;
+
+/// Whether `event` may have changed `key` in `namespace`, or the keys below
+/// it when `recurse`.
+fn touches(event: &RegistryEvent, namespace: &str, key: &str, recurse: bool) -> bool {
+    match event {
+        RegistryEvent::KeyChanged { namespace: changed_namespace, key: changed } => {
+            // A changed key may itself be the prefix of a recursive delete.
+            changed_namespace == namespace
+                && (key.starts_with(changed.as_str()) || (recurse && changed.starts_with(key)))
+        }
+        // The session may have held the key as a lock.
+        RegistryEvent::SessionDestroyed { namespace: ended_namespace, .. } => ended_namespace == namespace,
+        RegistryEvent::Replaced => true,
+        _ => false,
+    }
+}
+
+/// Waits until an event that [`touches`] the key arrives or `wait` passes.
+/// `events` must be subscribed before the caller read the current index, so
+/// that no write in between is missed.
+pub async fn wait_for_change(
+    mut events: broadcast::Receiver<RegistryEvent>,
+    namespace: &str,
+    key: &str,
+    recurse: bool,
+    wait: Duration,
+) {
+    let changed = async {
+        loop {
+            match events.recv().await {
+                Ok(event) if touches(&event, namespace, key, recurse) => return,
+                Ok(_) => {}
+                // Missed events may have touched the key.
+                Err(broadcast::error::RecvError::Lagged(_)) => return,
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    };
+    let _ = tokio::time::timeout(wait, changed).await;
+}
@@ -0,0 +1,232 @@
+//! Per-identity rate limits and registration quotas.
+//!
+//! Every identity has a token bucket per route class: reads (`GET`) and
+//! writes (everything else). A bucket holds up to `burst` requests and
+//! refills at `per_sec`; a request finding it empty is refused with
+//! `429 Too Many Requests` and a `Retry-After` of the time until the next
+//! token. Quotas on instances per service and services per namespace are
+//! checked by the registration handlers and refused the same way.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use logpose_core::Claims;
+
+use crate::config::LimitsConfig;
+use crate::{stats, AppState};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Read,
+    Write,
+}
+
+impl RouteClass {
+    fn of(method: &Method) -> Self {
+        if method == Method::GET { Self::Read } else { Self::Write }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Quota {
+    InstancesPerService,
+    ServicesPerNamespace,
+}
+
+impl Quota {
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::InstancesPerService => "instances_per_service",
+            Self::ServicesPerNamespace => "services_per_namespace",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct Limits {
+    config: LimitsConfig,
+    buckets: Mutex<HashMap<(String, RouteClass), Bucket>>,
+    registrations: tokio::sync::Mutex<()>,
+}
+
+impl Limits {
+    pub fn new(config: LimitsConfig) -> Self {
+        Self { config, buckets: Mutex::new(HashMap::new()), registrations: tokio::sync::Mutex::new(()) }
+    }
+
+    /// Takes one request from the bucket of `identity`, or returns how long
+    /// until the bucket holds one again.
+    fn acquire(&self, identity: &str, class: RouteClass) -> Result<(), Duration> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let (per_sec, burst) = match class {
+            RouteClass::Read => (self.config.read_per_sec, self.config.read_burst),
+            RouteClass::Write => (self.config.write_per_sec, self.config.write_burst),
+        };
+        let (per_sec, burst) = (per_sec as f64, burst as f64);
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry((identity.to_string(), class))
+            .or_insert(Bucket { tokens: burst, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+
+    /// Held from a quota check until the registration it admitted is stored,
+    /// so concurrent registrations cannot overshoot a quota together. In a
+    /// cluster storing waits for replication, so waiters yield rather than
+    /// block a worker thread.
+    pub async fn registration_lock(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.registrations.lock().await
+    }
+
+    /// Refuses a registration when `used` entries already fill `quota`.
+    pub fn check_quota(&self, quota: Quota, used: usize) -> Result<(), QuotaExceeded> {
+        let max = match quota {
+            Quota::InstancesPerService => self.config.max_instances_per_service,
+            Quota::ServicesPerNamespace => self.config.max_services_per_namespace,
+        };
+        if !self.config.enabled || max == 0 || (used as u64) < max {
+            return Ok(());
+        }
+        stats::record_quota_rejection(quota);
+        Err(QuotaExceeded { quota, max, retry_after: Duration::from_secs(self.config.quota_retry_after_secs) })
+    }
+}
+
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    quota: Quota,
+    max: u64,
+    retry_after: Duration,
+}
+
+impl IntoResponse for QuotaExceeded {
+    fn into_response(self) -> Response {
+        let message = match self.quota {
+            Quota::InstancesPerService => format!("Quota exceeded: a service may have at most {} instances", self.max),
+            Quota::ServicesPerNamespace => format!("Quota exceeded: a namespace may have at most {} services", self.max),
+        };
+        too_many_requests(self.retry_after, message)
+    }
+}
+
+fn too_many_requests(retry_after: Duration, message: String) -> Response {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, secs.max(1).to_string())], message).into_response()
+}
+
+/// Applies the rate limits to authenticated requests; runs after
+/// `auth_middleware` has identified the caller.
+pub async fn rate_limit<B>(State(state): State<AppState>, req: Request<B>, next: Next<B>) -> Response {
+    let Some(claims) = req.extensions().get::<Claims>() else {
+        return next.run(req).await;
+    };
+    let class = RouteClass::of(req.method());
+    if let Err(wait) = state.limits.acquire(&claims.sub, class) {
+        tracing::debug!(identity = %claims.sub, class = class.label(), "rate limit exceeded");
+        stats::record_rate_limited(&claims.sub, class);
+        return too_many_requests(wait, "Rate limit exceeded".to_string());
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits::new(LimitsConfig { write_per_sec: 2, write_burst: 3, read_per_sec: 1, read_burst: 1, ..Default::default() })
+    }
+
+    /// Moves the bucket's last refill `by` into the past.
+    fn age(limits: &Limits, identity: &str, class: RouteClass, by: Duration) {
+        let mut buckets = limits.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(&(identity.to_string(), class)).unwrap();
+        bucket.updated -= by;
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refuses() {
+        let limits = limits();
+        for _ in 0..3 {
+            assert!(limits.acquire("alice", RouteClass::Write).is_ok());
+        }
+        let wait = limits.acquire("alice", RouteClass::Write).unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "{:?}", wait);
+    }
+
+    #[test]
+    fn bucket_refills_at_the_sustained_rate_up_to_the_burst() {
+        let limits = limits();
+        for _ in 0..3 {
+            limits.acquire("alice", RouteClass::Write).unwrap();
+        }
+        age(&limits, "alice", RouteClass::Write, Duration::from_secs(1));
+        assert!(limits.acquire("alice", RouteClass::Write).is_ok());
+        assert!(limits.acquire("alice", RouteClass::Write).is_ok());
+        assert!(limits.acquire("alice", RouteClass::Write).is_err());
+
+        age(&limits, "alice", RouteClass::Write, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(limits.acquire("alice", RouteClass::Write).is_ok());
+        }
+        assert!(limits.acquire("alice", RouteClass::Write).is_err());
+    }
+
+    #[test]
+    fn buckets_are_per_identity_and_route_class() {
+        let limits = limits();
+        limits.acquire("alice", RouteClass::Read).unwrap();
+        assert!(limits.acquire("alice", RouteClass::Read).is_err());
+        assert!(limits.acquire("bob", RouteClass::Read).is_ok());
+        assert!(limits.acquire("alice", RouteClass::Write).is_ok());
+    }
+
+    #[test]
+    fn disabled_limits_allow_everything() {
+        let limits = Limits::new(LimitsConfig { enabled: false, read_burst: 1, max_services_per_namespace: 1, ..Default::default() });
+        for _ in 0..10 {
+            assert!(limits.acquire("alice", RouteClass::Read).is_ok());
+        }
+        assert!(limits.check_quota(Quota::ServicesPerNamespace, 5).is_ok());
+    }
+
+    #[test]
+    fn quota_refuses_once_full() {
+        let limits = Limits::new(LimitsConfig { max_instances_per_service: 2, max_services_per_namespace: 0, ..Default::default() });
+        assert!(limits.check_quota(Quota::InstancesPerService, 1).is_ok());
+        let refused = limits.check_quota(Quota::InstancesPerService, 2).unwrap_err().into_response();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers()[header::RETRY_AFTER], "60");
+        assert!(limits.check_quota(Quota::ServicesPerNamespace, 10_000).is_ok(), "0 means no limit");
+    }
+}
@@ -3,49 +3,178 @@ use axum::{
     http::{StatusCode, Request},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
-    Json, Router,
+    routing::{delete, get, patch, post, put},
+    Json, Router, ServiceExt,
 };
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
-use logpose_core::{Identity, Role, Permission, Claims, RegistryStore, HealthStatus, ServiceInstance};
+use logpose_core::audit::{identity_target, instance_target, kv_target, query_target, role_target, service_target, session_target, token_target, webhook_target};
+use logpose_core::{credential, Identity, Role, RoleDefinition, Permission, Policy, Claims, RegistryError, RegistryStore, HealthStatus, RegistryEvent, AuditFilter, KvEntry, PreparedQuery, Webhook, DEFAULT_NAMESPACE};
+use logpose_core::webhook::{DeadLetter, DeliveryAttempt};
+use logpose_core::query::Locality;
+use logpose_core::graph::{Impact, ServiceGraph};
+use logpose_core::{Catalog, CatalogSearch, Service, ServicePatch};
 use logpose_db::DbRegistry;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+use tracing::Instrument;
 use utoipa::{OpenApi, ToSchema};
 use utoipa_swagger_ui::SwaggerUi;
-use metrics_exporter_prometheus::PrometheusBuilder;
+
+use audit::Actor;
+use limits::Quota;
+use namespace::Namespace;
+
+mod audit;
+mod authz;
+mod cluster;
+mod config;
+mod keys;
+mod kv;
+mod limits;
+mod namespace;
+mod federation;
+mod query;
+mod raft;
+mod sessions;
+mod stats;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod tokens;
+mod webhooks;
+mod xds;
 
 #[derive(Clone)]
 struct AppState {
-    registry: Arc<DbRegistry>,
-    jwt_secret: String,
+    registry: Arc<cluster::Registry>,
+    keys: Arc<keys::Keys>,
+    events: broadcast::Sender<RegistryEvent>,
+    config: Arc<config::Config>,
+    limits: Arc<limits::Limits>,
+    /// `None` unless `federation.enabled`
+    federation: Option<Arc<federation::Federation>>,
+    webhooks: Arc<webhooks::Dispatcher>,
+}
+
+impl AppState {
+    /// Broadcasts a registry change to subscribers such as the xDS server.
+    /// Having no subscribers is not an error.
+    fn publish(&self, event: RegistryEvent) {
+        let _ = self.events.send(event);
+    }
 }
 
 #[derive(OpenApi)]
 #[openapi(
     paths(
         get_token,
+        refresh_token,
+        revoke_token,
         list_services,
         register_service,
+        get_service,
+        update_service,
+        deregister_service,
+        get_graph,
+        service_impact,
         discover_service,
         list_instances,
         register_instance,
         update_health,
+        deregister_instance,
+        prometheus_sd,
+        list_queries,
+        create_query,
+        get_query,
+        delete_query,
+        execute_query,
+        list_kv,
+        get_kv,
+        put_kv,
+        delete_kv,
+        list_sessions,
+        create_session,
+        get_session,
+        renew_session,
+        destroy_session,
+        get_lock,
+        acquire_lock,
+        release_lock,
+        list_webhooks,
+        create_webhook,
+        get_webhook,
+        delete_webhook,
+        list_deliveries,
+        list_dead_letters,
+        redeliver_dead_letter,
+        discard_dead_letter,
+        list_identities,
         register_identity,
+        remove_identity,
         assign_role,
+        remove_role,
+        disable_identity,
+        enable_identity,
+        list_roles,
+        create_role,
+        delete_role,
+        list_audit,
+        verify_audit,
+        cluster_status,
+        add_cluster_member,
+        remove_cluster_member,
+        federation_status,
         health_check,
+        jwks,
     ),
     components(
         schemas(
             AuthRequest, 
             AuthResponse, 
+            RefreshRequest,
+            RevokeRequest,
             RegisterServiceRequest, 
+            logpose_core::service::Service,
+            ServicePatch,
+            logpose_core::catalog::Catalog,
+            logpose_core::catalog::Tier,
+            ServiceGraph,
+            logpose_core::graph::GraphNode,
+            logpose_core::graph::GraphEdge,
+            logpose_core::graph::ServiceHealth,
+            Impact,
+            logpose_core::graph::AffectedService,
             RegisterInstanceRequest,
             HealthUpdate,
+            PrometheusTargetGroup,
             RegisterIdentityRequest,
             AssignRoleRequest,
+            AuditVerification,
+            query::QueryResult,
+            logpose_core::query::PreparedQuery,
+            logpose_core::query::Locality,
+            logpose_core::query::FailoverTarget,
+            logpose_core::kv::KvEntry,
+            logpose_core::session::Session,
+            CreateSessionRequest,
+            LockStatus,
+            logpose_core::webhook::Webhook,
+            logpose_core::webhook::EventKind,
+            DeliveryAttempt,
+            logpose_core::webhook::DeadLetter,
+            CreateWebhookRequest,
+            raft::ClusterStatus,
+            raft::Member,
+            raft::NodeRole,
+            federation::FederationStatus,
+            federation::PeerStatus,
+            logpose_core::audit::AuditRecord,
+            logpose_core::auth::Identity,
             logpose_core::auth::Role,
+            logpose_core::auth::RoleDefinition,
+            logpose_core::auth::Grant,
+            logpose_core::auth::Permission,
             logpose_core::instance::ServiceInstance,
             logpose_core::protocol::Protocol,
             logpose_core::runtime::Runtime,
@@ -77,84 +206,293 @@ impl utoipa::Modify for SecurityAddon {
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
-    
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+
+    let cli = <config::Cli as clap::Parser>::parse();
+    let config = match config::Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if cli.print_config {
+        print!("{}", config.to_redacted_toml());
+        return;
+    }
+
+    telemetry::init();
 
     // Initialize metrics
-    let recorder = PrometheusBuilder::new().build_recorder();
+    let recorder = stats::prometheus_builder().build_recorder();
     let handle = recorder.handle();
     metrics::set_global_recorder(recorder).ok();
+    stats::describe();
+
+    let registry = Arc::new(DbRegistry::new(&config.storage.database_url).expect("Failed to open database"));
+    registry.set_replicated(config.cluster.enabled).expect("Failed to open database");
+    let (events, _) = broadcast::channel(1024);
+
+    let raft = config.cluster.enabled.then(|| {
+        raft::Raft::open(&config.cluster, cluster::Machine::new(registry.clone(), events.clone())).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        })
+    });
+    // A new cluster starts from the bootstrap node's registry; every other
+    // clustered node takes its registry from the Raft log.
+    let seed = raft.as_ref().is_none_or(|raft| raft.is_fresh() && config.cluster.bootstrap);
 
-    let db_path = std::env::var("DATABASE_URL").unwrap_or_else(|_| "logpose.db".to_string());
-    let registry = Arc::new(DbRegistry::new(&db_path).expect("Failed to open database"));
-    
-    let admin_cn = "admin.logpose.local";
-    if registry.get_identity(admin_cn).is_err() {
+    let admin_cn = config.auth.admin_common_name.as_str();
+    if seed && registry.get_identity(admin_cn).is_err() {
         let admin = Identity {
             common_name: admin_cn.to_string(),
             organization: Some("LogPose".to_string()),
-            roles: vec![Role::Admin],
+            roles: [(DEFAULT_NAMESPACE.to_string(), vec![Role::Admin])].into(),
+            disabled: false,
         };
         registry.add_identity(&admin).expect("Failed to seed admin");
     }
+    // The seeded admin gets a generated password that is shown only once;
+    // only its hash is stored.
+    if seed && matches!(registry.get_identity_secret(admin_cn), Ok(None)) {
+        let password = credential::generate_secret();
+        registry
+            .set_identity_secret(admin_cn, &credential::hash_secret(&password))
+            .expect("Failed to set admin password");
+        println!("Generated password for {}: {}", admin_cn, password);
+        println!("It will not be shown again; change it with `logpose-command identity set-secret`.");
+    }
+
+    // Keys generated here must exist before a bootstrapping node snapshots
+    // its registry; any other node loads its keys once the log is restored.
+    let seeded_keys = seed.then(|| keys::init(&config.auth, registry.as_ref()));
+
+    let store = match &raft {
+        Some(raft) => {
+            if let Err(e) = raft.start() {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+            Arc::new(cluster::Registry::clustered(registry.clone(), raft.clone()))
+        }
+        None => Arc::new(cluster::Registry::standalone(registry.clone())),
+    };
+
+    let keys = match seeded_keys.unwrap_or_else(|| keys::load_stored(&config.auth, registry.as_ref())) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    keys::spawn_rotation(keys.clone(), store.clone(), config.auth.clone());
 
     let state = AppState {
-        registry: registry.clone(),
-        jwt_secret: std::env::var("JWT_SECRET").unwrap_or_else(|_| "super-secret-key".to_string()),
+        registry: store.clone(),
+        keys: keys.clone(),
+        events: events.clone(),
+        config: Arc::new(config.clone()),
+        limits: Arc::new(limits::Limits::new(config.limits.clone())),
+        federation: config.federation.enabled.then(|| Arc::new(federation::Federation::new(config.federation.clone()))),
+        webhooks: webhooks::Dispatcher::new(store.clone(), config.webhooks.clone()),
     };
 
+    // Spawn xDS control plane
+    tokio::spawn(xds::serve(config.server.xds_bind, registry.clone(), events.subscribe()));
+
+    stats::spawn_gauge_refresher(registry.clone(), events.subscribe());
+
+    sessions::spawn_reaper(store.clone(), events.clone());
+
+    state.webhooks.spawn(events.subscribe());
+
     // Spawn Health Worker
-    let worker_registry = registry.clone();
+    let worker_registry = store.clone();
+    let health_config = config.health.clone();
     tokio::spawn(async move {
         tracing::info!("Health worker started");
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        let probe_timeout = Duration::from_secs(health_config.timeout_secs);
+        let mut interval = tokio::time::interval(Duration::from_secs(health_config.interval_secs));
         loop {
             interval.tick().await;
-            if let Ok(instances) = worker_registry.get_all_instances() {
-                for instance in instances {
-                    let health = check_health(&instance.address).await;
-                    let _ = worker_registry.update_instance_health(&instance.id, health);
-                }
+            // Followers see the leader's results through replication.
+            if !worker_registry.is_leader() {
+                continue;
             }
+            let sweep = async {
+                if let Ok(instances) = worker_registry.get_all_instances() {
+                    for instance in instances {
+                        if instance.get_metadata(HEALTH_CHECK_METADATA_KEY).map(String::as_str) == Some("gossip") {
+                            continue;
+                        }
+                        let health = check_health(&instance, probe_timeout).await;
+                        if health != instance.health && worker_registry.update_instance_health(&instance.id, health).is_ok() {
+                            let _ = events.send(RegistryEvent::InstanceHealthChanged {
+                                namespace: instance.namespace,
+                                service_code: instance.service_name,
+                                id: instance.id,
+                                from: instance.health,
+                                to: health,
+                            });
+                        }
+                    }
+                }
+            };
+            sweep.instrument(tracing::info_span!("health.sweep")).await;
         }
     });
 
-    let app = Router::new()
+    let mut app = Router::new()
         .merge(SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", ApiDoc::openapi()))
         .route("/health", get(health_check))
-        .route("/metrics", get(move || {
+        .route("/.well-known/jwks.json", get(jwks));
+    if config.metrics.enabled {
+        app = app.route(&config.metrics.path, get(move || {
             let rendered = handle.render();
             async move { rendered }
-        }))
+        }));
+    }
+    let app = api(app, state.clone());
+    let app = match &raft {
+        Some(raft) => app.merge(raft::routes(raft.clone())),
+        None => app,
+    };
+    let app = match &state.federation {
+        Some(federation) => app.merge(federation::routes(federation.clone(), registry.clone())),
+        None => app,
+    };
+    let app = tower::Layer::layer(&middleware::from_fn(namespace::select), app);
+    let app = tower::Layer::layer(&middleware::from_fn_with_state(store, cluster::forward_writes), app);
+
+    let addr = config.server.bind;
+
+    if config.tls.enabled {
+        let rustls = match tls::load(&config.tls) {
+            Ok(rustls) => rustls,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        tls::spawn_reloader(rustls.clone(), config.tls.clone());
+        if let Some(redirect_bind) = config.tls.redirect_bind {
+            tokio::spawn(tls::serve_redirect(redirect_bind, addr.port()));
+        }
+
+        let handle = axum_server::Handle::new();
+        let shutdown_handle = handle.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            shutdown_handle.graceful_shutdown(Some(Duration::from_secs(10)));
+        });
+
+        tracing::info!("listening on https://{}", addr);
+        axum_server::bind(addr)
+            .acceptor(tls::ClientCertAcceptor::new(rustls))
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    } else {
+        tracing::info!("listening on {}", addr);
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown_signal())
+            .await
+            .unwrap();
+    }
+
+    telemetry::shutdown();
+}
+
+/// Adds the API routes to `app`, behind authentication, rate limiting and
+/// authorization.
+fn api(app: Router<AppState>, state: AppState) -> Router {
+    app
         .route("/api/auth/token", post(get_token))
+        .route("/api/auth/refresh", post(refresh_token))
+        .route("/api/auth/revoke", post(revoke_token))
         .route("/api/services", get(list_services))
         .route("/api/services", post(register_service))
+        .route("/api/services/:code", get(get_service))
+        .route("/api/services/:code", patch(update_service))
+        .route("/api/services/:code", delete(deregister_service))
+        .route("/api/services/:code/impact", get(service_impact))
+        .route("/api/graph", get(get_graph))
         .route("/api/services/:code/instances", get(list_instances))
         .route("/api/services/:code/instances", post(register_instance))
         .route("/api/discover/:code", get(discover_service))
+        .route("/api/instances/:id", delete(deregister_instance))
         .route("/api/instances/:id/health", post(update_health))
+        .route("/api/sd/prometheus", get(prometheus_sd))
+        .route("/api/query", get(list_queries))
+        .route("/api/query", post(create_query))
+        .route("/api/query/:name", get(get_query))
+        .route("/api/query/:name", delete(delete_query))
+        .route("/api/query/:name/execute", get(execute_query))
+        .route("/api/kv", get(list_kv))
+        .route("/api/kv/*key", get(get_kv))
+        .route("/api/kv/*key", put(put_kv))
+        .route("/api/kv/*key", delete(delete_kv))
+        .route("/api/sessions", get(list_sessions))
+        .route("/api/sessions", post(create_session))
+        .route("/api/sessions/:session", get(get_session))
+        .route("/api/sessions/:session", delete(destroy_session))
+        .route("/api/sessions/:session/renew", post(renew_session))
+        .route("/api/locks/*key", get(get_lock))
+        .route("/api/locks/*key", put(acquire_lock))
+        .route("/api/locks/*key", delete(release_lock))
+        .route("/api/webhooks", get(list_webhooks))
+        .route("/api/webhooks", post(create_webhook))
+        .route("/api/webhooks/:webhook", get(get_webhook))
+        .route("/api/webhooks/:webhook", delete(delete_webhook))
+        .route("/api/webhooks/:webhook/deliveries", get(list_deliveries))
+        .route("/api/webhooks/:webhook/dead-letters", get(list_dead_letters))
+        .route("/api/webhooks/:webhook/dead-letters/:delivery", delete(discard_dead_letter))
+        .route("/api/webhooks/:webhook/dead-letters/:delivery/redeliver", post(redeliver_dead_letter))
+        .route("/api/identities", get(list_identities))
         .route("/api/identities", post(register_identity))
+        .route("/api/identities/:cn", delete(remove_identity))
         .route("/api/identities/:cn/roles", post(assign_role))
+        .route("/api/identities/:cn/roles/:role", delete(remove_role))
+        .route("/api/identities/:cn/disable", post(disable_identity))
+        .route("/api/identities/:cn/enable", post(enable_identity))
+        .route("/api/roles", get(list_roles))
+        .route("/api/roles", post(create_role))
+        .route("/api/roles/:name", delete(delete_role))
+        .route("/api/audit", get(list_audit))
+        .route("/api/audit/verify", get(verify_audit))
+        .route("/api/cluster", get(cluster_status))
+        .route("/api/cluster/members", post(add_cluster_member))
+        .route("/api/cluster/members/:id", delete(remove_cluster_member))
+        .route("/api/federation", get(federation_status))
+        .layer(middleware::from_fn_with_state(state.clone(), authz::authorize))
+        .layer(middleware::from_fn_with_state(state.clone(), limits::rate_limit))
         .layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
-        .with_state(state);
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    tracing::info!("listening on {}", addr);
-    
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+        .layer(middleware::from_fn(stats::track_http))
+        .layer(middleware::from_fn(telemetry::trace_http))
+        .with_state(state)
 }
 
-async fn check_health(addr: &SocketAddr) -> HealthStatus {
-    match tokio::time::timeout(Duration::from_secs(2), tokio::net::TcpStream::connect(addr)).await {
+#[tracing::instrument(
+    name = "health.probe",
+    skip_all,
+    fields(
+        instance.id = %instance.id,
+        service = %instance.service_name,
+        protocol = %stats::protocol_label(&instance.protocol),
+        health = tracing::field::Empty,
+    )
+)]
+async fn check_health(instance: &logpose_core::ServiceInstance, timeout: Duration) -> HealthStatus {
+    let start = std::time::Instant::now();
+    let health = match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(instance.address)).await {
         Ok(Ok(_)) => HealthStatus::Healthy,
         _ => HealthStatus::Unhealthy,
-    }
+    };
+    stats::record_probe(&instance.protocol, start.elapsed(), health);
+    tracing::Span::current().record("health", tracing::field::debug(health));
+    health
 }
 
 async fn shutdown_signal() {
@@ -187,11 +525,31 @@ async fn shutdown_signal() {
 struct AuthRequest {
     #[schema(example = "admin.logpose.local")]
     common_name: String,
+    /// Secret or API key set for the identity
+    secret: String,
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
 struct AuthResponse {
+    /// Access token for the `Authorization: Bearer` header
     token: String,
+    /// Token to exchange at `/api/auth/refresh` for a new pair
+    refresh_token: String,
+    /// Seconds until `token` expires
+    expires_in: u64,
+}
+
+impl AuthResponse {
+    fn issue(state: &AppState, identity: &Identity) -> Response {
+        match tokens::issue(identity, &state.config.auth, &state.keys.current()) {
+            Ok(pair) => (StatusCode::OK, Json(AuthResponse {
+                token: pair.access_token,
+                refresh_token: pair.refresh_token,
+                expires_in: pair.expires_in,
+            })).into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to issue token").into_response(),
+        }
+    }
 }
 
 #[utoipa::path(
@@ -200,29 +558,117 @@ struct AuthResponse {
     request_body = AuthRequest,
     responses(
         (status = 200, description = "Token generated successfully", body = AuthResponse),
-        (status = 401, description = "Identity not found")
+        (status = 401, description = "Invalid credentials"),
+        (status = 403, description = "Identity is disabled")
     )
 )]
+#[tracing::instrument(skip_all, fields(common_name = %payload.common_name))]
 async fn get_token(
     State(state): State<AppState>,
     Json(payload): Json<AuthRequest>,
 ) -> impl IntoResponse {
+    // Identities without a secret can only authenticate with a client certificate.
+    let secret_hash = match state.registry.get_identity_secret(&payload.common_name) {
+        Ok(Some(hash)) => hash,
+        _ => return (StatusCode::UNAUTHORIZED, "Invalid credentials").into_response(),
+    };
+    let verified = tokio::task::spawn_blocking(move || credential::verify_secret(&payload.secret, &secret_hash))
+        .await
+        .unwrap_or(false);
+    if !verified {
+        return (StatusCode::UNAUTHORIZED, "Invalid credentials").into_response();
+    }
+
     match state.registry.get_identity(&payload.common_name) {
-        Ok(identity) => {
-            let claims = Claims {
-                sub: identity.common_name,
-                roles: identity.roles,
-                exp: 10000000000,
-            };
-            let token = encode(
-                &Header::default(),
-                &claims,
-                &EncodingKey::from_secret(state.jwt_secret.as_ref()),
-            ).unwrap();
-            
-            (StatusCode::OK, Json(AuthResponse { token })).into_response()
+        Ok(identity) if identity.disabled => (StatusCode::FORBIDDEN, "Identity is disabled").into_response(),
+        Ok(identity) => AuthResponse::issue(&state, &identity),
+        Err(_) => (StatusCode::UNAUTHORIZED, "Invalid credentials").into_response(),
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/refresh",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "New token pair issued", body = AuthResponse),
+        (status = 401, description = "Invalid, expired or revoked refresh token")
+    )
+)]
+#[tracing::instrument(skip_all)]
+async fn refresh_token(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshRequest>,
+) -> impl IntoResponse {
+    let Ok(refresh) = tokens::decode_refresh(&payload.refresh_token, &state.keys.current()) else {
+        return (StatusCode::UNAUTHORIZED, "Invalid refresh token").into_response();
+    };
+    if state.registry.is_token_revoked(&refresh.jti).unwrap_or(true) {
+        return (StatusCode::UNAUTHORIZED, "Invalid refresh token").into_response();
+    }
+    let Ok(identity) = state.registry.get_identity(&refresh.sub) else {
+        return (StatusCode::UNAUTHORIZED, "Invalid refresh token").into_response();
+    };
+    if identity.disabled {
+        return (StatusCode::UNAUTHORIZED, "Identity is disabled").into_response();
+    }
+
+    // Refresh tokens are single use.
+    if state.registry.revoke_token(&refresh.jti, refresh.exp as i64).is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to rotate refresh token").into_response();
+    }
+    AuthResponse::issue(&state, &identity)
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+struct RevokeRequest {
+    /// Access or refresh token to revoke
+    token: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/auth/revoke",
+    request_body = RevokeRequest,
+    responses(
+        (status = 200, description = "Token revoked"),
+        (status = 400, description = "Not a token issued by this server"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Token belongs to another identity")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(caller = %claims.sub))]
+async fn revoke_token(
+    State(state): State<AppState>,
+    actor: Actor,
+    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Json(payload): Json<RevokeRequest>,
+) -> impl IntoResponse {
+    let Ok(token) = tokens::decode_any(&payload.token, &state.keys.current()) else {
+        return (StatusCode::BAD_REQUEST, "Invalid token").into_response();
+    };
+
+    // Anyone may revoke their own tokens; revoking another identity's needs UserManage.
+    let has_permission = token.sub == claims.sub || policy.allows(Permission::UserManage, None);
+
+    if !has_permission {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+
+    match state.registry.revoke_token(&token.jti, token.exp as i64) {
+        Ok(_) => {
+            let entry = actor.entry("token.revoke", token_target(&token.jti)).after(&token);
+            audit::record(state.registry.as_ref(), entry);
+            (StatusCode::OK, "Token revoked").into_response()
         }
-        Err(_) => (StatusCode::UNAUTHORIZED, "Identity not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
     }
 }
 
@@ -230,24 +676,99 @@ async fn get_token(
     get,
     path = "/api/services",
     responses(
-        (status = 200, description = "List of services retrieved successfully"),
+        (status = 200, description = "Services of the namespace matching every criterion, ordered by code", body = Vec<Service>),
         (status = 401, description = "Unauthorized"),
         (status = 403, description = "Insufficient permissions")
     ),
+    params(CatalogSearch),
     security(("api_jwt" = []))
 )]
+#[tracing::instrument(skip_all)]
 async fn list_services(
-    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    axum::extract::Query(search): axum::extract::Query<CatalogSearch>,
 ) -> impl IntoResponse {
-    let has_permission = claims.roles.iter().any(|role| {
-        role.permissions().contains(&Permission::ServiceRead)
-    });
+    match state.registry.get_all_services() {
+        Ok(services) => {
+            let mut services: Vec<Service> = services
+                .into_iter()
+                .filter(|s| s.namespace == *namespace && policy.allows(Permission::ServiceRead, Some(&s.code)))
+                .filter(|s| search.matches(s))
+                .collect();
+            services.sort_by(|a, b| a.code.cmp(&b.code));
+            (StatusCode::OK, Json(services)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
 
-    if !has_permission {
-        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+#[utoipa::path(
+    get,
+    path = "/api/services/{code}",
+    responses(
+        (status = 200, description = "The service with its catalog fields", body = Service),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Service not found")
+    ),
+    params(("code" = String, Path, description = "Service code")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(code = %code))]
+async fn get_service(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    Path(code): Path<String>,
+) -> impl IntoResponse {
+    match state.registry.get_service(&namespace, &code) {
+        Ok(service) => (StatusCode::OK, Json(service)).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND, "Service not found").into_response(),
     }
+}
 
-    (StatusCode::OK, "List of services").into_response()
+#[utoipa::path(
+    patch,
+    path = "/api/services/{code}",
+    request_body = ServicePatch,
+    responses(
+        (status = 200, description = "The updated service", body = Service),
+        (status = 400, description = "Invalid dependencies or catalog fields"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Service not found")
+    ),
+    params(("code" = String, Path, description = "Service code")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(code = %code))]
+async fn update_service(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(code): Path<String>,
+    Json(patch): Json<ServicePatch>,
+) -> impl IntoResponse {
+    let Ok(existing) = state.registry.get_service(&namespace, &code) else {
+        return (StatusCode::NOT_FOUND, "Service not found").into_response();
+    };
+    let mut service = existing.clone();
+    patch.apply(&mut service);
+    if let Err(e) = service.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    match state.registry.update_service(&service) {
+        Ok(_) => {
+            let entry = actor.entry("service.update", service_target(&service.namespace, &service.code))
+                .before(existing)
+                .after(&service);
+            audit::record(state.registry.as_ref(), entry);
+            state.publish(RegistryEvent::ServiceUpdated { namespace: service.namespace.clone(), code: service.code.clone() });
+            (StatusCode::OK, Json(service)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -258,6 +779,15 @@ struct RegisterServiceRequest {
     code: String,
     #[schema(example = "Handles user authentication and authorization")]
     description: String,
+    /// Codes of the services of the namespace this one calls
+    #[serde(default)]
+    #[schema(example = json!(["users-db"]))]
+    dependencies: Vec<String>,
+    /// Owner, on-call contact, repository, tier and tags
+    #[serde(default)]
+    catalog: Catalog,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
 }
 
 #[utoipa::path(
@@ -266,58 +796,268 @@ struct RegisterServiceRequest {
     request_body = RegisterServiceRequest,
     responses(
         (status = 201, description = "Service registered successfully"),
+        (status = 400, description = "Invalid dependencies or catalog fields"),
         (status = 401, description = "Unauthorized"),
-        (status = 403, description = "Insufficient permissions")
+        (status = 403, description = "Insufficient permissions"),
+        (status = 429, description = "Rate limit or services-per-namespace quota exceeded")
     ),
     security(("api_jwt" = []))
 )]
+#[tracing::instrument(skip_all, fields(code = %payload.code))]
 async fn register_service(
     State(state): State<AppState>,
-    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
     Json(payload): Json<RegisterServiceRequest>,
 ) -> impl IntoResponse {
-    let has_permission = claims.roles.iter().any(|role| {
-        role.permissions().contains(&Permission::ServiceWrite)
-    });
-
-    if !has_permission {
+    // The service code is only known from the body, so its scope is checked here.
+    if !policy.allows(Permission::ServiceWrite, Some(&payload.code)) {
         return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
     }
+    let mut service = logpose_core::Service::new(payload.name, payload.code, payload.description);
+    service.namespace = namespace.0;
+    service.dependencies = payload.dependencies;
+    service.catalog = payload.catalog;
+    service.metadata = payload.metadata;
+    if let Err(e) = service.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    let registering = state.limits.registration_lock().await;
+    let existing = state.registry.get_service(&service.namespace, &service.code).ok();
+    if existing.is_none() {
+        let services = state.registry.get_all_services().unwrap_or_default();
+        let used = services.iter().filter(|s| s.namespace == service.namespace).count();
+        if let Err(e) = state.limits.check_quota(Quota::ServicesPerNamespace, used) {
+            return e.into_response();
+        }
+    }
+    let added = state.registry.add_service(&service);
+    drop(registering);
+    match added {
+        Ok(_) => {
+            let mut entry = actor.entry("service.register", service_target(&service.namespace, &service.code));
+            if let Some(existing) = existing {
+                entry = entry.before(existing);
+            }
+            audit::record(state.registry.as_ref(), entry.after(&service));
+            stats::record_registration("service");
+            state.publish(RegistryEvent::ServiceRegistered { namespace: service.namespace, code: service.code });
+            (StatusCode::CREATED, "Service registered").into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/services/{code}",
+    responses(
+        (status = 200, description = "Service and its instances deregistered"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Service not found")
+    ),
+    params(("code" = String, Path, description = "Service code")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(code = %code))]
+async fn deregister_service(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(code): Path<String>,
+) -> impl IntoResponse {
+    let service = state.registry.get_service(&namespace, &code).ok();
+    let instances = state.registry.get_instances(&namespace, &code).unwrap_or_default();
+    match state.registry.remove_service(&namespace, &code) {
+        Ok(_) => {
+            let entry = actor.entry("service.deregister", service_target(&namespace, &code))
+                .before(serde_json::json!({ "service": service, "instances": instances }));
+            audit::record(state.registry.as_ref(), entry);
+            for instance in instances {
+                stats::record_deregistration("instance");
+                state.publish(RegistryEvent::InstanceDeregistered {
+                    namespace: instance.namespace,
+                    service_code: instance.service_name,
+                    id: instance.id,
+                });
+            }
+            stats::record_deregistration("service");
+            state.publish(RegistryEvent::ServiceDeregistered { namespace: namespace.0, code });
+            (StatusCode::OK, "Service deregistered").into_response()
+        }
+        Err(_) => (StatusCode::NOT_FOUND, "Service not found").into_response(),
+    }
+}
 
-    let service = logpose_core::Service::new(payload.name, payload.code, payload.description);
-    match state.registry.add_service(&service) {
-        Ok(_) => (StatusCode::CREATED, "Service registered").into_response(),
+/// The dependency graph of the namespace, without the services the caller
+/// may not read.
+fn service_graph(state: &AppState, namespace: &str, policy: &Policy) -> Result<ServiceGraph, RegistryError> {
+    let services = state.registry.get_all_services()?;
+    let instances = state.registry.get_all_instances()?;
+    let mut graph = ServiceGraph::build(namespace, &services, &instances);
+    graph.retain(|code| policy.allows(Permission::ServiceRead, Some(code)));
+    Ok(graph)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/graph",
+    responses(
+        (status = 200, description = "Services of the namespace with their dependencies and health", body = ServiceGraph),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn get_graph(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+) -> impl IntoResponse {
+    match service_graph(&state, &namespace, &policy) {
+        Ok(graph) => (StatusCode::OK, Json(graph)).into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/services/{code}/impact",
+    responses(
+        (status = 200, description = "Services transitively affected when this one is down", body = Impact),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Service not found")
+    ),
+    params(("code" = String, Path, description = "Service code")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(code = %code))]
+async fn service_impact(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(code): Path<String>,
+) -> impl IntoResponse {
+    let services = match state.registry.get_all_services() {
+        Ok(services) => services,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    let instances = state.registry.get_all_instances().unwrap_or_default();
+    // The outage is traced through every service, but only those the caller
+    // may read are reported.
+    let graph = ServiceGraph::build(&namespace, &services, &instances);
+    match graph.impact(&code) {
+        Some(mut impact) => {
+            impact.affected.retain(|a| policy.allows(Permission::ServiceRead, Some(&a.code)));
+            (StatusCode::OK, Json(impact)).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "Service not found").into_response(),
+    }
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct DiscoverQuery {
+    /// Datacenter to discover in, or `any` to fail over to another
+    /// datacenter when this one has no healthy instance
+    dc: Option<String>,
+}
+
+/// Response header naming the datacenter discovered instances are in.
+const DATACENTER_HEADER: &str = "x-logpose-datacenter";
+/// Response header telling whether another datacenter's instances came from
+/// a `fresh` or `stale` cache entry; the `Age` header holds its age.
+const CACHE_HEADER: &str = "x-logpose-cache";
+
 #[utoipa::path(
     get,
     path = "/api/discover/{code}",
-    responses((status = 200, description = "Discovery", body = Vec<ServiceInstance>)),
-    params(("code" = String, Path, description = "Service code"))
+    responses(
+        (status = 200, description = "Discovery", body = Vec<ServiceInstance>),
+        (status = 400, description = "Federation is not enabled"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Unknown datacenter"),
+        (status = 503, description = "Datacenter unreachable and nothing cached")
+    ),
+    params(("code" = String, Path, description = "Service code"), DiscoverQuery),
+    security(("api_jwt" = []))
 )]
+#[tracing::instrument(skip_all, fields(code = %code))]
 async fn discover_service(
     State(state): State<AppState>,
+    namespace: Namespace,
     Path(code): Path<String>,
+    axum::extract::Query(query): axum::extract::Query<DiscoverQuery>,
 ) -> impl IntoResponse {
-    match state.registry.get_instances(&code) {
-        Ok(instances) => (StatusCode::OK, Json(instances)).into_response(),
-        Err(_) => (StatusCode::NOT_FOUND, "Service not found").into_response(),
+    let local = || state.registry.get_instances(&namespace, &code);
+    let Some(federation) = &state.federation else {
+        if query.dc.is_some() {
+            return (StatusCode::BAD_REQUEST, "Federation is not enabled").into_response();
+        }
+        return match local() {
+            Ok(instances) => (StatusCode::OK, Json(instances)).into_response(),
+            Err(_) => (StatusCode::NOT_FOUND, "Service not found").into_response(),
+        };
+    };
+
+    let answer = match query.dc.as_deref() {
+        None => None,
+        Some(dc) if dc == federation.datacenter() => None,
+        Some("any") => match local() {
+            Ok(instances) if instances.iter().any(|i| i.health == HealthStatus::Healthy) => None,
+            _ => federation.failover(&namespace, &code).await,
+        },
+        Some(dc) => match federation.discover(dc, &namespace, &code).await {
+            Ok(answer) => Some(answer),
+            Err(e @ federation::FederationError::UnknownDatacenter(_)) => {
+                return (StatusCode::NOT_FOUND, e.to_string()).into_response();
+            }
+            Err(e @ federation::FederationError::Unreachable(_)) => {
+                return (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response();
+            }
+        },
+    };
+    match answer {
+        Some(answer) => {
+            let headers = [
+                (DATACENTER_HEADER, answer.datacenter),
+                (CACHE_HEADER, answer.freshness.label().to_string()),
+                ("age", answer.age.as_secs().to_string()),
+            ];
+            (StatusCode::OK, headers, Json(answer.instances)).into_response()
+        }
+        None => match local() {
+            Ok(instances) => {
+                let headers = [(DATACENTER_HEADER, federation.datacenter().to_string())];
+                (StatusCode::OK, headers, Json(instances)).into_response()
+            }
+            Err(_) => (StatusCode::NOT_FOUND, "Service not found").into_response(),
+        },
     }
 }
 
 #[utoipa::path(
     get,
     path = "/api/services/{code}/instances",
-    responses((status = 200, description = "Instance list", body = Vec<ServiceInstance>)),
-    params(("code" = String, Path, description = "Service code"))
+    responses(
+        (status = 200, description = "Instance list", body = Vec<ServiceInstance>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    params(("code" = String, Path, description = "Service code")),
+    security(("api_jwt" = []))
 )]
+#[tracing::instrument(skip_all, fields(code = %code))]
 async fn list_instances(
     State(state): State<AppState>,
+    namespace: Namespace,
     Path(code): Path<String>,
 ) -> impl IntoResponse {
-    match state.registry.get_instances(&code) {
+    match state.registry.get_instances(&namespace, &code) {
         Ok(instances) => (StatusCode::OK, Json(instances)).into_response(),
         Err(_) => (StatusCode::NOT_FOUND, "Service not found").into_response(),
     }
@@ -330,40 +1070,62 @@ struct RegisterInstanceRequest {
     protocol: logpose_core::protocol::Protocol,
     #[schema(example = "Container")]
     runtime: logpose_core::runtime::Runtime,
+    /// Free-form labels; `weight`, `region`, `zone` and `sub_zone` are used by xDS,
+    /// `health_check = gossip` hands health checks to gossip agents.
+    #[serde(default)]
+    metadata: HashMap<String, String>,
 }
 
 #[utoipa::path(
     post,
     path = "/api/services/{code}/instances",
     request_body = RegisterInstanceRequest,
-    responses((status = 201, description = "Instance registered")),
+    responses(
+        (status = 201, description = "Instance registered"),
+        (status = 429, description = "Rate limit or instances-per-service quota exceeded")
+    ),
     params(("code" = String, Path, description = "Service code")),
     security(("api_jwt" = []))
 )]
+#[tracing::instrument(skip_all, fields(code = %code))]
 async fn register_instance(
     State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
     axum::extract::Extension(claims): axum::extract::Extension<Claims>,
     Path(code): Path<String>,
     Json(payload): Json<RegisterInstanceRequest>,
 ) -> impl IntoResponse {
-    let has_permission = claims.roles.iter().any(|role| {
-        role.permissions().contains(&Permission::InstanceWrite)
-    });
-
-    if !has_permission {
-        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
-    }
-
-    let instance = logpose_core::ServiceInstance::new(
+    let mut instance = logpose_core::ServiceInstance::new(
         code,
         payload.address,
         payload.protocol,
         payload.runtime,
         logpose_core::time::now()
     );
+    instance.namespace = namespace.0;
+    instance.metadata = payload.metadata;
+    instance.registered_by = Some(claims.sub);
 
-    match state.registry.add_instance(&instance) {
-        Ok(_) => (StatusCode::CREATED, "Instance registered").into_response(),
+    let registering = state.limits.registration_lock().await;
+    let used = state.registry.get_instances(&instance.namespace, &instance.service_name).map_or(0, |i| i.len());
+    if let Err(e) = state.limits.check_quota(Quota::InstancesPerService, used) {
+        return e.into_response();
+    }
+    let added = state.registry.add_instance(&instance);
+    drop(registering);
+    match added {
+        Ok(_) => {
+            let entry = actor.entry("instance.register", instance_target(&instance.namespace, &instance.id));
+            audit::record(state.registry.as_ref(), entry.after(&instance));
+            stats::record_registration("instance");
+            state.publish(RegistryEvent::InstanceRegistered {
+                namespace: instance.namespace,
+                service_code: instance.service_name,
+                id: instance.id,
+            });
+            (StatusCode::CREATED, "Instance registered").into_response()
+        }
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
     }
 }
@@ -377,11 +1139,22 @@ struct HealthUpdate {
     post,
     path = "/api/instances/{id}/health",
     request_body = HealthUpdate,
-    responses((status = 200, description = "Updated")),
-    params(("id" = String, Path, description = "Instance ID"))
+    responses(
+        (status = 200, description = "Updated"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Not the registering identity, an Admin or a holder of HealthReport"),
+        (status = 404, description = "Instance not found")
+    ),
+    params(("id" = String, Path, description = "Instance ID")),
+    security(("api_jwt" = []))
 )]
+#[tracing::instrument(skip_all, fields(id = %id))]
 async fn update_health(
     State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
     Path(id): Path<String>,
     Json(payload): Json<HealthUpdate>,
 ) -> impl IntoResponse {
@@ -389,46 +1162,1417 @@ async fn update_health(
         Ok(id) => id,
         Err(_) => return (StatusCode::BAD_REQUEST, "Invalid ID").into_response(),
     };
+    let instance = match state.registry.get_instance(&id) {
+        Ok(instance) if instance.namespace == *namespace => instance,
+        _ => return (StatusCode::NOT_FOUND, "Instance not found").into_response(),
+    };
+
+    // Health may only be reported by the instance's own registrant, an Admin,
+    // or an observer such as a gossip agent granted HealthReport.
+    let is_registrant = instance.registered_by.as_deref() == Some(claims.sub.as_str());
+    if !is_registrant
+        && !claims.roles_in(&namespace).contains(&Role::Admin)
+        && !policy.allows(Permission::HealthReport, Some(&instance.service_name))
+    {
+        return (StatusCode::FORBIDDEN, "Only the registering identity, an Admin or a holder of HealthReport may update this instance").into_response();
+    }
+
     match state.registry.update_instance_health(&id, payload.status) {
-        Ok(_) => (StatusCode::OK, "Updated").into_response(),
+        Ok(_) => {
+            let entry = actor.entry("instance.health", instance_target(&instance.namespace, &id))
+                .before(serde_json::json!({ "health": instance.health }))
+                .after(serde_json::json!({ "health": payload.status }));
+            audit::record(state.registry.as_ref(), entry);
+            if instance.health != payload.status {
+                state.publish(RegistryEvent::InstanceHealthChanged {
+                    namespace: instance.namespace,
+                    service_code: instance.service_name,
+                    id,
+                    from: instance.health,
+                    to: payload.status,
+                });
+            }
+            (StatusCode::OK, "Updated").into_response()
+        }
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
     }
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
-struct RegisterIdentityRequest {
-    common_name: String,
-    organization: Option<String>,
-}
-
 #[utoipa::path(
-    post,
-    path = "/api/identities",
-    request_body = RegisterIdentityRequest,
-    responses((status = 201, description = "Identity created")),
+    delete,
+    path = "/api/instances/{id}",
+    responses(
+        (status = 200, description = "Instance deregistered"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Instance not found")
+    ),
+    params(("id" = String, Path, description = "Instance ID")),
     security(("api_jwt" = []))
 )]
-async fn register_identity(
+#[tracing::instrument(skip_all, fields(id = %id))]
+async fn deregister_instance(
     State(state): State<AppState>,
-    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
-    Json(payload): Json<RegisterIdentityRequest>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let id = match uuid::Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, "Invalid ID").into_response(),
+    };
+    let instance = match state.registry.get_instance(&id) {
+        Ok(instance) if instance.namespace == *namespace => instance,
+        _ => return (StatusCode::NOT_FOUND, "Instance not found").into_response(),
+    };
+    match state.registry.remove_instance(&id) {
+        Ok(_) => {
+            let entry = actor.entry("instance.deregister", instance_target(&instance.namespace, &id)).before(&instance);
+            audit::record(state.registry.as_ref(), entry);
+            stats::record_deregistration("instance");
+            state.publish(RegistryEvent::InstanceDeregistered {
+                namespace: instance.namespace,
+                service_code: instance.service_name,
+                id,
+            });
+            (StatusCode::OK, "Instance deregistered").into_response()
+        }
+        Err(_) => (StatusCode::NOT_FOUND, "Instance not found").into_response(),
+    }
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct PrometheusSdQuery {
+    /// Only return targets for this service code
+    service: Option<String>,
+}
+
+/// One entry of the Prometheus `http_sd_config` response.
+#[derive(Serialize, Deserialize, ToSchema)]
+struct PrometheusTargetGroup {
+    targets: Vec<String>,
+    labels: HashMap<String, String>,
+}
+
+/// Instance metadata key choosing who checks the instance's health: `gossip`
+/// leaves it to the instance's gossip agents instead of the health worker.
+const HEALTH_CHECK_METADATA_KEY: &str = "health_check";
+
+/// Instance metadata key holding the port (or `host:port`) Prometheus should
+/// scrape when it differs from the instance's service address.
+const METRICS_METADATA_KEY: &str = "metrics";
+
+#[utoipa::path(
+    get,
+    path = "/api/sd/prometheus",
+    responses(
+        (status = 200, description = "Prometheus HTTP service discovery targets", body = Vec<PrometheusTargetGroup>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    params(PrometheusSdQuery),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn prometheus_sd(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    axum::extract::Query(query): axum::extract::Query<PrometheusSdQuery>,
 ) -> impl IntoResponse {
-    let has_permission = claims.roles.iter().any(|role| {
-        role.permissions().contains(&Permission::UserManage)
+    let instances = match &query.service {
+        Some(code) => state.registry.get_instances(&namespace, code),
+        None => state.registry.get_all_instances().map(|all| {
+            all.into_iter().filter(|instance| instance.namespace == *namespace).collect()
+        }),
+    };
+    let instances = match instances {
+        Ok(instances) => instances,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+
+    let groups: Vec<PrometheusTargetGroup> = instances
+        .into_iter()
+        .filter(|instance| policy.allows(Permission::InstanceRead, Some(&instance.service_name)))
+        .map(prometheus_target_group)
+        .collect();
+    (StatusCode::OK, Json(groups)).into_response()
+}
+
+fn prometheus_target_group(instance: logpose_core::ServiceInstance) -> PrometheusTargetGroup {
+    let target = match instance.get_metadata(METRICS_METADATA_KEY) {
+        Some(metrics) => match metrics.parse::<u16>() {
+            Ok(port) => SocketAddr::new(instance.address.ip(), port).to_string(),
+            Err(_) => metrics.clone(),
+        },
+        None => instance.address.to_string(),
+    };
+
+    let mut labels = HashMap::new();
+    labels.insert("__meta_logpose_namespace".to_string(), instance.namespace.clone());
+    labels.insert("__meta_logpose_service".to_string(), instance.service_name.clone());
+    labels.insert("__meta_logpose_instance_id".to_string(), instance.id.to_string());
+    labels.insert("__meta_logpose_address".to_string(), instance.address.to_string());
+    labels.insert("__meta_logpose_health".to_string(), format!("{:?}", instance.health));
+    labels.insert("__meta_logpose_protocol".to_string(), stats::protocol_label(&instance.protocol));
+    labels.insert("__meta_logpose_runtime".to_string(), match &instance.runtime {
+        logpose_core::Runtime::Vm { .. } => "Vm".to_string(),
+        logpose_core::Runtime::Container { .. } => "Container".to_string(),
+        logpose_core::Runtime::Serverless { .. } => "Serverless".to_string(),
+        logpose_core::Runtime::Custom(name) => name.clone(),
     });
+    for (key, value) in &instance.metadata {
+        // Prometheus label names may only contain [a-zA-Z0-9_].
+        let key: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        labels.insert(format!("__meta_logpose_metadata_{}", key), value.clone());
+    }
 
-    if !has_permission {
+    PrometheusTargetGroup { targets: vec![target], labels }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/query",
+    responses(
+        (status = 200, description = "Prepared queries of the namespace, ordered by name", body = Vec<PreparedQuery>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn list_queries(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+) -> impl IntoResponse {
+    match state.registry.get_queries(&namespace) {
+        Ok(queries) => {
+            let queries: Vec<PreparedQuery> = queries
+                .into_iter()
+                .filter(|q| policy.allows(Permission::ServiceRead, Some(&q.service)))
+                .collect();
+            (StatusCode::OK, Json(queries)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/query",
+    request_body = PreparedQuery,
+    responses(
+        (status = 201, description = "Query created"),
+        (status = 400, description = "Invalid query"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 409, description = "A query with this name already exists")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %payload.name))]
+async fn create_query(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Json(mut payload): Json<PreparedQuery>,
+) -> impl IntoResponse {
+    if let Err(e) = payload.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    // The service is only known from the body, so its scope is checked here.
+    if !policy.allows(Permission::ServiceWrite, Some(&payload.service)) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    payload.namespace = namespace.0;
+    match state.registry.add_query(&payload) {
+        Ok(_) => {
+            let entry = actor.entry("query.create", query_target(&payload.namespace, &payload.name)).after(&payload);
+            audit::record(state.registry.as_ref(), entry);
+            (StatusCode::CREATED, "Query created").into_response()
+        }
+        Err(RegistryError::DuplicateQuery) => (StatusCode::CONFLICT, "Query already exists").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/query/{name}",
+    responses(
+        (status = 200, description = "Query definition", body = PreparedQuery),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Query not found")
+    ),
+    params(("name" = String, Path, description = "Query name")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %name))]
+async fn get_query(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match state.registry.get_query(&namespace, &name) {
+        Ok(query) if !policy.allows(Permission::ServiceRead, Some(&query.service)) => {
+            (StatusCode::FORBIDDEN, "Insufficient permissions").into_response()
+        }
+        Ok(query) => (StatusCode::OK, Json(query)).into_response(),
+        Err(RegistryError::QueryNotFound) => (StatusCode::NOT_FOUND, "Query not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/query/{name}",
+    responses(
+        (status = 200, description = "Query deleted"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Query not found")
+    ),
+    params(("name" = String, Path, description = "Query name")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %name))]
+async fn delete_query(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let query = match state.registry.get_query(&namespace, &name) {
+        Ok(query) => query,
+        Err(RegistryError::QueryNotFound) => return (StatusCode::NOT_FOUND, "Query not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    if !policy.allows(Permission::ServiceWrite, Some(&query.service)) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    match state.registry.remove_query(&namespace, &name) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("query.delete", query_target(&namespace, &name)).before(query));
+            (StatusCode::OK, "Query deleted").into_response()
+        }
+        Err(RegistryError::QueryNotFound) => (StatusCode::NOT_FOUND, "Query not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct ExecuteQueryParams {
+    /// Overrides the query's `near` locality, together with `zone` and `sub_zone`
+    region: Option<String>,
+    zone: Option<String>,
+    sub_zone: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/query/{name}/execute",
+    responses(
+        (status = 200, description = "Matching instances of the query's service, or of the failover target that answered", body = query::QueryResult),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Query not found")
+    ),
+    params(("name" = String, Path, description = "Query name"), ExecuteQueryParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %name))]
+async fn execute_query(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(name): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<ExecuteQueryParams>,
+) -> impl IntoResponse {
+    let query = match state.registry.get_query(&namespace, &name) {
+        Ok(query) => query,
+        Err(RegistryError::QueryNotFound) => return (StatusCode::NOT_FOUND, "Query not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    if !policy.allows(Permission::InstanceRead, Some(&query.service)) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    let requested = Locality { region: params.region, zone: params.zone, sub_zone: params.sub_zone };
+    let near = if requested.is_empty() { query.near.as_ref() } else { Some(&requested) };
+
+    let execution = query::execute(&state, &policy, &query, near).await;
+    match execution.cache {
+        Some((freshness, age)) => {
+            let headers = [(CACHE_HEADER, freshness.label().to_string()), ("age", age.as_secs().to_string())];
+            (StatusCode::OK, headers, Json(execution.result)).into_response()
+        }
+        None => (StatusCode::OK, Json(execution.result)).into_response(),
+    }
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct KvReadParams {
+    /// Return every key starting with the given one
+    #[serde(default)]
+    recurse: bool,
+    /// Return the value alone as the response body
+    #[serde(default)]
+    raw: bool,
+    /// Block until the store is written after this `x-logpose-index`
+    index: Option<u64>,
+    /// Seconds to block for with `index`; 60 by default, at most 300
+    wait: Option<u64>,
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct KvWriteParams {
+    /// Write only if the key's `modify_index` is still this; 0 writes only
+    /// if the key does not exist
+    cas: Option<u64>,
+    /// Delete every key starting with the given one
+    #[serde(default)]
+    recurse: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/kv",
+    responses(
+        (status = 200, description = "Every key of the namespace the caller may read", body = Vec<KvEntry>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    params(KvReadParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn list_kv(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    axum::extract::Query(params): axum::extract::Query<KvReadParams>,
+) -> impl IntoResponse {
+    read_kv(&state, &namespace, &policy, "", KvReadParams { recurse: true, ..params }).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/kv/{key}",
+    responses(
+        (status = 200, description = "The entry, the entries below it with `recurse`, or its value with `raw`", body = KvEntry),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Key not found")
+    ),
+    params(("key" = String, Path, description = "Key, e.g. config/billing/timeout"), KvReadParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(key = %key))]
+async fn get_kv(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(key): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<KvReadParams>,
+) -> impl IntoResponse {
+    read_kv(&state, &namespace, &policy, &key, params).await
+}
+
+/// Blocks a read given an `index` until the store is written after it; see
+/// [`kv::wait_for_change`].
+async fn wait_for_kv(
+    state: &AppState,
+    namespace: &str,
+    key: &str,
+    recurse: bool,
+    index: Option<u64>,
+    wait: Option<u64>,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Some(index) = index else {
+        return Ok(());
+    };
+    let events = state.events.subscribe();
+    let current = match state.registry.kv_index() {
+        Ok(current) => current,
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed")),
+    };
+    if current <= index {
+        let wait = Duration::from_secs(wait.unwrap_or(kv::DEFAULT_WAIT_SECS).min(kv::MAX_WAIT_SECS));
+        kv::wait_for_change(events, namespace, key, recurse, wait).await;
+    }
+    Ok(())
+}
+
+async fn read_kv(state: &AppState, namespace: &str, policy: &Policy, key: &str, params: KvReadParams) -> Response {
+    if let Err(response) = wait_for_kv(state, namespace, key, params.recurse, params.index, params.wait).await {
+        return response.into_response();
+    }
+
+    // Read before the entries, so a write in between is reported on the
+    // next blocking read rather than missed.
+    let index = match state.registry.kv_index() {
+        Ok(index) => [(kv::INDEX_HEADER, index.to_string())],
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    if params.recurse {
+        return match state.registry.get_keys(namespace, key) {
+            Ok(entries) => {
+                let entries: Vec<KvEntry> = entries
+                    .into_iter()
+                    .filter(|entry| policy.allows(Permission::KvRead, Some(&entry.key)))
+                    .collect();
+                (StatusCode::OK, index, Json(entries)).into_response()
+            }
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+        };
+    }
+    if !policy.allows(Permission::KvRead, Some(key)) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    match state.registry.get_key(namespace, key) {
+        Ok(entry) if params.raw => (StatusCode::OK, index, entry.value).into_response(),
+        Ok(entry) => (StatusCode::OK, index, Json(entry)).into_response(),
+        Err(RegistryError::KeyNotFound) => (StatusCode::NOT_FOUND, index, "Key not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/kv/{key}",
+    request_body(content = String, description = "The value, as UTF-8 text", content_type = "text/plain"),
+    responses(
+        (status = 200, description = "Key written", body = KvEntry),
+        (status = 400, description = "Invalid key"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 409, description = "The key was modified since the `cas` index"),
+        (status = 413, description = "Value larger than 512 KiB")
+    ),
+    params(("key" = String, Path, description = "Key, e.g. config/billing/timeout"), KvWriteParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(key = %key))]
+async fn put_kv(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(key): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<KvWriteParams>,
+    value: String,
+) -> impl IntoResponse {
+    if let Err(e) = logpose_core::kv::validate_key(&key) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    if value.len() > logpose_core::kv::MAX_VALUE_LEN {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Value too large").into_response();
+    }
+    if !policy.allows(Permission::KvWrite, Some(&key)) {
         return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
     }
+    let existing = state.registry.get_key(&namespace, &key).ok();
+    match state.registry.put_key(&namespace, &key, &value, params.cas) {
+        Ok(entry) => {
+            let mut audit_entry = actor.entry("kv.put", kv_target(&namespace, &key));
+            if let Some(existing) = existing {
+                audit_entry = audit_entry.before(existing);
+            }
+            audit::record(state.registry.as_ref(), audit_entry.after(&entry));
+            state.publish(RegistryEvent::KeyChanged { namespace: namespace.0, key });
+            let index = [(kv::INDEX_HEADER, entry.modify_index.to_string())];
+            (StatusCode::OK, index, Json(entry)).into_response()
+        }
+        Err(RegistryError::CasMismatch) => (StatusCode::CONFLICT, "Key was modified since the given index").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/kv/{key}",
+    responses(
+        (status = 200, description = "Key deleted, or every key below it with `recurse`"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Key not found"),
+        (status = 409, description = "The key was modified since the `cas` index")
+    ),
+    params(("key" = String, Path, description = "Key, e.g. config/billing/timeout"), KvWriteParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(key = %key))]
+async fn delete_kv(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(key): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<KvWriteParams>,
+) -> impl IntoResponse {
+    let removed = if params.recurse {
+        let entries = match state.registry.get_keys(&namespace, &key) {
+            Ok(entries) => entries,
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+        };
+        if entries.iter().any(|entry| !policy.allows(Permission::KvWrite, Some(&entry.key))) {
+            return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+        }
+        state.registry.remove_keys(&namespace, &key).map(|()| serde_json::json!(entries))
+    } else {
+        if !policy.allows(Permission::KvWrite, Some(&key)) {
+            return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+        }
+        let existing = state.registry.get_key(&namespace, &key).ok();
+        state.registry.remove_key(&namespace, &key, params.cas).map(|()| serde_json::json!(existing))
+    };
+    match removed {
+        Ok(before) => {
+            audit::record(state.registry.as_ref(), actor.entry("kv.delete", kv_target(&namespace, &key)).before(before));
+            state.publish(RegistryEvent::KeyChanged { namespace: namespace.0, key });
+            (StatusCode::OK, "Key deleted").into_response()
+        }
+        Err(RegistryError::KeyNotFound) => (StatusCode::NOT_FOUND, "Key not found").into_response(),
+        Err(RegistryError::CasMismatch) => (StatusCode::CONFLICT, "Key was modified since the given index").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateSessionRequest {
+    #[schema(example = "report-builder")]
+    name: String,
+    /// Instance of the namespace whose liveness the session follows
+    instance: Option<uuid::Uuid>,
+    /// Seconds the session lives without being renewed, 10 to 86400
+    ttl_secs: Option<u64>,
+}
+
+/// Only a session's owner, or an identity that may lock every key, may
+/// renew, destroy or lock with it.
+fn may_use_session(session: &logpose_core::Session, actor: &Actor, policy: &Policy) -> bool {
+    session.owner.as_deref() == Some(actor.sub.as_str()) || policy.allows(Permission::Lock, Some("*"))
+}
+
+/// Looks up a session of `namespace`; sessions of other namespaces are not
+/// found.
+fn find_session(state: &AppState, namespace: &str, id: &uuid::Uuid) -> Result<logpose_core::Session, (StatusCode, &'static str)> {
+    match state.registry.get_session(id) {
+        Ok(session) if session.namespace == namespace => Ok(session),
+        Ok(_) | Err(RegistryError::SessionNotFound) => Err((StatusCode::NOT_FOUND, "Session not found")),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed")),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sessions",
+    responses(
+        (status = 200, description = "Sessions of the namespace, oldest first", body = Vec<logpose_core::Session>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn list_sessions(State(state): State<AppState>, namespace: Namespace) -> impl IntoResponse {
+    match state.registry.get_sessions(&namespace) {
+        Ok(sessions) => (StatusCode::OK, Json(sessions)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sessions",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 201, description = "Session created", body = logpose_core::Session),
+        (status = 400, description = "Invalid session, or unknown instance"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 409, description = "The instance is unhealthy")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %payload.name))]
+async fn create_session(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Json(payload): Json<CreateSessionRequest>,
+) -> impl IntoResponse {
+    let mut session = logpose_core::Session::new(namespace.0, payload.name, payload.instance, payload.ttl_secs);
+    if let Err(e) = session.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    if let Some(id) = &session.instance {
+        match state.registry.get_instance(id) {
+            Ok(instance) if instance.namespace != session.namespace => {
+                return (StatusCode::BAD_REQUEST, "Instance not found").into_response();
+            }
+            // The reaper would end the session right away.
+            Ok(instance) if instance.health == HealthStatus::Unhealthy => {
+                return (StatusCode::CONFLICT, "Instance is unhealthy").into_response();
+            }
+            Ok(_) => {}
+            Err(RegistryError::InstanceNotFound) => return (StatusCode::BAD_REQUEST, "Instance not found").into_response(),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+        }
+    }
+    session.owner = Some(actor.sub.clone());
+    match state.registry.add_session(&session) {
+        Ok(_) => {
+            let entry = actor.entry("session.create", session_target(&session.namespace, &session.id)).after(&session);
+            audit::record(state.registry.as_ref(), entry);
+            (StatusCode::CREATED, Json(session)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/sessions/{session}",
+    responses(
+        (status = 200, description = "The session", body = logpose_core::Session),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Session not found")
+    ),
+    params(("session" = Uuid, Path, description = "Session ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(session = %id))]
+async fn get_session(State(state): State<AppState>, namespace: Namespace, Path(id): Path<uuid::Uuid>) -> impl IntoResponse {
+    match find_session(&state, &namespace, &id) {
+        Ok(session) => (StatusCode::OK, Json(session)).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/sessions/{session}/renew",
+    responses(
+        (status = 200, description = "Session renewed; sessions without a TTL are returned unchanged", body = logpose_core::Session),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Not the session's owner"),
+        (status = 404, description = "Session not found")
+    ),
+    params(("session" = Uuid, Path, description = "Session ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(session = %id))]
+async fn renew_session(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let mut session = match find_session(&state, &namespace, &id) {
+        Ok(session) => session,
+        Err(response) => return response.into_response(),
+    };
+    if !may_use_session(&session, &actor, &policy) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    // Renewals are heartbeats, so like health reports they are not audited.
+    let Some(expires_at) = session.renewed_at(logpose_core::time::now()) else {
+        return (StatusCode::OK, Json(session)).into_response();
+    };
+    match state.registry.renew_session(&id, expires_at) {
+        Ok(_) => {
+            session.expires_at = Some(expires_at);
+            (StatusCode::OK, Json(session)).into_response()
+        }
+        // Ended by the reaper in the meantime.
+        Err(RegistryError::SessionNotFound) => (StatusCode::NOT_FOUND, "Session not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/sessions/{session}",
+    responses(
+        (status = 200, description = "Session destroyed and its locks released"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Not the session's owner"),
+        (status = 404, description = "Session not found")
+    ),
+    params(("session" = Uuid, Path, description = "Session ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(session = %id))]
+async fn destroy_session(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let session = match find_session(&state, &namespace, &id) {
+        Ok(session) => session,
+        Err(response) => return response.into_response(),
+    };
+    if !may_use_session(&session, &actor, &policy) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    match state.registry.remove_session(&id) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("session.destroy", session_target(&namespace, &id)).before(session));
+            state.publish(RegistryEvent::SessionDestroyed { namespace: namespace.0, id });
+            (StatusCode::OK, "Session destroyed").into_response()
+        }
+        Err(RegistryError::SessionNotFound) => (StatusCode::NOT_FOUND, "Session not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+/// The holder of a lock, as seen by the other candidates of an election.
+#[derive(Serialize, ToSchema)]
+struct LockStatus {
+    #[schema(example = "service/report-builder/leader")]
+    key: String,
+    /// Session holding the lock; `None` while nobody does
+    session: Option<uuid::Uuid>,
+    /// Name of the holding session
+    name: Option<String>,
+    /// Instance the holding session follows
+    instance: Option<uuid::Uuid>,
+    /// Set by the holder on acquiring, e.g. to its address
+    value: String,
+    /// How many times the lock has been acquired
+    lock_index: u64,
+    modify_index: u64,
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct LockReadParams {
+    /// Block until the store is written after this `x-logpose-index`
+    index: Option<u64>,
+    /// Seconds to block for with `index`; 60 by default, at most 300
+    wait: Option<u64>,
+}
+
+#[derive(Deserialize, utoipa::IntoParams)]
+struct LockWriteParams {
+    /// Session acquiring or releasing the lock
+    session: uuid::Uuid,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/locks/{key}",
+    responses(
+        (status = 200, description = "The lock's current holder", body = LockStatus),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "The lock was never acquired")
+    ),
+    params(("key" = String, Path, description = "Lock key, e.g. service/report-builder/leader"), LockReadParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(key = %key))]
+async fn get_lock(
+    State(state): State<AppState>,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(key): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<LockReadParams>,
+) -> impl IntoResponse {
+    if !policy.allows(Permission::KvRead, Some(&key)) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    if let Err(response) = wait_for_kv(&state, &namespace, &key, false, params.index, params.wait).await {
+        return response.into_response();
+    }
+    let index = match state.registry.kv_index() {
+        Ok(index) => [(kv::INDEX_HEADER, index.to_string())],
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    let entry = match state.registry.get_key(&namespace, &key) {
+        Ok(entry) => entry,
+        Err(RegistryError::KeyNotFound) => return (StatusCode::NOT_FOUND, index, "Lock not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    let holder = entry.session.and_then(|id| state.registry.get_session(&id).ok());
+    let status = LockStatus {
+        key: entry.key,
+        session: entry.session,
+        name: holder.as_ref().map(|session| session.name.clone()),
+        instance: holder.and_then(|session| session.instance),
+        value: entry.value,
+        lock_index: entry.lock_index,
+        modify_index: entry.modify_index,
+    };
+    (StatusCode::OK, index, Json(status)).into_response()
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/locks/{key}",
+    request_body(content = String, description = "The value to store with the lock, as UTF-8 text", content_type = "text/plain"),
+    responses(
+        (status = 200, description = "Lock acquired, or already held by the session", body = KvEntry),
+        (status = 400, description = "Invalid key"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Session not found"),
+        (status = 409, description = "The lock is held by another session"),
+        (status = 413, description = "Value larger than 512 KiB")
+    ),
+    params(("key" = String, Path, description = "Lock key, e.g. service/report-builder/leader"), LockWriteParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(key = %key, session = %params.session))]
+async fn acquire_lock(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(key): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<LockWriteParams>,
+    value: String,
+) -> impl IntoResponse {
+    if let Err(e) = logpose_core::kv::validate_key(&key) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    if value.len() > logpose_core::kv::MAX_VALUE_LEN {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Value too large").into_response();
+    }
+    if !policy.allows(Permission::Lock, Some(&key)) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    match find_session(&state, &namespace, &params.session) {
+        Ok(session) if !may_use_session(&session, &actor, &policy) => {
+            return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+        }
+        Ok(_) => {}
+        Err(response) => return response.into_response(),
+    }
+    let existing = state.registry.get_key(&namespace, &key).ok();
+    match state.registry.acquire_lock(&namespace, &key, &value, &params.session) {
+        Ok(entry) => {
+            let mut audit_entry = actor.entry("lock.acquire", kv_target(&namespace, &key));
+            if let Some(existing) = existing {
+                audit_entry = audit_entry.before(existing);
+            }
+            audit::record(state.registry.as_ref(), audit_entry.after(&entry));
+            state.publish(RegistryEvent::KeyChanged { namespace: namespace.0, key });
+            let index = [(kv::INDEX_HEADER, entry.modify_index.to_string())];
+            (StatusCode::OK, index, Json(entry)).into_response()
+        }
+        Err(RegistryError::LockHeld) => (StatusCode::CONFLICT, "Lock is held by another session").into_response(),
+        Err(RegistryError::SessionNotFound) => (StatusCode::NOT_FOUND, "Session not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/locks/{key}",
+    responses(
+        (status = 200, description = "Lock released; the key keeps its value", body = KvEntry),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Session not found"),
+        (status = 409, description = "The lock is not held by the session")
+    ),
+    params(("key" = String, Path, description = "Lock key, e.g. service/report-builder/leader"), LockWriteParams),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(key = %key, session = %params.session))]
+async fn release_lock(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    axum::extract::Extension(policy): axum::extract::Extension<Policy>,
+    Path(key): Path<String>,
+    axum::extract::Query(params): axum::extract::Query<LockWriteParams>,
+) -> impl IntoResponse {
+    if !policy.allows(Permission::Lock, Some(&key)) {
+        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+    match find_session(&state, &namespace, &params.session) {
+        Ok(session) if !may_use_session(&session, &actor, &policy) => {
+            return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+        }
+        Ok(_) => {}
+        Err(response) => return response.into_response(),
+    }
+    let existing = state.registry.get_key(&namespace, &key).ok();
+    match state.registry.release_lock(&namespace, &key, &params.session) {
+        Ok(entry) => {
+            let mut audit_entry = actor.entry("lock.release", kv_target(&namespace, &key));
+            if let Some(existing) = existing {
+                audit_entry = audit_entry.before(existing);
+            }
+            audit::record(state.registry.as_ref(), audit_entry.after(&entry));
+            state.publish(RegistryEvent::KeyChanged { namespace: namespace.0, key });
+            (StatusCode::OK, Json(entry)).into_response()
+        }
+        Err(RegistryError::LockNotHeld) => (StatusCode::CONFLICT, "Lock is not held by the session").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateWebhookRequest {
+    #[schema(example = "oncall-alerts")]
+    name: String,
+    #[schema(example = "https://alerts.example.com/hooks/logpose")]
+    url: String,
+    /// Kinds of event delivered; every kind when empty
+    #[serde(default)]
+    events: Vec<logpose_core::webhook::EventKind>,
+    /// Glob of the service codes whose events are delivered; `*` by default
+    services: Option<String>,
+    /// Health changes delivered, by new status; every change when empty
+    #[serde(default)]
+    statuses: Vec<HealthStatus>,
+    /// Key of the payload signature; generated when not given
+    secret: Option<String>,
+}
+
+/// Looks up a webhook of `namespace`; webhooks of other namespaces are not
+/// found.
+fn find_webhook(state: &AppState, namespace: &str, id: &uuid::Uuid) -> Result<Webhook, (StatusCode, &'static str)> {
+    match state.registry.get_webhook(id) {
+        Ok(webhook) if webhook.namespace == namespace => Ok(webhook),
+        Ok(_) | Err(RegistryError::WebhookNotFound) => Err((StatusCode::NOT_FOUND, "Webhook not found")),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed")),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks",
+    responses(
+        (status = 200, description = "Webhooks of the namespace, without their secrets", body = Vec<Webhook>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn list_webhooks(State(state): State<AppState>, namespace: Namespace) -> impl IntoResponse {
+    match state.registry.get_webhooks(&namespace) {
+        Ok(webhooks) => {
+            let webhooks: Vec<Webhook> = webhooks.into_iter().map(Webhook::redacted).collect();
+            (StatusCode::OK, Json(webhooks)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/webhooks",
+    request_body = CreateWebhookRequest,
+    responses(
+        (status = 201, description = "Webhook created; the only response that includes its secret", body = Webhook),
+        (status = 400, description = "Invalid webhook"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %payload.name))]
+async fn create_webhook(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Json(payload): Json<CreateWebhookRequest>,
+) -> impl IntoResponse {
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4(),
+        namespace: namespace.0,
+        name: payload.name,
+        url: payload.url,
+        events: payload.events,
+        services: payload.services.unwrap_or_else(|| "*".to_string()),
+        statuses: payload.statuses,
+        secret: Some(payload.secret.unwrap_or_else(credential::generate_secret)),
+        created_at: logpose_core::time::now(),
+    };
+    if let Err(e) = webhook.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    match state.registry.add_webhook(&webhook) {
+        Ok(_) => {
+            let target = webhook_target(&webhook.namespace, &webhook.id);
+            audit::record(state.registry.as_ref(), actor.entry("webhook.create", target).after(webhook.clone().redacted()));
+            (StatusCode::CREATED, Json(webhook)).into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks/{webhook}",
+    responses(
+        (status = 200, description = "The webhook, without its secret", body = Webhook),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Webhook not found")
+    ),
+    params(("webhook" = Uuid, Path, description = "Webhook ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(webhook = %id))]
+async fn get_webhook(State(state): State<AppState>, namespace: Namespace, Path(id): Path<uuid::Uuid>) -> impl IntoResponse {
+    match find_webhook(&state, &namespace, &id) {
+        Ok(webhook) => (StatusCode::OK, Json(webhook.redacted())).into_response(),
+        Err(response) => response.into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/webhooks/{webhook}",
+    responses(
+        (status = 200, description = "Webhook deleted, with its delivery log and dead letters"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Webhook not found")
+    ),
+    params(("webhook" = Uuid, Path, description = "Webhook ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(webhook = %id))]
+async fn delete_webhook(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    let webhook = match find_webhook(&state, &namespace, &id) {
+        Ok(webhook) => webhook,
+        Err(response) => return response.into_response(),
+    };
+    match state.registry.remove_webhook(&id) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("webhook.delete", webhook_target(&namespace, &id)).before(webhook.redacted()));
+            (StatusCode::OK, "Webhook deleted").into_response()
+        }
+        Err(RegistryError::WebhookNotFound) => (StatusCode::NOT_FOUND, "Webhook not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks/{webhook}/deliveries",
+    responses(
+        (status = 200, description = "The webhook's latest delivery attempts, newest first", body = Vec<DeliveryAttempt>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Webhook not found")
+    ),
+    params(("webhook" = Uuid, Path, description = "Webhook ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(webhook = %id))]
+async fn list_deliveries(State(state): State<AppState>, namespace: Namespace, Path(id): Path<uuid::Uuid>) -> impl IntoResponse {
+    if let Err(response) = find_webhook(&state, &namespace, &id) {
+        return response.into_response();
+    }
+    match state.registry.get_deliveries(&id) {
+        Ok(attempts) => (StatusCode::OK, Json(attempts)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/webhooks/{webhook}/dead-letters",
+    responses(
+        (status = 200, description = "Deliveries that failed on every attempt, oldest first", body = Vec<DeadLetter>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Webhook not found")
+    ),
+    params(("webhook" = Uuid, Path, description = "Webhook ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(webhook = %id))]
+async fn list_dead_letters(State(state): State<AppState>, namespace: Namespace, Path(id): Path<uuid::Uuid>) -> impl IntoResponse {
+    if let Err(response) = find_webhook(&state, &namespace, &id) {
+        return response.into_response();
+    }
+    match state.registry.get_dead_letters(&id) {
+        Ok(dead_letters) => (StatusCode::OK, Json(dead_letters)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+/// Looks up a dead letter of a webhook of `namespace`.
+fn find_dead_letter(state: &AppState, namespace: &str, webhook: &uuid::Uuid, delivery: &uuid::Uuid) -> Result<DeadLetter, (StatusCode, &'static str)> {
+    find_webhook(state, namespace, webhook)?;
+    let dead_letters = state.registry.get_dead_letters(webhook).map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed"))?;
+    dead_letters
+        .into_iter()
+        .find(|dead_letter| dead_letter.delivery == *delivery)
+        .ok_or((StatusCode::NOT_FOUND, "Dead letter not found"))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/webhooks/{webhook}/dead-letters/{delivery}/redeliver",
+    responses(
+        (status = 202, description = "Delivery restarted with a fresh set of attempts"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Webhook or dead letter not found"),
+        (status = 409, description = "Webhooks are disabled on this server")
+    ),
+    params(("webhook" = Uuid, Path, description = "Webhook ID"), ("delivery" = Uuid, Path, description = "Delivery ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(webhook = %webhook, delivery = %delivery))]
+async fn redeliver_dead_letter(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Path((webhook, delivery)): Path<(uuid::Uuid, uuid::Uuid)>,
+) -> impl IntoResponse {
+    if !state.config.webhooks.enabled {
+        return (StatusCode::CONFLICT, "Webhooks are disabled").into_response();
+    }
+    let dead_letter = match find_dead_letter(&state, &namespace, &webhook, &delivery) {
+        Ok(dead_letter) => dead_letter,
+        Err(response) => return response.into_response(),
+    };
+    match state.registry.remove_dead_letter(&delivery) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("webhook.redeliver", webhook_target(&namespace, &webhook)).before(&dead_letter));
+            state.webhooks.deliver(webhook, delivery, dead_letter.kind, dead_letter.payload);
+            (StatusCode::ACCEPTED, "Redelivery started").into_response()
+        }
+        Err(RegistryError::DeadLetterNotFound) => (StatusCode::NOT_FOUND, "Dead letter not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/webhooks/{webhook}/dead-letters/{delivery}",
+    responses(
+        (status = 200, description = "Dead letter discarded"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Webhook or dead letter not found")
+    ),
+    params(("webhook" = Uuid, Path, description = "Webhook ID"), ("delivery" = Uuid, Path, description = "Delivery ID")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(webhook = %webhook, delivery = %delivery))]
+async fn discard_dead_letter(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Path((webhook, delivery)): Path<(uuid::Uuid, uuid::Uuid)>,
+) -> impl IntoResponse {
+    let dead_letter = match find_dead_letter(&state, &namespace, &webhook, &delivery) {
+        Ok(dead_letter) => dead_letter,
+        Err(response) => return response.into_response(),
+    };
+    match state.registry.remove_dead_letter(&delivery) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("webhook.discard", webhook_target(&namespace, &webhook)).before(&dead_letter));
+            (StatusCode::OK, "Dead letter discarded").into_response()
+        }
+        Err(RegistryError::DeadLetterNotFound) => (StatusCode::NOT_FOUND, "Dead letter not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/identities",
+    responses(
+        (status = 200, description = "All identities", body = Vec<Identity>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn list_identities(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match state.registry.list_identities() {
+        Ok(identities) => (StatusCode::OK, Json(identities)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+struct RegisterIdentityRequest {
+    common_name: String,
+    organization: Option<String>,
+    /// Secret the identity exchanges for tokens; without one it can only
+    /// authenticate with a client certificate
+    secret: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/identities",
+    request_body = RegisterIdentityRequest,
+    responses(
+        (status = 201, description = "Identity created"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 409, description = "Identity already exists")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(common_name = %payload.common_name))]
+async fn register_identity(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Json(payload): Json<RegisterIdentityRequest>,
+) -> impl IntoResponse {
+    let identity = Identity {
+        common_name: payload.common_name,
+        organization: payload.organization,
+        roles: [(namespace.0, vec![Role::Viewer])].into(), // Default role
+        disabled: false,
+    };
+
+    // Registering again would reset the identity's roles; its secret is
+    // changed with `logpose-command identity set-secret` instead.
+    if state.registry.get_identity(&identity.common_name).is_ok() {
+        return (StatusCode::CONFLICT, "Identity already exists").into_response();
+    }
+    if state.registry.add_identity(&identity).is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response();
+    }
+    let has_secret = payload.secret.is_some();
+    if let Some(secret) = payload.secret {
+        let hash = tokio::task::spawn_blocking(move || credential::hash_secret(&secret))
+            .await
+            .expect("hashing task panicked");
+        if state.registry.set_identity_secret(&identity.common_name, &hash).is_err() {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response();
+        }
+    }
+    // Only whether a secret was set is recorded, never the secret.
+    let entry = actor.entry("identity.register", identity_target(&identity.common_name))
+        .after(serde_json::json!({ "identity": identity, "secret_set": has_secret }));
+    audit::record(state.registry.as_ref(), entry);
+    (StatusCode::CREATED, "Identity registered").into_response()
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/identities/{cn}",
+    responses(
+        (status = 200, description = "Identity removed; its tokens stop working"),
+        (status = 400, description = "Cannot remove the calling identity"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Identity not found")
+    ),
+    params(("cn" = String, Path, description = "Common Name")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(common_name = %cn))]
+async fn remove_identity(
+    State(state): State<AppState>,
+    actor: Actor,
+    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
+    Path(cn): Path<String>,
+) -> impl IntoResponse {
+    if cn == claims.sub {
+        return (StatusCode::BAD_REQUEST, "Cannot remove the calling identity").into_response();
+    }
+    let identity = state.registry.get_identity(&cn).ok();
+    match state.registry.remove_identity(&cn) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("identity.remove", identity_target(&cn)).before(identity));
+            (StatusCode::OK, "Identity removed").into_response()
+        }
+        Err(RegistryError::IdentityNotFound) => (StatusCode::NOT_FOUND, "Identity not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
 
-    let identity = Identity {
-        common_name: payload.common_name,
-        organization: payload.organization,
-        roles: vec![Role::Viewer], // Default role
-    };
+#[utoipa::path(
+    post,
+    path = "/api/identities/{cn}/disable",
+    responses(
+        (status = 200, description = "Identity disabled; its tokens stop working"),
+        (status = 400, description = "Cannot disable the calling identity"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Identity not found")
+    ),
+    params(("cn" = String, Path, description = "Common Name")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(common_name = %cn))]
+async fn disable_identity(
+    State(state): State<AppState>,
+    actor: Actor,
+    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
+    Path(cn): Path<String>,
+) -> impl IntoResponse {
+    if cn == claims.sub {
+        return (StatusCode::BAD_REQUEST, "Cannot disable the calling identity").into_response();
+    }
+    let identity = state.registry.get_identity(&cn).ok();
+    match state.registry.disable_identity(&cn) {
+        Ok(_) => {
+            let entry = actor.entry("identity.disable", identity_target(&cn))
+                .before(serde_json::json!({ "disabled": identity.map(|i| i.disabled) }))
+                .after(serde_json::json!({ "disabled": true }));
+            audit::record(state.registry.as_ref(), entry);
+            (StatusCode::OK, "Identity disabled").into_response()
+        }
+        Err(RegistryError::IdentityNotFound) => (StatusCode::NOT_FOUND, "Identity not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
 
-    match state.registry.add_identity(&identity) {
-        Ok(_) => (StatusCode::CREATED, "Identity registered").into_response(),
+#[utoipa::path(
+    post,
+    path = "/api/identities/{cn}/enable",
+    responses(
+        (status = 200, description = "Identity enabled"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Identity not found")
+    ),
+    params(("cn" = String, Path, description = "Common Name")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(common_name = %cn))]
+async fn enable_identity(
+    State(state): State<AppState>,
+    actor: Actor,
+    Path(cn): Path<String>,
+) -> impl IntoResponse {
+    let identity = state.registry.get_identity(&cn).ok();
+    match state.registry.enable_identity(&cn) {
+        Ok(_) => {
+            let entry = actor.entry("identity.enable", identity_target(&cn))
+                .before(serde_json::json!({ "disabled": identity.map(|i| i.disabled) }))
+                .after(serde_json::json!({ "disabled": false }));
+            audit::record(state.registry.as_ref(), entry);
+            (StatusCode::OK, "Identity enabled").into_response()
+        }
+        Err(RegistryError::IdentityNotFound) => (StatusCode::NOT_FOUND, "Identity not found").into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
     }
 }
@@ -442,30 +2586,370 @@ struct AssignRoleRequest {
     post,
     path = "/api/identities/{cn}/roles",
     request_body = AssignRoleRequest,
-    responses((status = 200, description = "Role assigned")),
+    responses(
+        (status = 200, description = "Role assigned"),
+        (status = 404, description = "Role not found")
+    ),
     params(("cn" = String, Path, description = "Common Name")),
     security(("api_jwt" = []))
 )]
+#[tracing::instrument(skip_all, fields(common_name = %cn))]
 async fn assign_role(
     State(state): State<AppState>,
-    axum::extract::Extension(claims): axum::extract::Extension<Claims>,
+    actor: Actor,
+    namespace: Namespace,
     Path(cn): Path<String>,
     Json(payload): Json<AssignRoleRequest>,
 ) -> impl IntoResponse {
-    let has_permission = claims.roles.iter().any(|role| {
-        role.permissions().contains(&Permission::UserManage)
-    });
+    if !payload.role.is_builtin() {
+        match state.registry.get_role(payload.role.name()) {
+            Ok(_) => {}
+            Err(RegistryError::RoleNotFound) => return (StatusCode::NOT_FOUND, "Role not found").into_response(),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+        }
+    }
+    let before = role_assignments(&state, &cn, &namespace);
+    match state.registry.add_role_to_identity(&cn, &namespace, payload.role) {
+        Ok(_) => {
+            let entry = actor.entry("identity.role.assign", identity_target(&cn))
+                .before(before)
+                .after(role_assignments(&state, &cn, &namespace));
+            audit::record(state.registry.as_ref(), entry);
+            (StatusCode::OK, "Role assigned").into_response()
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
 
-    if !has_permission {
-        return (StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+#[utoipa::path(
+    delete,
+    path = "/api/identities/{cn}/roles/{role}",
+    responses(
+        (status = 200, description = "Role removed; tokens issued with it stop working"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Identity does not hold the role")
+    ),
+    params(
+        ("cn" = String, Path, description = "Common Name"),
+        ("role" = String, Path, description = "Role name")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(common_name = %cn, role = %role))]
+async fn remove_role(
+    State(state): State<AppState>,
+    actor: Actor,
+    namespace: Namespace,
+    Path((cn, role)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let before = role_assignments(&state, &cn, &namespace);
+    match state.registry.remove_role_from_identity(&cn, &namespace, Role::from(role)) {
+        Ok(_) => {
+            let entry = actor.entry("identity.role.remove", identity_target(&cn))
+                .before(before)
+                .after(role_assignments(&state, &cn, &namespace));
+            audit::record(state.registry.as_ref(), entry);
+            (StatusCode::OK, "Role removed").into_response()
+        }
+        Err(RegistryError::RoleNotFound) => (StatusCode::NOT_FOUND, "Identity does not hold the role").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+/// Roles `common_name` holds in `namespace`, as recorded in the audit log.
+fn role_assignments(state: &AppState, common_name: &str, namespace: &str) -> serde_json::Value {
+    let roles = state.registry.get_identity(common_name).map(|i| i.roles_in(namespace).to_vec()).unwrap_or_default();
+    serde_json::json!({ "namespace": namespace, "roles": roles })
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/roles",
+    responses(
+        (status = 200, description = "Built-in and custom roles", body = Vec<RoleDefinition>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn list_roles(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let custom = match state.registry.get_roles() {
+        Ok(roles) => roles,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    let roles: Vec<RoleDefinition> = [Role::Admin, Role::Agent, Role::Viewer]
+        .iter()
+        .filter_map(RoleDefinition::builtin)
+        .chain(custom)
+        .collect();
+    (StatusCode::OK, Json(roles)).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/roles",
+    request_body = RoleDefinition,
+    responses(
+        (status = 201, description = "Role created"),
+        (status = 400, description = "Invalid role definition"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 409, description = "A role with this name already exists")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %payload.name))]
+async fn create_role(
+    State(state): State<AppState>,
+    actor: Actor,
+    Json(payload): Json<RoleDefinition>,
+) -> impl IntoResponse {
+    if let Err(e) = payload.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+    match state.registry.add_role(&payload) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("role.create", role_target(&payload.name)).after(&payload));
+            (StatusCode::CREATED, "Role created").into_response()
+        }
+        Err(RegistryError::DuplicateRole) => (StatusCode::CONFLICT, "Role already exists").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/roles/{name}",
+    responses(
+        (status = 200, description = "Role deleted and taken away from every identity holding it"),
+        (status = 400, description = "Built-in roles cannot be deleted"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Role not found")
+    ),
+    params(("name" = String, Path, description = "Role name")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(name = %name))]
+async fn delete_role(
+    State(state): State<AppState>,
+    actor: Actor,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    if Role::from(name.clone()).is_builtin() {
+        return (StatusCode::BAD_REQUEST, "Built-in roles cannot be deleted").into_response();
     }
+    let role = state.registry.get_role(&name).ok();
+    match state.registry.remove_role(&name) {
+        Ok(_) => {
+            audit::record(state.registry.as_ref(), actor.entry("role.delete", role_target(&name)).before(role));
+            (StatusCode::OK, "Role deleted").into_response()
+        }
+        Err(RegistryError::RoleNotFound) => (StatusCode::NOT_FOUND, "Role not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    }
+}
 
-    match state.registry.add_role_to_identity(&cn, payload.role) {
-        Ok(_) => (StatusCode::OK, "Role assigned").into_response(),
+/// Records returned by `GET /api/audit` when no `limit` is given, and the most it returns.
+const AUDIT_DEFAULT_LIMIT: u32 = 100;
+const AUDIT_MAX_LIMIT: u32 = 1000;
+
+#[utoipa::path(
+    get,
+    path = "/api/audit",
+    responses(
+        (status = 200, description = "Matching audit records, oldest first", body = Vec<logpose_core::audit::AuditRecord>),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    params(AuditFilter),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn list_audit(
+    State(state): State<AppState>,
+    axum::extract::Query(mut filter): axum::extract::Query<AuditFilter>,
+) -> impl IntoResponse {
+    filter.limit = Some(filter.limit.unwrap_or(AUDIT_DEFAULT_LIMIT).min(AUDIT_MAX_LIMIT));
+    match state.registry.get_audit(&filter) {
+        Ok(records) => (StatusCode::OK, Json(records)).into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
     }
 }
 
+#[derive(Serialize, Deserialize, ToSchema)]
+struct AuditVerification {
+    valid: bool,
+    /// Number of records checked
+    records: usize,
+    /// Where the chain breaks, when it does
+    error: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/audit/verify",
+    responses(
+        (status = 200, description = "Result of checking the whole hash chain", body = AuditVerification),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all)]
+async fn verify_audit(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let records = match state.registry.get_audit(&AuditFilter::default()) {
+        Ok(records) => records,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    let head = match state.registry.get_audit_head() {
+        Ok(head) => head,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response(),
+    };
+    let result = logpose_core::audit::verify(&records, head.as_ref());
+    let verification = AuditVerification {
+        valid: result.is_ok(),
+        records: records.len(),
+        error: result.err().map(|e| e.to_string()),
+    };
+    (StatusCode::OK, Json(verification)).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/cluster",
+    responses(
+        (status = 200, description = "This node's view of the cluster", body = raft::ClusterStatus),
+        (status = 401, description = "Unauthorized"),
+        (status = 404, description = "Clustering is not enabled")
+    ),
+    security(("api_jwt" = []))
+)]
+async fn cluster_status(State(state): State<AppState>) -> impl IntoResponse {
+    match state.registry.raft() {
+        Some(raft) => (StatusCode::OK, Json(raft.status())).into_response(),
+        None => (StatusCode::NOT_FOUND, "Clustering is not enabled").into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/federation",
+    responses(
+        (status = 200, description = "This server's datacenter and its peers", body = federation::FederationStatus),
+        (status = 401, description = "Unauthorized"),
+        (status = 404, description = "Federation is not enabled")
+    ),
+    security(("api_jwt" = []))
+)]
+async fn federation_status(State(state): State<AppState>) -> impl IntoResponse {
+    match &state.federation {
+        Some(federation) => (StatusCode::OK, Json(federation.status())).into_response(),
+        None => (StatusCode::NOT_FOUND, "Federation is not enabled").into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/cluster/members",
+    request_body = raft::Member,
+    responses(
+        (status = 200, description = "Member added; it receives the registry from the leader"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Clustering is not enabled"),
+        (status = 409, description = "Already a member, or another membership change is in progress"),
+        (status = 503, description = "The change could not be committed")
+    ),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(node = member.id))]
+async fn add_cluster_member(
+    State(state): State<AppState>,
+    actor: Actor,
+    Json(member): Json<raft::Member>,
+) -> impl IntoResponse {
+    let Some(raft) = state.registry.raft() else {
+        return (StatusCode::NOT_FOUND, "Clustering is not enabled").into_response();
+    };
+    if member.id == 0 || !(member.url.starts_with("http://") || member.url.starts_with("https://")) {
+        return (StatusCode::BAD_REQUEST, "A member needs a non-zero id and an http(s) URL").into_response();
+    }
+    match raft.add_member(member.clone()).await {
+        Ok(()) => {
+            let target = logpose_core::audit::member_target(member.id);
+            audit::record(state.registry.as_ref(), actor.entry("cluster.member.add", target).after(&member));
+            (StatusCode::OK, "Member added").into_response()
+        }
+        Err(e) => membership_error(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/cluster/members/{id}",
+    responses(
+        (status = 200, description = "Member removed"),
+        (status = 401, description = "Unauthorized"),
+        (status = 403, description = "Insufficient permissions"),
+        (status = 404, description = "Not a member, or clustering is not enabled"),
+        (status = 409, description = "The last member, or another membership change is in progress"),
+        (status = 503, description = "The change could not be committed")
+    ),
+    params(("id" = u64, Path, description = "Node id")),
+    security(("api_jwt" = []))
+)]
+#[tracing::instrument(skip_all, fields(node = id))]
+async fn remove_cluster_member(
+    State(state): State<AppState>,
+    actor: Actor,
+    Path(id): Path<u64>,
+) -> impl IntoResponse {
+    let Some(raft) = state.registry.raft() else {
+        return (StatusCode::NOT_FOUND, "Clustering is not enabled").into_response();
+    };
+    let status = raft.status();
+    let Some(member) = status.members.iter().find(|m| m.id == id).cloned() else {
+        return membership_error(raft::ProposeError::NotMember(id));
+    };
+    let entry = actor.entry("cluster.member.remove", logpose_core::audit::member_target(id)).before(&member);
+    // A leader removing itself stops leading once the change commits and
+    // could no longer append the record, so it does so beforehand.
+    let entry = match id == status.node_id {
+        true => {
+            audit::record(state.registry.as_ref(), entry);
+            None
+        }
+        false => Some(entry),
+    };
+    match raft.remove_member(id).await {
+        Ok(()) => {
+            if let Some(entry) = entry {
+                audit::record(state.registry.as_ref(), entry);
+            }
+            (StatusCode::OK, "Member removed").into_response()
+        }
+        Err(e) => membership_error(e),
+    }
+}
+
+fn membership_error(error: raft::ProposeError) -> Response {
+    use raft::ProposeError::*;
+    let status = match error {
+        NotMember(_) => StatusCode::NOT_FOUND,
+        AlreadyMember(_) | MembershipPending | LastMember => StatusCode::CONFLICT,
+        NotLeader | NotCommitted(_) => StatusCode::SERVICE_UNAVAILABLE,
+        Registry(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, error.to_string()).into_response()
+}
+
 #[utoipa::path(
     get,
     path = "/health",
@@ -475,13 +2959,22 @@ async fn health_check() -> impl IntoResponse {
     (StatusCode::OK, "OK").into_response()
 }
 
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    responses((status = 200, description = "Public keys that verify LogPose tokens (empty with HS256)"))
+)]
+async fn jwks(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.keys.current().jwks().clone())
+}
+
 async fn auth_middleware<B>(
     State(state): State<AppState>,
     mut req: Request<B>,
     next: Next<B>,
 ) -> Result<Response, StatusCode> {
     let path = req.uri().path();
-    if path == "/api/auth/token" || path == "/health" || path == "/metrics" || path.starts_with("/swagger-ui") || path.starts_with("/api-docs") {
+    if path == "/api/auth/token" || path == "/api/auth/refresh" || path == "/health" || path == "/.well-known/jwks.json" || path == state.config.metrics.path || path.starts_with("/swagger-ui") || path.starts_with("/api-docs") {
         return Ok(next.run(req).await);
     }
 
@@ -490,17 +2983,279 @@ async fn auth_middleware<B>(
         .and_then(|h| h.to_str().ok())
         .and_then(|h| h.strip_prefix("Bearer "));
 
-    match auth_header {
-        Some(token) => {
-            let token_data = decode::<Claims>(
-                token,
-                &DecodingKey::from_secret(state.jwt_secret.as_ref()),
-                &Validation::new(Algorithm::HS256),
-            ).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let client_cert = req.extensions().get::<Option<tls::ClientCertificate>>().cloned().flatten();
+
+    match (auth_header, client_cert) {
+        (Some(token), _) => {
+            let claims = tokens::decode_access(token, &state.keys.current()).map_err(|_| StatusCode::UNAUTHORIZED)?;
+            if state.registry.is_token_revoked(&claims.jti).unwrap_or(true) {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+
+            // A token carries the roles held when it was issued; it stops
+            // working as soon as any of them is taken away.
+            let identity = state.registry.get_identity(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+            let roles_kept = claims.roles.iter().all(|(namespace, roles)| {
+                roles.iter().all(|role| identity.roles_in(namespace).contains(role))
+            });
+            if identity.disabled || !roles_kept {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
 
-            req.extensions_mut().insert(token_data.claims);
+            req.extensions_mut().insert(claims);
+            Ok(next.run(req).await)
+        }
+        (None, Some(cert)) => {
+            let claims = client_cert_claims(&state, &cert).ok_or(StatusCode::UNAUTHORIZED)?;
+            req.extensions_mut().insert(claims);
             Ok(next.run(req).await)
         }
-        None => Err(StatusCode::UNAUTHORIZED),
+        (None, None) => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Maps a verified client certificate onto the registered `Identity` with the
+/// same common name. When the identity records an organization, the
+/// certificate's O attribute must match it.
+fn client_cert_claims(state: &AppState, cert: &tls::ClientCertificate) -> Option<Claims> {
+    let identity = state.registry.get_identity(&cert.common_name).ok()?;
+    if identity.disabled {
+        tracing::debug!(common_name = %cert.common_name, "client certificate for disabled identity");
+        return None;
+    }
+    if identity.organization.is_some() && identity.organization != cert.organization {
+        tracing::debug!(common_name = %cert.common_name, "client certificate organization mismatch");
+        return None;
+    }
+    Some(Claims {
+        sub: identity.common_name,
+        roles: identity.roles,
+        exp: cert.not_after.max(0) as usize,
+        iat: tokens::now(),
+        jti: String::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::testing;
+
+    async fn post(url: &str, token: Option<&str>, body: serde_json::Value) -> reqwest::Response {
+        let request = reqwest::Client::new().post(url).json(&body);
+        let request = match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        request.send().await.unwrap()
+    }
+
+    async fn get(url: &str, token: &str) -> reqwest::StatusCode {
+        reqwest::Client::new().get(url).bearer_auth(token).send().await.unwrap().status()
+    }
+
+    fn instance(address: &str, metadata: &[(&str, &str)]) -> logpose_core::ServiceInstance {
+        let runtime = logpose_core::Runtime::Vm { provider: None, id: None };
+        let mut instance =
+            logpose_core::ServiceInstance::new("billing-api", address.parse().unwrap(), logpose_core::Protocol::Http, runtime, 0);
+        for (key, value) in metadata {
+            instance.add_metadata(*key, *value);
+        }
+        instance
+    }
+
+    #[test]
+    fn prometheus_targets_use_the_metrics_port_and_bracket_ipv6() {
+        let target = |instance| prometheus_target_group(instance).targets;
+        assert_eq!(target(instance("10.0.0.1:8080", &[])), ["10.0.0.1:8080"]);
+        assert_eq!(target(instance("10.0.0.1:8080", &[("metrics", "9100")])), ["10.0.0.1:9100"]);
+        assert_eq!(target(instance("10.0.0.1:8080", &[("metrics", "exporter:9100")])), ["exporter:9100"]);
+        assert_eq!(target(instance("[::1]:8080", &[])), ["[::1]:8080"]);
+        assert_eq!(target(instance("[::1]:8080", &[("metrics", "9100")])), ["[::1]:9100"]);
+        assert_eq!(target(instance("[fd00::2]:8080", &[("metrics", "9100")])), ["[fd00::2]:9100"]);
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_work_once() {
+        let state = testing::state(Config::default());
+        let identity = testing::identity(&state, "ci", &[("default", Role::Viewer)]);
+        let pair = tokens::issue(&identity, &state.config.auth, &state.keys.current()).unwrap();
+        let url = format!("{}/api/auth/refresh", testing::api(&state));
+
+        let refreshed = post(&url, None, json!({ "refresh_token": pair.refresh_token })).await;
+        assert_eq!(refreshed.status(), reqwest::StatusCode::OK);
+        let refreshed: AuthResponse = refreshed.json().await.unwrap();
+        let replayed = post(&url, None, json!({ "refresh_token": pair.refresh_token })).await;
+        assert_eq!(replayed.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // Access tokens are not refresh tokens; the new refresh token works once too.
+        let access = post(&url, None, json!({ "refresh_token": pair.access_token })).await;
+        assert_eq!(access.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let next = post(&url, None, json!({ "refresh_token": refreshed.refresh_token })).await;
+        assert_eq!(next.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_rejected() {
+        let state = testing::state(Config::default());
+        let identity = testing::identity(&state, "ci", &[("default", Role::Viewer)]);
+        let other = testing::identity(&state, "other", &[("default", Role::Viewer)]);
+        let (url, token) = (testing::api(&state), testing::token(&state, &identity));
+        let services = format!("{}/api/services", url);
+        assert_eq!(get(&services, &token).await, reqwest::StatusCode::OK);
+
+        // Revoking another identity's token needs UserManage.
+        let other_token = testing::token(&state, &other);
+        let revoke = format!("{}/api/auth/revoke", url);
+        let refused = post(&revoke, Some(&token), json!({ "token": other_token })).await;
+        assert_eq!(refused.status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(get(&services, &other_token).await, reqwest::StatusCode::OK);
+
+        let revoked = post(&revoke, Some(&token), json!({ "token": token })).await;
+        assert_eq!(revoked.status(), reqwest::StatusCode::OK);
+        assert_eq!(get(&services, &token).await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(get(&services, &testing::token(&state, &identity)).await, reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn removing_a_role_invalidates_tokens_carrying_it() {
+        let state = testing::state(Config::default());
+        let identity = testing::identity(&state, "ci", &[("default", Role::Viewer), ("payments", Role::Agent)]);
+        let (url, token) = (testing::api(&state), testing::token(&state, &identity));
+        let services = format!("{}/api/services", url);
+        assert_eq!(get(&services, &token).await, reqwest::StatusCode::OK);
+
+        // Adding a role leaves existing tokens valid; removing one does not.
+        state.registry.add_role_to_identity("ci", "payments", Role::Viewer).unwrap();
+        assert_eq!(get(&services, &token).await, reqwest::StatusCode::OK);
+        state.registry.remove_role_from_identity("ci", "payments", Role::Agent).unwrap();
+        assert_eq!(get(&services, &token).await, reqwest::StatusCode::UNAUTHORIZED);
+
+        let identity = state.registry.get_identity("ci").unwrap();
+        assert_eq!(get(&services, &testing::token(&state, &identity)).await, reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn tokens_need_the_identity_secret() {
+        let state = testing::state(Config::default());
+        testing::identity(&state, "ci", &[("default", Role::Viewer)]);
+        testing::identity(&state, "cert-only", &[("default", Role::Viewer)]);
+        state.registry.set_identity_secret("ci", &credential::hash_secret("s3cret")).unwrap();
+        let url = format!("{}/api/auth/token", testing::api(&state));
+        let status = |common_name: &str, secret: &str| {
+            let body = json!({ "common_name": common_name, "secret": secret });
+            let url = url.clone();
+            async move { post(&url, None, body).await.status() }
+        };
+
+        assert_eq!(status("ci", "s3cret").await, reqwest::StatusCode::OK);
+        assert_eq!(status("ci", "wrong").await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(status("ci", "").await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(status("cert-only", "").await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(status("cert-only", "s3cret").await, reqwest::StatusCode::UNAUTHORIZED);
+        assert_eq!(status("unknown", "s3cret").await, reqwest::StatusCode::UNAUTHORIZED);
+
+        state.registry.disable_identity("ci").unwrap();
+        assert_eq!(status("ci", "s3cret").await, reqwest::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn set_secret_replaces_the_previous_secret() {
+        let state = testing::state(Config::default());
+        testing::identity(&state, "ci", &[("default", Role::Viewer)]);
+        let base = testing::api(&state);
+        let url = format!("{}/api/auth/token", base);
+        let status = |secret: &str| {
+            let body = json!({ "common_name": "ci", "secret": secret });
+            let url = url.clone();
+            async move { post(&url, None, body).await.status() }
+        };
+
+        // As `logpose-command identity set-secret` stores it.
+        state.registry.set_identity_secret("ci", &credential::hash_secret("first")).unwrap();
+        assert_eq!(status("first").await, reqwest::StatusCode::OK);
+        let generated = credential::generate_secret();
+        state.registry.set_identity_secret("ci", &credential::hash_secret(&generated)).unwrap();
+        assert_eq!(status(&generated).await, reqwest::StatusCode::OK);
+        assert_eq!(status("first").await, reqwest::StatusCode::UNAUTHORIZED);
+
+        let issued: AuthResponse = post(&url, None, json!({ "common_name": "ci", "secret": generated })).await.json().await.unwrap();
+        assert_eq!(get(&format!("{}/api/services", base), &issued.token).await, reqwest::StatusCode::OK);
+    }
+
+    async fn patch(url: &str, token: &str, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new().patch(url).bearer_auth(token).json(&body).send().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn patching_a_service_sets_clears_and_keeps_fields() {
+        let state = testing::state(Config::default());
+        let mut service = Service::new("Auth", "auth-svc", "Handles logins");
+        service.catalog = Catalog {
+            owner: Some("identity-team".to_string()),
+            on_call: Some("#identity-oncall".to_string()),
+            repository: Some("https://github.com/example/auth-svc".to_string()),
+            tier: Some(logpose_core::Tier::Critical),
+            tags: vec!["auth".to_string()],
+        };
+        state.registry.add_service(&service).unwrap();
+        let identity = testing::identity(&state, "ci", &[("default", Role::Admin)]);
+        let (url, token) = (testing::api(&state), testing::token(&state, &identity));
+        let auth = format!("{}/api/services/auth-svc", url);
+
+        let patched = patch(&auth, &token, json!({ "owner": "platform-team", "on_call": null, "tags": ["auth", "public"] })).await;
+        assert_eq!(patched.status(), reqwest::StatusCode::OK);
+        let stored = state.registry.get_service("default", "auth-svc").unwrap();
+        assert_eq!(stored.name, "Auth");
+        assert_eq!(stored.catalog.owner.as_deref(), Some("platform-team"));
+        assert_eq!(stored.catalog.on_call, None);
+        assert_eq!(stored.catalog.repository, service.catalog.repository);
+        assert_eq!(stored.catalog.tier, Some(logpose_core::Tier::Critical));
+        assert_eq!(stored.catalog.tags, ["auth", "public"]);
+
+        // Invalid changes are refused as a whole.
+        let invalid = patch(&auth, &token, json!({ "tier": "low", "repository": "ftp://example.com/auth" })).await;
+        assert_eq!(invalid.status(), reqwest::StatusCode::BAD_REQUEST);
+        let own = patch(&auth, &token, json!({ "dependencies": ["auth-svc"] })).await;
+        assert_eq!(own.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(state.registry.get_service("default", "auth-svc").unwrap().catalog.tier, Some(logpose_core::Tier::Critical));
+
+        let missing = patch(&format!("{}/api/services/unknown", url), &token, json!({ "owner": null })).await;
+        assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn services_are_searched_by_catalog_fields() {
+        let state = testing::state(Config::default());
+        for (code, owner, tier, tags) in [
+            ("auth-svc", "identity-team", "critical", &["auth", "public"][..]),
+            ("users-db", "identity-team", "high", &["db"][..]),
+            ("billing", "payments-team", "critical", &["public"][..]),
+        ] {
+            let mut service = testing::service(&state, "default", code);
+            service.catalog.owner = Some(owner.to_string());
+            service.catalog.tier = Some(tier.parse().unwrap());
+            service.catalog.tags = tags.iter().map(|tag| tag.to_string()).collect();
+            state.registry.update_service(&service).unwrap();
+        }
+        let identity = testing::identity(&state, "ci", &[("default", Role::Viewer)]);
+        let (url, token) = (testing::api(&state), testing::token(&state, &identity));
+        let search = |query: &str| {
+            let request = reqwest::Client::new().get(format!("{}/api/services?{}", url, query)).bearer_auth(&token);
+            async move {
+                let services: Vec<Service> = request.send().await.unwrap().json().await.unwrap();
+                services.into_iter().map(|service| service.code).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(search("owner=Identity-Team").await, ["auth-svc", "users-db"]);
+        assert_eq!(search("tier=critical").await, ["auth-svc", "billing"]);
+        assert_eq!(search("tags=public").await, ["auth-svc", "billing"]);
+        assert_eq!(search("tags=public,auth").await, ["auth-svc"]);
+        assert_eq!(search("owner=identity-team&tier=critical").await, ["auth-svc"]);
+        assert!(search("owner=identity-team&tags=public&tier=high").await.is_empty());
     }
 }
@@ -0,0 +1,135 @@
+//! Namespace selection for API requests.
+//!
+//! `/api/namespaces/{namespace}/...` serves the same routes as `/api/...`
+//! within `namespace`; paths without the prefix address the default
+//! namespace, so clients written before namespaces keep working.
+
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, uri::PathAndQuery, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use logpose_core::{namespace, DEFAULT_NAMESPACE};
+
+const PREFIX: &str = "/api/namespaces/";
+
+/// The namespace a request addresses.
+#[derive(Debug, Clone)]
+pub struct Namespace(pub String);
+
+impl Default for Namespace {
+    fn default() -> Self {
+        Self(DEFAULT_NAMESPACE.to_string())
+    }
+}
+
+impl std::ops::Deref for Namespace {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Namespace {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Namespace>().cloned().unwrap_or_default())
+    }
+}
+
+/// Strips the namespace prefix before routing and records the namespace as
+/// a request extension. Must wrap the router, since routing uses the path.
+pub async fn select<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let Some(rest) = req.uri().path().strip_prefix(PREFIX) else {
+        return next.run(req).await;
+    };
+    let (name, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if let Err(e) = namespace::validate(name) {
+        return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+    }
+
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("/api/{}?{}", path, query),
+        None => format!("/api/{}", path),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).expect("derived from a valid URI"));
+    let namespace = Namespace(name.to_string());
+    *req.uri_mut() = Uri::from_parts(parts).expect("derived from a valid URI");
+    req.extensions_mut().insert(namespace);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use logpose_core::{Role, Service};
+    use reqwest::StatusCode;
+
+    use crate::config::Config;
+    use crate::testing;
+
+    async fn get(url: String, token: &str) -> reqwest::Response {
+        reqwest::Client::new().get(url).bearer_auth(token).send().await.unwrap()
+    }
+
+    async fn codes(response: reqwest::Response) -> Vec<String> {
+        assert_eq!(response.status(), StatusCode::OK);
+        let services: Vec<Service> = response.json().await.unwrap();
+        services.into_iter().map(|service| service.code).collect()
+    }
+
+    #[tokio::test]
+    async fn prefixed_paths_address_their_namespace() {
+        let state = testing::state(Config::default());
+        testing::service(&state, "default", "auth");
+        testing::service(&state, "payments", "billing");
+        let identity = testing::identity(&state, "ci", &[("default", Role::Viewer), ("payments", Role::Viewer)]);
+        let (url, token) = (testing::api(&state), testing::token(&state, &identity));
+
+        assert_eq!(codes(get(format!("{}/api/services", url), &token).await).await, ["auth"]);
+        assert_eq!(codes(get(format!("{}/api/namespaces/default/services", url), &token).await).await, ["auth"]);
+        assert_eq!(codes(get(format!("{}/api/namespaces/payments/services", url), &token).await).await, ["billing"]);
+        let billing = get(format!("{}/api/namespaces/payments/services/billing", url), &token).await;
+        assert_eq!(billing.status(), StatusCode::OK);
+        let missing = get(format!("{}/api/services/billing", url), &token).await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn roles_only_apply_in_their_namespace() {
+        let state = testing::state(Config::default());
+        testing::service(&state, "default", "auth");
+        testing::service(&state, "payments", "billing");
+        let identity = testing::identity(&state, "payments-ci", &[("payments", Role::Admin)]);
+        let (url, token) = (testing::api(&state), testing::token(&state, &identity));
+
+        assert_eq!(codes(get(format!("{}/api/namespaces/payments/services", url), &token).await).await, ["billing"]);
+        for path in ["/api/services", "/api/services/auth", "/api/namespaces/default/services", "/api/namespaces/default/services/auth"] {
+            assert_eq!(get(format!("{}{}", url, path), &token).await.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+        let other = get(format!("{}/api/namespaces/shipping/services", url), &token).await;
+        assert_eq!(other.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn invalid_namespaces_are_rejected() {
+        let state = testing::state(Config::default());
+        let identity = testing::identity(&state, "ci", &[("default", Role::Admin)]);
+        let (url, token) = (testing::api(&state), testing::token(&state, &identity));
+
+        for namespace in ["Payments", "-payments", "payments-", "pay_ments", &"a".repeat(64)] {
+            let response = get(format!("{}/api/namespaces/{}/services", url, namespace), &token).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", namespace);
+            assert!(response.text().await.unwrap().contains("invalid namespace"));
+        }
+        let empty = get(format!("{}/api/namespaces//services", url), &token).await;
+        assert_eq!(empty.status(), StatusCode::BAD_REQUEST);
+    }
+}
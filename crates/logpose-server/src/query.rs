@@ -0,0 +1,101 @@
+//! Executing prepared queries.
+//!
+//! A query is answered from its own service when any instance passes its
+//! filters. Otherwise its failover targets are tried in order: another
+//! service is looked up in the query's namespace, another datacenter is
+//! asked through federation for the query's service. The filters apply to
+//! every target, and the first one with a matching instance answers.
+
+use std::time::Duration;
+
+use logpose_core::query::{FailoverTarget, Locality};
+use logpose_core::{Permission, Policy, PreparedQuery, RegistryStore, ServiceInstance};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::federation::Freshness;
+use crate::AppState;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QueryResult {
+    pub query: String,
+    /// Service the instances belong to
+    pub service: String,
+    /// Datacenter the instances are in; `None` unless federation is enabled
+    pub datacenter: Option<String>,
+    /// Failover targets tried before one answered; 0 when the query's own
+    /// service did
+    pub failovers: usize,
+    /// Matching instances, nearest first
+    pub instances: Vec<ServiceInstance>,
+}
+
+/// A query's result, with the cache state of an answer from another
+/// datacenter.
+pub struct Execution {
+    pub result: QueryResult,
+    pub cache: Option<(Freshness, Duration)>,
+}
+
+/// Runs `query`, ordering instances nearest to `near`. Services the caller
+/// may not read, datacenters that are unknown or unreachable, and
+/// datacenters while federation is disabled are skipped.
+pub async fn execute(state: &AppState, policy: &Policy, query: &PreparedQuery, near: Option<&Locality>) -> Execution {
+    let local_datacenter = state.federation.as_ref().map(|f| f.datacenter().to_string());
+    let local = |code: &str| -> Vec<ServiceInstance> {
+        if !policy.allows(Permission::InstanceRead, Some(code)) {
+            return Vec::new();
+        }
+        let instances = state.registry.get_instances(&query.namespace, code).unwrap_or_default();
+        query.select(instances, near)
+    };
+    let answer = |service: &str, failovers: usize, instances: Vec<ServiceInstance>, datacenter: Option<String>| QueryResult {
+        query: query.name.clone(),
+        service: service.to_string(),
+        datacenter,
+        failovers,
+        instances,
+    };
+
+    let instances = local(&query.service);
+    if !instances.is_empty() {
+        return Execution { result: answer(&query.service, 0, instances, local_datacenter), cache: None };
+    }
+
+    for (tried, target) in query.failover.iter().enumerate() {
+        match target {
+            FailoverTarget::Service(code) => {
+                let instances = local(code);
+                if !instances.is_empty() {
+                    return Execution { result: answer(code, tried + 1, instances, local_datacenter), cache: None };
+                }
+            }
+            FailoverTarget::Datacenter(dc) => {
+                if !policy.allows(Permission::InstanceRead, Some(&query.service)) {
+                    continue;
+                }
+                let Some(federation) = &state.federation else {
+                    tracing::debug!(query = %query.name, datacenter = %dc, "skipping failover: federation is not enabled");
+                    continue;
+                };
+                if dc == federation.datacenter() {
+                    continue;
+                }
+                match federation.discover(dc, &query.namespace, &query.service).await {
+                    Ok(remote) => {
+                        let instances = query.select(remote.instances, near);
+                        if !instances.is_empty() {
+                            return Execution {
+                                result: answer(&query.service, tried + 1, instances, Some(remote.datacenter)),
+                                cache: Some((remote.freshness, remote.age)),
+                            };
+                        }
+                    }
+                    Err(e) => tracing::debug!(query = %query.name, "skipping failover: {}", e),
+                }
+            }
+        }
+    }
+
+    Execution { result: answer(&query.service, query.failover.len(), Vec::new(), local_datacenter), cache: None }
+}
@@ -0,0 +1,1323 @@
+//! Raft consensus between clustered servers.
+//!
+//! The leader appends every registry write to a replicated log; once a
+//! majority of nodes hold an entry it is committed and each node applies it
+//! to its own database, in log order. A write acknowledged by the leader
+//! therefore survives the loss of any minority of nodes. Nodes exchange
+//! JSON over HTTP, authenticated by `cluster.secret`:
+//!
+//! - `POST /cluster/raft/vote`: RequestVote
+//! - `POST /cluster/raft/append`: AppendEntries, which doubles as heartbeat
+//! - `POST /cluster/raft/snapshot`: InstallSnapshot, for nodes that are
+//!   missing entries the leader has already discarded
+//!
+//! Membership changes one node at a time, so the majorities before and
+//! after a change always overlap, and a change takes effect as soon as it is
+//! in a node's log. Every `cluster.snapshot_threshold` applied entries the
+//! registry is snapshotted and the log up to that point discarded.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use logpose_core::RegistryError;
+use logpose_db::raft::{RaftStorage, StoredEntry, StoredSnapshot};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::sync::{oneshot, Notify};
+use utoipa::ToSchema;
+
+use crate::cluster::{Applied, Command, Machine};
+use crate::config::ClusterConfig;
+
+/// Header carrying `cluster.secret` on requests between nodes.
+pub const SECRET_HEADER: &str = "x-logpose-cluster-secret";
+
+const MAX_ENTRIES_PER_APPEND: usize = 256;
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(10);
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum RaftError {
+    #[error("failed to access the Raft log: {0}")]
+    Storage(String),
+
+    #[error("invalid Raft log: {0}")]
+    Corrupt(String),
+
+    #[error("failed to load the replicated registry: {0}")]
+    Registry(#[from] RegistryError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProposeError {
+    #[error("this node is not the cluster leader")]
+    NotLeader,
+
+    #[error("a membership change is already in progress")]
+    MembershipPending,
+
+    #[error("node {0} is already a member")]
+    AlreadyMember(u64),
+
+    #[error("node {0} is not a member")]
+    NotMember(u64),
+
+    #[error("the last member cannot be removed")]
+    LastMember,
+
+    #[error("the write was not committed: {0}")]
+    NotCommitted(&'static str),
+
+    #[error(transparent)]
+    Registry(#[from] RegistryError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Member {
+    pub id: u64,
+    /// Base URL of the node's HTTP API, its `cluster.advertise_url`
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+pub enum NodeRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClusterStatus {
+    pub node_id: u64,
+    pub role: NodeRole,
+    pub term: u64,
+    pub leader: Option<Member>,
+    /// Last log entry known to be held by a majority
+    pub commit_index: u64,
+    /// Last log entry applied to this node's registry
+    pub last_applied: u64,
+    pub last_log_index: u64,
+    /// Last log entry covered by this node's snapshot
+    pub snapshot_index: u64,
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Payload {
+    /// Appended by a new leader to commit the entries of earlier terms
+    Noop,
+    Command(Box<Command>),
+    Membership(Vec<Member>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    index: u64,
+    term: u64,
+    payload: Payload,
+}
+
+impl Entry {
+    fn stored(&self) -> StoredEntry {
+        StoredEntry {
+            index: self.index,
+            term: self.term,
+            data: serde_json::to_string(&self.payload).expect("log entries are serializable"),
+        }
+    }
+
+    fn from_stored(stored: StoredEntry) -> Result<Self, RaftError> {
+        let payload = serde_json::from_str(&stored.data)
+            .map_err(|e| RaftError::Corrupt(format!("entry {}: {}", stored.index, e)))?;
+        Ok(Self { index: stored.index, term: stored.term, payload })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VoteRequest {
+    term: u64,
+    candidate_id: u64,
+    last_log_index: u64,
+    last_log_term: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VoteResponse {
+    term: u64,
+    granted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendRequest {
+    term: u64,
+    leader: Member,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<Entry>,
+    leader_commit: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendResponse {
+    term: u64,
+    success: bool,
+    /// On failure, where the leader should resume sending from
+    conflict_index: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotRequest {
+    term: u64,
+    leader: Member,
+    last_included_index: u64,
+    last_included_term: u64,
+    members: Vec<Member>,
+    data: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotResponse {
+    term: u64,
+}
+
+type Waiter = oneshot::Sender<Result<Applied, RegistryError>>;
+
+/// Volatile and persistent state, guarded by one lock. Persistent fields are
+/// written to storage before any reply depending on them leaves the node.
+struct Core {
+    role: NodeRole,
+    term: u64,
+    voted_for: Option<u64>,
+    leader: Option<Member>,
+    /// Entries after the snapshot
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_members: Vec<Member>,
+    /// Latest membership in the log, committed or not
+    members: Vec<Member>,
+    commit_index: u64,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    in_flight: HashSet<u64>,
+    votes: HashSet<u64>,
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+    leader_contact: Option<Instant>,
+    /// Proposals made on this node, by log index, with the term they were
+    /// made in
+    waiters: HashMap<u64, (u64, Waiter)>,
+}
+
+impl Core {
+    fn last_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_index, |e| e.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot_term, |e| e.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot_index {
+            return None;
+        }
+        self.log.get((index - self.snapshot_index - 1) as usize)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    fn members_at(&self, index: u64) -> Vec<Member> {
+        self.log
+            .iter()
+            .rev()
+            .filter(|e| e.index <= index)
+            .find_map(|e| match &e.payload {
+                Payload::Membership(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot_members.clone())
+    }
+
+    fn refresh_members(&mut self) {
+        self.members = self.members_at(u64::MAX);
+    }
+
+    fn is_member(&self, id: u64) -> bool {
+        self.members.iter().any(|m| m.id == id)
+    }
+
+    fn membership_pending(&self) -> bool {
+        self.log
+            .iter()
+            .any(|e| e.index > self.commit_index && matches!(e.payload, Payload::Membership(_)))
+    }
+
+    /// Drops entries up to and including `index`, which a snapshot covers.
+    fn discard_through(&mut self, index: u64) {
+        let keep = self.log.iter().position(|e| e.index > index).unwrap_or(self.log.len());
+        self.log.drain(..keep);
+    }
+}
+
+pub struct Raft {
+    id: u64,
+    config: ClusterConfig,
+    storage: RaftStorage,
+    machine: Machine,
+    core: Mutex<Core>,
+    /// Index of the last entry applied to the registry. Held while applying
+    /// or restoring, so a snapshot always matches the index it is taken at.
+    /// Taken before `core` when both are needed.
+    applied: Mutex<u64>,
+    apply_signal: Notify,
+    client: reqwest::Client,
+    fresh: bool,
+}
+
+impl Raft {
+    /// Opens the Raft log and restores the registry from its snapshot.
+    pub fn open(config: &ClusterConfig, machine: Machine) -> Result<Arc<Self>, RaftError> {
+        let storage = RaftStorage::new(&config.raft_path).map_err(|e| RaftError::Storage(e.to_string()))?;
+        let state = storage.load().map_err(|e| RaftError::Storage(e.to_string()))?;
+        let fresh = state.snapshot.is_none() && state.entries.is_empty();
+
+        let (snapshot_index, snapshot_term, snapshot_members) = match &state.snapshot {
+            Some(snapshot) => {
+                machine.restore(&snapshot.data)?;
+                let members = serde_json::from_str(&snapshot.membership)
+                    .map_err(|e| RaftError::Corrupt(format!("snapshot membership: {}", e)))?;
+                (snapshot.index, snapshot.term, members)
+            }
+            None => (0, 0, Vec::new()),
+        };
+        let log = state.entries.into_iter().map(Entry::from_stored).collect::<Result<Vec<_>, _>>()?;
+
+        let now = Instant::now();
+        let mut core = Core {
+            role: NodeRole::Follower,
+            term: state.term,
+            voted_for: state.voted_for,
+            leader: None,
+            log,
+            snapshot_index,
+            snapshot_term,
+            snapshot_members,
+            members: Vec::new(),
+            commit_index: snapshot_index,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            in_flight: HashSet::new(),
+            votes: HashSet::new(),
+            election_deadline: now,
+            heartbeat_due: now,
+            leader_contact: None,
+            waiters: HashMap::new(),
+        };
+        core.refresh_members();
+
+        let raft = Self {
+            id: config.node_id,
+            config: config.clone(),
+            storage,
+            machine,
+            core: Mutex::new(core),
+            applied: Mutex::new(snapshot_index),
+            apply_signal: Notify::new(),
+            client: reqwest::Client::new(),
+            fresh,
+        };
+        raft.core.lock().unwrap().election_deadline = raft.election_deadline();
+        Ok(Arc::new(raft))
+    }
+
+    /// Whether the node has no Raft state yet: it has neither formed nor
+    /// joined a cluster.
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Starts taking part in the cluster. A fresh node started with
+    /// `cluster.bootstrap` forms a cluster of its own from the current
+    /// contents of the registry; any other fresh node empties its registry
+    /// and waits to be added to an existing cluster.
+    pub fn start(self: &Arc<Self>) -> Result<(), RaftError> {
+        if self.fresh {
+            if self.config.bootstrap {
+                // The registry becomes the first entry of the log, so that
+                // nodes joining later receive it as a snapshot.
+                let members = vec![self.me()];
+                let snapshot = StoredSnapshot {
+                    index: 1,
+                    term: 1,
+                    membership: serde_json::to_string(&members).expect("members are serializable"),
+                    data: self.machine.capture()?,
+                };
+                self.storage.save_snapshot(&snapshot).map_err(|e| RaftError::Storage(e.to_string()))?;
+                let mut applied = self.applied.lock().unwrap();
+                let mut core = self.core.lock().unwrap();
+                core.snapshot_index = snapshot.index;
+                core.snapshot_term = snapshot.term;
+                core.snapshot_members = members;
+                core.commit_index = snapshot.index;
+                core.refresh_members();
+                *applied = snapshot.index;
+                tracing::info!(node = self.id, "bootstrapped a new cluster");
+            } else {
+                self.machine.reset()?;
+                tracing::info!(node = self.id, "waiting to be added to a cluster");
+            }
+        }
+
+        let raft = self.clone();
+        tokio::spawn(async move {
+            loop {
+                raft.apply_signal.notified().await;
+                raft.apply_committed();
+            }
+        });
+
+        let raft = self.clone();
+        let tick = Duration::from_millis((self.config.heartbeat_interval_ms / 3).clamp(5, 50));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tick);
+            loop {
+                interval.tick().await;
+                raft.tick();
+            }
+        });
+        Ok(())
+    }
+
+    fn me(&self) -> Member {
+        Member { id: self.id, url: self.config.advertise_url.clone() }
+    }
+
+    fn election_deadline(&self) -> Instant {
+        let timeout = self.config.election_timeout_ms;
+        Instant::now() + Duration::from_millis(rand::thread_rng().gen_range(timeout..2 * timeout))
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.core.lock().unwrap().role == NodeRole::Leader
+    }
+
+    pub fn leader(&self) -> Option<Member> {
+        self.core.lock().unwrap().leader.clone()
+    }
+
+    pub fn secret_matches(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(SECRET_HEADER)
+            .is_some_and(|v| bool::from(v.as_bytes().ct_eq(self.secret().as_bytes())))
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.config.secret
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let last_applied = *self.applied.lock().unwrap();
+        let core = self.core.lock().unwrap();
+        ClusterStatus {
+            node_id: self.id,
+            role: core.role,
+            term: core.term,
+            leader: core.leader.clone(),
+            commit_index: core.commit_index,
+            last_applied,
+            last_log_index: core.last_index(),
+            snapshot_index: core.snapshot_index,
+            members: core.members.clone(),
+        }
+    }
+
+    fn tick(self: &Arc<Self>) {
+        let now = Instant::now();
+        let mut core = self.core.lock().unwrap();
+        if core.role == NodeRole::Leader {
+            if now >= core.heartbeat_due {
+                core.heartbeat_due = now + Duration::from_millis(self.config.heartbeat_interval_ms);
+                drop(core);
+                self.replicate();
+            }
+        } else if now >= core.election_deadline && core.is_member(self.id) {
+            self.start_election(&mut core);
+        }
+    }
+
+    fn save_hard_state(&self, core: &Core) {
+        persist(self.storage.save_hard_state(core.term, core.voted_for));
+    }
+
+    fn step_down(&self, core: &mut Core, term: u64) {
+        if term > core.term {
+            core.term = term;
+            core.voted_for = None;
+            core.leader = None;
+            self.save_hard_state(core);
+        }
+        if core.role != NodeRole::Follower {
+            tracing::info!(node = self.id, term = core.term, "stepping down to follower");
+            core.role = NodeRole::Follower;
+        }
+        core.in_flight.clear();
+        core.election_deadline = self.election_deadline();
+    }
+
+    fn start_election(self: &Arc<Self>, core: &mut Core) {
+        core.term += 1;
+        core.role = NodeRole::Candidate;
+        core.voted_for = Some(self.id);
+        core.leader = None;
+        core.votes = HashSet::from([self.id]);
+        core.election_deadline = self.election_deadline();
+        self.save_hard_state(core);
+        tracing::info!(node = self.id, term = core.term, "starting election");
+
+        if core.votes.len() * 2 > core.members.len() {
+            self.become_leader(core);
+            return;
+        }
+        let request = Arc::new(VoteRequest {
+            term: core.term,
+            candidate_id: self.id,
+            last_log_index: core.last_index(),
+            last_log_term: core.last_term(),
+        });
+        for peer in core.members.iter().filter(|m| m.id != self.id).cloned() {
+            let (raft, request) = (self.clone(), request.clone());
+            tokio::spawn(async move {
+                let timeout = Duration::from_millis(raft.config.election_timeout_ms);
+                match raft.call::<_, VoteResponse>(&peer, "vote", &*request, timeout).await {
+                    Ok(response) => raft.on_vote_response(peer.id, request.term, response),
+                    Err(e) => tracing::debug!(peer = peer.id, "vote request failed: {}", e),
+                }
+            });
+        }
+    }
+
+    fn on_vote_response(self: &Arc<Self>, peer: u64, term: u64, response: VoteResponse) {
+        let mut core = self.core.lock().unwrap();
+        if response.term > core.term {
+            self.step_down(&mut core, response.term);
+            return;
+        }
+        if core.role != NodeRole::Candidate || core.term != term || !response.granted {
+            return;
+        }
+        core.votes.insert(peer);
+        let votes = core.members.iter().filter(|m| core.votes.contains(&m.id)).count();
+        if votes * 2 > core.members.len() {
+            self.become_leader(&mut core);
+        }
+    }
+
+    fn become_leader(self: &Arc<Self>, core: &mut Core) {
+        tracing::info!(node = self.id, term = core.term, "elected leader");
+        core.role = NodeRole::Leader;
+        core.leader = Some(self.me());
+        core.next_index.clear();
+        core.match_index.clear();
+        core.in_flight.clear();
+        core.heartbeat_due = Instant::now();
+        self.append_local(core, Payload::Noop);
+    }
+
+    /// Appends a new entry to the leader's own log.
+    fn append_local(&self, core: &mut Core, payload: Payload) -> u64 {
+        let entry = Entry { index: core.last_index() + 1, term: core.term, payload };
+        persist(self.storage.append(&[entry.stored()]));
+        let index = entry.index;
+        let membership = matches!(entry.payload, Payload::Membership(_));
+        core.log.push(entry);
+        if membership {
+            core.refresh_members();
+        }
+        self.advance_commit(core);
+        index
+    }
+
+    /// Commits the newest entry of the current term held by a majority,
+    /// and with it every entry before it.
+    fn advance_commit(&self, core: &mut Core) {
+        if core.role != NodeRole::Leader {
+            return;
+        }
+        let mut index = core.last_index();
+        while index > core.commit_index && core.term_at(index) == Some(core.term) {
+            let acks = core
+                .members
+                .iter()
+                .filter(|m| m.id == self.id || core.match_index.get(&m.id).is_some_and(|&i| i >= index))
+                .count();
+            if acks * 2 > core.members.len() {
+                core.commit_index = index;
+                self.apply_signal.notify_one();
+                break;
+            }
+            index -= 1;
+        }
+        // A leader that removed itself keeps leading until the change is
+        // committed, then leaves the remaining members to elect a successor.
+        if !core.is_member(self.id) && !core.membership_pending() {
+            tracing::info!(node = self.id, "no longer a member; stepping down");
+            core.role = NodeRole::Follower;
+            core.leader = None;
+        }
+    }
+
+    /// Sends whatever each follower is missing, or a heartbeat.
+    fn replicate(self: &Arc<Self>) {
+        let peers: Vec<Member> = {
+            let core = self.core.lock().unwrap();
+            if core.role != NodeRole::Leader {
+                return;
+            }
+            core.members.iter().filter(|m| m.id != self.id).cloned().collect()
+        };
+        for peer in peers {
+            self.send_to(peer);
+        }
+    }
+
+    fn send_to(self: &Arc<Self>, peer: Member) {
+        enum Outgoing {
+            Append(AppendRequest),
+            Snapshot(SnapshotRequest),
+        }
+
+        let outgoing = {
+            let mut core = self.core.lock().unwrap();
+            if core.role != NodeRole::Leader || core.in_flight.contains(&peer.id) {
+                return;
+            }
+            let last_index = core.last_index();
+            let next = *core.next_index.entry(peer.id).or_insert(last_index + 1);
+            let outgoing = if next <= core.snapshot_index {
+                let Some(snapshot) = persist(self.storage.snapshot()) else {
+                    return;
+                };
+                let members = serde_json::from_str(&snapshot.membership).unwrap_or_default();
+                Outgoing::Snapshot(SnapshotRequest {
+                    term: core.term,
+                    leader: self.me(),
+                    last_included_index: snapshot.index,
+                    last_included_term: snapshot.term,
+                    members,
+                    data: snapshot.data,
+                })
+            } else {
+                let prev_log_index = next - 1;
+                let entries = (next..=last_index)
+                    .take(MAX_ENTRIES_PER_APPEND)
+                    .filter_map(|i| core.entry(i).cloned())
+                    .collect();
+                Outgoing::Append(AppendRequest {
+                    term: core.term,
+                    leader: self.me(),
+                    prev_log_index,
+                    prev_log_term: core.term_at(prev_log_index).unwrap_or(0),
+                    entries,
+                    leader_commit: core.commit_index,
+                })
+            };
+            core.in_flight.insert(peer.id);
+            outgoing
+        };
+
+        let raft = self.clone();
+        tokio::spawn(async move {
+            let more = match outgoing {
+                Outgoing::Append(request) => {
+                    let timeout = Duration::from_millis(raft.config.election_timeout_ms);
+                    let response = raft.call::<_, AppendResponse>(&peer, "append", &request, timeout).await;
+                    raft.on_append_response(peer.id, &request, response)
+                }
+                Outgoing::Snapshot(request) => {
+                    tracing::info!(peer = peer.id, index = request.last_included_index, "sending snapshot");
+                    let response = raft.call::<_, SnapshotResponse>(&peer, "snapshot", &request, SNAPSHOT_TIMEOUT).await;
+                    raft.on_snapshot_response(peer.id, &request, response)
+                }
+            };
+            if more {
+                raft.send_to(peer);
+            }
+        });
+    }
+
+    /// Handles a follower's reply; returns whether it is still missing entries.
+    fn on_append_response(
+        &self,
+        peer: u64,
+        request: &AppendRequest,
+        response: Result<AppendResponse, reqwest::Error>,
+    ) -> bool {
+        let mut core = self.core.lock().unwrap();
+        core.in_flight.remove(&peer);
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::debug!(peer, "append request failed: {}", e);
+                return false;
+            }
+        };
+        if response.term > core.term {
+            self.step_down(&mut core, response.term);
+            return false;
+        }
+        if core.role != NodeRole::Leader || core.term != request.term {
+            return false;
+        }
+        if response.success {
+            let matched = request.prev_log_index + request.entries.len() as u64;
+            let match_index = core.match_index.entry(peer).or_default();
+            *match_index = (*match_index).max(matched);
+            core.next_index.insert(peer, matched + 1);
+            self.advance_commit(&mut core);
+        } else {
+            let next = response.conflict_index.min(request.prev_log_index).max(1);
+            core.next_index.insert(peer, next);
+        }
+        core.next_index.get(&peer).is_some_and(|&next| next <= core.last_index())
+    }
+
+    fn on_snapshot_response(
+        &self,
+        peer: u64,
+        request: &SnapshotRequest,
+        response: Result<SnapshotResponse, reqwest::Error>,
+    ) -> bool {
+        let mut core = self.core.lock().unwrap();
+        core.in_flight.remove(&peer);
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::debug!(peer, "sending snapshot failed: {}", e);
+                return false;
+            }
+        };
+        if response.term > core.term {
+            self.step_down(&mut core, response.term);
+            return false;
+        }
+        if core.role != NodeRole::Leader || core.term != request.term {
+            return false;
+        }
+        let match_index = core.match_index.entry(peer).or_default();
+        *match_index = (*match_index).max(request.last_included_index);
+        core.next_index.insert(peer, request.last_included_index + 1);
+        self.advance_commit(&mut core);
+        request.last_included_index < core.last_index()
+    }
+
+    async fn call<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        peer: &Member,
+        rpc: &str,
+        request: &Req,
+        timeout: Duration,
+    ) -> Result<Resp, reqwest::Error> {
+        self.client
+            .post(format!("{}/cluster/raft/{}", peer.url.trim_end_matches('/'), rpc))
+            .header(SECRET_HEADER, &self.config.secret)
+            .timeout(timeout)
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    fn handle_vote(&self, request: VoteRequest) -> VoteResponse {
+        let mut core = self.core.lock().unwrap();
+        // While a leader is heard from, candidates are ignored, so a node
+        // removed from the cluster cannot force elections on it.
+        let leader_alive = core.role == NodeRole::Leader
+            || core
+                .leader_contact
+                .is_some_and(|t| t.elapsed() < Duration::from_millis(self.config.election_timeout_ms));
+        if leader_alive && request.term > core.term {
+            return VoteResponse { term: core.term, granted: false };
+        }
+        if request.term > core.term {
+            self.step_down(&mut core, request.term);
+        }
+        let up_to_date = (request.last_log_term, request.last_log_index) >= (core.last_term(), core.last_index());
+        let granted = request.term == core.term
+            && core.voted_for.is_none_or(|id| id == request.candidate_id)
+            && up_to_date;
+        if granted {
+            core.voted_for = Some(request.candidate_id);
+            core.election_deadline = self.election_deadline();
+            self.save_hard_state(&core);
+        }
+        VoteResponse { term: core.term, granted }
+    }
+
+    /// Accepts `leader` as the leader of `term`, which is at least the
+    /// current one.
+    fn follow(&self, core: &mut Core, term: u64, leader: Member) {
+        if term > core.term || core.role != NodeRole::Follower {
+            self.step_down(core, term);
+        }
+        core.leader = Some(leader);
+        core.leader_contact = Some(Instant::now());
+        core.election_deadline = self.election_deadline();
+    }
+
+    fn handle_append(&self, request: AppendRequest) -> AppendResponse {
+        let mut core = self.core.lock().unwrap();
+        let reject = |core: &Core, conflict_index| AppendResponse { term: core.term, success: false, conflict_index };
+        if request.term < core.term {
+            return reject(&core, 0);
+        }
+        self.follow(&mut core, request.term, request.leader);
+
+        // Entries up to the snapshot are committed, so they match the leader's.
+        if request.prev_log_index > core.last_index() {
+            return reject(&core, core.last_index() + 1);
+        }
+        if request.prev_log_index > core.snapshot_index {
+            let term = core.term_at(request.prev_log_index);
+            if term != Some(request.prev_log_term) {
+                let conflict = core
+                    .log
+                    .iter()
+                    .find(|e| Some(e.term) == term)
+                    .map_or(request.prev_log_index, |e| e.index);
+                return reject(&core, conflict);
+            }
+        }
+
+        let last_new = request.prev_log_index + request.entries.len() as u64;
+        let mut appended = Vec::new();
+        let mut membership_changed = false;
+        for entry in request.entries {
+            if entry.index <= core.snapshot_index {
+                continue;
+            }
+            match core.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    let offset = (entry.index - core.snapshot_index - 1) as usize;
+                    membership_changed |= core.log[offset..].iter().any(|e| matches!(e.payload, Payload::Membership(_)));
+                    core.log.truncate(offset);
+                    core.waiters.retain(|&index, _| index < entry.index);
+                    persist(self.storage.truncate_from(entry.index));
+                }
+                None => {}
+            }
+            membership_changed |= matches!(entry.payload, Payload::Membership(_));
+            appended.push(entry);
+        }
+        if !appended.is_empty() {
+            persist(self.storage.append(&appended.iter().map(Entry::stored).collect::<Vec<_>>()));
+            core.log.extend(appended);
+        }
+        if membership_changed {
+            core.refresh_members();
+        }
+
+        let commit = request.leader_commit.min(last_new);
+        if commit > core.commit_index {
+            core.commit_index = commit;
+            self.apply_signal.notify_one();
+        }
+        AppendResponse { term: core.term, success: true, conflict_index: 0 }
+    }
+
+    fn handle_snapshot(&self, request: SnapshotRequest) -> SnapshotResponse {
+        {
+            let mut core = self.core.lock().unwrap();
+            if request.term < core.term {
+                return SnapshotResponse { term: core.term };
+            }
+            self.follow(&mut core, request.term, request.leader.clone());
+        }
+
+        let mut applied = self.applied.lock().unwrap();
+        let term = self.core.lock().unwrap().term;
+        if request.last_included_index <= *applied {
+            return SnapshotResponse { term };
+        }
+        if let Err(e) = self.machine.restore(&request.data) {
+            tracing::error!("failed to install snapshot: {}", e);
+            return SnapshotResponse { term };
+        }
+        *applied = request.last_included_index;
+
+        let mut core = self.core.lock().unwrap();
+        persist(self.storage.save_snapshot(&StoredSnapshot {
+            index: request.last_included_index,
+            term: request.last_included_term,
+            membership: serde_json::to_string(&request.members).expect("members are serializable"),
+            data: request.data,
+        }));
+        // Entries after the snapshot are kept only if the log agrees with it.
+        if core.term_at(request.last_included_index) == Some(request.last_included_term) {
+            core.discard_through(request.last_included_index);
+        } else {
+            core.log.clear();
+            persist(self.storage.truncate_from(request.last_included_index + 1));
+        }
+        core.waiters.retain(|&index, _| index > request.last_included_index);
+        core.snapshot_index = request.last_included_index;
+        core.snapshot_term = request.last_included_term;
+        core.snapshot_members = request.members;
+        core.commit_index = core.commit_index.max(request.last_included_index);
+        core.refresh_members();
+        tracing::info!(node = self.id, index = request.last_included_index, "installed snapshot from leader");
+        SnapshotResponse { term: core.term }
+    }
+
+    /// Applies committed entries to the registry, answering the proposals
+    /// waiting on them.
+    fn apply_committed(&self) {
+        let mut applied = self.applied.lock().unwrap();
+        loop {
+            let (entries, mut waiters) = {
+                let mut core = self.core.lock().unwrap();
+                if *applied >= core.commit_index {
+                    break;
+                }
+                let entries: Vec<Entry> =
+                    (*applied + 1..=core.commit_index).filter_map(|i| core.entry(i).cloned()).collect();
+                let waiters: HashMap<u64, (u64, Waiter)> =
+                    entries.iter().filter_map(|e| core.waiters.remove(&e.index).map(|w| (e.index, w))).collect();
+                (entries, waiters)
+            };
+            if entries.is_empty() {
+                break;
+            }
+            for entry in entries {
+                let waiter = waiters.remove(&entry.index).filter(|(term, _)| *term == entry.term);
+                let result = match &entry.payload {
+                    Payload::Command(command) => self.machine.apply(command, waiter.is_none()),
+                    Payload::Noop | Payload::Membership(_) => Ok(Applied::Done),
+                };
+                *applied = entry.index;
+                if let Some((_, waiter)) = waiter {
+                    let _ = waiter.send(result);
+                }
+            }
+        }
+
+        let snapshot_index = self.core.lock().unwrap().snapshot_index;
+        if *applied >= snapshot_index + self.config.snapshot_threshold {
+            self.take_snapshot(*applied);
+        }
+    }
+
+    /// Snapshots the registry at `applied`; the caller holds `self.applied`.
+    fn take_snapshot(&self, applied: u64) {
+        let data = match self.machine.capture() {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("failed to snapshot the registry: {}", e);
+                return;
+            }
+        };
+        let mut core = self.core.lock().unwrap();
+        let Some(term) = core.term_at(applied) else {
+            return;
+        };
+        let members = core.members_at(applied);
+        persist(self.storage.save_snapshot(&StoredSnapshot {
+            index: applied,
+            term,
+            membership: serde_json::to_string(&members).expect("members are serializable"),
+            data,
+        }));
+        core.discard_through(applied);
+        core.snapshot_index = applied;
+        core.snapshot_term = term;
+        core.snapshot_members = members;
+        tracing::info!(node = self.id, index = applied, "took snapshot");
+    }
+
+    /// Appends `payload` to the log and waits for it to be applied.
+    async fn submit(
+        self: &Arc<Self>,
+        check: impl FnOnce(&Core) -> Result<Payload, ProposeError>,
+    ) -> Result<Applied, ProposeError> {
+        let receiver = {
+            let mut core = self.core.lock().unwrap();
+            if core.role != NodeRole::Leader {
+                return Err(ProposeError::NotLeader);
+            }
+            let payload = check(&core)?;
+            let index = self.append_local(&mut core, payload);
+            let (sender, receiver) = oneshot::channel();
+            let term = core.term;
+            core.waiters.insert(index, (term, sender));
+            receiver
+        };
+        self.replicate();
+        match tokio::time::timeout(PROPOSE_TIMEOUT, receiver).await {
+            Ok(Ok(result)) => Ok(result?),
+            Ok(Err(_)) => Err(ProposeError::NotCommitted("leadership was lost")),
+            Err(_) => Err(ProposeError::NotCommitted("timed out waiting for a majority")),
+        }
+    }
+
+    /// Replicates a registry write; returns once it has been applied here.
+    pub async fn propose(self: &Arc<Self>, command: Command) -> Result<Applied, ProposeError> {
+        self.submit(|_| Ok(Payload::Command(Box::new(command)))).await
+    }
+
+    pub async fn add_member(self: &Arc<Self>, member: Member) -> Result<(), ProposeError> {
+        self.change_membership(|members| {
+            if members.iter().any(|m| m.id == member.id) {
+                return Err(ProposeError::AlreadyMember(member.id));
+            }
+            members.push(member);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn remove_member(self: &Arc<Self>, id: u64) -> Result<(), ProposeError> {
+        self.change_membership(|members| {
+            if !members.iter().any(|m| m.id == id) {
+                return Err(ProposeError::NotMember(id));
+            }
+            if members.len() == 1 {
+                return Err(ProposeError::LastMember);
+            }
+            members.retain(|m| m.id != id);
+            Ok(())
+        })
+        .await
+    }
+
+    async fn change_membership(
+        self: &Arc<Self>,
+        change: impl FnOnce(&mut Vec<Member>) -> Result<(), ProposeError>,
+    ) -> Result<(), ProposeError> {
+        self.submit(|core| {
+            if core.membership_pending() {
+                return Err(ProposeError::MembershipPending);
+            }
+            let mut members = core.members.clone();
+            change(&mut members)?;
+            Ok(Payload::Membership(members))
+        })
+        .await
+        .map(drop)
+    }
+}
+
+/// A node that cannot persist its Raft state must not go on voting or
+/// acknowledging entries.
+fn persist<T, E: Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        tracing::error!("failed to write the Raft log: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Routes for messages between nodes, outside the authenticated API.
+pub fn routes(raft: Arc<Raft>) -> Router {
+    Router::new()
+        .route("/cluster/raft/vote", post(vote))
+        .route("/cluster/raft/append", post(append))
+        .route("/cluster/raft/snapshot", post(install_snapshot))
+        .layer(middleware::from_fn_with_state(raft.clone(), check_secret))
+        .with_state(raft)
+}
+
+async fn check_secret<B>(State(raft): State<Arc<Raft>>, req: Request<B>, next: Next<B>) -> Response {
+    if !raft.secret_matches(req.headers()) {
+        return (StatusCode::UNAUTHORIZED, "Invalid cluster secret").into_response();
+    }
+    next.run(req).await
+}
+
+async fn vote(State(raft): State<Arc<Raft>>, Json(request): Json<VoteRequest>) -> Json<VoteResponse> {
+    Json(raft.handle_vote(request))
+}
+
+async fn append(State(raft): State<Arc<Raft>>, Json(request): Json<AppendRequest>) -> Json<AppendResponse> {
+    Json(raft.handle_append(request))
+}
+
+async fn install_snapshot(State(raft): State<Arc<Raft>>, Json(request): Json<SnapshotRequest>) -> Json<SnapshotResponse> {
+    Json(tokio::task::block_in_place(|| raft.handle_snapshot(request)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::PathBuf;
+
+    use logpose_core::{RegistryStore, Service};
+    use logpose_db::DbRegistry;
+    use tokio::runtime::Runtime;
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    const SECRET: &str = "cluster-secret";
+
+    /// A clustered server on a loopback port, reduced to its registry and
+    /// Raft routes. Each node runs on a runtime of its own, so that dropping
+    /// the runtime stops it the way a crash would.
+    struct Node {
+        id: u64,
+        addr: SocketAddr,
+        raft_path: String,
+        db: Arc<DbRegistry>,
+        raft: Arc<Raft>,
+        runtime: Option<Runtime>,
+    }
+
+    impl Node {
+        fn start(id: u64, addr: SocketAddr, raft_path: &str, bootstrap: bool, snapshot_threshold: u64) -> Self {
+            let listener = TcpListener::bind(addr).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let config = ClusterConfig {
+                enabled: true,
+                node_id: id,
+                advertise_url: format!("http://{}", addr),
+                bootstrap,
+                raft_path: raft_path.to_string(),
+                secret: SECRET.to_string(),
+                heartbeat_interval_ms: 30,
+                election_timeout_ms: 300,
+                snapshot_threshold,
+            };
+            let db = Arc::new(DbRegistry::new(":memory:").unwrap());
+            let machine = Machine::new(db.clone(), broadcast::channel(16).0);
+            let raft = Raft::open(&config, machine).unwrap();
+
+            let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+            let _guard = runtime.enter();
+            raft.start().unwrap();
+            runtime.spawn(axum::Server::from_tcp(listener).unwrap().serve(routes(raft.clone()).into_make_service()));
+            Self { id, addr, raft_path: raft_path.to_string(), db, raft, runtime: Some(runtime) }
+        }
+
+        fn member(&self) -> Member {
+            Member { id: self.id, url: format!("http://{}", self.addr) }
+        }
+
+        fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+            self.runtime.as_ref().unwrap().block_on(future)
+        }
+
+        fn propose(&self, command: Command) -> Result<Applied, ProposeError> {
+            self.block_on(self.raft.propose(command))
+        }
+
+        fn add_service(&self, code: &str) -> Result<Applied, ProposeError> {
+            self.propose(Command::AddService(Service::new(code, code, "")))
+        }
+
+        fn has_service(&self, code: &str) -> bool {
+            self.db.get_service("default", code).is_ok()
+        }
+
+        fn member_ids(&self) -> Vec<u64> {
+            self.raft.status().members.iter().map(|m| m.id).collect()
+        }
+
+        /// Stops the node without a word to its peers; its Raft log stays
+        /// where it is.
+        fn kill(&mut self) {
+            self.runtime.take().unwrap().shutdown_background();
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            if let Some(runtime) = self.runtime.take() {
+                runtime.shutdown_background();
+            }
+        }
+    }
+
+    fn free_addr() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    /// A Raft log file that is removed when the test ends.
+    struct LogFile(PathBuf);
+
+    impl LogFile {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("logpose-raft-test-{}.db", uuid::Uuid::new_v4())))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for LogFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Polls `condition` until it holds, failing the test after 10 seconds.
+    fn wait_for(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Adds `node` to the cluster led by `leader` and waits until it holds
+    /// the leader's log.
+    fn join(leader: &Node, node: &Node) {
+        leader.block_on(leader.raft.add_member(node.member())).unwrap();
+        wait_for("the new member is up to date", || {
+            node.raft.status().last_applied >= leader.raft.status().commit_index
+        });
+    }
+
+    /// A bootstrapped node 1 and `size - 1` members added to it, their logs
+    /// kept in memory.
+    fn cluster(size: u64, snapshot_threshold: u64) -> Vec<Node> {
+        let nodes: Vec<Node> = (1..=size)
+            .map(|id| Node::start(id, free_addr(), ":memory:", id == 1, snapshot_threshold))
+            .collect();
+        wait_for("node 1 leads", || nodes[0].raft.is_leader());
+        for node in &nodes[1..] {
+            join(&nodes[0], node);
+        }
+        nodes
+    }
+
+    fn leader(nodes: &[&Node]) -> Option<u64> {
+        nodes.iter().find(|n| n.raft.is_leader()).map(|n| n.id)
+    }
+
+    #[test]
+    fn write_is_replicated_to_followers() {
+        let nodes = cluster(3, 1000);
+        assert!(matches!(nodes[1].add_service("payments"), Err(ProposeError::NotLeader)));
+
+        nodes[0].add_service("payments").unwrap();
+        assert!(nodes[0].has_service("payments"), "applied on the leader before the write returns");
+        wait_for("followers apply the write", || nodes.iter().all(|n| n.has_service("payments")));
+        for node in &nodes {
+            let status = node.raft.status();
+            assert_eq!(status.leader, Some(nodes[0].member()));
+            assert_eq!(status.members.len(), 3);
+        }
+    }
+
+    #[test]
+    fn new_leader_is_elected_when_the_leader_is_killed() {
+        let mut nodes = cluster(3, 1000);
+        nodes[0].add_service("payments").unwrap();
+        let term = nodes[0].raft.status().term;
+        nodes[0].kill();
+
+        let survivors = [&nodes[1], &nodes[2]];
+        wait_for("a survivor is elected", || leader(&survivors).is_some());
+        let leader = survivors.iter().find(|n| n.raft.is_leader()).unwrap();
+        let follower = survivors.iter().find(|n| n.id != leader.id).unwrap();
+        assert!(leader.raft.status().term > term);
+        // The new leader holds every committed entry, and applies them once
+        // an entry of its own term commits.
+        wait_for("committed writes survive the election", || leader.has_service("payments"));
+
+        // Two of three nodes are still a majority.
+        leader.add_service("orders").unwrap();
+        wait_for("the other survivor applies the write", || follower.has_service("orders"));
+        wait_for("the other survivor follows the new leader", || {
+            follower.raft.leader() == Some(leader.member())
+        });
+    }
+
+    #[test]
+    fn follower_catches_up_through_a_snapshot() {
+        let log = LogFile::new();
+        let leader = Node::start(1, free_addr(), ":memory:", true, 5);
+        wait_for("node 1 leads", || leader.raft.is_leader());
+        let second = Node::start(2, free_addr(), ":memory:", false, 5);
+        join(&leader, &second);
+        let mut third = Node::start(3, free_addr(), log.path(), false, 5);
+        join(&leader, &third);
+        leader.add_service("before").unwrap();
+        wait_for("node 3 applies the write", || third.has_service("before"));
+
+        third.kill();
+        let behind = third.raft.status().last_log_index;
+        for i in 0..20 {
+            leader.add_service(&format!("svc-{}", i)).unwrap();
+        }
+        assert!(leader.raft.status().snapshot_index > behind, "the leader discarded entries node 3 misses");
+
+        // Restarting from its own log, node 3 is too far behind for the
+        // leader to send it entries.
+        let (addr, raft_path) = (third.addr, third.raft_path.clone());
+        drop(third);
+        let third = Node::start(3, addr, &raft_path, false, 5);
+        assert!(!third.raft.is_fresh());
+        wait_for("node 3 catches up", || (0..20).all(|i| third.has_service(&format!("svc-{}", i))));
+        assert!(third.has_service("before"));
+        assert!(third.raft.status().snapshot_index > behind, "node 3 installed a snapshot");
+
+        // Once caught up it is sent entries again.
+        leader.add_service("after").unwrap();
+        wait_for("every node applies a new write", || third.has_service("after") && second.has_service("after"));
+    }
+
+    #[test]
+    fn members_are_added_and_removed() {
+        let nodes = cluster(1, 1000);
+        let one = &nodes[0];
+        assert_eq!(one.member_ids(), [1]);
+        assert!(matches!(one.block_on(one.raft.remove_member(1)), Err(ProposeError::LastMember)));
+
+        let second = Node::start(2, free_addr(), ":memory:", false, 1000);
+        let mut third = Node::start(3, free_addr(), ":memory:", false, 1000);
+        assert!(second.raft.is_fresh() && second.member_ids().is_empty());
+        join(one, &second);
+        join(one, &third);
+        assert!(matches!(
+            one.block_on(one.raft.add_member(second.member())),
+            Err(ProposeError::AlreadyMember(2))
+        ));
+        for node in [one, &second, &third] {
+            wait_for("every node sees three members", || node.member_ids() == [1, 2, 3]);
+        }
+
+        one.block_on(one.raft.remove_member(3)).unwrap();
+        assert!(matches!(one.block_on(one.raft.remove_member(3)), Err(ProposeError::NotMember(3))));
+        wait_for("node 2 sees node 3 removed", || second.member_ids() == [1, 2]);
+        assert_eq!(one.member_ids(), [1, 2]);
+
+        // Writes need only the remaining members, and reach only them.
+        third.kill();
+        one.add_service("payments").unwrap();
+        wait_for("node 2 applies the write", || second.has_service("payments"));
+        assert!(!third.has_service("payments"));
+    }
+}
@@ -0,0 +1,79 @@
+//! Ending sessions whose instance or TTL ran out.
+//!
+//! Sessions are checked every second, and as soon as an instance changes.
+//! A session ends once its TTL has passed without a renewal, or once its
+//! instance is deregistered or turns `Unhealthy`; the locks it held are
+//! released in the same write, and `SessionDestroyed` tells watchers of
+//! the namespace's keys that a lock may have changed hands.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use logpose_core::{HealthStatus, RegistryError, RegistryEvent, RegistryStore, Session};
+use tokio::sync::broadcast;
+
+use crate::cluster::Registry;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why a session should end now, if it should.
+fn expiry(registry: &dyn RegistryStore, session: &Session, now: u64) -> Option<&'static str> {
+    if session.is_expired(now) {
+        return Some("TTL expired");
+    }
+    let id = session.instance?;
+    match registry.get_instance(&id) {
+        Ok(instance) if instance.health == HealthStatus::Unhealthy => Some("instance is unhealthy"),
+        Ok(_) => None,
+        Err(RegistryError::InstanceNotFound) => Some("instance is deregistered"),
+        Err(_) => None,
+    }
+}
+
+/// Ends expired sessions, on the leader only: followers learn of it through
+/// replication.
+pub fn spawn_reaper(registry: Arc<Registry>, events: broadcast::Sender<RegistryEvent>) {
+    let mut changes = events.subscribe();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                event = changes.recv() => match event {
+                    Ok(
+                        RegistryEvent::InstanceDeregistered { .. }
+                        | RegistryEvent::InstanceHealthChanged { .. }
+                        | RegistryEvent::Replaced,
+                    ) => {}
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+            if !registry.is_leader() {
+                continue;
+            }
+            let Ok(sessions) = registry.get_all_sessions() else {
+                continue;
+            };
+            let now = logpose_core::time::now();
+            for session in sessions {
+                let Some(reason) = expiry(registry.as_ref(), &session, now) else {
+                    continue;
+                };
+                match registry.remove_session(&session.id) {
+                    Ok(()) => {
+                        tracing::info!(session = %session.id, name = %session.name, "session ended: {}", reason);
+                        let _ = events.send(RegistryEvent::SessionDestroyed {
+                            namespace: session.namespace,
+                            id: session.id,
+                        });
+                    }
+                    // Destroyed in the meantime.
+                    Err(RegistryError::SessionNotFound) => {}
+                    Err(e) => tracing::warn!(session = %session.id, "failed to end session: {}", e),
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,161 @@
+//! Registry-level Prometheus metrics, recorded through the `metrics` facade
+//! and rendered by the exporter installed in `main`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use logpose_core::{HealthStatus, Protocol, RegistryEvent, RegistryStore};
+use logpose_db::DbRegistry;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use tokio::sync::broadcast;
+
+use crate::limits::{Quota, RouteClass};
+
+pub const SERVICES: &str = "logpose_services";
+pub const INSTANCES: &str = "logpose_instances";
+pub const SERVICE_INSTANCES: &str = "logpose_service_instances";
+pub const PROBE_DURATION: &str = "logpose_health_probe_duration_seconds";
+pub const PROBE_FAILURES: &str = "logpose_health_probe_failures_total";
+pub const REGISTRATIONS: &str = "logpose_registrations_total";
+pub const DEREGISTRATIONS: &str = "logpose_deregistrations_total";
+pub const HTTP_REQUESTS: &str = "logpose_http_requests_total";
+pub const HTTP_REQUEST_DURATION: &str = "logpose_http_request_duration_seconds";
+pub const RATE_LIMITED: &str = "logpose_rate_limited_requests_total";
+pub const QUOTA_REJECTIONS: &str = "logpose_quota_rejections_total";
+
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Exporter configuration; latency metrics are rendered as histograms
+/// rather than the exporter's default summaries.
+pub fn prometheus_builder() -> PrometheusBuilder {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)
+        .expect("latency buckets are non-empty")
+}
+
+pub fn describe() {
+    describe_gauge!(SERVICES, Unit::Count, "Number of registered services");
+    describe_gauge!(INSTANCES, Unit::Count, "Number of registered instances by health status");
+    describe_gauge!(SERVICE_INSTANCES, Unit::Count, "Number of registered instances by namespace and service");
+    describe_histogram!(PROBE_DURATION, Unit::Seconds, "Latency of health probes by protocol");
+    describe_counter!(PROBE_FAILURES, Unit::Count, "Failed health probes by protocol");
+    describe_counter!(REGISTRATIONS, Unit::Count, "Service and instance registrations");
+    describe_counter!(DEREGISTRATIONS, Unit::Count, "Service and instance deregistrations");
+    describe_counter!(HTTP_REQUESTS, Unit::Count, "HTTP requests by method, route and status");
+    describe_histogram!(HTTP_REQUEST_DURATION, Unit::Seconds, "HTTP request latency by method, route and status");
+    describe_counter!(RATE_LIMITED, Unit::Count, "Requests refused by a rate limit, by identity and route class");
+    describe_counter!(QUOTA_REJECTIONS, Unit::Count, "Registrations refused by a quota");
+}
+
+pub fn protocol_label(protocol: &Protocol) -> String {
+    match protocol {
+        Protocol::Custom(name) => name.clone(),
+        other => format!("{:?}", other),
+    }
+}
+
+pub fn record_probe(protocol: &Protocol, elapsed: Duration, health: HealthStatus) {
+    let protocol = protocol_label(protocol);
+    histogram!(PROBE_DURATION, "protocol" => protocol.clone()).record(elapsed.as_secs_f64());
+    if health != HealthStatus::Healthy {
+        counter!(PROBE_FAILURES, "protocol" => protocol).increment(1);
+    }
+}
+
+/// `kind` is either `service` or `instance`.
+pub fn record_registration(kind: &'static str) {
+    counter!(REGISTRATIONS, "kind" => kind).increment(1);
+}
+
+/// `kind` is either `service` or `instance`.
+pub fn record_deregistration(kind: &'static str) {
+    counter!(DEREGISTRATIONS, "kind" => kind).increment(1);
+}
+
+pub fn record_rate_limited(identity: &str, class: RouteClass) {
+    counter!(RATE_LIMITED, "identity" => identity.to_string(), "class" => class.label()).increment(1);
+}
+
+pub fn record_quota_rejection(quota: Quota) {
+    counter!(QUOTA_REJECTIONS, "quota" => quota.label()).increment(1);
+}
+
+/// Recomputes the registry gauges from the store. `reported` holds the
+/// services with a per-service gauge so removed ones can be reset to zero.
+fn refresh_gauges(registry: &DbRegistry, reported: &mut HashSet<(String, String)>) {
+    if let Ok(services) = registry.get_all_services() {
+        gauge!(SERVICES).set(services.len() as f64);
+    }
+    let Ok(instances) = registry.get_all_instances() else {
+        return;
+    };
+
+    let mut by_health: HashMap<HealthStatus, usize> =
+        [HealthStatus::Healthy, HealthStatus::Unhealthy, HealthStatus::Unknown, HealthStatus::Suspect]
+            .into_iter()
+            .map(|h| (h, 0))
+            .collect();
+    let mut by_service: HashMap<(String, String), usize> = HashMap::new();
+    for instance in &instances {
+        *by_health.entry(instance.health).or_default() += 1;
+        *by_service.entry((instance.namespace.clone(), instance.service_name.clone())).or_default() += 1;
+    }
+
+    for (health, count) in by_health {
+        gauge!(INSTANCES, "health" => format!("{:?}", health)).set(count as f64);
+    }
+    for (namespace, service) in reported.iter().filter(|s| !by_service.contains_key(*s)) {
+        gauge!(SERVICE_INSTANCES, "namespace" => namespace.clone(), "service" => service.clone()).set(0.0);
+    }
+    reported.clear();
+    for ((namespace, service), count) in by_service {
+        gauge!(SERVICE_INSTANCES, "namespace" => namespace.clone(), "service" => service.clone()).set(count as f64);
+        reported.insert((namespace, service));
+    }
+}
+
+/// Keeps the gauges current: refreshes on every registry event, and on a
+/// timer so that writes made directly by `logpose-command` are picked up.
+pub fn spawn_gauge_refresher(registry: Arc<DbRegistry>, mut events: broadcast::Receiver<RegistryEvent>) {
+    tokio::spawn(async move {
+        let mut reported = HashSet::new();
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                event = events.recv() => match event {
+                    Err(broadcast::error::RecvError::Closed) => break,
+                    Ok(RegistryEvent::KeyChanged { .. } | RegistryEvent::SessionDestroyed { .. }) => continue,
+                    _ => {}
+                },
+            }
+            refresh_gauges(&registry, &mut reported);
+        }
+    });
+}
+
+/// Records request counts and latencies labelled by the matched route
+/// template (e.g. `/api/discover/:code`) rather than the raw path.
+pub async fn track_http<B>(req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    counter!(HTTP_REQUESTS, &labels).increment(1);
+    histogram!(HTTP_REQUEST_DURATION, &labels).record(start.elapsed().as_secs_f64());
+    response
+}
@@ -0,0 +1,169 @@
+//! Tracing setup: console logging plus an optional OpenTelemetry (OTLP/HTTP)
+//! exporter, and W3C `traceparent` propagation for incoming requests.
+//!
+//! The exporter is enabled when `OTEL_EXPORTER_OTLP_ENDPOINT` or
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. The remaining standard
+//! variables (`OTEL_SERVICE_NAME`, `OTEL_EXPORTER_OTLP_HEADERS`,
+//! `OTEL_EXPORTER_OTLP_TIMEOUT`, `OTEL_TRACES_SAMPLER`, ...) are honoured by
+//! the OpenTelemetry SDK itself.
+
+use axum::{
+    extract::MatchedPath,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+const DEFAULT_SERVICE_NAME: &str = "logpose-server";
+
+pub fn init() {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let otel = match otlp_endpoint() {
+        Some(endpoint) => match build_tracer(endpoint) {
+            Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer)),
+            Err(e) => {
+                eprintln!("failed to initialise OTLP exporter: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+}
+
+/// Flushes spans still buffered in the batch exporter.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+fn otlp_endpoint() -> Option<String> {
+    if std::env::var("OTEL_SDK_DISABLED").is_ok_and(|v| v.eq_ignore_ascii_case("true")) {
+        return None;
+    }
+    std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+        .or_else(|_| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT"))
+        .ok()
+}
+
+fn build_tracer(endpoint: String) -> Result<trace::Tracer, opentelemetry::trace::TraceError> {
+    let mut resource = Resource::default();
+    if std::env::var("OTEL_SERVICE_NAME").is_err() {
+        resource = resource.merge(&Resource::new([KeyValue::new("service.name", DEFAULT_SERVICE_NAME)]));
+    }
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Wraps every request in a server span, continuing the caller's trace when
+/// a `traceparent` header is present.
+pub async fn trace_http<B>(req: Request<B>, next: Next<B>) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let span = tracing::info_span!(
+        "http.request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.route = %route,
+        http.status_code = tracing::field::Empty,
+    );
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+
+    let response = next.run(req).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{body::Bytes, extract::State, http::{StatusCode, Uri}, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(String, Bytes)>>>;
+
+    async fn collect(State(received): State<Received>, uri: Uri, body: Bytes) -> StatusCode {
+        received.lock().unwrap().push((uri.path().to_string(), body));
+        StatusCode::OK
+    }
+
+    /// An OTLP/HTTP collector on a loopback port that keeps every export.
+    fn collector() -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new().fallback(collect).with_state(received.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        (endpoint, received)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn request_spans_are_exported_in_the_callers_trace() {
+        let (endpoint, received) = collector();
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = build_tracer(endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let app = Router::new()
+            .route("/api/services/:code", get(|| async { "ok" }))
+            .layer(middleware::from_fn(trace_http));
+        let request = Request::get("/api/services/auth")
+            .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::OK);
+        drop(guard);
+        tokio::task::spawn_blocking(shutdown).await.unwrap();
+
+        let received = received.lock().unwrap();
+        let (path, body) = received.first().expect("the collector received no export");
+        assert_eq!(path, "/v1/traces");
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|window| window == needle);
+        let trace_id = 0x4bf92f3577b34da6a3ce929d0e0e4736u128.to_be_bytes();
+        let parent_span_id = 0x00f067aa0ba902b7u64.to_be_bytes();
+        assert!(contains(&trace_id), "span is not in the caller's trace");
+        assert!(contains(&parent_span_id), "span is not a child of the caller's span");
+        assert!(contains(b"GET /api/services/:code"), "span is not named after the route");
+        assert!(contains(DEFAULT_SERVICE_NAME.as_bytes()), "service name is missing");
+    }
+}
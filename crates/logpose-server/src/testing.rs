@@ -0,0 +1,88 @@
+//! Helpers for tests that send requests through the API as `main` serves it.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{middleware, Router, ServiceExt};
+use logpose_core::{Grant, Identity, RegistryStore, Role, RoleDefinition, Service, ServiceInstance};
+use logpose_db::DbRegistry;
+use tokio::sync::broadcast;
+
+use crate::config::Config;
+use crate::{cluster, keys, limits, namespace, tokens, webhooks, AppState};
+
+/// The state of a standalone server with an empty in-memory registry.
+pub fn state(config: Config) -> AppState {
+    let registry = Arc::new(cluster::Registry::standalone(Arc::new(DbRegistry::new(":memory:").unwrap())));
+    AppState {
+        keys: keys::init(&config.auth, registry.as_ref()).unwrap(),
+        events: broadcast::channel(16).0,
+        limits: Arc::new(limits::Limits::new(config.limits.clone())),
+        federation: None,
+        webhooks: webhooks::Dispatcher::new(registry.clone(), config.webhooks.clone()),
+        config: Arc::new(config),
+        registry,
+    }
+}
+
+/// Serves `app` on a loopback port behind namespace selection, as `main`
+/// does; returns its base URL.
+pub fn serve(app: Router) -> String {
+    let app = tower::Layer::layer(&middleware::from_fn(namespace::select), app);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+    tokio::spawn(server);
+    url
+}
+
+/// Serves the API of `state`; returns its base URL.
+pub fn api(state: &AppState) -> String {
+    serve(crate::api(Router::new(), state.clone()))
+}
+
+/// Registers an identity holding `roles`, given as `(namespace, role)`.
+pub fn identity(state: &AppState, common_name: &str, roles: &[(&str, Role)]) -> Identity {
+    let mut held: BTreeMap<String, Vec<Role>> = BTreeMap::new();
+    for (namespace, role) in roles {
+        held.entry(namespace.to_string()).or_default().push(role.clone());
+    }
+    let identity = Identity { common_name: common_name.to_string(), organization: None, roles: held, disabled: false };
+    state.registry.add_identity(&identity).unwrap();
+    identity
+}
+
+/// Defines a role from grants such as `InstanceWrite:billing-*`.
+pub fn role(state: &AppState, name: &str, grants: &[&str]) -> Role {
+    let grants: Vec<Grant> = grants.iter().map(|grant| grant.parse().unwrap()).collect();
+    let definition = RoleDefinition { name: name.to_string(), description: String::new(), grants };
+    state.registry.add_role(&definition).unwrap();
+    Role::Custom(name.to_string())
+}
+
+/// An access token for `identity` as it is now.
+pub fn token(state: &AppState, identity: &Identity) -> String {
+    tokens::issue(identity, &state.config.auth, &state.keys.current()).unwrap().access_token
+}
+
+/// Registers service `code` in `namespace`.
+pub fn service(state: &AppState, namespace: &str, code: &str) -> Service {
+    let mut service = Service::new(code, code, "");
+    service.namespace = namespace.to_string();
+    state.registry.add_service(&service).unwrap();
+    service
+}
+
+/// Registers an instance of `service` on `port`, as `registered_by` did.
+pub fn instance(state: &AppState, service: &Service, port: u16, registered_by: &str) -> ServiceInstance {
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    let runtime = logpose_core::Runtime::Vm { provider: None, id: None };
+    let mut instance = ServiceInstance::new(&service.code, address, logpose_core::Protocol::Http, runtime, 0);
+    instance.namespace = service.namespace.clone();
+    instance.registered_by = Some(registered_by.to_string());
+    state.registry.add_instance(&instance).unwrap();
+    instance
+}
@@ -0,0 +1,315 @@
+//! HTTPS serving: rustls configuration, client certificate authentication,
+//! certificate hot reload and the optional plain-HTTP redirect listener.
+
+use std::future::Future;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    http::{header, uri::Authority, StatusCode, Uri},
+    middleware::AddExtension,
+    response::{IntoResponse, Redirect},
+    Extension, Router,
+};
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+
+use crate::config::TlsConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {source}")]
+    Read { path: String, source: std::io::Error },
+
+    #[error("no certificate found in {0}")]
+    NoCertificate(String),
+
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+
+    #[error("invalid CA certificate in {0}")]
+    InvalidCa(String),
+
+    #[error("invalid certificate or key: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+fn read_pem(path: &Path) -> Result<Vec<rustls_pemfile::Item>, TlsError> {
+    let read_err = |source| TlsError::Read { path: path.display().to_string(), source };
+    let file = std::fs::File::open(path).map_err(read_err)?;
+    rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(read_err)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsError::NoPrivateKey(path.display().to_string()))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).map_err(|_| TlsError::InvalidCa(path.display().to_string()))?;
+    }
+    Ok(roots)
+}
+
+/// Builds a rustls server config from the PEM files named in `tls`.
+/// Paths are guaranteed present by `Config::validate`.
+pub fn server_config(tls: &TlsConfig) -> Result<ServerConfig, TlsError> {
+    let cert_path = tls.cert_path.as_deref().expect("validated");
+    let key_path = tls.key_path.as_deref().expect("validated");
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &tls.client_ca_path {
+        Some(ca_path) if tls.require_client_cert => {
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca_path)?).boxed())
+        }
+        Some(ca_path) => builder
+            .with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(ca_path)?).boxed()),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+pub fn load(tls: &TlsConfig) -> Result<RustlsConfig, TlsError> {
+    Ok(RustlsConfig::from_config(Arc::new(server_config(tls)?)))
+}
+
+fn modified(tls: &TlsConfig) -> Option<Vec<SystemTime>> {
+    [&tls.cert_path, &tls.key_path, &tls.client_ca_path]
+        .into_iter()
+        .flatten()
+        .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Swaps in fresh certificates when the PEM files change on disk or the
+/// process receives SIGHUP. A failed reload keeps the current certificates.
+pub fn spawn_reloader(rustls: RustlsConfig, tls: TlsConfig) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+
+        let mut last_modified = modified(&tls);
+        let mut interval = tokio::time::interval(Duration::from_secs(tls.reload_interval_secs));
+        interval.tick().await;
+        loop {
+            #[cfg(unix)]
+            let trigger = tokio::select! {
+                _ = interval.tick() => None,
+                _ = hangup.recv() => Some("SIGHUP"),
+            };
+            #[cfg(not(unix))]
+            let trigger = {
+                interval.tick().await;
+                None
+            };
+
+            let current = modified(&tls);
+            let reason = match trigger {
+                Some(reason) => reason,
+                None if current.is_some() && current != last_modified => "file change",
+                None => continue,
+            };
+            last_modified = current;
+
+            match server_config(&tls) {
+                Ok(config) => {
+                    rustls.reload_from_config(Arc::new(config));
+                    tracing::info!("reloaded TLS certificates ({})", reason);
+                }
+                Err(e) => tracing::error!("failed to reload TLS certificates ({}): {}", reason, e),
+            }
+        }
+    });
+}
+
+/// Subject of a verified client certificate.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub common_name: String,
+    pub organization: Option<String>,
+    /// Expiry of the certificate as a Unix timestamp
+    pub not_after: i64,
+}
+
+impl ClientCertificate {
+    fn parse(cert: &Certificate) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+        let subject = cert.subject();
+        Some(Self {
+            common_name: subject.iter_common_name().next()?.as_str().ok()?.to_string(),
+            organization: subject
+                .iter_organization()
+                .next()
+                .and_then(|o| o.as_str().ok())
+                .map(str::to_string),
+            not_after: cert.validity().not_after.timestamp(),
+        })
+    }
+}
+
+/// TLS acceptor that makes the peer's verified certificate, if any,
+/// available to handlers as an `Option<ClientCertificate>` extension.
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self { inner: RustlsAcceptor::new(config) }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, Option<ClientCertificate>>;
+    type Future = Pin<Box<dyn Future<Output = std::io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let handshake = self.inner.accept(stream, service);
+        Box::pin(async move {
+            let (stream, service) = handshake.await?;
+            let cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(ClientCertificate::parse);
+            Ok((stream, Extension(cert).layer(service)))
+        })
+    }
+}
+
+/// Plain-HTTP listener that permanently redirects every request to the
+/// HTTPS listener on `https_port`.
+pub async fn serve_redirect(addr: SocketAddr, https_port: u16) {
+    let app = Router::new().fallback(move |headers: axum::http::HeaderMap, uri: Uri| async move {
+        let Some(host) = headers.get(header::HOST).and_then(|h| h.to_str().ok()) else {
+            return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+        };
+        let path = uri.path_and_query().map_or("/", |pq| pq.as_str());
+        match redirect_location(host, https_port, path) {
+            Some(location) => Redirect::permanent(&location).into_response(),
+            None => (StatusCode::BAD_REQUEST, "Invalid Host header").into_response(),
+        }
+    });
+
+    tracing::info!("redirecting http://{} to https", addr);
+    if let Err(e) = axum::Server::bind(&addr).serve(app.into_make_service()).await {
+        tracing::error!("redirect listener failed: {}", e);
+    }
+}
+
+/// Where a request for `path` on `host` moves to: the same host name, with
+/// any port replaced by `https_port`. Bracketed IPv6 hosts keep their
+/// brackets; `None` when `host` is not a valid authority.
+fn redirect_location(host: &str, https_port: u16, path: &str) -> Option<String> {
+    let host = host.parse::<Authority>().ok()?;
+    let host = host.host();
+    if https_port == 443 {
+        Some(format!("https://{}{}", host, path))
+    } else {
+        Some(format!("https://{}:{}{}", host, https_port, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use logpose_core::{RegistryStore, Role};
+
+    use super::*;
+    use crate::config::Config;
+    use crate::testing;
+
+    /// A client certificate for `common_name`, with `organization` as its O.
+    fn certificate(common_name: &str, organization: Option<&str>) -> ClientCertificate {
+        let mut params = rcgen::CertificateParams::new(Vec::new());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::CommonName, common_name);
+        if let Some(organization) = organization {
+            params.distinguished_name.push(rcgen::DnType::OrganizationName, organization);
+        }
+        let der = rcgen::Certificate::from_params(params).unwrap().serialize_der().unwrap();
+        ClientCertificate::parse(&Certificate(der)).unwrap()
+    }
+
+    #[test]
+    fn client_certificates_name_their_subject() {
+        let cert = certificate("payments.svc", Some("Payments"));
+        assert_eq!(cert.common_name, "payments.svc");
+        assert_eq!(cert.organization.as_deref(), Some("Payments"));
+        assert!(cert.not_after > 0);
+        assert_eq!(certificate("payments.svc", None).organization, None);
+    }
+
+    #[test]
+    fn client_certificates_map_onto_enabled_identities_of_their_organization() {
+        let state = testing::state(Config::default());
+        let mut identity = testing::identity(&state, "payments.svc", &[("payments", Role::Agent)]);
+        let claims = crate::client_cert_claims(&state, &certificate("payments.svc", Some("Anyone"))).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.roles_in("payments")), ("payments.svc", &[Role::Agent][..]));
+
+        assert!(crate::client_cert_claims(&state, &certificate("unknown.svc", None)).is_none());
+
+        identity.organization = Some("Payments".to_string());
+        state.registry.remove_identity(&identity.common_name).unwrap();
+        state.registry.add_identity(&identity).unwrap();
+        assert!(crate::client_cert_claims(&state, &certificate("payments.svc", Some("Payments"))).is_some());
+        assert!(crate::client_cert_claims(&state, &certificate("payments.svc", Some("Billing"))).is_none());
+        assert!(crate::client_cert_claims(&state, &certificate("payments.svc", None)).is_none());
+
+        state.registry.disable_identity(&identity.common_name).unwrap();
+        assert!(crate::client_cert_claims(&state, &certificate("payments.svc", Some("Payments"))).is_none());
+    }
+
+    #[test]
+    fn redirects_keep_the_host_and_replace_the_port() {
+        let location = |host| redirect_location(host, 8443, "/api/services?ns=default");
+        assert_eq!(location("logpose.local").as_deref(), Some("https://logpose.local:8443/api/services?ns=default"));
+        assert_eq!(location("logpose.local:8080").as_deref(), Some("https://logpose.local:8443/api/services?ns=default"));
+        assert_eq!(location("10.0.0.1:80").as_deref(), Some("https://10.0.0.1:8443/api/services?ns=default"));
+        assert_eq!(location("[::1]").as_deref(), Some("https://[::1]:8443/api/services?ns=default"));
+        assert_eq!(location("[::1]:8080").as_deref(), Some("https://[::1]:8443/api/services?ns=default"));
+        assert_eq!(location("[fd00::2"), None);
+        assert_eq!(redirect_location("[::1]:80", 443, "/").as_deref(), Some("https://[::1]/"));
+    }
+}
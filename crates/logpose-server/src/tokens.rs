@@ -0,0 +1,88 @@
+//! Access and refresh token issuance and decoding.
+//!
+//! Access tokens carry the identity's roles and are short-lived. Refresh
+//! tokens only name the identity; exchanging one at `/api/auth/refresh`
+//! revokes it and issues a fresh pair with the identity's current roles.
+//! Every token has a `jti` so it can be put on the revocation list.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::errors::Error;
+use logpose_core::{Claims, Identity};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AuthConfig;
+use crate::keys::KeyRing;
+
+const REFRESH_TOKEN_USE: &str = "refresh";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshClaims {
+    pub sub: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub jti: String,
+    /// Always `refresh`; keeps access tokens from being used as refresh tokens.
+    pub token_use: String,
+}
+
+/// The claims shared by both token kinds, enough to revoke either.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenId {
+    pub sub: String,
+    pub exp: usize,
+    pub jti: String,
+}
+
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: u64,
+}
+
+pub fn now() -> usize {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("clock before 1970").as_secs() as usize
+}
+
+pub fn issue(identity: &Identity, auth: &AuthConfig, keys: &KeyRing) -> Result<TokenPair, Error> {
+    let iat = now();
+
+    let access = Claims {
+        sub: identity.common_name.clone(),
+        roles: identity.roles.clone(),
+        exp: iat + auth.access_token_ttl_secs as usize,
+        iat,
+        jti: uuid::Uuid::new_v4().to_string(),
+    };
+    let refresh = RefreshClaims {
+        sub: identity.common_name.clone(),
+        exp: iat + auth.refresh_token_ttl_secs as usize,
+        iat,
+        jti: uuid::Uuid::new_v4().to_string(),
+        token_use: REFRESH_TOKEN_USE.to_string(),
+    };
+
+    Ok(TokenPair {
+        access_token: keys.encode(&access)?,
+        refresh_token: keys.encode(&refresh)?,
+        expires_in: auth.access_token_ttl_secs,
+    })
+}
+
+pub fn decode_access(token: &str, keys: &KeyRing) -> Result<Claims, Error> {
+    keys.decode(token, true)
+}
+
+pub fn decode_refresh(token: &str, keys: &KeyRing) -> Result<RefreshClaims, Error> {
+    let claims: RefreshClaims = keys.decode(token, true)?;
+    if claims.token_use != REFRESH_TOKEN_USE {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+/// Verifies the signature of either token kind, accepting expired tokens so
+/// that revoking one is never an error.
+pub fn decode_any(token: &str, keys: &KeyRing) -> Result<TokenId, Error> {
+    keys.decode(token, false)
+}
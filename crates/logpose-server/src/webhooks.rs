@@ -0,0 +1,308 @@
+//! Delivering registry events to webhooks.
+//!
+//! The leader matches every event against the webhooks of its namespace and
+//! POSTs a [`WebhookPayload`] to each that subscribes to it. A delivery is
+//! retried with exponential backoff until the endpoint answers with a 2xx
+//! status or `webhooks.max_attempts` run out, when it becomes a dead letter.
+//! Every attempt is appended to the webhook's delivery log. Retries are held
+//! in memory, so deliveries in flight when the leader changes are lost.
+//!
+//! Requests carry the event kind in `x-logpose-event`, the delivery ID in
+//! `x-logpose-delivery`, and, for webhooks with a secret, the HMAC-SHA256 of
+//! `{x-logpose-timestamp}.{body}` as `x-logpose-signature: sha256=<hex>`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use logpose_core::webhook::{self, DeadLetter, DeliveryAttempt, EventKind, WebhookPayload};
+use logpose_core::{RegistryEvent, RegistryStore, Webhook};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::cluster::Registry;
+use crate::config::WebhooksConfig;
+
+pub const EVENT_HEADER: &str = "x-logpose-event";
+pub const DELIVERY_HEADER: &str = "x-logpose-delivery";
+/// Unix ms at which the request was signed.
+pub const TIMESTAMP_HEADER: &str = "x-logpose-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-logpose-signature";
+
+pub struct Dispatcher {
+    registry: Arc<Registry>,
+    config: WebhooksConfig,
+    client: reqwest::Client,
+}
+
+impl Dispatcher {
+    pub fn new(registry: Arc<Registry>, config: WebhooksConfig) -> Arc<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .build()
+            .expect("HTTP client configuration is valid");
+        Arc::new(Self { registry, config, client })
+    }
+
+    /// Delivers `events` to the webhooks subscribing to them, unless
+    /// `webhooks.enabled` is off.
+    pub fn spawn(self: &Arc<Self>, mut events: broadcast::Receiver<RegistryEvent>) {
+        if !self.config.enabled {
+            return;
+        }
+        let dispatcher = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => dispatcher.dispatch(&event),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!(missed, "webhook dispatcher fell behind; events were not delivered");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Starts a delivery of `event` to every matching webhook. Followers
+    /// leave deliveries to the leader.
+    fn dispatch(self: &Arc<Self>, event: &RegistryEvent) {
+        if !self.registry.is_leader() {
+            return;
+        }
+        let (Some(kind), Some(namespace)) = (EventKind::of(event), event.namespace()) else {
+            return;
+        };
+        let webhooks = match self.registry.get_webhooks(namespace) {
+            Ok(webhooks) => webhooks,
+            Err(e) => {
+                tracing::warn!(namespace, "failed to look up webhooks: {}", e);
+                return;
+            }
+        };
+        let timestamp = logpose_core::time::now();
+        for webhook in webhooks.into_iter().filter(|webhook| webhook.matches(event)) {
+            let payload = WebhookPayload { id: Uuid::new_v4(), webhook: webhook.id, kind, timestamp, event: event.clone() };
+            match serde_json::to_string(&payload) {
+                Ok(body) => self.deliver(webhook.id, payload.id, kind, body),
+                Err(e) => tracing::error!(webhook = %webhook.id, "failed to encode webhook payload: {}", e),
+            }
+        }
+    }
+
+    /// Sends `body` to the webhook in the background, retrying until it is
+    /// accepted or attempts run out.
+    pub fn deliver(self: &Arc<Self>, webhook: Uuid, delivery: Uuid, kind: EventKind, body: String) {
+        let dispatcher = self.clone();
+        tokio::spawn(async move {
+            let mut last_error = String::new();
+            for attempt in 1..=dispatcher.config.max_attempts {
+                if attempt > 1 {
+                    tokio::time::sleep(dispatcher.backoff(attempt - 1)).await;
+                }
+                // The webhook may have been deleted in the meantime, or this
+                // node may have stopped leading.
+                if !dispatcher.registry.is_leader() {
+                    return;
+                }
+                let Ok(target) = dispatcher.registry.get_webhook(&webhook) else {
+                    return;
+                };
+                let (status_code, error) = dispatcher.send(&target, delivery, kind, &body).await;
+                let record = DeliveryAttempt {
+                    delivery,
+                    webhook,
+                    kind,
+                    attempt,
+                    timestamp: logpose_core::time::now(),
+                    status_code,
+                    error: error.clone(),
+                };
+                if let Err(e) = dispatcher.registry.record_delivery(&record) {
+                    tracing::warn!(webhook = %webhook, %delivery, "failed to record webhook delivery: {}", e);
+                }
+                match error {
+                    None => return,
+                    Some(error) => {
+                        tracing::debug!(webhook = %webhook, %delivery, attempt, "webhook delivery failed: {}", error);
+                        last_error = error;
+                    }
+                }
+            }
+            tracing::warn!(webhook = %webhook, %delivery, "webhook delivery failed on every attempt: {}", last_error);
+            let dead_letter = DeadLetter {
+                delivery,
+                webhook,
+                kind,
+                payload: body,
+                attempts: dispatcher.config.max_attempts,
+                last_error,
+                failed_at: logpose_core::time::now(),
+            };
+            if let Err(e) = dispatcher.registry.add_dead_letter(&dead_letter) {
+                tracing::error!(webhook = %webhook, %delivery, "failed to store dead letter: {}", e);
+            }
+        });
+    }
+
+    /// Wait before the `retry`th retry: the initial backoff, doubled for
+    /// every retry after the first, up to `max_backoff_secs`.
+    fn backoff(&self, retry: u32) -> Duration {
+        let initial = Duration::from_millis(self.config.initial_backoff_ms);
+        let max = Duration::from_secs(self.config.max_backoff_secs);
+        initial.saturating_mul(2u32.saturating_pow(retry - 1)).min(max)
+    }
+
+    /// Sends one attempt; returns the status the endpoint answered with and,
+    /// unless it accepted the delivery, why it failed.
+    async fn send(&self, webhook: &Webhook, delivery: Uuid, kind: EventKind, body: &str) -> (Option<u16>, Option<String>) {
+        let timestamp = logpose_core::time::now();
+        let mut request = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, kind.to_string())
+            .header(DELIVERY_HEADER, delivery.to_string())
+            .header(TIMESTAMP_HEADER, timestamp.to_string());
+        if let Some(secret) = &webhook.secret {
+            let signature = webhook::sign(secret, timestamp, body.as_bytes());
+            request = request.header(SIGNATURE_HEADER, format!("sha256={}", signature));
+        }
+        match request.body(body.to_string()).send().await {
+            Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+            Ok(response) => (Some(response.status().as_u16()), Some(format!("endpoint answered {}", response.status()))),
+            Err(e) => (None, Some(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::{extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+    use logpose_db::DbRegistry;
+
+    use super::*;
+
+    /// A webhook endpoint on a loopback port that answers with `statuses` in
+    /// turn, then 200, and keeps every request it receives.
+    struct Endpoint {
+        url: String,
+        received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+    }
+
+    type EndpointState = (Arc<Mutex<Vec<(HeaderMap, String)>>>, Arc<Mutex<Vec<StatusCode>>>);
+
+    async fn receive(State((received, statuses)): State<EndpointState>, headers: HeaderMap, body: String) -> StatusCode {
+        received.lock().unwrap().push((headers, body));
+        let mut statuses = statuses.lock().unwrap();
+        if statuses.is_empty() { StatusCode::OK } else { statuses.remove(0) }
+    }
+
+    fn endpoint(statuses: Vec<StatusCode>) -> Endpoint {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state((received.clone(), Arc::new(Mutex::new(statuses))));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service());
+        tokio::spawn(server);
+        Endpoint { url, received }
+    }
+
+    fn config(max_attempts: u32) -> WebhooksConfig {
+        WebhooksConfig { max_attempts, initial_backoff_ms: 10, max_backoff_secs: 1, ..Default::default() }
+    }
+
+    fn dispatcher(config: WebhooksConfig) -> Arc<Dispatcher> {
+        let db = Arc::new(DbRegistry::new(":memory:").unwrap());
+        Dispatcher::new(Arc::new(Registry::standalone(db)), config)
+    }
+
+    fn subscribe(dispatcher: &Dispatcher, url: &str) -> Webhook {
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            namespace: "default".to_string(),
+            name: "alerts".to_string(),
+            url: url.to_string(),
+            events: vec![EventKind::ServiceRegistered],
+            services: "*".to_string(),
+            statuses: Vec::new(),
+            secret: Some("whsec_test".to_string()),
+            created_at: 0,
+        };
+        dispatcher.registry.add_webhook(&webhook).unwrap();
+        webhook
+    }
+
+    fn registered(code: &str) -> RegistryEvent {
+        RegistryEvent::ServiceRegistered { namespace: "default".to_string(), code: code.to_string() }
+    }
+
+    /// Waits for `done` to hold, for up to five seconds.
+    async fn eventually(done: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = WebhooksConfig { initial_backoff_ms: 1000, max_backoff_secs: 300, ..Default::default() };
+        let dispatcher = dispatcher(config);
+        assert_eq!(dispatcher.backoff(1), Duration::from_secs(1));
+        assert_eq!(dispatcher.backoff(2), Duration::from_secs(2));
+        assert_eq!(dispatcher.backoff(3), Duration::from_secs(4));
+        assert_eq!(dispatcher.backoff(9), Duration::from_secs(256));
+        assert_eq!(dispatcher.backoff(10), Duration::from_secs(300));
+        assert_eq!(dispatcher.backoff(100), Duration::from_secs(300));
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_payloads_and_retries_failures() {
+        let endpoint = endpoint(vec![StatusCode::INTERNAL_SERVER_ERROR]);
+        let dispatcher = dispatcher(config(3));
+        let webhook = subscribe(&dispatcher, &endpoint.url);
+
+        dispatcher.dispatch(&registered("auth"));
+        dispatcher.dispatch(&RegistryEvent::ServiceDeregistered { namespace: "default".to_string(), code: "auth".to_string() });
+        eventually(|| dispatcher.registry.get_deliveries(&webhook.id).unwrap().len() == 2).await;
+
+        let received = endpoint.received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2, "only the subscribed kind is delivered");
+        let (headers, body) = &received[1];
+        assert_eq!(headers[EVENT_HEADER], "service_registered");
+        let timestamp: u64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        let signature = format!("sha256={}", webhook::sign("whsec_test", timestamp, body.as_bytes()));
+        assert_eq!(headers[SIGNATURE_HEADER], signature.as_str());
+        let payload: WebhookPayload = serde_json::from_str(body).unwrap();
+        assert_eq!(payload.webhook, webhook.id);
+        assert_eq!(headers[DELIVERY_HEADER], payload.id.to_string().as_str());
+        assert_eq!(received[0].1, *body, "a retry resends the same payload");
+
+        let deliveries = dispatcher.registry.get_deliveries(&webhook.id).unwrap();
+        assert_eq!(deliveries.iter().map(|d| (d.attempt, d.status_code)).collect::<Vec<_>>(), [(2, Some(200)), (1, Some(500))]);
+        assert!(dispatcher.registry.get_dead_letters(&webhook.id).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn exhausted_deliveries_become_dead_letters() {
+        let endpoint = endpoint(vec![StatusCode::SERVICE_UNAVAILABLE; 3]);
+        let dispatcher = dispatcher(config(2));
+        let webhook = subscribe(&dispatcher, &endpoint.url);
+
+        dispatcher.dispatch(&registered("auth"));
+        eventually(|| !dispatcher.registry.get_dead_letters(&webhook.id).unwrap().is_empty()).await;
+
+        let dead_letter = dispatcher.registry.get_dead_letters(&webhook.id).unwrap().remove(0);
+        assert_eq!(dead_letter.attempts, 2);
+        assert_eq!(dead_letter.kind, EventKind::ServiceRegistered);
+        assert_eq!(endpoint.received.lock().unwrap().len(), 2);
+        assert_eq!(dead_letter.payload, endpoint.received.lock().unwrap()[1].1);
+    }
+}
@@ -0,0 +1,706 @@
+//! Envoy xDS control plane.
+//!
+//! Serves the Aggregated Discovery Service (ADS) over gRPC using the
+//! state-of-the-world protocol. Every LogPose service is published as an EDS
+//! `Cluster` (CDS) and its instances as a `ClusterLoadAssignment` (EDS).
+//! Snapshots are rebuilt on every [`RegistryEvent`] and pushed to all
+//! connected Envoys whose last acknowledged version is stale.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use logpose_core::{namespace, HealthStatus, RegistryError, RegistryEvent, RegistryStore, ServiceInstance, DEFAULT_NAMESPACE};
+use logpose_db::DbRegistry;
+use prost_types::Any;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tonic::codegen::{empty_body, http, Body, BoxFuture, StdError};
+use tonic::{Status, Streaming};
+
+pub const CLUSTER_TYPE_URL: &str = "type.googleapis.com/envoy.config.cluster.v3.Cluster";
+pub const ENDPOINT_TYPE_URL: &str =
+    "type.googleapis.com/envoy.config.endpoint.v3.ClusterLoadAssignment";
+
+const ADS_SERVICE: &str = "envoy.service.discovery.v3.AggregatedDiscoveryService";
+const STREAM_ADS_PATH: &str =
+    "/envoy.service.discovery.v3.AggregatedDiscoveryService/StreamAggregatedResources";
+
+/// Hand-written subset of the Envoy v3 API. Only the fields LogPose
+/// populates are declared; tags match the upstream `.proto` definitions.
+pub mod proto {
+    use prost_types::{Any, Duration};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Node {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub cluster: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct RpcStatus {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DiscoveryRequest {
+        #[prost(string, tag = "1")]
+        pub version_info: String,
+        #[prost(message, optional, tag = "2")]
+        pub node: Option<Node>,
+        #[prost(string, repeated, tag = "3")]
+        pub resource_names: Vec<String>,
+        #[prost(string, tag = "4")]
+        pub type_url: String,
+        #[prost(string, tag = "5")]
+        pub response_nonce: String,
+        #[prost(message, optional, tag = "6")]
+        pub error_detail: Option<RpcStatus>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DiscoveryResponse {
+        #[prost(string, tag = "1")]
+        pub version_info: String,
+        #[prost(message, repeated, tag = "2")]
+        pub resources: Vec<Any>,
+        #[prost(string, tag = "4")]
+        pub type_url: String,
+        #[prost(string, tag = "5")]
+        pub nonce: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AggregatedConfigSource {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ConfigSource {
+        #[prost(message, optional, tag = "3")]
+        pub ads: Option<AggregatedConfigSource>,
+        /// `envoy.config.core.v3.ApiVersion`; 2 is `V3`.
+        #[prost(int32, tag = "6")]
+        pub resource_api_version: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EdsClusterConfig {
+        #[prost(message, optional, tag = "1")]
+        pub eds_config: Option<ConfigSource>,
+        #[prost(string, tag = "2")]
+        pub service_name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Cluster {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        /// `Cluster.DiscoveryType`; 3 is `EDS`.
+        #[prost(int32, tag = "2")]
+        pub r#type: i32,
+        #[prost(message, optional, tag = "3")]
+        pub eds_cluster_config: Option<EdsClusterConfig>,
+        #[prost(message, optional, tag = "4")]
+        pub connect_timeout: Option<Duration>,
+        /// `Cluster.LbPolicy`; 0 is `ROUND_ROBIN`.
+        #[prost(int32, tag = "6")]
+        pub lb_policy: i32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UInt32Value {
+        #[prost(uint32, tag = "1")]
+        pub value: u32,
+    }
+
+    #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, prost::Message)]
+    pub struct Locality {
+        #[prost(string, tag = "1")]
+        pub region: String,
+        #[prost(string, tag = "2")]
+        pub zone: String,
+        #[prost(string, tag = "3")]
+        pub sub_zone: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SocketAddress {
+        /// `SocketAddress.Protocol`; 0 is `TCP`, 1 is `UDP`.
+        #[prost(int32, tag = "1")]
+        pub protocol: i32,
+        #[prost(string, tag = "2")]
+        pub address: String,
+        #[prost(uint32, tag = "3")]
+        pub port_value: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Address {
+        #[prost(message, optional, tag = "1")]
+        pub socket_address: Option<SocketAddress>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Endpoint {
+        #[prost(message, optional, tag = "1")]
+        pub address: Option<Address>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LbEndpoint {
+        #[prost(message, optional, tag = "1")]
+        pub endpoint: Option<Endpoint>,
+        /// `envoy.config.core.v3.HealthStatus`.
+        #[prost(int32, tag = "2")]
+        pub health_status: i32,
+        #[prost(message, optional, tag = "4")]
+        pub load_balancing_weight: Option<UInt32Value>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct LocalityLbEndpoints {
+        #[prost(message, optional, tag = "1")]
+        pub locality: Option<Locality>,
+        #[prost(message, repeated, tag = "2")]
+        pub lb_endpoints: Vec<LbEndpoint>,
+        #[prost(message, optional, tag = "3")]
+        pub load_balancing_weight: Option<UInt32Value>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ClusterLoadAssignment {
+        #[prost(string, tag = "1")]
+        pub cluster_name: String,
+        #[prost(message, repeated, tag = "2")]
+        pub endpoints: Vec<LocalityLbEndpoints>,
+    }
+
+    pub fn pack<M: prost::Message>(type_url: &str, message: &M) -> Any {
+        Any {
+            type_url: type_url.to_string(),
+            value: message.encode_to_vec(),
+        }
+    }
+}
+
+use proto::{DiscoveryRequest, DiscoveryResponse};
+
+/// A consistent view of the registry, encoded as xDS resources.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// 0 until the registry has been read successfully; nothing is served
+    /// before then
+    pub version: u64,
+    pub clusters: Vec<Any>,
+    pub endpoints: BTreeMap<String, Any>,
+}
+
+impl Snapshot {
+    fn build(registry: &DbRegistry, version: u64) -> Result<Self, RegistryError> {
+        let services = registry.get_all_services()?;
+        let mut instances: HashMap<(String, String), Vec<ServiceInstance>> = HashMap::new();
+        for instance in registry.get_all_instances()? {
+            let key = (instance.namespace.clone(), instance.service_name.clone());
+            instances.entry(key).or_default().push(instance);
+        }
+
+        let mut clusters = Vec::new();
+        let mut endpoints = BTreeMap::new();
+        for service in services {
+            let name = cluster_name(&service.namespace, &service.code);
+            let members = instances.remove(&(service.namespace, service.code)).unwrap_or_default();
+            clusters.push(proto::pack(CLUSTER_TYPE_URL, &cluster_for(&name)));
+            endpoints.insert(name.clone(), proto::pack(ENDPOINT_TYPE_URL, &load_assignment_for(&name, &members)));
+        }
+
+        Ok(Self { version, clusters, endpoints })
+    }
+
+    fn same_resources(&self, other: &Snapshot) -> bool {
+        self.clusters == other.clusters && self.endpoints == other.endpoints
+    }
+
+    fn resources(&self, type_url: &str, names: &[String]) -> Vec<Any> {
+        match type_url {
+            CLUSTER_TYPE_URL => self.clusters.clone(),
+            ENDPOINT_TYPE_URL if names.is_empty() => self.endpoints.values().cloned().collect(),
+            ENDPOINT_TYPE_URL => names
+                .iter()
+                .filter_map(|name| self.endpoints.get(name).cloned())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Services in the default namespace keep their bare code as cluster name;
+/// others are qualified with their namespace.
+fn cluster_name(namespace: &str, code: &str) -> String {
+    if namespace == DEFAULT_NAMESPACE {
+        code.to_string()
+    } else {
+        namespace::qualified_name(namespace, code)
+    }
+}
+
+fn cluster_for(code: &str) -> proto::Cluster {
+    proto::Cluster {
+        name: code.to_string(),
+        r#type: 3,
+        eds_cluster_config: Some(proto::EdsClusterConfig {
+            eds_config: Some(proto::ConfigSource {
+                ads: Some(proto::AggregatedConfigSource {}),
+                resource_api_version: 2,
+            }),
+            service_name: code.to_string(),
+        }),
+        connect_timeout: Some(prost_types::Duration { seconds: 5, nanos: 0 }),
+        lb_policy: 0,
+    }
+}
+
+/// Groups instances by the `region`/`zone`/`sub_zone` metadata keys and maps
+/// the optional `weight` key to the endpoint's load balancing weight.
+fn load_assignment_for(code: &str, instances: &[ServiceInstance]) -> proto::ClusterLoadAssignment {
+    let mut localities: BTreeMap<proto::Locality, Vec<proto::LbEndpoint>> = BTreeMap::new();
+    for instance in instances {
+        let locality = proto::Locality {
+            region: instance.get_metadata("region").cloned().unwrap_or_default(),
+            zone: instance.get_metadata("zone").cloned().unwrap_or_default(),
+            sub_zone: instance.get_metadata("sub_zone").cloned().unwrap_or_default(),
+        };
+        let weight = instance
+            .get_metadata("weight")
+            .and_then(|w| w.parse::<u32>().ok())
+            .filter(|w| *w > 0);
+
+        localities.entry(locality).or_default().push(proto::LbEndpoint {
+            endpoint: Some(proto::Endpoint {
+                address: Some(proto::Address {
+                    socket_address: Some(proto::SocketAddress {
+                        protocol: match instance.protocol {
+                            logpose_core::Protocol::Udp => 1,
+                            _ => 0,
+                        },
+                        address: instance.address.ip().to_string(),
+                        port_value: instance.address.port() as u32,
+                    }),
+                }),
+            }),
+            health_status: match instance.health {
+                HealthStatus::Unknown => 0,
+                HealthStatus::Healthy => 1,
+                HealthStatus::Unhealthy => 2,
+                // DEGRADED: used only when there are not enough healthy hosts
+                HealthStatus::Suspect => 5,
+            },
+            load_balancing_weight: weight.map(|value| proto::UInt32Value { value }),
+        });
+    }
+
+    proto::ClusterLoadAssignment {
+        cluster_name: code.to_string(),
+        endpoints: localities
+            .into_iter()
+            .map(|(locality, lb_endpoints)| proto::LocalityLbEndpoints {
+                locality: Some(locality),
+                lb_endpoints,
+                load_balancing_weight: None,
+            })
+            .collect(),
+    }
+}
+
+/// How long to wait before reading the registry again after a failed read.
+const REBUILD_RETRY: Duration = Duration::from_secs(5);
+
+/// Rebuilds the snapshot whenever the registry reports a change. A failed
+/// read of the registry keeps the previous snapshot, since an empty one
+/// would remove every cluster from Envoy, and is retried.
+pub fn spawn_snapshot_builder(
+    registry: Arc<DbRegistry>,
+    mut events: broadcast::Receiver<RegistryEvent>,
+) -> watch::Receiver<Arc<Snapshot>> {
+    let (tx, rx) = watch::channel(Arc::new(Snapshot::default()));
+    tokio::spawn(async move {
+        let mut stale = true;
+        loop {
+            if !stale {
+                match events.recv().await {
+                    // The key/value store and its sessions are not part of what xDS serves.
+                    Ok(RegistryEvent::KeyChanged { .. } | RegistryEvent::SessionDestroyed { .. }) => continue,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            let current = tx.borrow().clone();
+            match Snapshot::build(&registry, current.version + 1) {
+                Ok(next) => {
+                    stale = false;
+                    if current.version == 0 || !next.same_resources(&current) {
+                        tracing::debug!("xds snapshot version {}", next.version);
+                        tx.send_replace(Arc::new(next));
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("failed to read the registry for xds, keeping snapshot version {}: {}", current.version, e);
+                    stale = true;
+                    tokio::time::sleep(REBUILD_RETRY).await;
+                }
+            }
+        }
+    });
+    rx
+}
+
+#[derive(Default)]
+struct Subscription {
+    names: Vec<String>,
+    nonce: String,
+    sent_version: Option<u64>,
+}
+
+/// Drives one ADS stream: answers explicit requests and pushes a new
+/// response for every subscribed type when the snapshot version moves on.
+async fn run_stream(
+    mut requests: Streaming<DiscoveryRequest>,
+    mut snapshots: watch::Receiver<Arc<Snapshot>>,
+    responses: mpsc::Sender<Result<DiscoveryResponse, Status>>,
+) {
+    let mut subscriptions: HashMap<String, Subscription> = HashMap::new();
+    let mut nonce = 0u64;
+
+    loop {
+        let mut pending: Vec<String> = Vec::new();
+        tokio::select! {
+            request = requests.next() => {
+                let request = match request {
+                    Some(Ok(request)) => request,
+                    Some(Err(status)) => {
+                        tracing::debug!("xds stream closed: {}", status);
+                        return;
+                    }
+                    None => return,
+                };
+                let node = request.node.as_ref().map(|n| n.id.as_str()).unwrap_or_default();
+                if let Some(error) = &request.error_detail {
+                    tracing::warn!("envoy {} rejected {} version {}: {}", node, request.type_url, request.version_info, error.message);
+                }
+
+                let subscription = subscriptions.entry(request.type_url.clone()).or_default();
+                if !request.response_nonce.is_empty() && request.response_nonce != subscription.nonce {
+                    // Stale request for a response that has since been superseded.
+                    continue;
+                }
+                let names_changed = subscription.names != request.resource_names;
+                subscription.names = request.resource_names;
+                if request.response_nonce.is_empty() || names_changed {
+                    pending.push(request.type_url);
+                }
+            }
+            changed = snapshots.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let snapshot = snapshots.borrow_and_update().clone();
+        // Requests are answered once the first snapshot is built.
+        if snapshot.version == 0 {
+            continue;
+        }
+        for (type_url, subscription) in &subscriptions {
+            if subscription.sent_version != Some(snapshot.version) && !pending.contains(type_url) {
+                pending.push(type_url.clone());
+            }
+        }
+        for type_url in pending {
+            let subscription = subscriptions.entry(type_url.clone()).or_default();
+            nonce += 1;
+            subscription.nonce = nonce.to_string();
+            subscription.sent_version = Some(snapshot.version);
+            let response = DiscoveryResponse {
+                version_info: snapshot.version.to_string(),
+                resources: snapshot.resources(&type_url, &subscription.names),
+                type_url,
+                nonce: subscription.nonce.clone(),
+            };
+            if responses.send(Ok(response)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<DiscoveryResponse, Status>> + Send>>;
+
+/// gRPC service implementing `StreamAggregatedResources`. Delta xDS is not
+/// supported and answers with `UNIMPLEMENTED`.
+#[derive(Clone)]
+pub struct AdsServer {
+    snapshots: watch::Receiver<Arc<Snapshot>>,
+}
+
+impl AdsServer {
+    pub fn new(snapshots: watch::Receiver<Arc<Snapshot>>) -> Self {
+        Self { snapshots }
+    }
+}
+
+struct StreamAggregatedResources(watch::Receiver<Arc<Snapshot>>);
+
+impl tonic::server::StreamingService<DiscoveryRequest> for StreamAggregatedResources {
+    type Response = DiscoveryResponse;
+    type ResponseStream = ResponseStream;
+    type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<DiscoveryRequest>>) -> Self::Future {
+        let snapshots = self.0.clone();
+        Box::pin(async move {
+            let (tx, rx) = mpsc::channel(16);
+            tokio::spawn(run_stream(request.into_inner(), snapshots, tx));
+            Ok(tonic::Response::new(Box::pin(ReceiverStream::new(rx)) as ResponseStream))
+        })
+    }
+}
+
+impl<B> tonic::codegen::Service<http::Request<B>> for AdsServer
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if req.uri().path() == STREAM_ADS_PATH {
+            let service = StreamAggregatedResources(self.snapshots.clone());
+            return Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+                Ok(grpc.streaming(service, req).await)
+            });
+        }
+
+        Box::pin(async move {
+            Ok(http::Response::builder()
+                .status(200)
+                .header("grpc-status", (tonic::Code::Unimplemented as i32).to_string())
+                .header("content-type", "application/grpc")
+                .body(empty_body())
+                .unwrap())
+        })
+    }
+}
+
+impl tonic::server::NamedService for AdsServer {
+    const NAME: &'static str = ADS_SERVICE;
+}
+
+pub async fn serve(addr: SocketAddr, registry: Arc<DbRegistry>, events: broadcast::Receiver<RegistryEvent>) {
+    let snapshots = spawn_snapshot_builder(registry, events);
+    tracing::info!("xds listening on {}", addr);
+    if let Err(e) = tonic::transport::Server::builder()
+        .add_service(AdsServer::new(snapshots))
+        .serve(addr)
+        .await
+    {
+        tracing::error!("xds server failed: {}", e);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use logpose_core::{Protocol, Runtime, Service};
+    use prost::Message;
+    use tonic::codegen::http::uri::PathAndQuery;
+
+    use super::*;
+
+    fn service(registry: &DbRegistry, namespace: &str, code: &str) {
+        let mut service = Service::new(code, code, "");
+        service.namespace = namespace.to_string();
+        registry.add_service(&service).unwrap();
+    }
+
+    fn instance(registry: &DbRegistry, namespace: &str, code: &str, address: &str, health: HealthStatus, metadata: &[(&str, &str)]) {
+        let runtime = Runtime::Vm { provider: None, id: None };
+        let mut instance = ServiceInstance::new(code, address.parse().unwrap(), Protocol::Http, runtime, 0);
+        instance.namespace = namespace.to_string();
+        instance.health = health;
+        for (key, value) in metadata {
+            instance.add_metadata(*key, *value);
+        }
+        registry.add_instance(&instance).unwrap();
+    }
+
+    fn registry() -> DbRegistry {
+        let registry = DbRegistry::new(":memory:").unwrap();
+        service(&registry, DEFAULT_NAMESPACE, "billing");
+        service(&registry, "payments", "billing");
+        instance(&registry, DEFAULT_NAMESPACE, "billing", "10.0.0.1:8080", HealthStatus::Healthy, &[("region", "eu"), ("zone", "eu-1"), ("weight", "3")]);
+        instance(&registry, DEFAULT_NAMESPACE, "billing", "10.0.0.2:8080", HealthStatus::Unhealthy, &[("region", "eu"), ("zone", "eu-1"), ("weight", "0")]);
+        instance(&registry, DEFAULT_NAMESPACE, "billing", "[fd00::3]:8080", HealthStatus::Suspect, &[("region", "us")]);
+        instance(&registry, "payments", "billing", "10.1.0.1:9090", HealthStatus::Unknown, &[]);
+        registry
+    }
+
+    fn load_assignment(snapshot: &Snapshot, name: &str) -> proto::ClusterLoadAssignment {
+        proto::ClusterLoadAssignment::decode(snapshot.endpoints[name].value.as_slice()).unwrap()
+    }
+
+    /// `(address, port, health status, weight)` of every endpoint in `locality`.
+    fn endpoints(locality: &proto::LocalityLbEndpoints) -> Vec<(String, u32, i32, Option<u32>)> {
+        locality
+            .lb_endpoints
+            .iter()
+            .map(|lb| {
+                let address = lb.endpoint.clone().unwrap().address.unwrap().socket_address.unwrap();
+                (address.address, address.port_value, lb.health_status, lb.load_balancing_weight.clone().map(|w| w.value))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn services_become_eds_clusters_named_by_namespace() {
+        let snapshot = Snapshot::build(&registry(), 1).unwrap();
+        let clusters: Vec<proto::Cluster> =
+            snapshot.clusters.iter().map(|any| proto::Cluster::decode(any.value.as_slice()).unwrap()).collect();
+        let mut names: Vec<&str> = clusters.iter().map(|cluster| cluster.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["billing", "billing.payments"]);
+        for cluster in &clusters {
+            assert_eq!(cluster.r#type, 3);
+            let eds = cluster.eds_cluster_config.clone().unwrap();
+            assert_eq!(eds.service_name, cluster.name);
+            assert!(eds.eds_config.unwrap().ads.is_some());
+        }
+        assert_eq!(snapshot.endpoints.keys().collect::<Vec<_>>(), ["billing", "billing.payments"]);
+    }
+
+    #[test]
+    fn instances_are_grouped_by_locality_with_their_weight_and_health() {
+        let snapshot = Snapshot::build(&registry(), 1).unwrap();
+        let billing = load_assignment(&snapshot, "billing");
+        assert_eq!(billing.cluster_name, "billing");
+        let localities: Vec<(String, String)> = billing
+            .endpoints
+            .iter()
+            .map(|l| l.locality.clone().unwrap())
+            .map(|l| (l.region, l.zone))
+            .collect();
+        assert_eq!(localities, [("eu".to_string(), "eu-1".to_string()), ("us".to_string(), String::new())]);
+
+        let mut eu = endpoints(&billing.endpoints[0]);
+        eu.sort();
+        // A weight of 0 is not a valid Envoy weight and is left unset.
+        assert_eq!(eu, [("10.0.0.1".to_string(), 8080, 1, Some(3)), ("10.0.0.2".to_string(), 8080, 2, None)]);
+        assert_eq!(endpoints(&billing.endpoints[1]), [("fd00::3".to_string(), 8080, 5, None)]);
+
+        let payments = load_assignment(&snapshot, "billing.payments");
+        assert_eq!(payments.cluster_name, "billing.payments");
+        assert_eq!(payments.endpoints.len(), 1);
+        assert_eq!(payments.endpoints[0].locality, Some(proto::Locality::default()));
+        assert_eq!(endpoints(&payments.endpoints[0]), [("10.1.0.1".to_string(), 9090, 0, None)]);
+    }
+
+    /// An ADS stream to `server`, called in memory.
+    struct Stream {
+        requests: mpsc::Sender<DiscoveryRequest>,
+        responses: Streaming<DiscoveryResponse>,
+    }
+
+    impl Stream {
+        async fn open(server: AdsServer) -> Self {
+            let (requests, rx) = mpsc::channel(16);
+            let mut client = tonic::client::Grpc::new(server);
+            client.ready().await.unwrap();
+            let responses = client
+                .streaming(
+                    tonic::Request::new(ReceiverStream::new(rx)),
+                    PathAndQuery::from_static(STREAM_ADS_PATH),
+                    tonic::codec::ProstCodec::default(),
+                )
+                .await
+                .unwrap()
+                .into_inner();
+            Self { requests, responses }
+        }
+
+        async fn send(&self, version: &str, nonce: &str, error: Option<&str>) {
+            let request = DiscoveryRequest {
+                version_info: version.to_string(),
+                type_url: CLUSTER_TYPE_URL.to_string(),
+                response_nonce: nonce.to_string(),
+                error_detail: error.map(|message| proto::RpcStatus { code: 3, message: message.to_string() }),
+                ..Default::default()
+            };
+            self.requests.send(request).await.unwrap();
+        }
+
+        /// The `(version, nonce)` of the next response.
+        async fn next(&mut self) -> (String, String) {
+            let response = tokio::time::timeout(Duration::from_secs(5), self.responses.message()).await.unwrap().unwrap().unwrap();
+            (response.version_info, response.nonce)
+        }
+
+        async fn assert_idle(&mut self) {
+            let next = tokio::time::timeout(Duration::from_millis(200), self.responses.message()).await;
+            assert!(next.is_err(), "unexpected response {:?}", next);
+        }
+    }
+
+    fn snapshot(registry: &DbRegistry, version: u64) -> Arc<Snapshot> {
+        Arc::new(Snapshot::build(registry, version).unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_nack_is_not_answered_until_the_next_version() {
+        let registry = registry();
+        let (snapshots, rx) = watch::channel(snapshot(&registry, 1));
+        let mut stream = Stream::open(AdsServer::new(rx)).await;
+
+        stream.send("", "", None).await;
+        assert_eq!(stream.next().await, ("1".to_string(), "1".to_string()));
+        stream.send("1", "1", None).await;
+        stream.assert_idle().await;
+
+        snapshots.send_replace(snapshot(&registry, 2));
+        assert_eq!(stream.next().await, ("2".to_string(), "2".to_string()));
+        // Envoy keeps version 1 and says so; version 2 is not sent again.
+        stream.send("1", "2", Some("invalid cluster")).await;
+        stream.assert_idle().await;
+        // A request for a superseded response is ignored.
+        stream.send("1", "1", None).await;
+        stream.assert_idle().await;
+
+        snapshots.send_replace(snapshot(&registry, 3));
+        assert_eq!(stream.next().await, ("3".to_string(), "3".to_string()));
+        stream.send("3", "3", None).await;
+        stream.assert_idle().await;
+    }
+
+    #[tokio::test]
+    async fn requests_wait_for_the_first_snapshot() {
+        let (snapshots, rx) = watch::channel(Arc::new(Snapshot::default()));
+        let mut stream = Stream::open(AdsServer::new(rx)).await;
+        stream.send("", "", None).await;
+        stream.assert_idle().await;
+
+        snapshots.send_replace(snapshot(&registry(), 1));
+        assert_eq!(stream.next().await, ("1".to_string(), "1".to_string()));
+    }
+}